@@ -0,0 +1,128 @@
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use pcap_parser::{traits::PcapReaderIterator, *};
+use std::{fs::File, net::*, path::Path};
+
+const READ_BUFFER_SIZE: usize = 1 << 16;
+
+// Size of the Linux "cooked" capture header, used when capturing on the "any" interface
+const LINUX_SLL_HEADER_SIZE: usize = 16;
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+
+pub struct UdpDatagram<'a> {
+    pub timestamp_s: f64,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+fn parse_udp(link_type: Linktype, data: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let packet = match link_type {
+        Linktype::ETHERNET => SlicedPacket::from_ethernet(data).ok()?,
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => SlicedPacket::from_ip(data).ok()?,
+        Linktype::LINUX_SLL if data.len() > LINUX_SLL_HEADER_SIZE => {
+            match u16::from_be_bytes([data[14], data[15]]) {
+                ETHER_TYPE_IPV4 | ETHER_TYPE_IPV6 => {
+                    SlicedPacket::from_ip(&data[LINUX_SLL_HEADER_SIZE..]).ok()?
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    let (source_ip, destination_ip) = match packet.ip? {
+        InternetSlice::Ipv4(header) => {
+            // IP fragments cannot be decoded without reassembly. BridgeVR avoids them by using
+            // fragments that fit the path MTU.
+            if header.more_fragments() || header.fragments_offset() != 0 {
+                return None;
+            }
+            (
+                IpAddr::V4(header.source_addr()),
+                IpAddr::V4(header.destination_addr()),
+            )
+        }
+        InternetSlice::Ipv6(header, _) => (
+            IpAddr::V6(header.source_addr()),
+            IpAddr::V6(header.destination_addr()),
+        ),
+    };
+
+    if let Some(TransportSlice::Udp(header)) = packet.transport {
+        Some((
+            SocketAddr::new(source_ip, header.source_port()),
+            SocketAddr::new(destination_ip, header.destination_port()),
+            packet.payload,
+        ))
+    } else {
+        None
+    }
+}
+
+// Calls `datagram_callback` for each UDP datagram in a pcap or pcapng file. Other packets are
+// ignored.
+pub fn read_udp_datagrams(
+    path: &Path,
+    mut datagram_callback: impl FnMut(UdpDatagram),
+) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("Open {}: {}", path.display(), e))?;
+    let mut reader =
+        create_reader(READ_BUFFER_SIZE, file).map_err(|e| format!("Capture format: {:?}", e))?;
+
+    // pcapng files can contain multiple interfaces, each with its own link type and timestamp
+    // resolution
+    let mut legacy_link_type = Linktype::ETHERNET;
+    let mut interfaces: Vec<(Linktype, u64, u64)> = vec![];
+
+    loop {
+        match reader.next() {
+            Ok((offset, block)) => {
+                match block {
+                    PcapBlockOwned::LegacyHeader(header) => legacy_link_type = header.network,
+                    PcapBlockOwned::Legacy(block) => {
+                        if let Some((source, destination, payload)) =
+                            parse_udp(legacy_link_type, block.data)
+                        {
+                            datagram_callback(UdpDatagram {
+                                timestamp_s: block.ts_sec as f64 + block.ts_usec as f64 / 1e6,
+                                source,
+                                destination,
+                                payload,
+                            });
+                        }
+                    }
+                    PcapBlockOwned::NG(Block::SectionHeader(_)) => interfaces.clear(),
+                    PcapBlockOwned::NG(Block::InterfaceDescription(interface)) => {
+                        let resolution = build_ts_resolution(interface.if_tsresol).unwrap_or(1);
+                        interfaces.push((interface.linktype, interface.if_tsoffset, resolution));
+                    }
+                    PcapBlockOwned::NG(Block::EnhancedPacket(packet)) => {
+                        if let Some(&(link_type, ts_offset, resolution)) =
+                            interfaces.get(packet.if_id as usize)
+                        {
+                            if let Some((source, destination, payload)) =
+                                parse_udp(link_type, packet.data)
+                            {
+                                let (ts_sec, ts_frac) = packet.decode_ts(ts_offset, resolution);
+                                datagram_callback(UdpDatagram {
+                                    timestamp_s: ts_sec as f64 + ts_frac as f64 / resolution as f64,
+                                    source,
+                                    destination,
+                                    payload,
+                                });
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                reader.consume(offset);
+            }
+            Err(PcapError::Eof) => break Ok(()),
+            Err(PcapError::Incomplete) => reader
+                .refill()
+                .map_err(|e| format!("Capture read: {:?}", e))?,
+            Err(e) => break Err(format!("Capture read: {:?}", e)),
+        }
+    }
+}
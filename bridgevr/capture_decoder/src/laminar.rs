@@ -0,0 +1,328 @@
+// Parser for the Laminar 0.3 packet headers. It must be kept in sync with the Laminar version used
+// by bridgevr_common. All fields are big endian.
+//
+// Packet layout:
+// * standard header: protocol version CRC (u16), packet type (u8), delivery guarantee (u8),
+//   ordering guarantee (u8)
+// * packet type "packet": acked header (only if reliable), arranging header (only if sequenced or
+//   ordered), payload
+// * packet type "fragment": fragment header, acked header (only for the first fragment), payload
+// * packet type "heartbeat": no payload
+
+use std::collections::HashMap;
+
+const STANDARD_HEADER_SIZE: usize = 5;
+const ACKED_HEADER_SIZE: usize = 8;
+const ARRANGING_HEADER_SIZE: usize = 3;
+const FRAGMENT_HEADER_SIZE: usize = 4;
+
+const PACKET_TYPE_PACKET: u8 = 0;
+const PACKET_TYPE_FRAGMENT: u8 = 1;
+const PACKET_TYPE_HEARTBEAT: u8 = 2;
+
+const DELIVERY_RELIABLE: u8 = 1;
+const ORDERING_NONE: u8 = 0;
+
+pub enum Datagram<'a> {
+    Packet {
+        // Only reliable packets have a sequence number
+        sequence: Option<u16>,
+        payload: &'a [u8],
+    },
+    Fragment {
+        // Shared by all fragments of the same packet
+        sequence: u16,
+        id: u8,
+        count: u8,
+        payload: &'a [u8],
+    },
+    Heartbeat,
+}
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    u16::from_be_bytes([buffer[0], buffer[1]])
+}
+
+fn check_size(buffer: &[u8], header_size: usize) -> Result<(), String> {
+    if buffer.len() >= header_size {
+        Ok(())
+    } else {
+        Err(format!("Truncated header: {} bytes left", buffer.len()))
+    }
+}
+
+pub fn parse_datagram(buffer: &[u8]) -> Result<Datagram, String> {
+    if buffer.len() < STANDARD_HEADER_SIZE {
+        return Err("Datagram too small".into());
+    }
+    let packet_type = buffer[2];
+    let delivery = buffer[3];
+    let ordering = buffer[4];
+    let body = &buffer[STANDARD_HEADER_SIZE..];
+
+    match packet_type {
+        PACKET_TYPE_PACKET => {
+            let mut offset = 0;
+            let sequence = if delivery == DELIVERY_RELIABLE {
+                check_size(body, ACKED_HEADER_SIZE)?;
+                offset += ACKED_HEADER_SIZE;
+                Some(read_u16(body))
+            } else {
+                None
+            };
+            if ordering != ORDERING_NONE {
+                check_size(&body[offset..], ARRANGING_HEADER_SIZE)?;
+                offset += ARRANGING_HEADER_SIZE;
+            }
+
+            Ok(Datagram::Packet {
+                sequence,
+                payload: &body[offset..],
+            })
+        }
+        PACKET_TYPE_FRAGMENT => {
+            check_size(body, FRAGMENT_HEADER_SIZE)?;
+            let sequence = read_u16(body);
+            let id = body[2];
+            let count = body[3];
+
+            let mut offset = FRAGMENT_HEADER_SIZE;
+            if id == 0 {
+                check_size(&body[offset..], ACKED_HEADER_SIZE)?;
+                offset += ACKED_HEADER_SIZE;
+            }
+            if count == 0 || id >= count {
+                return Err(format!("Invalid fragment {} of {}", id, count));
+            }
+
+            Ok(Datagram::Fragment {
+                sequence,
+                id,
+                count,
+                payload: &body[offset..],
+            })
+        }
+        PACKET_TYPE_HEARTBEAT => Ok(Datagram::Heartbeat),
+        _ => Err(format!("Unknown packet type {}", packet_type)),
+    }
+}
+
+struct PartialPacket {
+    fragments: Vec<Option<Vec<u8>>>,
+    received_count: usize,
+}
+
+// Reassembles the fragments of one direction of the connection
+#[derive(Default)]
+pub struct FragmentAssembler {
+    partial_packets: HashMap<u16, PartialPacket>,
+}
+
+impl FragmentAssembler {
+    // Returns the whole payload when the last missing fragment is received
+    pub fn push(&mut self, sequence: u16, id: u8, count: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        let partial_packet =
+            self.partial_packets
+                .entry(sequence)
+                .or_insert_with(|| PartialPacket {
+                    fragments: vec![None; count as usize],
+                    received_count: 0,
+                });
+
+        // Laminar sequence numbers wrap around. A stale packet with a different fragment count is
+        // replaced.
+        if partial_packet.fragments.len() != count as usize {
+            *partial_packet = PartialPacket {
+                fragments: vec![None; count as usize],
+                received_count: 0,
+            };
+        }
+
+        let fragment = &mut partial_packet.fragments[id as usize];
+        if fragment.is_none() {
+            *fragment = Some(payload.to_vec());
+            partial_packet.received_count += 1;
+        }
+
+        if partial_packet.received_count == partial_packet.fragments.len() {
+            let partial_packet = self.partial_packets.remove(&sequence).unwrap();
+            Some(
+                partial_packet
+                    .fragments
+                    .into_iter()
+                    .flat_map(Option::unwrap)
+                    .collect(),
+            )
+        } else {
+            None
+        }
+    }
+
+    // Packets for which at least one fragment was lost
+    pub fn incomplete_count(&self) -> usize {
+        self.partial_packets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROTOCOL_CRC: [u8; 2] = [0x12, 0x34];
+    const DELIVERY_UNRELIABLE: u8 = 0;
+    const ORDERING_ORDERED: u8 = 2;
+
+    fn standard_header(packet_type: u8, delivery: u8, ordering: u8) -> Vec<u8> {
+        vec![
+            PROTOCOL_CRC[0],
+            PROTOCOL_CRC[1],
+            packet_type,
+            delivery,
+            ordering,
+        ]
+    }
+
+    // Sequence number followed by the ack fields, which are not parsed
+    fn acked_header(sequence: u16) -> Vec<u8> {
+        let mut header = sequence.to_be_bytes().to_vec();
+        header.extend(&[0xAA; ACKED_HEADER_SIZE - 2]);
+        header
+    }
+
+    fn fragment_header(sequence: u16, id: u8, count: u8) -> Vec<u8> {
+        let mut header = sequence.to_be_bytes().to_vec();
+        header.extend(&[id, count]);
+        header
+    }
+
+    #[test]
+    fn unreliable_packet() {
+        let mut buffer = standard_header(PACKET_TYPE_PACKET, DELIVERY_UNRELIABLE, ORDERING_NONE);
+        buffer.extend(&[1, 2, 3]);
+
+        match parse_datagram(&buffer) {
+            Ok(Datagram::Packet { sequence, payload }) => {
+                assert_eq!(sequence, None);
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reliable_packet() {
+        let mut buffer = standard_header(PACKET_TYPE_PACKET, DELIVERY_RELIABLE, ORDERING_NONE);
+        buffer.extend(acked_header(0x0102));
+        buffer.extend(&[1, 2, 3]);
+
+        match parse_datagram(&buffer) {
+            Ok(Datagram::Packet { sequence, payload }) => {
+                assert_eq!(sequence, Some(0x0102));
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reliable_ordered_packet() {
+        let mut buffer = standard_header(PACKET_TYPE_PACKET, DELIVERY_RELIABLE, ORDERING_ORDERED);
+        buffer.extend(acked_header(7));
+        buffer.extend(&[0xBB; ARRANGING_HEADER_SIZE]);
+        buffer.extend(&[1, 2, 3]);
+
+        match parse_datagram(&buffer) {
+            Ok(Datagram::Packet { sequence, payload }) => {
+                assert_eq!(sequence, Some(7));
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn first_fragment() {
+        let mut buffer = standard_header(PACKET_TYPE_FRAGMENT, DELIVERY_RELIABLE, ORDERING_NONE);
+        buffer.extend(fragment_header(0x0304, 0, 3));
+        buffer.extend(acked_header(0x0304));
+        buffer.extend(&[1, 2, 3]);
+
+        match parse_datagram(&buffer) {
+            Ok(Datagram::Fragment {
+                sequence,
+                id,
+                count,
+                payload,
+            }) => {
+                assert_eq!((sequence, id, count), (0x0304, 0, 3));
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn later_fragment() {
+        let mut buffer = standard_header(PACKET_TYPE_FRAGMENT, DELIVERY_RELIABLE, ORDERING_NONE);
+        buffer.extend(fragment_header(0x0304, 2, 3));
+        buffer.extend(&[4, 5]);
+
+        match parse_datagram(&buffer) {
+            Ok(Datagram::Fragment {
+                sequence,
+                id,
+                count,
+                payload,
+            }) => {
+                assert_eq!((sequence, id, count), (0x0304, 2, 3));
+                assert_eq!(payload, &[4, 5]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn invalid_datagrams() {
+        assert!(parse_datagram(&[0; STANDARD_HEADER_SIZE - 1]).is_err());
+
+        let mut buffer = standard_header(PACKET_TYPE_PACKET, DELIVERY_RELIABLE, ORDERING_NONE);
+        buffer.extend(&[0; ACKED_HEADER_SIZE - 1]);
+        assert!(parse_datagram(&buffer).is_err());
+
+        let mut buffer = standard_header(PACKET_TYPE_FRAGMENT, DELIVERY_RELIABLE, ORDERING_NONE);
+        buffer.extend(fragment_header(0, 3, 3));
+        assert!(parse_datagram(&buffer).is_err());
+
+        assert!(parse_datagram(&standard_header(7, 0, 0)).is_err());
+
+        assert!(matches!(
+            parse_datagram(&standard_header(PACKET_TYPE_HEARTBEAT, 0, 0)),
+            Ok(Datagram::Heartbeat)
+        ));
+    }
+
+    #[test]
+    fn fragment_reassembly() {
+        let mut assembler = FragmentAssembler::default();
+
+        // Out of order and duplicated fragments
+        assert_eq!(assembler.push(10, 2, 3, &[5]), None);
+        assert_eq!(assembler.push(10, 0, 3, &[1, 2]), None);
+        assert_eq!(assembler.push(10, 2, 3, &[5]), None);
+        assert_eq!(assembler.incomplete_count(), 1);
+        assert_eq!(assembler.push(10, 1, 3, &[3, 4]), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(assembler.incomplete_count(), 0);
+
+        // A lost fragment leaves the packet incomplete
+        assert_eq!(assembler.push(11, 0, 2, &[1]), None);
+        assert_eq!(assembler.push(12, 0, 1, &[2]), Some(vec![2]));
+        assert_eq!(assembler.incomplete_count(), 1);
+
+        // After wrap around, a stale packet with the same sequence and a different fragment count
+        // is replaced
+        assert_eq!(assembler.push(11, 1, 3, &[4]), None);
+        assert_eq!(assembler.push(11, 0, 3, &[3]), None);
+        assert_eq!(assembler.push(11, 2, 3, &[5]), Some(vec![3, 4, 5]));
+        assert_eq!(assembler.incomplete_count(), 0);
+    }
+}
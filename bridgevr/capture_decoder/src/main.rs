@@ -0,0 +1,512 @@
+mod capture;
+mod laminar;
+
+use bridgevr_common::{data::*, sockets::StreamType};
+use capture::*;
+use laminar::*;
+use pico_args::Arguments;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::exit,
+};
+
+const HANDSHAKE_PORT: u16 = 9943;
+const DEFAULT_PORT: u16 = 9944;
+
+// Same threshold as the audio player: bigger jumps of the audio sequence numbers mean that the
+// sender restarted the stream, they are not counted as lost packets.
+const MAX_SEQUENCE_JUMP: u64 = 1000;
+
+fn print_help() {
+    println!(
+        r#"
+bridgevr_capture_decoder
+Decode BridgeVR traffic from a pcap or pcapng capture.
+
+USAGE:
+    bridgevr_capture_decoder [FLAGS] <CAPTURE FILE>
+
+FLAGS:
+    --server-ip <IP>    IP of the server. If omitted, it is inferred from the client handshake
+    --port <PORT>       Port used by the server and the client [default: 9944]
+    --json <PATH>       Export decoded packets and statistics as JSON instead of printing them
+    --stats-only        Print only the per stream statistics
+    -h, --help          Print this message
+
+Only UDP traffic can be decoded. Tethered connections using the TCP tunnel are not supported.
+"#
+    );
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Direction {
+    ServerToClient,
+    ClientToServer,
+}
+
+#[derive(Serialize, Default)]
+struct StreamStatistics {
+    packet_count: usize,
+    total_bytes: usize,
+    decode_error_count: usize,
+    first_timestamp_s: f64,
+    last_timestamp_s: f64,
+    average_bitrate_mbps: f64,
+    average_interval_ms: f64,
+    max_interval_ms: f64,
+
+    // Only for video streams. Indices of the NALs are used as sequence numbers.
+    incomplete_nal_count: Option<usize>,
+
+    // Only for audio streams. Sequence numbers missing from the capture.
+    lost_audio_packet_count: Option<u64>,
+}
+
+#[derive(Default)]
+struct StreamState {
+    statistics: StreamStatistics,
+
+    // NAL index -> (received sub NALs, sub NAL count)
+    nals: HashMap<u64, (usize, usize)>,
+
+    audio_sequences: HashSet<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct DirectionStatistics {
+    datagram_count: usize,
+    invalid_datagram_count: usize,
+    heartbeat_count: usize,
+    incomplete_fragmented_packet_count: usize,
+
+    // Estimated from the gaps in the sequence numbers of reliable packets
+    reliable_packet_loss: Option<f64>,
+}
+
+#[derive(Default)]
+struct DirectionState {
+    statistics: DirectionStatistics,
+    assembler: FragmentAssembler,
+    first_sequence: Option<u16>,
+    last_sequence: u16,
+    // Laminar sequence numbers wrap around, this counts the sequence numbers seen so far
+    sequence_span: u64,
+    reliable_count: u64,
+}
+
+impl DirectionState {
+    fn push_sequence(&mut self, sequence: u16) {
+        self.reliable_count += 1;
+        if self.first_sequence.is_none() {
+            self.first_sequence = Some(sequence);
+            self.last_sequence = sequence;
+            self.sequence_span = 1;
+            return;
+        }
+
+        // Retransmissions and reordered packets don't advance the span
+        let advance = sequence.wrapping_sub(self.last_sequence);
+        if advance != 0 && advance < u16::MAX / 2 {
+            self.sequence_span += advance as u64;
+            self.last_sequence = sequence;
+        }
+    }
+}
+
+// Counts the sequence numbers missing inside each run of received ones. Runs are split where the
+// stream was restarted.
+fn lost_sequence_count(sequences: &HashSet<u64>) -> u64 {
+    let mut sequences: Vec<_> = sequences.iter().copied().collect();
+    sequences.sort_unstable();
+
+    sequences
+        .windows(2)
+        .map(|pair| pair[1] - pair[0] - 1)
+        .filter(|&missing_count| missing_count <= MAX_SEQUENCE_JUMP)
+        .sum()
+}
+
+struct Decoder {
+    server_ip: IpAddr,
+    port: u16,
+    stats_only: bool,
+    json_packets: Option<Vec<Value>>,
+    directions: BTreeMap<Direction, DirectionState>,
+    streams: BTreeMap<(Direction, u8), StreamState>,
+}
+
+fn stream_name(stream_id: u8) -> String {
+    match StreamType::from(stream_id) {
+        StreamType::VideoSlice(idx) => format!("video slice {}", idx),
+        StreamType::GameAudio => "game audio".into(),
+        StreamType::Microphone => "microphone".into(),
+        StreamType::Probe => "probe".into(),
+        StreamType::Other => "other".into(),
+    }
+}
+
+fn to_json(packet: impl Serialize) -> Value {
+    serde_json::to_value(packet).unwrap_or_else(|e| json!({ "error": e.to_string() }))
+}
+
+fn decode_packet(direction: Direction, stream_id: u8, data: &[u8]) -> Result<Value, String> {
+    let value = match (StreamType::from(stream_id), direction) {
+        (StreamType::VideoSlice(_), _) => {
+            let packet = deserialize_bounded::<VideoPacket>(data, data.len() as _)?;
+            json!({
+                "nal_index": packet.nal_index,
+                "sub_nal_index": packet.sub_nal_index,
+                "sub_nal_count": packet.sub_nal_count,
+                "hmd_pose": to_json(&packet.hmd_pose),
+                "capture_timestamp_ns": packet.capture_timestamp_ns,
+                "sub_nal_size": packet.sub_nal.len(),
+            })
+        }
+        (StreamType::GameAudio, _) | (StreamType::Microphone, _) => {
+            let packet = deserialize_bounded::<AudioPacket>(data, data.len() as _)?;
+            json!({
+                "sequence": packet.sequence,
+                "sample_count": packet.sample_count,
+                "capture_timestamp_ns": packet.capture_timestamp_ns,
+                "samples_size": packet.samples.len(),
+            })
+        }
+        (StreamType::Probe, _) => {
+            match deserialize_bounded::<ProbePacket>(data, data.len() as _)? {
+                ProbePacket::Data { sequence, padding } => {
+                    json!({ "Data": { "sequence": sequence, "padding_size": padding.len() } })
+                }
+                packet => to_json(packet),
+            }
+        }
+        (StreamType::Other, Direction::ServerToClient) => {
+            let packet = deserialize_bounded::<OtherServerPacket>(data, data.len() as _)?;
+            to_json(packet)
+        }
+        (StreamType::Other, Direction::ClientToServer) => {
+            let packet = deserialize_bounded::<OtherClientPacket>(data, data.len() as _)?;
+            to_json(packet)
+        }
+    };
+
+    Ok(value)
+}
+
+impl Decoder {
+    fn record(&mut self, timestamp_s: f64, direction: Direction, content: Value) {
+        if let Some(packets) = &mut self.json_packets {
+            packets.push(json!({
+                "timestamp_s": timestamp_s,
+                "direction": direction,
+                "content": content,
+            }));
+        } else if !self.stats_only {
+            let arrow = match direction {
+                Direction::ServerToClient => "S->C",
+                Direction::ClientToServer => "C->S",
+            };
+            println!("{:.6} {} {}", timestamp_s, arrow, content);
+        }
+    }
+
+    fn push_handshake(&mut self, datagram: &UdpDatagram) {
+        let content = match deserialize_bounded::<ClientHandshakePacket>(
+            datagram.payload,
+            MAX_CLIENT_HANDSHAKE_PACKET_SIZE,
+        ) {
+            Ok(packet) => to_json(packet),
+            Err(e) => json!({ "error": e }),
+        };
+        let content = json!({ "client handshake": content });
+        self.record(datagram.timestamp_s, Direction::ClientToServer, content);
+    }
+
+    fn push_payload(&mut self, timestamp_s: f64, direction: Direction, payload: &[u8]) {
+        let (stream_id, data) = match payload.split_first() {
+            Some((&stream_id, data)) => (stream_id, data),
+            None => return,
+        };
+
+        let decoded = decode_packet(direction, stream_id, data);
+
+        let stream = self.streams.entry((direction, stream_id)).or_default();
+        let statistics = &mut stream.statistics;
+        if statistics.packet_count == 0 {
+            statistics.first_timestamp_s = timestamp_s;
+        } else {
+            let interval_ms = (timestamp_s - statistics.last_timestamp_s) * 1000.;
+            statistics.max_interval_ms = f64::max(statistics.max_interval_ms, interval_ms);
+        }
+        statistics.last_timestamp_s = timestamp_s;
+        statistics.packet_count += 1;
+        statistics.total_bytes += payload.len();
+
+        match &decoded {
+            Ok(value) => {
+                if let (Some(nal_index), Some(sub_nal_count)) =
+                    (value["nal_index"].as_u64(), value["sub_nal_count"].as_u64())
+                {
+                    let nal = stream
+                        .nals
+                        .entry(nal_index)
+                        .or_insert((0, sub_nal_count as usize));
+                    nal.0 += 1;
+                } else if let (Some(sequence), Some(_)) =
+                    (value["sequence"].as_u64(), value["sample_count"].as_u64())
+                {
+                    stream.audio_sequences.insert(sequence);
+                }
+            }
+            Err(_) => statistics.decode_error_count += 1,
+        }
+
+        let content = match decoded {
+            Ok(value) => json!({ stream_name(stream_id): value }),
+            Err(e) => json!({ stream_name(stream_id): { "error": e } }),
+        };
+        self.record(timestamp_s, direction, content);
+    }
+
+    fn push_datagram(&mut self, datagram: UdpDatagram) {
+        if datagram.destination.port() == HANDSHAKE_PORT {
+            self.push_handshake(&datagram);
+            return;
+        }
+
+        let server_address = SocketAddr::new(self.server_ip, self.port);
+        let direction = if datagram.source == server_address {
+            Direction::ServerToClient
+        } else if datagram.destination == server_address {
+            Direction::ClientToServer
+        } else {
+            return;
+        };
+
+        let state = self.directions.entry(direction).or_default();
+        state.statistics.datagram_count += 1;
+
+        let payload = match parse_datagram(datagram.payload) {
+            Ok(Datagram::Packet { sequence, payload }) => {
+                if let Some(sequence) = sequence {
+                    state.push_sequence(sequence);
+                }
+                payload.to_vec()
+            }
+            Ok(Datagram::Fragment {
+                sequence,
+                id,
+                count,
+                payload,
+            }) => {
+                if id == 0 {
+                    state.push_sequence(sequence);
+                }
+                match state.assembler.push(sequence, id, count, payload) {
+                    Some(payload) => payload,
+                    None => return,
+                }
+            }
+            Ok(Datagram::Heartbeat) => {
+                state.statistics.heartbeat_count += 1;
+                return;
+            }
+            Err(_) => {
+                state.statistics.invalid_datagram_count += 1;
+                return;
+            }
+        };
+
+        self.push_payload(datagram.timestamp_s, direction, &payload);
+    }
+
+    fn finish(mut self) -> Value {
+        for state in self.directions.values_mut() {
+            let statistics = &mut state.statistics;
+            statistics.incomplete_fragmented_packet_count = state.assembler.incomplete_count();
+            if state.sequence_span > 0 {
+                let received = u64::min(state.reliable_count, state.sequence_span);
+                statistics.reliable_packet_loss =
+                    Some(1. - received as f64 / state.sequence_span as f64);
+            }
+        }
+
+        for ((_, stream_id), stream) in &mut self.streams {
+            let statistics = &mut stream.statistics;
+            let duration_s = statistics.last_timestamp_s - statistics.first_timestamp_s;
+            if duration_s > 0. {
+                statistics.average_bitrate_mbps =
+                    statistics.total_bytes as f64 * 8. / duration_s / 1e6;
+            }
+            if statistics.packet_count > 1 {
+                statistics.average_interval_ms =
+                    duration_s * 1000. / (statistics.packet_count - 1) as f64;
+            }
+            if let StreamType::VideoSlice(_) = StreamType::from(*stream_id) {
+                statistics.incomplete_nal_count = Some(
+                    stream
+                        .nals
+                        .values()
+                        .filter(|(received, count)| received < count)
+                        .count(),
+                );
+            }
+            if !stream.audio_sequences.is_empty() {
+                statistics.lost_audio_packet_count =
+                    Some(lost_sequence_count(&stream.audio_sequences));
+            }
+        }
+
+        json!({
+            "directions": self
+                .directions
+                .iter()
+                .map(|(direction, state)| json!({
+                    "direction": direction,
+                    "statistics": to_json(&state.statistics),
+                }))
+                .collect::<Vec<_>>(),
+            "streams": self
+                .streams
+                .iter()
+                .map(|((direction, stream_id), stream)| json!({
+                    "direction": direction,
+                    "stream": stream_name(*stream_id),
+                    "statistics": to_json(&stream.statistics),
+                }))
+                .collect::<Vec<_>>(),
+            "packets": self.json_packets,
+        })
+    }
+}
+
+fn run(
+    capture_path: PathBuf,
+    server_ip: Option<IpAddr>,
+    port: u16,
+    json_path: Option<PathBuf>,
+    stats_only: bool,
+) -> Result<(), String> {
+    // The server IP is needed to tell the direction of each datagram. If not provided, the first
+    // pass looks for a client handshake and assumes the only other peer on the port is the server.
+    let server_ip = match server_ip {
+        Some(ip) => ip,
+        None => {
+            let mut client_ip = None;
+            let mut server_ip = None;
+            read_udp_datagrams(&capture_path, |datagram| {
+                if datagram.destination.port() == HANDSHAKE_PORT && client_ip.is_none() {
+                    client_ip = Some(datagram.source.ip());
+                } else if let Some(client_ip) = client_ip {
+                    if server_ip.is_none()
+                        && datagram.destination.ip() == client_ip
+                        && datagram.source.port() == port
+                    {
+                        server_ip = Some(datagram.source.ip());
+                    }
+                }
+            })?;
+            server_ip.ok_or_else(|| "Cannot find the server IP, use --server-ip".to_owned())?
+        }
+    };
+
+    let mut decoder = Decoder {
+        server_ip,
+        port,
+        stats_only,
+        json_packets: json_path.as_ref().map(|_| vec![]),
+        directions: BTreeMap::new(),
+        streams: BTreeMap::new(),
+    };
+
+    read_udp_datagrams(&capture_path, |datagram| decoder.push_datagram(datagram))?;
+
+    let report = decoder.finish();
+
+    if let Some(path) = json_path {
+        let json_string = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+        fs::write(&path, json_string).map_err(|e| format!("Write {}: {}", path.display(), e))
+    } else {
+        println!("\nStatistics:");
+        for entry in report["directions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(report["streams"].as_array().unwrap())
+        {
+            println!("{}", entry);
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let mut args = Arguments::from_env();
+
+    if args.contains(["-h", "--help"]) {
+        print_help();
+        return;
+    }
+
+    let parse_args = || -> Result<_, pico_args::Error> {
+        let server_ip = args.opt_value_from_str("--server-ip")?;
+        let port = args.opt_value_from_str("--port")?.unwrap_or(DEFAULT_PORT);
+        let json_path = args.opt_value_from_str::<_, String>("--json")?;
+        let stats_only = args.contains("--stats-only");
+        let free_args = args.free()?;
+        Ok((server_ip, port, json_path, stats_only, free_args))
+    };
+
+    let (server_ip, port, json_path, stats_only, free_args) = match parse_args() {
+        Ok(values) if values.4.len() == 1 => values,
+        _ => {
+            println!("\nWrong arguments.");
+            print_help();
+            exit(1);
+        }
+    };
+
+    if let Err(e) = run(
+        PathBuf::from(&free_args[0]),
+        server_ip,
+        port,
+        json_path.map(PathBuf::from),
+        stats_only,
+    ) {
+        println!("{}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_audio_packets() {
+        let sequences = (0..100).filter(|&sequence| sequence % 10 != 5).collect();
+        assert_eq!(lost_sequence_count(&sequences), 10);
+
+        // The sender restarted the stream
+        let sequences = (0..10)
+            .chain(100_000..100_010)
+            .filter(|&sequence| sequence != 3)
+            .collect();
+        assert_eq!(lost_sequence_count(&sequences), 1);
+
+        let sequences = (0..10)
+            .chain(10 + MAX_SEQUENCE_JUMP..11 + MAX_SEQUENCE_JUMP)
+            .collect();
+        assert_eq!(lost_sequence_count(&sequences), MAX_SEQUENCE_JUMP);
+        let sequences = (0..10)
+            .chain(11 + MAX_SEQUENCE_JUMP..12 + MAX_SEQUENCE_JUMP)
+            .collect();
+        assert_eq!(lost_sequence_count(&sequences), 0);
+
+        assert_eq!(lost_sequence_count(&[42].iter().copied().collect()), 0);
+    }
+}
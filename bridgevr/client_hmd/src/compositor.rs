@@ -20,6 +20,12 @@ impl Compositor {
         todo!();
     }
 
+    // The client has no video receive and decode loops yet and this compositor does not display
+    // frames, so the Receive, Decode and Display stages are not recorded and
+    // `AvSync::notify_frame_displayed()` is never called. Without it the game audio is played with
+    // no video alignment. When frames are displayed here, call it with the `capture_timestamp_ns`
+    // of the frame, record `FrameStage::Display` and send the spans of the frame with
+    // `OtherClientPacket::FrameTrace`.
     pub fn render_stream_frame(&self) {
         todo!();
     }
@@ -55,20 +55,76 @@ fn begin_client_loop(
 
             // // connection_manager.send_message_udp(packet: &SM);
 
-            // let maybe_game_audio_player = match settings.game_audio {
+            // // Client stages are not recorded yet: there are no video receive and decode loops and
+            // // the compositor does not display frames. See `Compositor::render_stream_frame()`.
+            // let maybe_frame_trace_recorder = match &settings.video.frame_trace {
+            //     Switch::Enabled(_) => Some(FrameTraceRecorder::new(Timebase::new())),
+            //     Switch::Disabled => None,
+            // };
+
+            // // Shared with the compositor, that reports when each frame is displayed
+            // let maybe_av_sync = match &settings.game_audio {
             //     Switch::Enabled(desc) => {
-            //         let (producer, consumer) = keyed_channel_split(TIMEOUT);
-            //         connection_manager.lock().begin_receive_indexed_buffers(
-            //             "Game audio receive loop",
-            //             next_receiver_data_port,
-            //             producer,
-            //         )?;
+            //         desc.video_sync.clone().into_option().map(|desc| AvSync::new(&desc))
+            //     }
+            //     Switch::Disabled => None,
+            // };
+
+            // let ServerConfig {
+            //     game_audio_config,
+            //     microphone_config,
+            // } = server_handshake_packet.config;
+
+            // // The game audio played by the headset is picked up by its microphone. The player
+            // // shares the played samples with the recorder, that removes them from the microphone.
+            // let maybe_echo_reference = match (&settings.microphone, &microphone_config) {
+            //     (Switch::Enabled(desc), Some(config)) => match desc.processing.echo_cancellation {
+            //         Switch::Enabled(_) => Some(EchoReference::new(config.sample_rate)),
+            //         Switch::Disabled => None,
+            //     },
+            //     _ => None,
+            // };
+
+            // let maybe_game_audio_player = match (&settings.game_audio, game_audio_config) {
+            //     (Switch::Enabled(desc), Some(config)) => {
+            //         let send_mode = if desc.reliable {
+            //             SendMode::ReliableOrdered
+            //         } else {
+            //             SendMode::UnreliableSequential
+            //         };
+            //         let packet_dequeuer = connection_manager
+            //             .lock()
+            //             .register_dequeuer(&StreamDesc::game_audio(send_mode));
+
             //         Some(AudioPlayer::start_playback(
-            //             desc.output_device_index,
-            //             consumer,
+            //             AudioSink::from_desc(desc),
+            //             config,
+            //             desc.buffering_latency.clone(),
+            //             maybe_echo_reference.clone(),
+            //             maybe_av_sync.clone(),
+            //             packet_dequeuer,
             //         )?)
             //     }
-            //     Switch::Disabled => None,
+            //     _ => None,
+            // };
+
+            // let maybe_microphone_recorder = match (&settings.microphone, microphone_config) {
+            //     (Switch::Enabled(desc), Some(config)) => {
+            //         let packet_enqueuer = connection_manager
+            //             .lock()
+            //             .register_enqueuer(&StreamDesc::microphone(SendMode::UnreliableSequential));
+
+            //         Some(AudioRecorder::start_recording(
+            //             desc.input_device.clone(),
+            //             false,
+            //             config,
+            //             &desc.processing,
+            //             maybe_echo_reference,
+            //             Timebase::new(),
+            //             packet_enqueuer,
+            //         )?)
+            //     }
+            //     _ => None,
             // };
 
             // thread_loop::spawn("Pose data get loop", {
@@ -0,0 +1,164 @@
+// Counts the heap allocations performed by the packet send and receive paths when streaming video
+// at 150 Mbps. Laminar is replaced by the same buffer conversions it performs internally, so the
+// numbers are not affected by the network. Only the allocations of this crate are counted, the ones
+// made by Laminar for fragmentation and acknowledgement are the same before and after.
+// Run with `cargo bench -p bridgevr_common --bench packet_allocations`
+
+use bridgevr_common::{data::*, sockets::*};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::mpsc::*,
+};
+
+const BITRATE_BPS: usize = 150_000_000;
+const SUB_NAL_SIZE: usize = 8_192;
+const SIMULATED_SECONDS: usize = 10;
+
+struct CountingAllocator;
+
+static ALLOCATION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn video_packet(nal_index: u64, sub_nal: &[u8]) -> VideoPacket {
+    VideoPacket {
+        nal_index,
+        sub_nal_index: 0,
+        sub_nal_count: 1,
+        hmd_pose: Pose {
+            position: [0.; 3],
+            orientation: [1., 0., 0., 0.],
+        },
+        capture_timestamp_ns: 0,
+        sub_nal,
+    }
+}
+
+// Send and receive path of the baseline, without the buffer pool. The send buffer grows while
+// serializing and Laminar shrinks it to a boxed slice. The receive thread takes a returned buffer
+// before waiting for the next packet, or allocates an empty one, and the consumer returns it
+// through an unbounded channel when the packet is dropped.
+fn baseline_round_trip(
+    stream_id: u8,
+    packet: &VideoPacket,
+    stream_channel: &(Sender<Vec<u8>>, Receiver<Vec<u8>>),
+    return_buffer_channel: &(Sender<Vec<u8>>, Receiver<Vec<u8>>),
+) -> usize {
+    // PacketEnqueuer::enqueue()
+    let mut send_buffer = vec![stream_id];
+    bincode::serialize_into(&mut send_buffer, packet).unwrap();
+    let payload = send_buffer.into_boxed_slice();
+
+    // Receive thread loop
+    let (stream_enqueuer, stream_dequeuer) = stream_channel;
+    let (return_buffer_enqueuer, return_buffer_dequeuer) = return_buffer_channel;
+    let mut buffer = if let Ok(mut buffer) = return_buffer_dequeuer.try_recv() {
+        buffer.clear();
+        buffer
+    } else {
+        vec![]
+    };
+    buffer.extend(&payload[0..]);
+    stream_enqueuer.send(buffer).ok();
+
+    // PacketDequeuer::dequeue(), then drop of ReceivedPacket. The stream id is skipped here.
+    let receive_buffer = stream_dequeuer.try_recv().unwrap();
+    let size = bincode::deserialize::<VideoPacket>(&receive_buffer[1..])
+        .unwrap()
+        .sub_nal
+        .len();
+    return_buffer_enqueuer.send(receive_buffer).ok();
+
+    size
+}
+
+// Send and receive path with buffer pooling. The send buffer is still allocated for each packet,
+// because Laminar takes ownership of the payload.
+fn pooled_round_trip(
+    stream_id: u8,
+    packet: &VideoPacket,
+    stream_channel: &(Sender<Vec<u8>>, Receiver<Vec<u8>>),
+    buffer_pool: &BufferPool,
+) -> usize {
+    let payload = serialize_packet(stream_id, packet)
+        .unwrap()
+        .into_boxed_slice();
+
+    let (stream_enqueuer, stream_dequeuer) = stream_channel;
+    let mut buffer = buffer_pool.get();
+    buffer.extend_from_slice(&payload[1..]);
+    stream_enqueuer.send(buffer).ok();
+
+    let receive_buffer = stream_dequeuer.try_recv().unwrap();
+    let size = bincode::deserialize::<VideoPacket>(&receive_buffer)
+        .unwrap()
+        .sub_nal
+        .len();
+    buffer_pool.recycle(receive_buffer);
+
+    size
+}
+
+fn measure(name: &str, mut round_trip: impl FnMut(&VideoPacket) -> usize) {
+    let packet_count = BITRATE_BPS / 8 / SUB_NAL_SIZE * SIMULATED_SECONDS;
+    let sub_nal = vec![0xAA; SUB_NAL_SIZE];
+
+    // warm up pools and channels
+    for idx in 0..100 {
+        round_trip(&video_packet(idx, &sub_nal));
+    }
+
+    let mut total_bytes = 0;
+    let begin_allocation_count = ALLOCATION_COUNT.load(Ordering::Relaxed);
+    for idx in 0..packet_count {
+        total_bytes += round_trip(&video_packet(idx as _, &sub_nal));
+    }
+    let allocation_count = ALLOCATION_COUNT.load(Ordering::Relaxed) - begin_allocation_count;
+
+    println!(
+        "{}: {} packets, {} MB, {} allocations/s ({:.2} allocations/packet)",
+        name,
+        packet_count,
+        total_bytes / 1_000_000,
+        allocation_count / SIMULATED_SECONDS,
+        allocation_count as f32 / packet_count as f32
+    );
+}
+
+fn main() {
+    println!(
+        "Video stream at {} Mbps, sub NAL size {} bytes",
+        BITRATE_BPS / 1_000_000,
+        SUB_NAL_SIZE
+    );
+
+    let stream_channel = channel();
+    let return_buffer_channel = channel();
+    measure("Before (baseline)", |packet| {
+        baseline_round_trip(3, packet, &stream_channel, &return_buffer_channel)
+    });
+
+    let stream_channel = channel();
+    let buffer_pool = BufferPool::new(64, SUB_NAL_SIZE * 2);
+    measure("After (pooled)", |packet| {
+        pooled_round_trip(3, packet, &stream_channel, &buffer_pool)
+    });
+}
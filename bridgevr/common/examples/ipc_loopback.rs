@@ -0,0 +1,144 @@
+// Connects two GUI clients to a driver IPC server in the same process. Exits with an error if a
+// GUI that connects late does not get the current state, if events or commands are lost or
+// reordered, or if the GUIs are not notified when the driver stops:
+// cargo run -p bridgevr_common --example ipc_loopback
+
+use bridgevr_common::{data::*, ipc::*};
+use std::{
+    process::exit,
+    thread,
+    time::{Duration, Instant},
+};
+
+const IPC_NAME: &str = "bridgevr_ipc_loopback_example";
+const LOG_EVENT_COUNT: usize = 500;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn receive_events(client: &IpcClient, count: usize) -> Result<Vec<DriverEvent>, String> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut events = vec![];
+    while events.len() < count && Instant::now() < deadline {
+        events.extend(client.try_recv_events()?);
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(events)
+}
+
+fn log_messages(events: &[DriverEvent]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            DriverEvent::Log { message, .. } => Some(message.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn main() {
+    let mut server = IpcServer::start(IPC_NAME).unwrap();
+    let publisher = server.publisher();
+
+    let mut success = true;
+
+    let early_client = IpcClient::connect(IPC_NAME).unwrap();
+
+    // Wait for the connection to be accepted, so the client gets all the events
+    thread::sleep(Duration::from_millis(100));
+
+    publisher.publish(&DriverEvent::ConnectionState(ConnectionState::Searching));
+    publisher.publish(&DriverEvent::Statistics(Box::new(ServerStatistics {
+        encoded_fps: 72.,
+        ..<_>::default()
+    })));
+    for idx in 0..LOG_EVENT_COUNT {
+        publisher.publish(&DriverEvent::Log {
+            level: LogLevel::Info,
+            message: format!("Message {}", idx),
+        });
+    }
+
+    let expected_messages: Vec<_> = (0..LOG_EVENT_COUNT)
+        .map(|idx| format!("Message {}", idx))
+        .collect();
+    match receive_events(&early_client, LOG_EVENT_COUNT + 2) {
+        Ok(events) => {
+            println!("First GUI: {} events", events.len());
+            if log_messages(&events) != expected_messages {
+                println!("Log events: FAILED");
+                success = false;
+            }
+        }
+        Err(e) => {
+            println!("First GUI: FAILED ({})", e);
+            success = false;
+        }
+    }
+
+    // Only the latest connection state and statistics are replayed, not the log
+    let late_client = IpcClient::connect(IPC_NAME).unwrap();
+    match receive_events(&late_client, 2) {
+        Ok(events) => {
+            println!("Late GUI: {} events", events.len());
+            let got_state = events.iter().any(|event| {
+                matches!(
+                    event,
+                    DriverEvent::ConnectionState(ConnectionState::Searching)
+                )
+            });
+            let got_statistics = events.iter().any(|event| match event {
+                DriverEvent::Statistics(statistics) => statistics.encoded_fps == 72.,
+                _ => false,
+            });
+            if events.len() != 2 || !got_state || !got_statistics {
+                println!("Initial state: FAILED");
+                success = false;
+            }
+        }
+        Err(e) => {
+            println!("Late GUI: FAILED ({})", e);
+            success = false;
+        }
+    }
+
+    let commands = [
+        DriverCommand::ForceIdr,
+        DriverCommand::Recenter,
+        DriverCommand::ReloadSettings,
+        DriverCommand::DisconnectClient,
+    ];
+    for command in &commands {
+        early_client.send_command(*command).unwrap();
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    let mut received_commands = vec![];
+    while received_commands.len() < commands.len() && Instant::now() < deadline {
+        match server.try_recv_command() {
+            Some(command) => received_commands.push(command),
+            None => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    println!("Commands: {:?}", received_commands);
+    if received_commands != commands {
+        println!("Commands: FAILED");
+        success = false;
+    }
+
+    // The GUIs are disconnected when the server and all its publishers are dropped
+    server.request_stop();
+    drop(server);
+    drop(publisher);
+    let deadline = Instant::now() + TIMEOUT;
+    let mut disconnected = false;
+    while !disconnected && Instant::now() < deadline {
+        disconnected = late_client.try_recv_events().is_err();
+        thread::sleep(Duration::from_millis(1));
+    }
+    if !disconnected {
+        println!("Disconnection: FAILED");
+        success = false;
+    }
+
+    if !success {
+        exit(1);
+    }
+}
@@ -0,0 +1,127 @@
+// Compares the miss rate obtained by the Gaussian and the quantile latency estimators of
+// `EventTiming` on Wi-Fi like latency: mostly small jitter with rare long spikes. Exits with an
+// error if the quantile estimator misses the requested rate or is not better than the Gaussian one:
+// cargo run --release -p bridgevr_common --example latency_estimator_comparison
+
+use bridgevr_common::{data::*, event_timing::*};
+use std::{process::exit, time::Duration};
+
+const NOTIFS_PER_SEC: f64 = 100.;
+const EXPECTED_MISSES_PER_HOUR: u32 = 60;
+const HISTORY_MEAN_LIFETIME_S: u32 = 120;
+
+const SIMULATION_DURATION_S: f64 = 4. * 60. * 60.;
+
+// The misses are counted only after the estimators converged
+const SETTLING_TIME_S: f64 = 10. * 60.;
+
+// The network delay is uniform up to this value, plus a Pareto distributed spike for a fraction of
+// the samples
+const BASE_JITTER_S: f64 = 0.002;
+const SPIKE_PROBABILITY: f64 = 0.05;
+const SPIKE_SCALE_S: f64 = 0.002;
+const SPIKE_SHAPE: f64 = 2.5;
+
+// The queue latency without jitter. The measured latency is this minus the network delay.
+const QUEUE_LATENCY_S: f64 = 0.2;
+
+// Accepted ratio between the measured and the requested miss rate for the quantile estimator
+const MAX_MISS_RATE_RATIO: f64 = 2.;
+
+// xorshift32, uniform in (0, 1)
+fn next_random(state: &mut u32) -> f64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    (*state as f64 + 0.5) / (u32::MAX as f64 + 1.)
+}
+
+fn network_delay_s(random_state: &mut u32) -> f64 {
+    let mut delay_s = next_random(random_state) * BASE_JITTER_S;
+    if next_random(random_state) < SPIKE_PROBABILITY {
+        delay_s += SPIKE_SCALE_S / next_random(random_state).powf(1. / SPIKE_SHAPE);
+    }
+
+    delay_s.min(QUEUE_LATENCY_S)
+}
+
+// A sample is missed if its deviation below the average is larger than the target latency, which
+// is what happens when the queue is kept at the target latency
+fn misses_per_hour(estimator: LatencyEstimator) -> (f64, LatencyStatistics) {
+    let mut event_timing = EventTiming::new(
+        LatencyDesc {
+            default_ms: 30,
+            history_mean_lifetime_s: HISTORY_MEAN_LIFETIME_S,
+            mode: LatencyMode::Automatic {
+                expected_misses_per_hour: EXPECTED_MISSES_PER_HOUR,
+                estimator,
+            },
+        },
+        NOTIFS_PER_SEC as f32,
+    );
+
+    let mut random_state = 0x1234_5678;
+    let mut miss_count = 0;
+    let sample_count = (SIMULATION_DURATION_S * NOTIFS_PER_SEC) as u64;
+    let settling_sample_count = (SETTLING_TIME_S * NOTIFS_PER_SEC) as u64;
+    for idx in 0..sample_count {
+        let latency_s = QUEUE_LATENCY_S - network_delay_s(&mut random_state);
+
+        let deviation_s = event_timing.average_latency().as_secs_f64() - latency_s;
+        if idx >= settling_sample_count && deviation_s > event_timing.target_latency().as_secs_f64()
+        {
+            miss_count += 1;
+        }
+
+        event_timing.notify_latency(Duration::from_secs_f64(latency_s));
+    }
+
+    let measured_hours = (SIMULATION_DURATION_S - SETTLING_TIME_S) / 3600.;
+    (
+        miss_count as f64 / measured_hours,
+        event_timing.latency_statistics(),
+    )
+}
+
+fn main() {
+    let (gaussian_misses_per_hour, gaussian_statistics) =
+        misses_per_hour(LatencyEstimator::Gaussian);
+    let (quantile_misses_per_hour, quantile_statistics) =
+        misses_per_hour(LatencyEstimator::Quantile);
+
+    println!("Requested: {} misses per hour", EXPECTED_MISSES_PER_HOUR);
+    println!(
+        "Gaussian: {:.0} misses per hour, target {:.1}ms",
+        gaussian_misses_per_hour, gaussian_statistics.target_ms
+    );
+    println!(
+        "Quantile: {:.0} misses per hour, target {:.1}ms",
+        quantile_misses_per_hour, quantile_statistics.target_ms
+    );
+    println!("Percentiles: {:?}", quantile_statistics.percentiles_ms);
+
+    let mut success = true;
+
+    let miss_rate_ratio = quantile_misses_per_hour / EXPECTED_MISSES_PER_HOUR as f64;
+    if !(1. / MAX_MISS_RATE_RATIO..=MAX_MISS_RATE_RATIO).contains(&miss_rate_ratio) {
+        println!("Quantile miss rate: FAILED");
+        success = false;
+    }
+
+    if (quantile_misses_per_hour.ln() - (EXPECTED_MISSES_PER_HOUR as f64).ln()).abs()
+        >= (gaussian_misses_per_hour.ln() - (EXPECTED_MISSES_PER_HOUR as f64).ln()).abs()
+    {
+        println!("Quantile closer to the requested rate than Gaussian: FAILED");
+        success = false;
+    }
+
+    let histogram_sum = quantile_statistics.histogram.iter().sum::<f32>();
+    if (histogram_sum - 1.).abs() > 0.001 || quantile_statistics.percentiles_ms.is_empty() {
+        println!("Exported distribution: FAILED");
+        success = false;
+    }
+
+    if !success {
+        exit(1);
+    }
+}
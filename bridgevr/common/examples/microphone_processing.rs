@@ -0,0 +1,236 @@
+// Runs the microphone processing chain on WAV files:
+// cargo run --release -p bridgevr_common --example microphone_processing -- \
+//     <microphone.wav> <reference.wav> <output.wav>
+// `reference.wav` is the audio played by the speakers while `microphone.wav` was recorded. The WAV
+// files must be 16 bit PCM or 32 bit float and have the same sample rate. The synthetic
+// fixtures in fixtures/microphone_processing are checked by the unit tests of `audio_processing`.
+
+use bridgevr_common::{audio_processing::*, data::*, resampler::*, *};
+use settings_schema::Switch;
+use std::{env, fs, process::exit};
+
+const TRACE_CONTEXT: &str = "Microphone processing";
+
+// Input is pushed in buffers not aligned to the processing blocks, like the audio device does
+const PUSH_FRAME_COUNT: usize = 480;
+
+fn switch<T>(enabled: bool, content: T) -> Switch<T> {
+    if enabled {
+        Switch::Enabled(content)
+    } else {
+        Switch::Disabled
+    }
+}
+
+fn processing_desc(
+    echo_cancellation: bool,
+    noise_suppression: bool,
+    gain_control: bool,
+) -> AudioProcessingDesc {
+    AudioProcessingDesc {
+        echo_cancellation: switch(
+            echo_cancellation,
+            EchoCancellationDesc {
+                tail_length_ms: 200,
+            },
+        ),
+        noise_suppression: switch(
+            noise_suppression,
+            NoiseSuppressionDesc {
+                max_attenuation_db: 20.,
+            },
+        ),
+        gain_control: switch(
+            gain_control,
+            GainControlDesc {
+                target_level_dbfs: -20.,
+                max_gain_db: 30.,
+            },
+        ),
+    }
+}
+
+fn process(
+    desc: &AudioProcessingDesc,
+    sample_rate: u32,
+    channel_count: u16,
+    microphone: &[f32],
+    reference: &[f32],
+) -> Vec<f32> {
+    let mut processor = MicrophoneProcessor::new(desc, sample_rate, channel_count);
+    let mut output = vec![];
+    let chunk_len = PUSH_FRAME_COUNT * channel_count as usize;
+    for (idx, samples) in microphone.chunks(chunk_len).enumerate() {
+        let begin = usize::min(idx * PUSH_FRAME_COUNT, reference.len());
+        let end = usize::min(
+            begin + samples.len() / channel_count as usize,
+            reference.len(),
+        );
+        processor.process(samples, &reference[begin..end], &mut output);
+    }
+
+    output
+}
+
+fn level_db(samples: &[f32]) -> f32 {
+    let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+    10. * mean_square.max(1e-12).log10()
+}
+
+struct Wav {
+    sample_rate: u32,
+    channel_count: u16,
+    samples: Vec<f32>,
+}
+
+fn read_wav(path: &str) -> StrResult<Wav> {
+    let data = trace_err!(fs::read(path))?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return trace_str!("{} is not a WAV file", path);
+    }
+
+    let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+    let read_u32 = |offset: usize| {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let chunk_size = read_u32(offset + 4) as usize;
+        let chunk_begin = offset + 8;
+        let chunk_end = usize::min(chunk_begin + chunk_size, data.len());
+
+        match &data[offset..offset + 4] {
+            b"fmt " if chunk_size >= 16 => {
+                format = Some((
+                    read_u16(chunk_begin),
+                    read_u16(chunk_begin + 2),
+                    read_u32(chunk_begin + 4),
+                    read_u16(chunk_begin + 14),
+                ))
+            }
+            b"data" => {
+                let (format_tag, channel_count, sample_rate, bits_per_sample) =
+                    trace_none!(format, "{}: missing format chunk", path)?;
+                let bytes = &data[chunk_begin..chunk_end];
+                let samples = match (format_tag, bits_per_sample) {
+                    (1, 16) => bytes
+                        .chunks_exact(2)
+                        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.)
+                        .collect(),
+                    (3, 32) => bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect(),
+                    _ => {
+                        return trace_str!(
+                            "{}: unsupported format {} with {} bits per sample",
+                            path,
+                            format_tag,
+                            bits_per_sample
+                        )
+                    }
+                };
+
+                return Ok(Wav {
+                    sample_rate,
+                    channel_count,
+                    samples,
+                });
+            }
+            _ => (),
+        }
+
+        // Chunks are padded to an even size
+        offset = chunk_begin + chunk_size + chunk_size % 2;
+    }
+
+    trace_str!("{}: missing data chunk", path)
+}
+
+fn write_wav(path: &str, wav: &Wav) -> StrResult {
+    let data_size = wav.samples.len() as u32 * 2;
+    let block_align = wav.channel_count * 2;
+
+    let mut data = vec![];
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&(36 + data_size).to_le_bytes());
+    data.extend_from_slice(b"WAVEfmt ");
+    data.extend_from_slice(&16_u32.to_le_bytes());
+    data.extend_from_slice(&1_u16.to_le_bytes());
+    data.extend_from_slice(&wav.channel_count.to_le_bytes());
+    data.extend_from_slice(&wav.sample_rate.to_le_bytes());
+    data.extend_from_slice(&(wav.sample_rate * block_align as u32).to_le_bytes());
+    data.extend_from_slice(&block_align.to_le_bytes());
+    data.extend_from_slice(&16_u16.to_le_bytes());
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&data_size.to_le_bytes());
+    for sample in &wav.samples {
+        let value = (sample.clamp(-1., 1.) * 32767.) as i16;
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    trace_err!(fs::write(path, data))
+}
+
+fn process_files(microphone_path: &str, reference_path: &str, output_path: &str) -> StrResult {
+    let microphone = read_wav(microphone_path)?;
+    let reference = read_wav(reference_path)?;
+    if microphone.sample_rate != reference.sample_rate {
+        return trace_str!(
+            "Sample rates differ: {} and {}",
+            microphone.sample_rate,
+            reference.sample_rate
+        );
+    }
+
+    let mut mono_reference = vec![];
+    remap_channels(
+        &reference.samples,
+        reference.channel_count,
+        1,
+        &mut mono_reference,
+    );
+
+    let output = process(
+        &processing_desc(true, true, true),
+        microphone.sample_rate,
+        microphone.channel_count,
+        &microphone.samples,
+        &mono_reference,
+    );
+    println!(
+        "Level: microphone {:.1}dBFS, output {:.1}dBFS",
+        level_db(&microphone.samples),
+        level_db(&output)
+    );
+
+    write_wav(
+        output_path,
+        &Wav {
+            sample_rate: microphone.sample_rate,
+            channel_count: microphone.channel_count,
+            samples: output,
+        },
+    )
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.len() == 3 {
+        if let Err(e) = process_files(&args[0], &args[1], &args[2]) {
+            println!("{}", e);
+            exit(1);
+        }
+    } else {
+        println!("Usage: microphone_processing <microphone.wav> <reference.wav> <output.wav>");
+        exit(1);
+    }
+}
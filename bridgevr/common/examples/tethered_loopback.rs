@@ -0,0 +1,130 @@
+// Tests the tethered connection on a single machine using two processes:
+// cargo run -p bridgevr_common --example tethered_loopback -- server path/to/settings.json
+// cargo run -p bridgevr_common --example tethered_loopback -- client
+// `connection.mode` must be set to `Tethered` in the settings. With the UDP transport
+// `server_port` and `client_port` must be different.
+
+use bridgevr_common::{data::*, sockets::*, *};
+use std::{env, path::Path, thread, time::Duration};
+
+const TRACE_CONTEXT: &str = "Tethered loopback";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const PACKET_COUNT: u32 = 100;
+
+fn run_server(settings_path: &Path) -> StrResult {
+    let settings = load_settings(settings_path)?;
+
+    let (handshake_stream, client_handshake_packet) = search_tethered_client(TIMEOUT)?;
+    println!("Found client {}", client_handshake_packet.bridgevr_name);
+
+    let mut connection_manager = ConnectionManager::connect_to_tethered_client(
+        handshake_stream,
+        settings.connection.config.clone(),
+        ServerHandshakePacket {
+            config: ServerConfig {
+                version: BVR_SERVER_VERSION.into(),
+                target_eye_resolution: client_handshake_packet.native_eye_resolution,
+                game_audio_config: None,
+                microphone_config: None,
+            },
+            settings,
+        },
+        || println!("Client disconnected"),
+    )?;
+    println!("Link quality: {:?}", connection_manager.link_quality());
+
+    let mut enqueuer =
+        connection_manager.register_enqueuer(&StreamDesc::other_server(SendMode::ReliableOrdered));
+    let mut dequeuer =
+        connection_manager.register_dequeuer(&StreamDesc::other_client(SendMode::ReliableOrdered));
+
+    for idx in 0..PACKET_COUNT {
+        enqueuer.enqueue(&OtherServerPacket::Haptic {
+            device_type: TrackedDeviceType::LeftController,
+            sample: HapticSample {
+                duration_seconds: idx as _,
+                frequency: 0.,
+                amplitude: 1.,
+            },
+        })?;
+    }
+    enqueuer.enqueue(&OtherServerPacket::Shutdown)?;
+
+    loop {
+        if let Ok(OtherClientPacket::Disconnected) = dequeuer.dequeue(TIMEOUT)?.get() {
+            println!("Client received all packets");
+            break;
+        }
+    }
+
+    connection_manager.request_stop();
+
+    Ok(())
+}
+
+fn run_client() -> StrResult {
+    let client_handshake_packet = ClientHandshakePacket {
+        bridgevr_name: BVR_NAME.into(),
+        version: BVR_CLIENT_VERSION.into(),
+        native_eye_resolution: (1000, 1000),
+        fov: [Fov {
+            left: -45.,
+            top: 45.,
+            right: 45.,
+            bottom: -45.,
+        }; 2],
+        fps: 72,
+        max_video_encoder_instances: 1,
+        available_audio_player_sample_rates: vec![48000],
+        preferred_audio_player_sample_rates: 48000,
+        available_microphone_sample_rates: vec![48000],
+        preferred_microphone_sample_rates: vec![48000],
+        available_audio_player_channel_counts: vec![2],
+        available_microphone_channel_counts: vec![1],
+    };
+
+    let (mut connection_manager, _) =
+        ConnectionManager::connect_to_server(client_handshake_packet, || {
+            println!("Server disconnected")
+        })?;
+    println!("Link quality: {:?}", connection_manager.link_quality());
+
+    let mut enqueuer =
+        connection_manager.register_enqueuer(&StreamDesc::other_client(SendMode::ReliableOrdered));
+    let mut dequeuer =
+        connection_manager.register_dequeuer(&StreamDesc::other_server(SendMode::ReliableOrdered));
+
+    let mut received_count = 0;
+    loop {
+        match dequeuer.dequeue(TIMEOUT)?.get()? {
+            OtherServerPacket::Haptic { .. } => received_count += 1,
+            OtherServerPacket::Shutdown => break,
+        }
+    }
+    println!("Received {}/{} packets", received_count, PACKET_COUNT);
+
+    enqueuer.enqueue(&OtherClientPacket::Disconnected)?;
+
+    // Give time to the packet to be sent
+    thread::sleep(Duration::from_secs(1));
+    connection_manager.request_stop();
+
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let res = match args.get(1).map(|s| s.as_str()) {
+        Some("server") => match args.get(2) {
+            Some(path) => run_server(Path::new(path)),
+            None => trace_str!("Missing settings path"),
+        },
+        Some("client") => run_client(),
+        _ => trace_str!("Usage: tethered_loopback server <settings path> | client"),
+    };
+
+    if let Err(e) = res {
+        println!("{}", e);
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use bridgevr_common::data::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = deserialize_bounded::<OtherClientPacket>(data, data.len() as _);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use bridgevr_common::data::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = deserialize_bounded::<ServerHandshakePacket>(data, MAX_SERVER_HANDSHAKE_PACKET_SIZE);
+});
@@ -1,6 +1,11 @@
 use crate::{
+    audio_codec::*,
+    audio_processing::*,
+    av_sync::*,
     data::*,
-    event_timing::*,
+    drift_controller::*,
+    jitter_buffer::*,
+    resampler::*,
     sockets::*,
     thread_loop::{self, *},
     *,
@@ -10,122 +15,500 @@ use cpal::{
     *,
 };
 use log::*;
-use safe_transmute::*;
-use std::{cmp::min, sync::mpsc::*, sync::*, thread::*, time::Duration, time::*, *};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use settings_schema::Switch;
+use std::{
+    cmp::min,
+    io::Write,
+    path::PathBuf,
+    sync::{atomic::*, mpsc::*, Arc},
+    thread::*,
+    time::Duration,
+    time::*,
+    *,
+};
 
 const TRACE_CONTEXT: &str = "Audio";
 
 const TIMEOUT: Duration = Duration::from_millis(500);
 
-enum AudioMode {
+// Longest gap in the stream of received packets that is filled by loss concealment
+const MAX_CONCEALMENT_DURATION: Duration = Duration::from_millis(200);
+
+// Bigger jumps of the sequence numbers, in either direction, mean that the sender restarted the
+// stream. The player resynchronizes instead of counting the gap as lost or late packets.
+const MAX_SEQUENCE_JUMP: u64 = 1000;
+
+// Size of the device buffers assumed by the player. todo: use the actual size
+const DEFAULT_BUFFER_FRAME_COUNT: usize = 1024;
+
+// Exporting the latency distribution allocates, so it is not done at every callback
+const LATENCY_STATISTICS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Used to detect disconnected devices and changes of the default device
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sample rates tested when querying the capabilities of a device
+const STANDARD_SAMPLE_RATES: [u32; 12] = [
+    8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+// Supported device buffer formats, by preference
+const DEVICE_SAMPLE_FORMATS: [SampleFormat; 3] =
+    [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+fn frame_count_to_ns(frame_count: u64, sample_rate: u32) -> u64 {
+    frame_count * 1_000_000_000 / sample_rate as u64
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SequenceGap {
+    Late,
+    Lost(u64),
+    Resync,
+}
+
+// Compares the sequence number of a received packet with the expected one. Sequence numbers wrap
+// around.
+fn sequence_gap(next_sequence: u64, sequence: u64) -> SequenceGap {
+    let distance = sequence.wrapping_sub(next_sequence) as i64;
+    if distance > MAX_SEQUENCE_JUMP as i64 || distance < -(MAX_SEQUENCE_JUMP as i64) {
+        SequenceGap::Resync
+    } else if distance < 0 {
+        SequenceGap::Late
+    } else {
+        SequenceGap::Lost(distance as u64)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum AudioMode {
     Input,
     Output,
     Loopback,
 }
 
+fn supported_formats(device: &Device, mode: AudioMode) -> StrResult<Vec<SupportedFormat>> {
+    Ok(match mode {
+        AudioMode::Input => trace_err!(device.supported_input_formats())?.collect(),
+        AudioMode::Output | AudioMode::Loopback => {
+            trace_err!(device.supported_output_formats())?.collect()
+        }
+    })
+}
+
+fn default_device(mode: AudioMode) -> Option<Device> {
+    let host = cpal::default_host();
+    match mode {
+        AudioMode::Input => host.default_input_device(),
+        AudioMode::Output | AudioMode::Loopback => host.default_output_device(),
+    }
+}
+
+fn default_format(device: &Device, mode: AudioMode) -> StrResult<Format> {
+    Ok(match mode {
+        AudioMode::Input => trace_err!(device.default_input_format())?,
+        AudioMode::Output | AudioMode::Loopback => trace_err!(device.default_output_format())?,
+    })
+}
+
+fn device_name(device: &Device) -> String {
+    device.name().unwrap_or_else(|_| "Unknown".into())
+}
+
+// Devices are identified by name, which unlike their position in the list does not change when
+// other devices are connected. Devices with the same name, like two identical headsets, are told
+// apart by a counter appended to the name.
+fn enumerate_devices(mode: AudioMode) -> StrResult<Vec<(String, Device)>> {
+    let host = cpal::default_host();
+
+    let mut names = vec![];
+    let mut devices = vec![];
+    for device in trace_err!(host.devices())? {
+        if default_format(&device, mode).is_err() {
+            // The device does not support this mode
+            continue;
+        }
+
+        let name = device_name(&device);
+        let same_name_count = names.iter().filter(|n| **n == name).count();
+        let id = if same_name_count == 0 {
+            name.clone()
+        } else {
+            format!("{} ({})", name, same_name_count + 1)
+        };
+        names.push(name);
+        devices.push((id, device));
+    }
+
+    Ok(devices)
+}
+
+// If `device_id` is None the default device is selected. Returns the ID of the device.
+fn select_device(device_id: Option<&str>, mode: AudioMode) -> StrResult<(String, Device, Format)> {
+    let mut devices = enumerate_devices(mode)?;
+
+    let devices_str = devices
+        .iter()
+        .fold(String::new(), |s, (id, _)| s + &format!(" {{ {} }}", id));
+    let io_str = match mode {
+        AudioMode::Input => "input",
+        AudioMode::Output => "output",
+        AudioMode::Loopback => "loopback",
+    };
+    info!(
+        "[{}] Audio {} devices:{}",
+        TRACE_CONTEXT, io_str, devices_str
+    );
+
+    let (id, device) = if let Some(id) = device_id {
+        let idx = trace_none!(
+            devices.iter().position(|(other_id, _)| other_id == id),
+            "Audio device not found: {}",
+            id
+        )?;
+        devices.remove(idx)
+    } else {
+        let device = trace_none!(default_device(mode))?;
+        (device_name(&device), device)
+    };
+    let format = default_format(&device, mode)?;
+
+    Ok((id, device, format))
+}
+
+// Open the device with the requested config if supported, otherwise with its default format. The
+// caller must convert the samples if the returned format differs from the requested config.
+fn open_device(
+    device_id: Option<&str>,
+    mode: AudioMode,
+    requested_config: AudioConfig,
+) -> StrResult<(String, Device, Format)> {
+    let (id, device, mut format) = select_device(device_id, mode)?;
+
+    let supports_rate = |supported_format: &SupportedFormat| {
+        supported_format.min_sample_rate.0 <= requested_config.sample_rate
+            && requested_config.sample_rate <= supported_format.max_sample_rate.0
+    };
+    let supported_formats = supported_formats(&device, mode)?;
+    if supported_formats
+        .iter()
+        .any(|f| supports_rate(f) && f.channels == requested_config.channel_count)
+    {
+        format.channels = requested_config.channel_count;
+        format.sample_rate = SampleRate(requested_config.sample_rate);
+    } else if supported_formats
+        .iter()
+        .any(|f| supports_rate(f) && f.channels == format.channels)
+    {
+        format.sample_rate = SampleRate(requested_config.sample_rate);
+    }
+
+    // Some devices expose only integer buffers. Samples are converted from/to F32.
+    let sample_rate = format.sample_rate.0;
+    let channel_count = format.channels;
+    if let Some(data_type) = DEVICE_SAMPLE_FORMATS.iter().find(|data_type| {
+        supported_formats.iter().any(|f| {
+            f.data_type == **data_type
+                && f.channels == channel_count
+                && f.min_sample_rate.0 <= sample_rate
+                && sample_rate <= f.max_sample_rate.0
+        })
+    }) {
+        format.data_type = *data_type;
+    }
+
+    Ok((id, device, format))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AudioDeviceInfo {
+    // Used to select the device in the settings
+    pub id: String,
+    pub is_default: bool,
+    pub sample_rates: Vec<u32>,
+    pub channel_counts: Vec<u16>,
+    // Sample types of the device buffer, like "F32" or "I16"
+    pub sample_formats: Vec<String>,
+    pub default_sample_rate: u32,
+    pub default_channel_count: u16,
+}
+
+fn device_info(
+    id: String,
+    is_default: bool,
+    device: &Device,
+    mode: AudioMode,
+) -> StrResult<AudioDeviceInfo> {
+    let default_format = default_format(device, mode)?;
+    let supported_formats = supported_formats(device, mode)?;
+
+    let sample_rates = STANDARD_SAMPLE_RATES
+        .iter()
+        .cloned()
+        .filter(|rate| {
+            supported_formats
+                .iter()
+                .any(|f| f.min_sample_rate.0 <= *rate && *rate <= f.max_sample_rate.0)
+        })
+        .collect();
+
+    let mut channel_counts: Vec<_> = supported_formats.iter().map(|f| f.channels).collect();
+    channel_counts.sort();
+    channel_counts.dedup();
+
+    let sample_formats = DEVICE_SAMPLE_FORMATS
+        .iter()
+        .filter(|data_type| supported_formats.iter().any(|f| f.data_type == **data_type))
+        .map(|data_type| format!("{:?}", data_type))
+        .collect();
+
+    Ok(AudioDeviceInfo {
+        id,
+        is_default,
+        sample_rates,
+        channel_counts,
+        sample_formats,
+        default_sample_rate: default_format.sample_rate.0,
+        default_channel_count: default_format.channels,
+    })
+}
+
+// Used by the GUI to show the devices that can be selected in the settings
+pub fn list_audio_devices(mode: AudioMode) -> StrResult<Vec<AudioDeviceInfo>> {
+    let default_name = default_device(mode).map(|device| device_name(&device));
+
+    Ok(enumerate_devices(mode)?
+        .into_iter()
+        .filter_map(|(id, device)| {
+            let is_default = Some(&id) == default_name.as_ref();
+            device_info(id, is_default, &device, mode)
+                .map_err(|e| debug!("{}", e))
+                .ok()
+        })
+        .collect())
+}
+
+// Used to fill the audio fields of the client handshake packet and to negotiate the stream configs
+pub fn query_audio_device(device_id: Option<&str>, mode: AudioMode) -> StrResult<AudioDeviceInfo> {
+    let (id, device, _) = select_device(device_id, mode)?;
+    device_info(id, device_id.is_none(), &device, mode)
+}
+
+// Choose the config of an audio stream given what the peer supports. The sample rate in the
+// settings has precedence over the preferences of the peer, then the closest supported rate is
+// used. The channel count of the local device is used if supported, otherwise the closest lower
+// one. If Opus is enabled, the rates and channel counts it supports are preferred. Otherwise or if
+// the peer does not support any of them the stream falls back to PCM.
+pub fn negotiate_audio_config(
+    desc: &AudioDesc,
+    peer_preferred_sample_rates: &[u32],
+    peer_sample_rates: &[u32],
+    local_channel_count: u16,
+    peer_channel_counts: &[u16],
+) -> StrResult<AudioConfig> {
+    let mut peer_sample_rates = peer_sample_rates.to_vec();
+    let mut peer_channel_counts = peer_channel_counts.to_vec();
+    if let Switch::Enabled(_) = &desc.opus {
+        if peer_sample_rates.iter().any(|rate| supports_opus(*rate, 1)) {
+            peer_sample_rates.retain(|rate| supports_opus(*rate, 1));
+        }
+        if peer_channel_counts.iter().any(|count| *count <= 2) {
+            peer_channel_counts.retain(|count| *count <= 2);
+        }
+    }
+
+    let settings_sample_rate = desc.preferred_sample_rate;
+    let sample_rate = if peer_sample_rates.contains(&settings_sample_rate) {
+        settings_sample_rate
+    } else if let Some(rate) = peer_preferred_sample_rates
+        .iter()
+        .find(|rate| peer_sample_rates.contains(rate))
+    {
+        *rate
+    } else {
+        // On ties prefer the higher rate
+        *trace_none!(peer_sample_rates.iter().min_by_key(|rate| (
+            (**rate as i64 - settings_sample_rate as i64).abs(),
+            cmp::Reverse(**rate)
+        )))?
+    };
+
+    let channel_count = if let Some(count) = peer_channel_counts
+        .iter()
+        .filter(|count| **count <= local_channel_count)
+        .max()
+    {
+        *count
+    } else {
+        *trace_none!(peer_channel_counts.iter().min())?
+    };
+
+    let codec = match &desc.opus {
+        Switch::Enabled(opus_desc) if supports_opus(sample_rate, channel_count) => {
+            AudioCodec::Opus(*opus_desc)
+        }
+        _ => AudioCodec::Pcm(desc.preferred_format),
+    };
+
+    Ok(AudioConfig {
+        sample_rate,
+        channel_count,
+        codec,
+    })
+}
+
+struct OpenStream {
+    device_id: String,
+    stream: StreamId,
+    format: Format,
+}
+
+fn build_stream(
+    event_loop: &EventLoop,
+    device_id: Option<&str>,
+    mode: AudioMode,
+    requested_config: AudioConfig,
+) -> StrResult<OpenStream> {
+    let (device_id, device, format) = open_device(device_id, mode, requested_config)?;
+
+    let stream = trace_err!(match mode {
+        AudioMode::Input | AudioMode::Loopback => event_loop.build_input_stream(&device, &format),
+        AudioMode::Output => event_loop.build_output_stream(&device, &format),
+    })?;
+    trace_err!(event_loop.play_stream(stream.clone()))?;
+
+    Ok(OpenStream {
+        device_id,
+        stream,
+        format,
+    })
+}
+
 struct AudioSession {
     event_loop: Arc<EventLoop>,
-    stream: StreamId,
+    open_stream: Arc<Mutex<Option<OpenStream>>>,
+    running: Arc<AtomicBool>,
     join_handle: Option<JoinHandle<()>>,
+    device_monitor_thread: ThreadLoop,
 }
 
 impl AudioSession {
+    // If `device_id` is None the session follows the default device of the system. When the device
+    // is disconnected, the stream is reopened as soon as the device is available again.
+    // `buffer_callback` receives the format of the device buffer, that can change when the stream
+    // is reopened.
     fn start(
-        device_idx: Option<u64>,
+        device_id: Option<String>,
         mode: AudioMode,
-        mut buffer_callback: impl FnMut(StreamData) + Send + 'static,
+        requested_config: AudioConfig,
+        mut buffer_callback: impl FnMut(&Format, StreamData) + Send + 'static,
     ) -> StrResult<AudioSession> {
         let host = cpal::default_host();
         let event_loop = Arc::new(host.event_loop());
 
-        let mut devices_and_formats: Vec<_> = trace_err!(host.devices())?
-            .filter_map(|dev| {
-                match mode {
-                    AudioMode::Input => dev.default_input_format(),
-                    AudioMode::Output | AudioMode::Loopback => dev.default_output_format(),
-                }
-                .map(|format| (dev, format))
-                .ok()
-            })
-            .collect();
-
-        let devices_str =
-            devices_and_formats
-                .iter()
-                .enumerate()
-                .fold(String::new(), |s, (i, (dev, _))| {
-                    s + &format!(
-                        " {{ {}: {} }}",
-                        i,
-                        dev.name().unwrap_or_else(|_| "Unknown".into())
-                    )
-                });
-        let io_str = match mode {
-            AudioMode::Input => "input",
-            AudioMode::Output => "output",
-            AudioMode::Loopback => "loopback",
-        };
-        info!(
-            "[{}] Audio {} devices:{}",
-            TRACE_CONTEXT, io_str, devices_str
-        );
-
-        let (device, mut format) = if let Some(idx) = device_idx {
-            let idx = idx as usize;
-            if idx < devices_and_formats.len() {
-                // the bound check prevents panic
-                devices_and_formats.remove(idx)
-            } else {
-                return trace_str!("Index out of bound");
-            }
-        } else {
-            match mode {
-                AudioMode::Input => {
-                    let dev = trace_none!(host.default_input_device())?;
-                    let format = trace_err!(dev.default_input_format())?;
-                    (dev, format)
-                }
-                AudioMode::Output | AudioMode::Loopback => {
-                    let dev = trace_none!(host.default_output_device())?;
-                    let format = trace_err!(dev.default_output_format())?;
-                    (dev, format)
-                }
-            }
-        };
-        format.data_type = SampleFormat::F32;
-
-        let stream = trace_err!(match mode {
-            AudioMode::Input | AudioMode::Loopback => {
-                event_loop.build_input_stream(&device, &format)
-            }
-            AudioMode::Output => event_loop.build_output_stream(&device, &format),
-        })?;
-        trace_err!(event_loop.play_stream(stream.clone()))?;
+        let open_stream = Arc::new(Mutex::new(Some(build_stream(
+            &event_loop,
+            device_id.as_deref(),
+            mode,
+            requested_config,
+        )?)));
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
 
         let join_handle = Some(trace_err!(thread::Builder::new()
             .name("Audio thread".into())
             .spawn({
                 let event_loop = event_loop.clone();
+                let open_stream = open_stream.clone();
+                let device_lost = device_lost.clone();
                 move || {
-                    event_loop.run(move |_, maybe_data| match maybe_data {
-                        Ok(io_data) => {
-                            buffer_callback(io_data);
+                    event_loop.run(move |stream_id, maybe_data| {
+                        let format = match &*open_stream.lock() {
+                            Some(open_stream) if open_stream.stream == stream_id => {
+                                open_stream.format.clone()
+                            }
+                            // Data from a stream that has been replaced
+                            _ => return,
+                        };
+
+                        match maybe_data {
+                            Ok(io_data) => {
+                                buffer_callback(&format, io_data);
+                            }
+                            Err(StreamError::DeviceNotAvailable) => {
+                                device_lost.store(true, Ordering::Relaxed)
+                            }
+                            Err(e) => warn!("{}", e),
                         }
-                        Err(e) => warn!("{}", e),
                     });
                 }
             }))?);
 
+        let device_monitor_thread = thread_loop::spawn("Audio device monitor loop", {
+            let event_loop = event_loop.clone();
+            let open_stream = open_stream.clone();
+            let running = running.clone();
+            move || {
+                thread::sleep(DEVICE_POLL_INTERVAL);
+
+                let current_device_id = open_stream.lock().as_ref().map(|s| s.device_id.clone());
+                let device_lost = device_lost.swap(false, Ordering::Relaxed);
+                let default_device_changed = match (&device_id, &current_device_id) {
+                    (None, Some(current_id)) => default_device(mode)
+                        .map(|device| device_name(&device) != *current_id)
+                        .unwrap_or(false),
+                    _ => false,
+                };
+                if !device_lost && !default_device_changed && current_device_id.is_some() {
+                    return;
+                }
+
+                if let Some(old_stream) = open_stream.lock().take() {
+                    event_loop.destroy_stream(old_stream.stream);
+                }
+
+                // Avoid logging the device list at every poll while the device is missing
+                let device_available = match &device_id {
+                    Some(id) => enumerate_devices(mode)
+                        .map(|devices| devices.iter().any(|(other_id, _)| other_id == id))
+                        .unwrap_or(false),
+                    None => default_device(mode).is_some(),
+                };
+                if !device_available || !running.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                match build_stream(&event_loop, device_id.as_deref(), mode, requested_config) {
+                    Ok(stream) => {
+                        info!(
+                            "[{}] Audio stream reopened on device {}",
+                            TRACE_CONTEXT, stream.device_id
+                        );
+                        *open_stream.lock() = Some(stream);
+                    }
+                    Err(e) => debug!("{}", e),
+                }
+            }
+        })?;
+
         Ok(AudioSession {
             event_loop,
-            stream,
+            open_stream,
+            running,
             join_handle,
+            device_monitor_thread,
         })
     }
 
     fn request_stop(&mut self) {
         // todo: check that this is non blocking
-        self.event_loop.destroy_stream(self.stream.clone())
+        self.running.store(false, Ordering::Relaxed);
+        self.device_monitor_thread.request_stop();
+        if let Some(open_stream) = self.open_stream.lock().take() {
+            self.event_loop.destroy_stream(open_stream.stream)
+        }
     }
 }
 
@@ -141,10 +524,17 @@ pub struct AudioRecorder {
 }
 
 impl AudioRecorder {
+    // The samples are converted to `stream_config` if the device does not support it.
+    // `echo_reference` is the audio played on the same device, used for echo cancellation.
+    // The packets are timestamped with `timebase`, shared with the other captured streams.
     pub fn start_recording(
-        device_idx: Option<u64>,
+        device_id: Option<String>,
         loopback: bool,
-        mut packet_enqueuer: PacketEnqueuer,
+        stream_config: AudioConfig,
+        processing_desc: &AudioProcessingDesc,
+        echo_reference: Option<EchoReference>,
+        timebase: Timebase,
+        mut packet_enqueuer: PacketEnqueuer<AudioStream>,
     ) -> StrResult<AudioRecorder> {
         let mode = if loopback {
             AudioMode::Loopback
@@ -152,23 +542,136 @@ impl AudioRecorder {
             AudioMode::Input
         };
 
-        let session = trace_err!(AudioSession::start(device_idx, mode, move |io_data| {
-            match io_data {
-                StreamData::Input {
-                    buffer: UnknownTypeInputBuffer::F32(samples),
-                } => {
-                    let audio_packet = AudioPacket {
-                        samples: transmute_to_bytes(&samples[..]),
+        if let Some(echo_reference) = &echo_reference {
+            if echo_reference.sample_rate() != stream_config.sample_rate {
+                return trace_str!(
+                    "Echo reference sample rate {} differs from the stream sample rate {}",
+                    echo_reference.sample_rate(),
+                    stream_config.sample_rate
+                );
+            }
+        }
+
+        // Set at the first callback and when the device changes
+        let mut device_format = None;
+        let mut resampler = Resampler::new(
+            stream_config.sample_rate,
+            stream_config.sample_rate,
+            stream_config.channel_count,
+        );
+        let mut device_samples = vec![];
+        let mut remapped_samples = vec![];
+        let mut resampled_samples = vec![];
+
+        let mut processor = MicrophoneProcessor::new(
+            processing_desc,
+            stream_config.sample_rate,
+            stream_config.channel_count,
+        );
+        let mut reference_samples = vec![];
+        let mut processed_samples = vec![];
+
+        // Microphone audio is voice, game audio is captured in loopback mode
+        let mut encoder = AudioEncoder::new(stream_config, !loopback)?;
+        let mut sequence = 0;
+
+        // Frames given to the encoder and frames already sent in packets. The difference is
+        // buffered by the encoder.
+        let mut fed_frame_count = 0;
+        let mut encoded_frame_count = 0;
+
+        let session = trace_err!(AudioSession::start(
+            device_id,
+            mode,
+            stream_config,
+            move |format, io_data| {
+                // The last sample of the buffer has just been captured
+                let callback_timestamp_ns = timebase.now_ns();
+
+                if device_format.as_ref() != Some(format) {
+                    resampler = Resampler::new(
+                        format.sample_rate.0,
+                        stream_config.sample_rate,
+                        stream_config.channel_count,
+                    );
+                    device_format = Some(format.clone());
+                }
+
+                if let StreamData::Input { buffer } = io_data {
+                    device_samples.clear();
+                    match buffer {
+                        UnknownTypeInputBuffer::F32(samples) => {
+                            device_samples.extend_from_slice(&samples)
+                        }
+                        UnknownTypeInputBuffer::I16(samples) => {
+                            device_samples.extend(samples.iter().map(Sample::to_f32))
+                        }
+                        UnknownTypeInputBuffer::U16(samples) => {
+                            device_samples.extend(samples.iter().map(Sample::to_f32))
+                        }
+                    }
+
+                    remapped_samples.clear();
+                    remap_channels(
+                        &device_samples,
+                        format.channels,
+                        stream_config.channel_count,
+                        &mut remapped_samples,
+                    );
+                    resampled_samples.clear();
+                    resampler.process(&remapped_samples, &mut resampled_samples);
+
+                    let samples = if processor.is_passthrough() {
+                        &resampled_samples
+                    } else {
+                        reference_samples.clear();
+                        match &echo_reference {
+                            Some(echo_reference) if processor.uses_echo_reference() => {
+                                let frame_count =
+                                    resampled_samples.len() / stream_config.channel_count as usize;
+                                echo_reference.pop(frame_count, &mut reference_samples);
+                            }
+                            _ => (),
+                        }
+
+                        processed_samples.clear();
+                        processor.process(
+                            &resampled_samples,
+                            &reference_samples,
+                            &mut processed_samples,
+                        );
+                        &processed_samples
                     };
+                    fed_frame_count +=
+                        (samples.len() / stream_config.channel_count as usize) as u64;
 
-                    packet_enqueuer
-                        .enqueue(&audio_packet)
+                    encoder
+                        .encode(samples, |payload, sample_count| {
+                            let capture_timestamp_ns =
+                                callback_timestamp_ns.saturating_sub(frame_count_to_ns(
+                                    fed_frame_count - encoded_frame_count,
+                                    stream_config.sample_rate,
+                                ));
+                            encoded_frame_count += sample_count as u64;
+
+                            packet_enqueuer
+                                .enqueue(&AudioPacket {
+                                    sequence,
+                                    sample_count: sample_count as _,
+                                    capture_timestamp_ns,
+                                    samples: payload,
+                                })
+                                .map_err(|e| debug!("{}", e))
+                                .ok();
+                            sequence += 1;
+                        })
                         .map_err(|e| debug!("{}", e))
                         .ok();
+                } else {
+                    warn!("[Audio recorder] Invalid stream data")
                 }
-                _ => warn!("[Audio recorder] Invalid format"),
             }
-        }))?;
+        ))?;
 
         Ok(Self { session })
     }
@@ -178,146 +681,647 @@ impl AudioRecorder {
     }
 }
 
+// Where the audio player sends the samples
+pub enum AudioSink {
+    // If not set, the default output device is used
+    Device(Option<String>),
+
+    // Raw interleaved 32 bit float samples are written at the pace of a real device. Nothing is
+    // played while a named pipe has no reader.
+    File(PathBuf),
+}
+
+impl AudioSink {
+    pub fn from_desc(desc: &AudioDesc) -> Self {
+        match &desc.output_file {
+            Some(path) => AudioSink::File(path.into()),
+            None => AudioSink::Device(desc.output_device.clone()),
+        }
+    }
+}
+
+enum PlayerOutput {
+    Device(AudioSession),
+    File(ThreadLoop),
+}
+
+// Playback queue of the player. The packet thread sends the received samples, already converted
+// to the output format, and the output drains them one buffer at a time through a jitter buffer.
+struct PlaybackQueue {
+    // Arrival time, capture timestamp of the first sample and samples
+    timestamp_packet_receiver: Receiver<(Instant, u64, Vec<f32>)>,
+
+    // Packets come in order from the packet thread, so they are numbered on arrival
+    jitter_buffer: JitterBuffer<(Instant, u64, Vec<f32>)>,
+    next_packet_id: u64,
+
+    // The sample vectors are sent back to the packet thread once played, to be reused
+    recycled_samples_sender: Sender<Vec<f32>>,
+
+    // Shared with the packet thread
+    shared_device_format: Arc<Mutex<Option<Format>>>,
+    playback_speed: Arc<Mutex<f64>>,
+    statistics: Arc<Mutex<AudioPlayerStatistics>>,
+
+    device_format: Option<Format>,
+    callback_max_duration: Duration,
+    drift_controller: DriftController,
+    last_callback_time: Option<Instant>,
+    last_packet_latency: Option<Duration>,
+    last_latency_statistics_time: Option<Instant>,
+
+    // Contains unused samples from the previous packet
+    sample_buffer: Vec<f32>,
+
+    av_sync: Option<AvSync>,
+
+    // Applied by inserting silence or skipping samples. The latencies given to `jitter_buffer` and
+    // `drift_controller` do not include it, so changes of the delay are not mistaken for jitter.
+    sync_delay: Duration,
+    skipped_sample_count: usize,
+
+    echo_reference: Option<EchoReference>,
+
+    // Converts the played samples to the echo reference format
+    reference_resampler: Option<Resampler>,
+    reference_samples: Vec<f32>,
+    resampled_reference_samples: Vec<f32>,
+}
+
+impl PlaybackQueue {
+    fn recycle(&self, mut samples: Vec<f32>) {
+        samples.clear();
+        self.recycled_samples_sender.send(samples).ok();
+    }
+
+    fn push_packet(&mut self, packet: (Instant, u64, Vec<f32>)) {
+        let arrival_timestamp = packet.0;
+        self.jitter_buffer
+            .push(self.next_packet_id, packet, arrival_timestamp);
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+    }
+
+    // Waits for a packet until `deadline`. The latency of the packet is measured at `latency_time`.
+    fn pop_packet(
+        &mut self,
+        deadline: Instant,
+        latency_time: Instant,
+    ) -> Option<(Instant, u64, Vec<f32>)> {
+        loop {
+            while let Ok(packet) = self.timestamp_packet_receiver.try_recv() {
+                self.push_packet(packet);
+            }
+            if !self.jitter_buffer.is_empty() {
+                break;
+            }
+
+            let timeout = if let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                timeout
+            } else {
+                break;
+            };
+            match self.timestamp_packet_receiver.recv_timeout(timeout) {
+                Ok(packet) => self.push_packet(packet),
+                Err(e) => {
+                    debug!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        // If the buffer is still empty, this notifies the underrun
+        self.jitter_buffer
+            .pop(latency_time)
+            .map(|(_, packet)| packet)
+    }
+
+    // Fills `output` with `sample_count` samples, waiting for packets until the buffer would
+    // underrun. Samples not filled because of underrun are left silent.
+    fn fill(&mut self, format: &Format, sample_count: usize, output: &mut Vec<f32>) {
+        if self.device_format.as_ref() != Some(format) {
+            let notifs_per_sec = format.sample_rate.0 as f32 / DEFAULT_BUFFER_FRAME_COUNT as f32;
+            self.callback_max_duration = Duration::from_secs_f32(1_f32 / notifs_per_sec);
+            self.jitter_buffer.reset_pops_per_sec(notifs_per_sec);
+
+            // Samples converted for the previous device cannot be played. The queue restarts
+            // without the synchronization delay, it is inserted again below.
+            self.sample_buffer.clear();
+            while self.timestamp_packet_receiver.try_recv().is_ok() {}
+            self.jitter_buffer.clear();
+            self.sync_delay = Duration::from_secs(0);
+            self.skipped_sample_count = 0;
+
+            self.reference_resampler = self.echo_reference.as_ref().map(|echo_reference| {
+                Resampler::new(format.sample_rate.0, echo_reference.sample_rate(), 1)
+            });
+
+            *self.shared_device_format.lock() = Some(format.clone());
+            self.device_format = Some(format.clone());
+        }
+
+        // The audio/video offset is corrected in one step: correcting it through the playback speed
+        // would take minutes
+        if let Some(av_sync) = &self.av_sync {
+            let sync_delay = av_sync.audio_delay();
+            let delay_sample_count = |delay: Duration| {
+                (delay.as_secs_f64() * format.sample_rate.0 as f64) as usize
+                    * format.channels as usize
+            };
+            if sync_delay > self.sync_delay {
+                let inserted_count = delay_sample_count(sync_delay - self.sync_delay);
+                let unskipped_count = min(inserted_count, self.skipped_sample_count);
+                self.skipped_sample_count -= unskipped_count;
+                self.sample_buffer
+                    .splice(0..0, iter::repeat_n(0., inserted_count - unskipped_count));
+            } else {
+                self.skipped_sample_count += delay_sample_count(self.sync_delay - sync_delay);
+            }
+            self.sync_delay = sync_delay;
+        }
+
+        let callback_begin_time = Instant::now();
+        let callback_underrun_deadline = callback_begin_time + self.callback_max_duration;
+        let latency_time = callback_underrun_deadline - self.sync_delay;
+
+        output.clear();
+        output.resize(sample_count, 0.);
+
+        let skip_count = min(self.skipped_sample_count, self.sample_buffer.len());
+        self.sample_buffer.drain(0..skip_count);
+        self.skipped_sample_count -= skip_count;
+
+        let mut samples = &mut output[..];
+        let max_dequeue_count = min(samples.len(), self.sample_buffer.len());
+
+        samples[0..max_dequeue_count].copy_from_slice(&self.sample_buffer[0..max_dequeue_count]);
+        self.sample_buffer.drain(0..max_dequeue_count);
+        samples = &mut samples[max_dequeue_count..];
+
+        while !samples.is_empty() {
+            let (arrival_timestamp, capture_timestamp_ns, packet_samples) =
+                if let Some(packet) = self.pop_packet(callback_underrun_deadline, latency_time) {
+                    packet
+                } else {
+                    break;
+                };
+            self.last_packet_latency =
+                Some(latency_time.saturating_duration_since(arrival_timestamp));
+
+            if let Some(av_sync) = &self.av_sync {
+                av_sync.notify_audio_packet(
+                    capture_timestamp_ns,
+                    arrival_timestamp + self.jitter_buffer.target_latency(),
+                );
+            }
+
+            let skip_count = min(self.skipped_sample_count, packet_samples.len());
+            self.skipped_sample_count -= skip_count;
+            let received_samples = &packet_samples[skip_count..];
+
+            let max_copy_count = min(samples.len(), received_samples.len());
+            samples[0..max_copy_count].copy_from_slice(&received_samples[0..max_copy_count]);
+            samples = &mut samples[max_copy_count..];
+
+            let has_remaining_samples = max_copy_count < received_samples.len();
+            if has_remaining_samples {
+                // fill sample_buffer with remaining samples. It is empty, all its samples have
+                // been copied before receiving packets.
+                self.sample_buffer
+                    .extend_from_slice(&received_samples[max_copy_count..]);
+            }
+
+            self.recycle(packet_samples);
+            if has_remaining_samples {
+                break;
+            }
+        }
+
+        let underrun_count = self.jitter_buffer.statistics().underrun_count;
+        let mut statistics = self.statistics.lock();
+        if underrun_count > statistics.underrun_count {
+            debug!("Audio player underrun!");
+            statistics.underrun_count = underrun_count;
+        }
+        drop(statistics);
+
+        let callback_interval = self
+            .last_callback_time
+            .map(|time| callback_begin_time - time)
+            .unwrap_or(self.callback_max_duration);
+        self.last_callback_time = Some(callback_begin_time);
+        if let Some(latency) = self.last_packet_latency.take() {
+            *self.playback_speed.lock() = self.drift_controller.update(
+                latency,
+                self.jitter_buffer.target_latency(),
+                callback_interval,
+            );
+        }
+
+        if self
+            .last_latency_statistics_time
+            .map(|time| callback_begin_time - time >= LATENCY_STATISTICS_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.statistics.lock().latency = self.jitter_buffer.latency_statistics();
+            self.last_latency_statistics_time = Some(callback_begin_time);
+        }
+
+        if let (Some(echo_reference), Some(resampler)) =
+            (&self.echo_reference, &mut self.reference_resampler)
+        {
+            self.reference_samples.clear();
+            remap_channels(output, format.channels, 1, &mut self.reference_samples);
+            self.resampled_reference_samples.clear();
+            resampler.process(
+                &self.reference_samples,
+                &mut self.resampled_reference_samples,
+            );
+            echo_reference.push(&self.resampled_reference_samples);
+        }
+    }
+}
+
+// Opening a named pipe for writing blocks until there is a reader, so it is opened in non-blocking
+// mode, that fails instead. Returns None if there is no reader yet. Writes are blocking again after
+// the pipe is opened.
+#[cfg(unix)]
+fn open_file_sink(path: &path::Path) -> io::Result<Option<fs::File>> {
+    use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+
+    match fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => {
+            let fd = file.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Some(file))
+        }
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Opening a named pipe fails immediately if there is no reader
+#[cfg(not(unix))]
+fn open_file_sink(path: &path::Path) -> io::Result<Option<fs::File>> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map(Some)
+}
+
+// Plays into a file or named pipe, with the timing of a device that uses the stream format
+fn spawn_file_sink(
+    path: PathBuf,
+    stream_config: AudioConfig,
+    mut queue: PlaybackQueue,
+) -> StrResult<ThreadLoop> {
+    let format = Format {
+        channels: stream_config.channel_count,
+        sample_rate: SampleRate(stream_config.sample_rate),
+        data_type: SampleFormat::F32,
+    };
+    let sample_count = DEFAULT_BUFFER_FRAME_COUNT * stream_config.channel_count as usize;
+    let buffer_duration = Duration::from_secs_f32(
+        DEFAULT_BUFFER_FRAME_COUNT as f32 / stream_config.sample_rate as f32,
+    );
+
+    let mut maybe_file = None;
+    let mut next_buffer_time = Instant::now();
+    let mut samples = vec![];
+    let mut bytes = vec![];
+
+    thread_loop::spawn("Audio player file sink loop", move || {
+        // The thread loop checks if it must stop between the attempts
+        if maybe_file.is_none() {
+            match open_file_sink(&path) {
+                Ok(Some(file)) => {
+                    maybe_file = Some(file);
+                    next_buffer_time = Instant::now();
+                }
+                Ok(None) => {
+                    thread::sleep(TIMEOUT);
+                    return;
+                }
+                Err(e) => {
+                    warn!("[Audio player] Cannot open {}: {}", path.display(), e);
+                    thread::sleep(TIMEOUT);
+                    return;
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if next_buffer_time > now {
+            thread::sleep(next_buffer_time - now);
+        } else if now - next_buffer_time > buffer_duration {
+            // The reader of the pipe stalled, skip the missed buffers
+            next_buffer_time = now;
+        }
+        next_buffer_time += buffer_duration;
+
+        queue.fill(&format, sample_count, &mut samples);
+
+        bytes.clear();
+        for sample in &samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        if let Some(file) = &mut maybe_file {
+            if let Err(e) = file.write_all(&bytes) {
+                // The reader of the pipe disconnected, wait for the next one
+                debug!("[Audio player] {}", e);
+                maybe_file = None;
+            }
+        }
+    })
+}
+
 pub struct AudioPlayer {
-    session: AudioSession,
+    output: PlayerOutput,
     packet_timestamp_thread: ThreadLoop,
+    statistics: Arc<Mutex<AudioPlayerStatistics>>,
 }
 
 impl AudioPlayer {
+    // The received samples are in `stream_config` format and are converted if the device does not
+    // support it. The played samples are sent to `echo_reference` if set. If `av_sync` is set, the
+    // playout is delayed to match the displayed video.
     pub fn start_playback(
-        device_idx: Option<u64>,
+        sink: AudioSink,
+        stream_config: AudioConfig,
         latency_desc: LatencyDesc,
-        mut packet_dequeuer: PacketDequeuer,
+        echo_reference: Option<EchoReference>,
+        av_sync: Option<AvSync>,
+        mut packet_dequeuer: PacketDequeuer<AudioStream>,
     ) -> StrResult<AudioPlayer> {
         let (timestamp_packet_sender, timestamp_packet_receiver) = channel();
+        let (recycled_samples_sender, recycled_samples_receiver) = channel();
+
+        // Set by the device callback, used by the packet thread to convert the samples
+        let shared_device_format = Arc::new(Mutex::new(None));
+        let mut device_format: Option<Format> = None;
+        let mut resampler = Resampler::new(
+            stream_config.sample_rate,
+            stream_config.sample_rate,
+            stream_config.channel_count,
+        );
+        let mut decoder = AudioDecoder::new(stream_config)?;
+        let mut decoded_samples = vec![];
+        let mut remapped_samples = vec![];
 
-        let packet_timestamp_thread =
-            thread_loop::spawn("Audio player packet forward loop", move || {
+        let statistics = Arc::new(Mutex::new(AudioPlayerStatistics::default()));
+
+        // Set by the device callback, applied by the packet thread
+        let playback_speed = Arc::new(Mutex::new(1_f64));
+
+        // Longer gaps are not concealed: the stream was interrupted and there is no point in
+        // filling the queue with silence
+        let max_concealed_sample_count =
+            (stream_config.sample_rate as f32 * MAX_CONCEALMENT_DURATION.as_secs_f32()) as u64;
+        let mut next_sequence = None;
+
+        let packet_timestamp_thread = thread_loop::spawn("Audio player packet forward loop", {
+            let statistics = statistics.clone();
+            let playback_speed = playback_speed.clone();
+            let shared_device_format = shared_device_format.clone();
+            move || {
                 let maybe_packet = packet_dequeuer
                     .dequeue(TIMEOUT)
                     .map_err(|e| debug!("{}", e));
 
                 if let Ok(packet) = maybe_packet {
-                    let maybe_audio_packet =
-                        packet.get::<AudioPacket>().map_err(|e| debug!("{}", e));
+                    let maybe_audio_packet = packet.get().map_err(|e| debug!("{}", e));
                     if let Ok(audio_packet) = maybe_audio_packet {
-                        // Ignore the packet if transmute fails. The chance of a packet having the
-                        // length corrupted but resulting valid by bincode is non existent
-                        if let Ok(samples) =
-                            transmute_many::<f32, PermissiveGuard>(audio_packet.samples)
+                        let mut statistics = statistics.lock();
+                        statistics.received_packet_count += 1;
+
+                        let lost_count = match next_sequence
+                            .map(|next_sequence| sequence_gap(next_sequence, audio_packet.sequence))
                         {
+                            Some(SequenceGap::Late) => {
+                                // The samples that would have been filled by this packet have
+                                // already been concealed
+                                statistics.late_packet_count += 1;
+                                return;
+                            }
+                            Some(SequenceGap::Lost(count)) => count,
+                            Some(SequenceGap::Resync) => {
+                                info!("[Audio player] Stream restarted");
+                                0
+                            }
+                            None => 0,
+                        };
+                        next_sequence = Some(audio_packet.sequence.wrapping_add(1));
+
+                        decoded_samples.clear();
+                        statistics.lost_packet_count += lost_count;
+                        let concealable = lost_count
+                            .checked_mul(audio_packet.sample_count as u64)
+                            .map(|count| count <= max_concealed_sample_count)
+                            .unwrap_or(false);
+                        if concealable {
+                            for idx in 0..lost_count {
+                                // FEC data in a packet refers only to the packet before it
+                                let next_packet = if idx == lost_count - 1 {
+                                    Some(audio_packet.samples)
+                                } else {
+                                    None
+                                };
+                                match decoder.decode_lost(
+                                    next_packet,
+                                    audio_packet.sample_count as _,
+                                    &mut decoded_samples,
+                                ) {
+                                    Ok(LossRecovery::Fec) => statistics.recovered_packet_count += 1,
+                                    Ok(LossRecovery::Concealment) => {
+                                        statistics.concealed_packet_count += 1
+                                    }
+                                    Err(e) => debug!("{}", e),
+                                }
+                            }
+                        }
+
+                        drop(statistics);
+
+                        // The concealed samples are played before the ones of this packet
+                        let concealed_frame_count =
+                            decoded_samples.len() / stream_config.channel_count as usize;
+                        let capture_timestamp_ns = audio_packet
+                            .capture_timestamp_ns
+                            .saturating_sub(frame_count_to_ns(
+                                concealed_frame_count as _,
+                                stream_config.sample_rate,
+                            ));
+
+                        let maybe_decoded = decoder
+                            .decode(audio_packet.samples, &mut decoded_samples)
+                            .map_err(|e| debug!("{}", e));
+
+                        let current_format = shared_device_format.lock().clone();
+                        if current_format.is_some() && current_format != device_format {
+                            if let Some(format) = &current_format {
+                                resampler = Resampler::new(
+                                    stream_config.sample_rate,
+                                    format.sample_rate.0,
+                                    format.channels,
+                                );
+                            }
+                            device_format = current_format;
+                        }
+
+                        // Until the device is started there is nowhere to send the samples
+                        if let (Ok(()), Some(format)) = (maybe_decoded, &device_format) {
+                            remapped_samples.clear();
+                            remap_channels(
+                                &decoded_samples,
+                                stream_config.channel_count,
+                                format.channels,
+                                &mut remapped_samples,
+                            );
+                            let mut device_samples =
+                                recycled_samples_receiver.try_recv().unwrap_or_default();
+                            resampler.set_speed(*playback_speed.lock());
+                            resampler.process(&remapped_samples, &mut device_samples);
+
                             timestamp_packet_sender
-                                .send((Instant::now(), samples.to_vec()))
+                                .send((Instant::now(), capture_timestamp_ns, device_samples))
                                 .map_err(|e| debug!("{}", e))
                                 .ok();
                         }
                     }
                 }
-            })?;
-
-        let sample_rate_hz = 44100_f32; // todo query
-        let default_buffer_size = 1024_f32; // todo update
-        let notifs_per_sec = sample_rate_hz / default_buffer_size;
-
-        let callback_max_duration = Duration::from_secs_f32(1_f32 / notifs_per_sec);
-
-        let mut event_timing = EventTiming::new(latency_desc, notifs_per_sec);
+            }
+        })?;
 
-        // Contains unused samples from the previous packet
-        let mut sample_buffer = vec![];
+        let notifs_per_sec = stream_config.sample_rate as f32 / DEFAULT_BUFFER_FRAME_COUNT as f32;
+        let mut queue = PlaybackQueue {
+            timestamp_packet_receiver,
+            jitter_buffer: JitterBuffer::new(latency_desc, notifs_per_sec),
+            next_packet_id: 0,
+            recycled_samples_sender,
+            shared_device_format,
+            playback_speed,
+            statistics: statistics.clone(),
+            device_format: None,
+            callback_max_duration: Duration::from_secs_f32(1_f32 / notifs_per_sec),
+            drift_controller: DriftController::new(),
+            last_callback_time: None,
+            last_packet_latency: None,
+            last_latency_statistics_time: None,
+            sample_buffer: vec![],
+            av_sync,
+            sync_delay: Duration::from_secs(0),
+            skipped_sample_count: 0,
+            echo_reference,
+            reference_resampler: None,
+            reference_samples: vec![],
+            resampled_reference_samples: vec![],
+        };
 
-        let session = trace_err!(AudioSession::start(
-            device_idx,
-            AudioMode::Output,
-            move |io_data| {
-                let callback_begin_time = Instant::now();
-                let callback_underrun_deadline = callback_begin_time + callback_max_duration;
-
-                match io_data {
-                    StreamData::Output {
-                        buffer: UnknownTypeOutputBuffer::F32(mut samples),
-                    } => {
-                        let mut samples = &mut samples[..];
-                        let max_dequeue_count = min(samples.len(), sample_buffer.len());
-
-                        samples[0..max_dequeue_count].copy_from_slice(
-                            &sample_buffer
-                                .drain(0..max_dequeue_count)
-                                .collect::<Vec<_>>(),
-                        );
-                        samples = &mut samples[max_dequeue_count..];
-
-                        while !samples.is_empty() {
-                            let estimated_underrun_timeout =
-                                if let Some(estimated_underrun_timeout) =
-                                    callback_max_duration.checked_sub(callback_begin_time.elapsed())
-                                {
-                                    estimated_underrun_timeout
-                                } else {
-                                    break;
-                                };
+        let output = match sink {
+            AudioSink::Device(device_id) => {
+                let mut output_samples = vec![];
+                let mut ditherer = Ditherer::new();
 
-                            let (arrival_timestamp, received_samples) = if let Ok(pair) =
-                                timestamp_packet_receiver
-                                    .recv_timeout(estimated_underrun_timeout)
-                                    .map_err(|e| debug!("{}", e))
-                            {
-                                pair
-                            } else {
-                                break;
+                PlayerOutput::Device(trace_err!(AudioSession::start(
+                    device_id,
+                    AudioMode::Output,
+                    stream_config,
+                    move |format, io_data| match io_data {
+                        StreamData::Output { buffer } => {
+                            let buffer_len = match &buffer {
+                                UnknownTypeOutputBuffer::F32(samples) => samples.len(),
+                                UnknownTypeOutputBuffer::I16(samples) => samples.len(),
+                                UnknownTypeOutputBuffer::U16(samples) => samples.len(),
                             };
 
-                            event_timing
-                                .notify_latency(callback_underrun_deadline - arrival_timestamp);
-                            if let Some(target_latency_deviation) = event_timing
-                                .average_latency()
-                                .checked_sub(event_timing.target_latency())
-                            {
-                                if target_latency_deviation > callback_max_duration {
-                                    // the packet queue length was > 1 for a while, so drop one
-                                    // packet.
-
-                                    // since EventTiming has "momentum", add a fake packet notify to
-                                    // normalize its state and to avoid dropping packets until
-                                    // underrun.
-                                    event_timing.notify_latency(event_timing.target_latency());
-
-                                    continue;
-                                }
-                            }
+                            queue.fill(format, buffer_len, &mut output_samples);
 
-                            let max_copy_count = min(samples.len(), received_samples.len());
-                            samples[0..max_copy_count]
-                                .copy_from_slice(&received_samples[0..max_copy_count]);
-                            samples = &mut samples[max_dequeue_count..];
-
-                            if max_copy_count < received_samples.len() {
-                                // fill sample_buffer with remaining samples
-                                sample_buffer = received_samples[max_copy_count..].to_vec();
-                                break;
+                            match buffer {
+                                UnknownTypeOutputBuffer::F32(mut samples) => {
+                                    samples.copy_from_slice(&output_samples)
+                                }
+                                UnknownTypeOutputBuffer::I16(mut samples) => {
+                                    for (sample, value) in samples.iter_mut().zip(&output_samples) {
+                                        *sample = ditherer.dither(*value, 16).to_i16();
+                                    }
+                                }
+                                UnknownTypeOutputBuffer::U16(mut samples) => {
+                                    for (sample, value) in samples.iter_mut().zip(&output_samples) {
+                                        *sample = ditherer.dither(*value, 16).to_u16();
+                                    }
+                                }
                             }
                         }
-
-                        if callback_begin_time.elapsed() > callback_max_duration {
-                            debug!("Audio player underrun!");
-
-                            // fake packet notify to account for no packets found
-                            event_timing.notify_latency(Duration::new(0, 0))
-                        }
+                        _ => warn!("[Audio player] Invalid stream data"),
                     }
-                    _ => warn!("[Audio player] Invalid Format"),
-                }
+                ))?)
             }
-        ))?;
+            AudioSink::File(path) => {
+                PlayerOutput::File(spawn_file_sink(path, stream_config, queue)?)
+            }
+        };
 
         Ok(Self {
-            session,
+            output,
             packet_timestamp_thread,
+            statistics,
         })
     }
 
+    pub fn statistics(&self) -> AudioPlayerStatistics {
+        self.statistics.lock().clone()
+    }
+
     pub fn request_stop(&mut self) {
-        self.session.request_stop();
+        match &mut self.output {
+            PlayerOutput::Device(session) => session.request_stop(),
+            PlayerOutput::File(file_sink_thread) => file_sink_thread.request_stop(),
+        }
         self.packet_timestamp_thread.request_stop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_gap_in_order() {
+        assert_eq!(sequence_gap(10, 10), SequenceGap::Lost(0));
+        assert_eq!(sequence_gap(10, 13), SequenceGap::Lost(3));
+        assert_eq!(sequence_gap(10, 9), SequenceGap::Late);
+    }
+
+    #[test]
+    fn sequence_gap_wraps_around() {
+        assert_eq!(sequence_gap(u64::MAX, 0), SequenceGap::Lost(1));
+        assert_eq!(sequence_gap(0, u64::MAX), SequenceGap::Late);
+        assert_eq!(sequence_gap(u64::MAX - 2, 1), SequenceGap::Lost(4));
+    }
+
+    #[test]
+    fn sequence_gap_resyncs_on_jumps() {
+        assert_eq!(
+            sequence_gap(10, 10 + MAX_SEQUENCE_JUMP),
+            SequenceGap::Lost(MAX_SEQUENCE_JUMP)
+        );
+        assert_eq!(
+            sequence_gap(10, 11 + MAX_SEQUENCE_JUMP),
+            SequenceGap::Resync
+        );
+        assert_eq!(sequence_gap(5000, 0), SequenceGap::Resync);
+        assert_eq!(sequence_gap(0, u64::MAX / 2), SequenceGap::Resync);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::{data::*, *};
+use opus::{Application, Bitrate, Channels};
+
+const TRACE_CONTEXT: &str = "Audio codec";
+
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+// Enough for 120ms at 48KHz, the longest frame allowed by Opus
+const OPUS_MAX_FRAME_SIZE: usize = 5760;
+
+// Recommended by the Opus documentation
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+
+// Audio played back and forth to extrapolate lost PCM packets. It should be longer than the period
+// of the lowest pitch expected, to avoid a buzzing sound.
+const CONCEALMENT_HISTORY_MS: u32 = 20;
+
+// The extrapolated audio fades to silence in this time, then silence is played
+const CONCEALMENT_FADE_OUT_MS: u32 = 40;
+
+// Duration of the crossfade between the extrapolated audio and the first packet after a loss
+const CONCEALMENT_CROSSFADE_MS: u32 = 3;
+
+pub fn supports_opus(sample_rate: u32, channel_count: u16) -> bool {
+    OPUS_SAMPLE_RATES.contains(&sample_rate) && (channel_count == 1 || channel_count == 2)
+}
+
+// Adds triangular (TPDF) dither noise before reducing the bit depth, to decorrelate the
+// quantization error from the signal
+pub struct Ditherer {
+    // xorshift32 state. The noise does not need to be of high quality.
+    state: u32,
+}
+
+impl Ditherer {
+    pub fn new() -> Self {
+        Self { state: 0x1234_5678 }
+    }
+
+    // Uniform in [0, 1)
+    fn next_random(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    // `bit_depth` is the target bit depth, including the sign bit
+    pub fn dither(&mut self, sample: f32, bit_depth: u32) -> f32 {
+        let lsb = 1. / (1 << (bit_depth - 1)) as f32;
+        sample + (self.next_random() - self.next_random()) * lsb
+    }
+}
+
+impl Default for Ditherer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bit_depth(format: &AudioFormat) -> u32 {
+    match format {
+        AudioFormat::Bit8 => 8,
+        AudioFormat::Bit16 => 16,
+        AudioFormat::Bit24 => 24,
+    }
+}
+
+// Samples are signed little endian integers
+fn encode_pcm(
+    samples: &[f32],
+    format: &AudioFormat,
+    ditherer: &mut Ditherer,
+    output: &mut Vec<u8>,
+) {
+    let bit_depth = bit_depth(format);
+    let max_value = ((1 << (bit_depth - 1)) - 1) as f32;
+    for sample in samples {
+        let value = (ditherer.dither(*sample, bit_depth) * max_value)
+            .round()
+            .max(-max_value - 1.)
+            .min(max_value) as i32;
+        let bytes = value.to_le_bytes();
+        output.extend_from_slice(&bytes[..bit_depth as usize / 8]);
+    }
+}
+
+fn decode_pcm(packet: &[u8], format: &AudioFormat, output: &mut Vec<f32>) {
+    let bit_depth = bit_depth(format);
+    let sample_size = bit_depth as usize / 8;
+    let scale = 1. / (1 << (bit_depth - 1)) as f32;
+    output.extend(packet.chunks_exact(sample_size).map(|bytes| {
+        // Place the bytes in the most significant positions to extend the sign
+        let mut value_bytes = [0; 4];
+        value_bytes[4 - sample_size..].copy_from_slice(bytes);
+        (i32::from_le_bytes(value_bytes) >> (32 - bit_depth)) as f32 * scale
+    }));
+}
+
+fn opus_channels(channel_count: u16) -> StrResult<Channels> {
+    match channel_count {
+        1 => Ok(Channels::Mono),
+        2 => Ok(Channels::Stereo),
+        _ => trace_str!("Unsupported channel count: {}", channel_count),
+    }
+}
+
+fn frame_size(sample_rate: u32, frame_duration: OpusFrameDuration) -> usize {
+    let duration_ms = match frame_duration {
+        OpusFrameDuration::Ms5 => 5,
+        OpusFrameDuration::Ms10 => 10,
+        OpusFrameDuration::Ms20 => 20,
+        OpusFrameDuration::Ms40 => 40,
+        OpusFrameDuration::Ms60 => 60,
+    };
+    (sample_rate * duration_ms / 1000) as _
+}
+
+enum Encoder {
+    Pcm {
+        format: AudioFormat,
+        ditherer: Ditherer,
+        packet_buffer: Vec<u8>,
+    },
+    Opus {
+        encoder: opus::Encoder,
+        // Samples per channel in a frame
+        frame_size: usize,
+        pending_samples: Vec<f32>,
+        packet_buffer: Vec<u8>,
+    },
+}
+
+// Converts interleaved samples to the payload of audio packets
+pub struct AudioEncoder {
+    channel_count: usize,
+    encoder: Encoder,
+}
+
+impl AudioEncoder {
+    // `voice` tunes Opus for speech. In-band FEC is effective only in this mode.
+    pub fn new(config: AudioConfig, voice: bool) -> StrResult<Self> {
+        let encoder = match config.codec {
+            AudioCodec::Pcm(format) => Encoder::Pcm {
+                format,
+                ditherer: Ditherer::new(),
+                packet_buffer: vec![],
+            },
+            AudioCodec::Opus(desc) => {
+                let application = if voice {
+                    Application::Voip
+                } else {
+                    Application::Audio
+                };
+                let mut encoder = trace_err!(opus::Encoder::new(
+                    config.sample_rate,
+                    opus_channels(config.channel_count)?,
+                    application
+                ))?;
+                trace_err!(encoder.set_bitrate(Bitrate::Bits(desc.bitrate_kbps as i32 * 1000)))?;
+                trace_err!(encoder.set_inband_fec(desc.in_band_fec))?;
+                trace_err!(encoder.set_packet_loss_perc(desc.expected_packet_loss_percent as _))?;
+
+                Encoder::Opus {
+                    encoder,
+                    frame_size: frame_size(config.sample_rate, desc.frame_duration),
+                    pending_samples: vec![],
+                    packet_buffer: vec![0; OPUS_MAX_PACKET_SIZE],
+                }
+            }
+        };
+
+        Ok(Self {
+            channel_count: config.channel_count as _,
+            encoder,
+        })
+    }
+
+    // Opus needs fixed size frames: samples are accumulated and `packet_callback` is called once
+    // per complete frame, so it can be called zero or multiple times. The callback receives the
+    // payload and the number of samples per channel it contains.
+    pub fn encode(
+        &mut self,
+        samples: &[f32],
+        mut packet_callback: impl FnMut(&[u8], usize),
+    ) -> StrResult {
+        match &mut self.encoder {
+            Encoder::Pcm {
+                format,
+                ditherer,
+                packet_buffer,
+            } => {
+                packet_buffer.clear();
+                encode_pcm(samples, format, ditherer, packet_buffer);
+                packet_callback(packet_buffer, samples.len() / self.channel_count);
+                Ok(())
+            }
+            Encoder::Opus {
+                encoder,
+                frame_size,
+                pending_samples,
+                packet_buffer,
+            } => {
+                pending_samples.extend_from_slice(samples);
+
+                let frame_sample_count = *frame_size * self.channel_count;
+                let mut consumed_count = 0;
+                while pending_samples.len() - consumed_count >= frame_sample_count {
+                    let frame =
+                        &pending_samples[consumed_count..consumed_count + frame_sample_count];
+                    let packet_size = trace_err!(encoder.encode_float(frame, packet_buffer))?;
+                    packet_callback(&packet_buffer[..packet_size], *frame_size);
+                    consumed_count += frame_sample_count;
+                }
+                pending_samples.drain(0..consumed_count);
+
+                Ok(())
+            }
+        }
+    }
+}
+
+// Waveform extrapolation for codecs without a native concealment. Lost samples are synthesized by
+// playing the last received samples backwards and forwards, so the waveform stays continuous at
+// the turning points, while fading out. The first packet after a loss is crossfaded with the
+// extrapolated audio.
+struct Concealer {
+    channel_count: usize,
+    history_frame_count: usize,
+    crossfade_frame_count: usize,
+    gain_step: f32,
+
+    // Last received frames, interleaved
+    history: Vec<f32>,
+
+    concealing: bool,
+    position: usize,
+    forward: bool,
+    gain: f32,
+}
+
+impl Concealer {
+    fn new(sample_rate: u32, channel_count: usize) -> Self {
+        Self {
+            channel_count,
+            history_frame_count: (sample_rate * CONCEALMENT_HISTORY_MS / 1000) as _,
+            crossfade_frame_count: (sample_rate * CONCEALMENT_CROSSFADE_MS / 1000) as _,
+            gain_step: 1000. / (sample_rate * CONCEALMENT_FADE_OUT_MS) as f32,
+            history: vec![],
+            concealing: false,
+            position: 0,
+            forward: false,
+            gain: 0.,
+        }
+    }
+
+    fn extrapolate_frame(&mut self, output: &mut Vec<f32>) {
+        let frame_count = self.history.len() / self.channel_count;
+        if frame_count == 0 || self.gain <= 0. {
+            output.extend((0..self.channel_count).map(|_| 0_f32));
+            return;
+        }
+
+        let first_sample = self.position * self.channel_count;
+        let gain = self.gain;
+        output.extend(
+            self.history[first_sample..first_sample + self.channel_count]
+                .iter()
+                .map(|sample| sample * gain),
+        );
+
+        // Bounce at the ends of the history
+        if self.forward {
+            if self.position + 1 < frame_count {
+                self.position += 1;
+            } else {
+                self.forward = false;
+            }
+        } else if self.position > 0 {
+            self.position -= 1;
+        } else {
+            self.forward = true;
+        }
+        self.gain = (self.gain - self.gain_step).max(0.);
+    }
+
+    fn conceal(&mut self, frame_count: usize, output: &mut Vec<f32>) {
+        if !self.concealing {
+            // The last frame of the history has already been played, start from the one before
+            self.concealing = true;
+            self.position = (self.history.len() / self.channel_count).saturating_sub(2);
+            self.forward = false;
+            self.gain = 1.;
+        }
+
+        for _ in 0..frame_count {
+            self.extrapolate_frame(output);
+        }
+    }
+
+    // `samples` are the samples just decoded. They are modified to fade in after a loss.
+    fn push(&mut self, samples: &mut [f32]) {
+        if self.concealing {
+            self.concealing = false;
+
+            let frame_count = usize::min(
+                samples.len() / self.channel_count,
+                self.crossfade_frame_count,
+            );
+            let mut extrapolated = Vec::with_capacity(frame_count * self.channel_count);
+            for _ in 0..frame_count {
+                self.extrapolate_frame(&mut extrapolated);
+            }
+
+            for (frame, (samples, extrapolated)) in samples
+                .chunks_exact_mut(self.channel_count)
+                .zip(extrapolated.chunks_exact(self.channel_count))
+                .enumerate()
+            {
+                let fade_in = (frame + 1) as f32 / (frame_count + 1) as f32;
+                for (sample, extrapolated) in samples.iter_mut().zip(extrapolated) {
+                    *sample = *sample * fade_in + extrapolated * (1. - fade_in);
+                }
+            }
+        }
+
+        self.history.extend_from_slice(samples);
+        let max_len = self.history_frame_count * self.channel_count;
+        if self.history.len() > max_len {
+            self.history.drain(0..self.history.len() - max_len);
+        }
+    }
+}
+
+// How a lost packet has been replaced
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LossRecovery {
+    Fec,
+    Concealment,
+}
+
+enum Decoder {
+    Pcm {
+        format: AudioFormat,
+        concealer: Concealer,
+    },
+    Opus {
+        decoder: opus::Decoder,
+        in_band_fec: bool,
+        frame_buffer: Vec<f32>,
+    },
+}
+
+// Converts the payload of audio packets back to interleaved samples
+pub struct AudioDecoder {
+    channel_count: usize,
+    decoder: Decoder,
+}
+
+impl AudioDecoder {
+    pub fn new(config: AudioConfig) -> StrResult<Self> {
+        let channel_count = config.channel_count as usize;
+
+        let decoder = match config.codec {
+            AudioCodec::Pcm(format) => Decoder::Pcm {
+                format,
+                concealer: Concealer::new(config.sample_rate, channel_count),
+            },
+            AudioCodec::Opus(desc) => Decoder::Opus {
+                decoder: trace_err!(opus::Decoder::new(
+                    config.sample_rate,
+                    opus_channels(config.channel_count)?
+                ))?,
+                in_band_fec: desc.in_band_fec,
+                frame_buffer: vec![0.; OPUS_MAX_FRAME_SIZE * channel_count],
+            },
+        };
+
+        Ok(Self {
+            channel_count,
+            decoder,
+        })
+    }
+
+    // Decoded samples are appended to `output`
+    pub fn decode(&mut self, packet: &[u8], output: &mut Vec<f32>) -> StrResult {
+        match &mut self.decoder {
+            Decoder::Pcm { format, concealer } => {
+                let old_len = output.len();
+                decode_pcm(packet, format, output);
+                concealer.push(&mut output[old_len..]);
+            }
+            Decoder::Opus {
+                decoder,
+                frame_buffer,
+                ..
+            } => {
+                let frame_size = trace_err!(decoder.decode_float(packet, frame_buffer, false))?;
+                output.extend_from_slice(&frame_buffer[..frame_size * self.channel_count]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reconstruct a lost packet of `sample_count` samples per channel. With Opus, if FEC is enabled
+    // and the packet that follows the lost one is available, its FEC data is used. Otherwise the
+    // loss is concealed by extrapolation, natively by Opus or by playing back the last samples for
+    // PCM.
+    pub fn decode_lost(
+        &mut self,
+        next_packet: Option<&[u8]>,
+        sample_count: usize,
+        output: &mut Vec<f32>,
+    ) -> StrResult<LossRecovery> {
+        match &mut self.decoder {
+            Decoder::Pcm { concealer, .. } => {
+                concealer.conceal(sample_count, output);
+                Ok(LossRecovery::Concealment)
+            }
+            Decoder::Opus {
+                decoder,
+                in_band_fec,
+                frame_buffer,
+            } => {
+                let buffer_len = usize::min(sample_count, OPUS_MAX_FRAME_SIZE) * self.channel_count;
+                let buffer = &mut frame_buffer[..buffer_len];
+                let (frame_size, recovery) = match next_packet {
+                    Some(packet) if *in_band_fec => (
+                        trace_err!(decoder.decode_float(packet, buffer, true))?,
+                        LossRecovery::Fec,
+                    ),
+                    _ => (
+                        trace_err!(decoder.decode_float(&[], buffer, false))?,
+                        LossRecovery::Concealment,
+                    ),
+                };
+                output.extend_from_slice(&frame_buffer[..frame_size * self.channel_count]);
+
+                Ok(recovery)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SIGNAL_DURATION_S: f32 = 0.5;
+
+    // Input is pushed in buffers not aligned to the codec frames, like the audio device does
+    const PUSH_FRAME_COUNT: usize = 441;
+
+    // Upper bound of the codec delay, in frames
+    const MAX_DELAY_FRAMES: usize = 2000;
+
+    // Each channel has a different tone, with a slow frequency sweep to avoid a trivial signal
+    fn synthetic_signal(sample_rate: u32, channel_count: u16) -> Vec<f32> {
+        let frame_count = (sample_rate as f32 * SIGNAL_DURATION_S) as usize;
+        (0..frame_count)
+            .flat_map(|frame| {
+                let time_s = frame as f32 / sample_rate as f32;
+                (0..channel_count).map(move |channel| {
+                    let frequency = 300. * (channel + 1) as f32 + 100. * time_s;
+                    0.5 * (2. * PI * frequency * time_s).sin()
+                })
+            })
+            .collect()
+    }
+
+    // Signal to noise ratio in dB, searching the delay introduced by the codec
+    fn best_snr_db(reference: &[f32], decoded: &[f32], channel_count: usize) -> f32 {
+        let compared_count = reference.len() - MAX_DELAY_FRAMES * channel_count;
+        let signal_energy: f32 = reference[..compared_count].iter().map(|s| s * s).sum();
+
+        (0..MAX_DELAY_FRAMES)
+            .map(|delay| {
+                let offset = delay * channel_count;
+                let noise_energy: f32 = reference[..compared_count]
+                    .iter()
+                    .zip(&decoded[offset..offset + compared_count])
+                    .map(|(r, d)| (r - d) * (r - d))
+                    .sum();
+                10. * (signal_energy / noise_energy.max(f32::MIN_POSITIVE)).log10()
+            })
+            .fold(f32::MIN, f32::max)
+    }
+
+    // Encodes and decodes a synthetic signal and checks the quality of the result
+    fn round_trip(config: AudioConfig, voice: bool, min_snr_db: f32) {
+        let channel_count = config.channel_count as usize;
+        let signal = synthetic_signal(config.sample_rate, config.channel_count);
+
+        let mut encoder = AudioEncoder::new(config, voice).unwrap();
+        let mut decoder = AudioDecoder::new(config).unwrap();
+
+        let mut packets = vec![];
+        for buffer in signal.chunks(PUSH_FRAME_COUNT * channel_count) {
+            encoder
+                .encode(buffer, |packet, sample_count| {
+                    packets.push((packet.to_vec(), sample_count))
+                })
+                .unwrap();
+        }
+
+        let mut decoded = vec![];
+        for (packet, _) in &packets {
+            decoder.decode(packet, &mut decoded).unwrap();
+        }
+        // Pad for the samples retained by the encoder
+        decoded.resize(signal.len() + MAX_DELAY_FRAMES * channel_count, 0.);
+
+        let snr_db = best_snr_db(&signal, &decoded, channel_count);
+        assert!(snr_db >= min_snr_db, "SNR {} dB", snr_db);
+
+        // A lost packet must be replaced by a packet of the same duration, both when the next
+        // packet is available and when it is not
+        let mut decoder = AudioDecoder::new(config).unwrap();
+        let mut decoded = vec![];
+        decoder.decode(&packets[0].0, &mut decoded).unwrap();
+        let frame_sample_count = decoded.len();
+        assert_eq!(frame_sample_count, packets[0].1 * channel_count);
+        for next_packet in &[Some(&packets[2].0[..]), None] {
+            decoded.clear();
+            decoder
+                .decode_lost(*next_packet, packets[1].1, &mut decoded)
+                .unwrap();
+            assert_eq!(decoded.len(), frame_sample_count);
+        }
+    }
+
+    fn opus_desc(
+        bitrate_kbps: u32,
+        frame_duration: OpusFrameDuration,
+        in_band_fec: bool,
+    ) -> OpusDesc {
+        OpusDesc {
+            bitrate_kbps,
+            frame_duration,
+            in_band_fec,
+            expected_packet_loss_percent: 10,
+        }
+    }
+
+    #[test]
+    fn pcm_8_bit() {
+        let config = AudioConfig {
+            sample_rate: 44100,
+            channel_count: 2,
+            codec: AudioCodec::Pcm(AudioFormat::Bit8),
+        };
+        round_trip(config, false, 35.);
+    }
+
+    #[test]
+    fn pcm_16_bit() {
+        let config = AudioConfig {
+            sample_rate: 44100,
+            channel_count: 2,
+            codec: AudioCodec::Pcm(AudioFormat::Bit16),
+        };
+        round_trip(config, false, 80.);
+    }
+
+    #[test]
+    fn pcm_24_bit() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            channel_count: 1,
+            codec: AudioCodec::Pcm(AudioFormat::Bit24),
+        };
+        round_trip(config, false, 110.);
+    }
+
+    #[test]
+    fn opus_game_audio() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            channel_count: 2,
+            codec: AudioCodec::Opus(opus_desc(128, OpusFrameDuration::Ms10, false)),
+        };
+        round_trip(config, false, 15.);
+    }
+
+    #[test]
+    fn opus_voice_with_fec() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            channel_count: 1,
+            codec: AudioCodec::Opus(opus_desc(32, OpusFrameDuration::Ms20, true)),
+        };
+        round_trip(config, true, 10.);
+    }
+
+    #[test]
+    fn opus_narrowband_voice() {
+        let config = AudioConfig {
+            sample_rate: 16000,
+            channel_count: 1,
+            codec: AudioCodec::Opus(opus_desc(16, OpusFrameDuration::Ms5, false)),
+        };
+        round_trip(config, true, 10.);
+    }
+
+    #[test]
+    fn pcm_concealment_fades_to_silence() {
+        let sample_rate = 48000;
+        let mut concealer = Concealer::new(sample_rate, 1);
+
+        let mut samples = synthetic_signal(sample_rate, 1);
+        samples.truncate(sample_rate as usize / 10);
+        concealer.push(&mut samples);
+
+        let fade_out_frame_count = (sample_rate * CONCEALMENT_FADE_OUT_MS / 1000) as usize;
+        let mut concealed = vec![];
+        concealer.conceal(fade_out_frame_count * 2, &mut concealed);
+
+        assert_eq!(concealed.len(), fade_out_frame_count * 2);
+        assert!(concealed[..10].iter().any(|sample| *sample != 0.));
+        assert!(concealed[fade_out_frame_count + 1..]
+            .iter()
+            .all(|sample| *sample == 0.));
+    }
+}
@@ -0,0 +1,1063 @@
+use crate::data::*;
+use parking_lot::Mutex;
+use settings_schema::Switch;
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    ops::{Add, Mul, Sub},
+    sync::Arc,
+};
+
+// Frames processed at once. It must be a power of two, as required by the FFT.
+const BLOCK_SIZE: usize = 256;
+
+// Longest misalignment between the echo reference and the microphone before old reference samples
+// are discarded
+const MAX_ECHO_REFERENCE_DURATION_S: f32 = 1.;
+
+// Step size of the echo canceller adaptive filter, between 0 and 1. Higher values converge faster
+// but leave more residual echo.
+const ECHO_CANCELLER_STEP_SIZE: f32 = 0.5;
+
+// Smoothing of the error energies of the echo canceller filters, per block
+const ERROR_ENERGY_SMOOTHING: f32 = 0.9;
+
+// The background filter replaces the foreground filter when its error is 3dB lower, and it is
+// reset when its error is 6dB higher
+const FOREGROUND_UPDATE_RATIO: f32 = 0.5;
+const BACKGROUND_RESET_RATIO: f32 = 4.;
+
+// The reference is considered silent below this level and the echo canceller is not adapted
+const MIN_REFERENCE_POWER: f32 = 1e-8;
+
+// Smoothing of the decision-directed estimation of the a priori SNR
+const NOISE_SUPPRESSOR_SNR_SMOOTHING: f32 = 0.98;
+
+// The noise floor is estimated by tracking the minimum of the power of each frequency. It can rise
+// at most by this rate, so speech is not mistaken for noise.
+const NOISE_FLOOR_RISE_DB_PER_S: f32 = 3.;
+
+// The minimum of a noisy power is lower than its mean
+const NOISE_FLOOR_BIAS: f32 = 2.;
+
+// Blocks used to initialize the noise floor, assuming that the first samples do not contain speech
+const NOISE_FLOOR_INIT_BLOCKS: usize = 10;
+
+// Time constants of the level tracked by the automatic gain control
+const GAIN_CONTROL_ATTACK_S: f32 = 0.05;
+const GAIN_CONTROL_RELEASE_S: f32 = 1.;
+
+// The automatic gain control does not react to samples quieter than this (-50dBFS), so noise is
+// not amplified during silence
+const GAIN_CONTROL_GATE_LEVEL: f32 = 0.003;
+
+// The gain control can attenuate loud voices at most by 20dB
+const GAIN_CONTROL_MIN_GAIN: f32 = 0.1;
+
+// Samples above this level are soft clipped
+const LIMITER_THRESHOLD: f32 = 0.9;
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10_f32.powf(db / 20.)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// Iterative radix-2 FFT
+struct Fft {
+    twiddles: Vec<Complex>,
+    bit_reversed_indices: Vec<usize>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        let bit_count = size.trailing_zeros() as usize;
+        let usize_bit_count = std::mem::size_of::<usize>() * 8;
+
+        Self {
+            twiddles: (0..size / 2)
+                .map(|idx| {
+                    let angle = -2. * PI * idx as f32 / size as f32;
+                    Complex::new(angle.cos(), angle.sin())
+                })
+                .collect(),
+            bit_reversed_indices: (0..size)
+                .map(|idx| idx.reverse_bits() >> (usize_bit_count - bit_count))
+                .collect(),
+        }
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        let size = data.len();
+
+        for idx in 0..size {
+            let reversed_idx = self.bit_reversed_indices[idx];
+            if reversed_idx > idx {
+                data.swap(idx, reversed_idx);
+            }
+        }
+
+        let mut length = 2;
+        while length <= size {
+            let twiddle_stride = size / length;
+            for start in (0..size).step_by(length) {
+                for idx in 0..length / 2 {
+                    let twiddle = self.twiddles[idx * twiddle_stride];
+                    let twiddle = if inverse { twiddle.conj() } else { twiddle };
+                    let even = data[start + idx];
+                    let odd = data[start + idx + length / 2] * twiddle;
+                    data[start + idx] = even + odd;
+                    data[start + idx + length / 2] = even - odd;
+                }
+            }
+            length *= 2;
+        }
+
+        if inverse {
+            for value in data {
+                *value = value.scale(1. / size as f32);
+            }
+        }
+    }
+
+    fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false)
+    }
+
+    fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true)
+    }
+}
+
+// Partitioned block frequency domain adaptive filter, with overlap-save. It models the path from
+// the speakers to the microphone and subtracts the estimated echo. Two filters are used instead of
+// a double talk detector: the background filter adapts continuously and is copied to the
+// foreground filter, that produces the output, only when it cancels more echo. When the local user
+// speaks the background filter diverges and is restored from the foreground filter.
+struct EchoCanceller {
+    fft: Fft,
+
+    previous_reference: Vec<f32>,
+
+    // One spectrum per partition of the echo tail, the newest first
+    reference_spectra: VecDeque<Vec<Complex>>,
+    reference_power: Vec<f32>,
+
+    background_weights: Vec<Vec<Complex>>,
+    foreground_weights: Vec<Vec<Complex>>,
+    background_error_energy: f32,
+    foreground_error_energy: f32,
+
+    // Enforcing the overlap-save constraint is expensive, so it is done for one partition per block
+    next_constrained_partition: usize,
+
+    microphone: Vec<f32>,
+    background_error: Vec<f32>,
+    buffer: Vec<Complex>,
+}
+
+impl EchoCanceller {
+    fn new(sample_rate: u32, desc: &EchoCancellationDesc) -> Self {
+        let tail_frame_count = sample_rate as f32 * desc.tail_length_ms as f32 / 1000.;
+        let partition_count = usize::max(1, (tail_frame_count / BLOCK_SIZE as f32).ceil() as _);
+        let zero_spectra = || {
+            (0..partition_count)
+                .map(|_| vec![Complex::default(); 2 * BLOCK_SIZE])
+                .collect::<Vec<_>>()
+        };
+
+        Self {
+            fft: Fft::new(2 * BLOCK_SIZE),
+            previous_reference: vec![0.; BLOCK_SIZE],
+            reference_spectra: zero_spectra().into_iter().collect(),
+            reference_power: vec![0.; 2 * BLOCK_SIZE],
+            background_weights: zero_spectra(),
+            foreground_weights: zero_spectra(),
+            background_error_energy: 0.,
+            foreground_error_energy: 0.,
+            next_constrained_partition: 0,
+            microphone: vec![0.; BLOCK_SIZE],
+            background_error: vec![0.; BLOCK_SIZE],
+            buffer: vec![Complex::default(); 2 * BLOCK_SIZE],
+        }
+    }
+
+    // Subtracts the echo estimated with `weights` from `samples` into `error`. Returns the energy
+    // of the error.
+    fn cancel(
+        fft: &Fft,
+        weights: &[Vec<Complex>],
+        reference_spectra: &VecDeque<Vec<Complex>>,
+        samples: &[f32],
+        error: &mut [f32],
+        buffer: &mut [Complex],
+    ) -> f32 {
+        for value in buffer.iter_mut() {
+            *value = Complex::default();
+        }
+        for (weights, spectrum) in weights.iter().zip(reference_spectra) {
+            for ((value, weight), reference) in buffer.iter_mut().zip(weights).zip(spectrum) {
+                *value = *value + *weight * *reference;
+            }
+        }
+        fft.inverse(buffer);
+
+        // The last half of the filtered block is valid
+        let mut energy = 0.;
+        for ((error, sample), echo) in error.iter_mut().zip(samples).zip(&buffer[BLOCK_SIZE..]) {
+            *error = sample - echo.re;
+            energy += *error * *error;
+        }
+
+        energy
+    }
+
+    // `samples` and `reference` contain BLOCK_SIZE samples
+    fn process(&mut self, samples: &mut [f32], reference: &[f32]) {
+        // Spectrum of the last two reference blocks
+        let mut reference_spectrum = self.reference_spectra.pop_back().unwrap_or_default();
+        reference_spectrum.clear();
+        reference_spectrum.extend(
+            self.previous_reference
+                .iter()
+                .chain(reference)
+                .map(|sample| Complex::new(*sample, 0.)),
+        );
+        self.fft.forward(&mut reference_spectrum);
+        self.previous_reference.copy_from_slice(reference);
+
+        for (power, value) in self.reference_power.iter_mut().zip(&reference_spectrum) {
+            *power = 0.9 * *power + 0.1 * value.norm_sqr();
+        }
+        self.reference_spectra.push_front(reference_spectrum);
+
+        let background_error_energy = Self::cancel(
+            &self.fft,
+            &self.background_weights,
+            &self.reference_spectra,
+            samples,
+            &mut self.background_error,
+            &mut self.buffer,
+        );
+        self.microphone.copy_from_slice(samples);
+        let foreground_error_energy = Self::cancel(
+            &self.fft,
+            &self.foreground_weights,
+            &self.reference_spectra,
+            &self.microphone,
+            samples,
+            &mut self.buffer,
+        );
+
+        let reference_power_sum: f32 = self.reference_power.iter().sum();
+        if reference_power_sum < MIN_REFERENCE_POWER * (2 * BLOCK_SIZE) as f32 {
+            return;
+        }
+
+        self.background_error_energy = ERROR_ENERGY_SMOOTHING * self.background_error_energy
+            + (1. - ERROR_ENERGY_SMOOTHING) * background_error_energy;
+        self.foreground_error_energy = ERROR_ENERGY_SMOOTHING * self.foreground_error_energy
+            + (1. - ERROR_ENERGY_SMOOTHING) * foreground_error_energy;
+
+        if self.background_error_energy < FOREGROUND_UPDATE_RATIO * self.foreground_error_energy {
+            for (foreground, background) in self
+                .foreground_weights
+                .iter_mut()
+                .zip(&self.background_weights)
+            {
+                foreground.copy_from_slice(background);
+            }
+            self.foreground_error_energy = self.background_error_energy;
+        } else if self.background_error_energy
+            > BACKGROUND_RESET_RATIO * self.foreground_error_energy
+        {
+            for (background, foreground) in self
+                .background_weights
+                .iter_mut()
+                .zip(&self.foreground_weights)
+            {
+                background.copy_from_slice(foreground);
+            }
+            self.background_error_energy = self.foreground_error_energy;
+            return;
+        }
+
+        // Error spectrum
+        for (idx, value) in self.buffer.iter_mut().enumerate() {
+            let error = if idx < BLOCK_SIZE {
+                0.
+            } else {
+                self.background_error[idx - BLOCK_SIZE]
+            };
+            *value = Complex::new(error, 0.);
+        }
+        self.fft.forward(&mut self.buffer);
+
+        // NLMS update, normalized per frequency
+        let partition_count = self.background_weights.len() as f32;
+        let regularization = reference_power_sum / (2 * BLOCK_SIZE) as f32 * 0.01;
+        for (weights, spectrum) in self
+            .background_weights
+            .iter_mut()
+            .zip(&self.reference_spectra)
+        {
+            for (((weight, reference), error), power) in weights
+                .iter_mut()
+                .zip(spectrum)
+                .zip(&self.buffer)
+                .zip(&self.reference_power)
+            {
+                let step = ECHO_CANCELLER_STEP_SIZE / (partition_count * power + regularization);
+                *weight = *weight + (reference.conj() * *error).scale(step);
+            }
+        }
+
+        // The filter of each partition must be only BLOCK_SIZE long, zero the wrapped part
+        let weights = &mut self.background_weights[self.next_constrained_partition];
+        self.fft.inverse(weights);
+        for value in &mut weights[BLOCK_SIZE..] {
+            *value = Complex::default();
+        }
+        self.fft.forward(weights);
+        self.next_constrained_partition =
+            (self.next_constrained_partition + 1) % self.background_weights.len();
+    }
+}
+
+// Short time Fourier transform with 50% overlap and a Wiener gain for each frequency. The noise
+// floor is estimated continuously, so the noise can be stationary but does not need to be known.
+// The output is delayed by BLOCK_SIZE frames.
+struct NoiseSuppressor {
+    fft: Fft,
+
+    // Square root of a periodic Hann window, used both for analysis and synthesis
+    window: Vec<f32>,
+    previous_block: Vec<f32>,
+    overlap: Vec<f32>,
+
+    smoothed_power: Vec<f32>,
+    noise_power: Vec<f32>,
+    previous_clean_power: Vec<f32>,
+    noise_rise_factor: f32,
+    min_gain: f32,
+    block_count: usize,
+
+    buffer: Vec<Complex>,
+}
+
+impl NoiseSuppressor {
+    fn new(sample_rate: u32, desc: &NoiseSuppressionDesc) -> Self {
+        let frame_size = 2 * BLOCK_SIZE;
+        let bin_count = BLOCK_SIZE + 1;
+        let block_duration_s = BLOCK_SIZE as f32 / sample_rate as f32;
+
+        Self {
+            fft: Fft::new(frame_size),
+            window: (0..frame_size)
+                .map(|idx| (PI * idx as f32 / frame_size as f32).sin())
+                .collect(),
+            previous_block: vec![0.; BLOCK_SIZE],
+            overlap: vec![0.; BLOCK_SIZE],
+            smoothed_power: vec![0.; bin_count],
+            noise_power: vec![0.; bin_count],
+            previous_clean_power: vec![0.; bin_count],
+            noise_rise_factor: 10_f32.powf(NOISE_FLOOR_RISE_DB_PER_S * block_duration_s / 10.),
+            min_gain: db_to_amplitude(-desc.max_attenuation_db),
+            block_count: 0,
+            buffer: vec![Complex::default(); frame_size],
+        }
+    }
+
+    // `samples` contains BLOCK_SIZE samples
+    fn process(&mut self, samples: &mut [f32]) {
+        for (idx, value) in self.buffer.iter_mut().enumerate() {
+            let sample = if idx < BLOCK_SIZE {
+                self.previous_block[idx]
+            } else {
+                samples[idx - BLOCK_SIZE]
+            };
+            *value = Complex::new(sample * self.window[idx], 0.);
+        }
+        self.previous_block.copy_from_slice(samples);
+        self.fft.forward(&mut self.buffer);
+
+        self.block_count += 1;
+        for bin in 0..=BLOCK_SIZE {
+            let power = self.buffer[bin].norm_sqr();
+            self.smoothed_power[bin] = 0.7 * self.smoothed_power[bin] + 0.3 * power;
+
+            if self.block_count <= NOISE_FLOOR_INIT_BLOCKS {
+                let weight = 1. / self.block_count as f32;
+                self.noise_power[bin] += (power - self.noise_power[bin]) * weight;
+            } else {
+                self.noise_power[bin] = f32::min(
+                    self.smoothed_power[bin],
+                    self.noise_power[bin] * self.noise_rise_factor,
+                );
+            }
+            let noise_power = f32::max(self.noise_power[bin] * NOISE_FLOOR_BIAS, f32::MIN_POSITIVE);
+
+            let posterior_snr = power / noise_power;
+            let prior_snr = NOISE_SUPPRESSOR_SNR_SMOOTHING * self.previous_clean_power[bin]
+                / noise_power
+                + (1. - NOISE_SUPPRESSOR_SNR_SMOOTHING) * f32::max(posterior_snr - 1., 0.);
+            let gain = f32::max(prior_snr / (1. + prior_snr), self.min_gain);
+            self.previous_clean_power[bin] = gain * gain * power;
+
+            self.buffer[bin] = self.buffer[bin].scale(gain);
+            if bin > 0 && bin < BLOCK_SIZE {
+                self.buffer[2 * BLOCK_SIZE - bin] = self.buffer[2 * BLOCK_SIZE - bin].scale(gain);
+            }
+        }
+
+        self.fft.inverse(&mut self.buffer);
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            *sample = self.overlap[idx] + self.buffer[idx].re * self.window[idx];
+            self.overlap[idx] = self.buffer[idx + BLOCK_SIZE].re * self.window[idx + BLOCK_SIZE];
+        }
+    }
+}
+
+// Brings the level of the voice to a target, followed by a soft limiter. The same gain is applied
+// to all channels.
+struct GainControl {
+    target_level: f32,
+    max_gain: f32,
+    attack_coefficient: f32,
+    release_coefficient: f32,
+    level: f32,
+    gain: f32,
+}
+
+impl GainControl {
+    fn new(sample_rate: u32, desc: &GainControlDesc) -> Self {
+        let block_duration_s = BLOCK_SIZE as f32 / sample_rate as f32;
+        Self {
+            target_level: db_to_amplitude(desc.target_level_dbfs),
+            max_gain: db_to_amplitude(desc.max_gain_db),
+            attack_coefficient: 1. - (-block_duration_s / GAIN_CONTROL_ATTACK_S).exp(),
+            release_coefficient: 1. - (-block_duration_s / GAIN_CONTROL_RELEASE_S).exp(),
+            level: db_to_amplitude(desc.target_level_dbfs),
+            gain: 1.,
+        }
+    }
+
+    // `samples` is interleaved
+    fn process(&mut self, samples: &mut [f32]) {
+        let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+        let block_level = mean_square.sqrt();
+
+        if block_level > GAIN_CONTROL_GATE_LEVEL {
+            let coefficient = if block_level > self.level {
+                self.attack_coefficient
+            } else {
+                self.release_coefficient
+            };
+            self.level += (block_level - self.level) * coefficient;
+        }
+        let target_gain = (self.target_level / self.level)
+            .max(GAIN_CONTROL_MIN_GAIN)
+            .min(self.max_gain);
+
+        // Interpolate the gain inside the block to avoid steps
+        let start_gain = self.gain;
+        let sample_count = samples.len();
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            let gain = start_gain + (target_gain - start_gain) * idx as f32 / sample_count as f32;
+            let value = *sample * gain;
+            *sample = if value.abs() > LIMITER_THRESHOLD {
+                let excess = (value.abs() - LIMITER_THRESHOLD) / (1. - LIMITER_THRESHOLD);
+                value.signum() * (LIMITER_THRESHOLD + (1. - LIMITER_THRESHOLD) * excess.tanh())
+            } else {
+                value
+            };
+        }
+        self.gain = target_gain;
+    }
+}
+
+// Audio sent to the speakers by the game audio player, used as reference by the echo canceller of
+// the microphone. The player pushes the samples when they are sent to the device and the recorder
+// pops the same amount of samples when it receives the microphone samples, so the two streams stay
+// aligned up to the latency of the devices, that is covered by the echo tail length.
+#[derive(Clone)]
+pub struct EchoReference {
+    sample_rate: u32,
+    samples: Arc<Mutex<VecDeque<f32>>>,
+}
+
+impl EchoReference {
+    // The samples are mono
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            samples: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn push(&self, samples: &[f32]) {
+        let mut buffer = self.samples.lock();
+        buffer.extend(samples);
+
+        let max_len = (self.sample_rate as f32 * MAX_ECHO_REFERENCE_DURATION_S) as usize;
+        if buffer.len() > max_len {
+            let excess = buffer.len() - max_len;
+            buffer.drain(0..excess);
+        }
+    }
+
+    // If the player is not running, silence is returned
+    pub fn pop(&self, count: usize, output: &mut Vec<f32>) {
+        let mut buffer = self.samples.lock();
+        let available_count = usize::min(count, buffer.len());
+        output.extend(buffer.drain(0..available_count));
+        output.extend((available_count..count).map(|_| 0_f32));
+    }
+}
+
+// Echo cancellation, noise suppression and automatic gain control, in this order. Each stage is
+// optional. The samples are processed in blocks, so the output is delayed by up to two blocks.
+pub struct MicrophoneProcessor {
+    channel_count: usize,
+
+    // One per channel
+    echo_cancellers: Vec<EchoCanceller>,
+    noise_suppressors: Vec<NoiseSuppressor>,
+    gain_control: Option<GainControl>,
+
+    pending_samples: Vec<f32>,
+    pending_reference: Vec<f32>,
+    channel_block: Vec<f32>,
+}
+
+impl MicrophoneProcessor {
+    pub fn new(desc: &AudioProcessingDesc, sample_rate: u32, channel_count: u16) -> Self {
+        let channel_count = channel_count as usize;
+
+        let echo_cancellers = match &desc.echo_cancellation {
+            Switch::Enabled(desc) => (0..channel_count)
+                .map(|_| EchoCanceller::new(sample_rate, desc))
+                .collect(),
+            Switch::Disabled => vec![],
+        };
+        let noise_suppressors = match &desc.noise_suppression {
+            Switch::Enabled(desc) => (0..channel_count)
+                .map(|_| NoiseSuppressor::new(sample_rate, desc))
+                .collect(),
+            Switch::Disabled => vec![],
+        };
+        let gain_control = match &desc.gain_control {
+            Switch::Enabled(desc) => Some(GainControl::new(sample_rate, desc)),
+            Switch::Disabled => None,
+        };
+
+        Self {
+            channel_count,
+            echo_cancellers,
+            noise_suppressors,
+            gain_control,
+            pending_samples: vec![],
+            pending_reference: vec![],
+            channel_block: vec![0.; BLOCK_SIZE],
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.echo_cancellers.is_empty()
+            && self.noise_suppressors.is_empty()
+            && self.gain_control.is_none()
+    }
+
+    pub fn uses_echo_reference(&self) -> bool {
+        !self.echo_cancellers.is_empty()
+    }
+
+    // `samples` is interleaved. `reference` is mono, at the same sample rate, and contains the
+    // samples played while `samples` were recorded. The processed samples are appended to
+    // `output`.
+    pub fn process(&mut self, samples: &[f32], reference: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(samples);
+            return;
+        }
+
+        self.pending_samples.extend_from_slice(samples);
+        self.pending_reference.extend_from_slice(reference);
+
+        let block_sample_count = BLOCK_SIZE * self.channel_count;
+        let mut consumed_frame_count = 0;
+        while self.pending_samples.len() - consumed_frame_count * self.channel_count
+            >= block_sample_count
+        {
+            let first_sample = consumed_frame_count * self.channel_count;
+            let block = &mut self.pending_samples[first_sample..first_sample + block_sample_count];
+
+            // The reference can be shorter if the caller does not use it
+            if self.pending_reference.len() < consumed_frame_count + BLOCK_SIZE {
+                self.pending_reference
+                    .resize(consumed_frame_count + BLOCK_SIZE, 0.);
+            }
+            let reference_block =
+                &self.pending_reference[consumed_frame_count..consumed_frame_count + BLOCK_SIZE];
+
+            for channel in 0..self.channel_count {
+                for (frame, sample) in self.channel_block.iter_mut().enumerate() {
+                    *sample = block[frame * self.channel_count + channel];
+                }
+
+                if let Some(echo_canceller) = self.echo_cancellers.get_mut(channel) {
+                    echo_canceller.process(&mut self.channel_block, reference_block);
+                }
+                if let Some(noise_suppressor) = self.noise_suppressors.get_mut(channel) {
+                    noise_suppressor.process(&mut self.channel_block);
+                }
+
+                for (frame, sample) in self.channel_block.iter().enumerate() {
+                    block[frame * self.channel_count + channel] = *sample;
+                }
+            }
+
+            if let Some(gain_control) = &mut self.gain_control {
+                gain_control.process(block);
+            }
+
+            output.extend_from_slice(block);
+            consumed_frame_count += BLOCK_SIZE;
+        }
+
+        self.pending_samples
+            .drain(0..consumed_frame_count * self.channel_count);
+        let consumed_reference_count =
+            usize::min(consumed_frame_count, self.pending_reference.len());
+        self.pending_reference.drain(0..consumed_reference_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    // Wideband voice, like the fixtures. Lower than the device rate to keep the tests fast.
+    const SAMPLE_RATE: u32 = 16000;
+    const SIGNAL_DURATION_S: f32 = 8.;
+
+    // Input is pushed in buffers not aligned to the processing blocks, like the audio device does
+    const PUSH_FRAME_COUNT: usize = 160;
+
+    // Delay of the processing chain, in frames
+    const PROCESSING_DELAY_FRAMES: usize = BLOCK_SIZE;
+
+    // Echo path: direct sound after 40ms, followed by the reverberation
+    const ECHO_DELAY_FRAMES: usize = 640;
+    const ECHO_GAIN: f32 = 0.25;
+    const REVERBERATION_GAIN: f32 = 0.02;
+    const REVERBERATION_DECAY_FRAMES: f32 = 160.;
+
+    const MIN_ECHO_RETURN_LOSS_ENHANCEMENT_DB: f32 = 15.;
+    // The noise of the fixtures slows down the convergence and limits the cancellation
+    const MIN_FIXTURE_ECHO_RETURN_LOSS_ENHANCEMENT_DB: f32 = 10.;
+    const MIN_DOUBLE_TALK_RESIDUAL_ECHO_REDUCTION_DB: f32 = 10.;
+    const MIN_NOISE_REDUCTION_DB: f32 = 15.;
+    const MAX_SPEECH_DISTORTION_DB: f32 = -15.;
+    const MAX_LEVEL_ERROR_DB: f32 = 3.;
+
+    // The near voice must not be cancelled together with the echo
+    const MAX_NEAR_VOICE_ATTENUATION_DB: f32 = 3.;
+
+    fn switch<T>(enabled: bool, content: T) -> Switch<T> {
+        if enabled {
+            Switch::Enabled(content)
+        } else {
+            Switch::Disabled
+        }
+    }
+
+    fn processing_desc(
+        echo_cancellation: bool,
+        noise_suppression: bool,
+        gain_control: bool,
+    ) -> AudioProcessingDesc {
+        AudioProcessingDesc {
+            echo_cancellation: switch(
+                echo_cancellation,
+                EchoCancellationDesc {
+                    tail_length_ms: 200,
+                },
+            ),
+            noise_suppression: switch(
+                noise_suppression,
+                NoiseSuppressionDesc {
+                    max_attenuation_db: 20.,
+                },
+            ),
+            gain_control: switch(
+                gain_control,
+                GainControlDesc {
+                    target_level_dbfs: -20.,
+                    max_gain_db: 30.,
+                },
+            ),
+        }
+    }
+
+    fn process(
+        desc: &AudioProcessingDesc,
+        sample_rate: u32,
+        microphone: &[f32],
+        reference: &[f32],
+    ) -> Vec<f32> {
+        let mut processor = MicrophoneProcessor::new(desc, sample_rate, 1);
+        let mut output = vec![];
+        for (idx, samples) in microphone.chunks(PUSH_FRAME_COUNT).enumerate() {
+            let begin = usize::min(idx * PUSH_FRAME_COUNT, reference.len());
+            let end = usize::min(begin + samples.len(), reference.len());
+            processor.process(samples, &reference[begin..end], &mut output);
+        }
+
+        output
+    }
+
+    fn level_db(samples: &[f32]) -> f32 {
+        let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+        10. * mean_square.max(1e-12).log10()
+    }
+
+    // xorshift32, uniform in [-1, 1)
+    fn next_random(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state as f64 / (u32::MAX as f64 + 1.) * 2. - 1.) as f32
+    }
+
+    // Harmonic signal with a changing pitch and syllable-like envelope
+    fn synthetic_voice(seed: u32) -> Vec<f32> {
+        let mut random_state = seed;
+        let mut pitch = 140.;
+        (0..(SAMPLE_RATE as f32 * SIGNAL_DURATION_S) as usize)
+            .map(|frame| {
+                let time_s = frame as f32 / SAMPLE_RATE as f32;
+                if frame % (SAMPLE_RATE as usize / 10) == 0 {
+                    pitch = 110. + 60. * (next_random(&mut random_state) + 1.);
+                }
+                let envelope = (PI * 4. * time_s).sin().abs();
+                let harmonics: f32 = (1..10)
+                    .map(|idx| (2. * PI * pitch * idx as f32 * time_s).sin() / idx as f32)
+                    .sum();
+                0.1 * envelope * harmonics + 0.005 * next_random(&mut random_state)
+            })
+            .collect()
+    }
+
+    fn synthetic_echo(reference: &[f32]) -> Vec<f32> {
+        let mut random_state = 0x1234_5678;
+        let impulse_response: Vec<f32> = (0..ECHO_DELAY_FRAMES
+            + 6 * REVERBERATION_DECAY_FRAMES as usize)
+            .map(|frame| {
+                if frame == ECHO_DELAY_FRAMES {
+                    ECHO_GAIN
+                } else if frame > ECHO_DELAY_FRAMES {
+                    let decay =
+                        (-((frame - ECHO_DELAY_FRAMES) as f32) / REVERBERATION_DECAY_FRAMES).exp();
+                    REVERBERATION_GAIN * decay * next_random(&mut random_state)
+                } else {
+                    0.
+                }
+            })
+            .collect();
+
+        (0..reference.len())
+            .map(|frame| {
+                impulse_response
+                    .iter()
+                    .enumerate()
+                    .skip(ECHO_DELAY_FRAMES)
+                    .take_while(|(delay, _)| *delay <= frame)
+                    .map(|(delay, gain)| gain * reference[frame - delay])
+                    .sum()
+            })
+            .collect()
+    }
+
+    // Only mono 16 bit PCM, the format of the fixtures. Returns the sample rate and the samples.
+    fn read_wav(path: &Path) -> (u32, Vec<f32>) {
+        let data = fs::read(path).unwrap();
+        assert!(&data[0..4] == b"RIFF" && &data[8..12] == b"WAVE");
+
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+
+        let mut sample_rate = None;
+        let mut offset = 12;
+        while offset + 8 <= data.len() {
+            let chunk_size = read_u32(offset + 4) as usize;
+            let chunk_begin = offset + 8;
+
+            match &data[offset..offset + 4] {
+                b"fmt " => {
+                    // PCM, mono, 16 bit
+                    assert_eq!(read_u16(chunk_begin), 1);
+                    assert_eq!(read_u16(chunk_begin + 2), 1);
+                    assert_eq!(read_u16(chunk_begin + 14), 16);
+                    sample_rate = Some(read_u32(chunk_begin + 4));
+                }
+                b"data" => {
+                    let samples = data[chunk_begin..chunk_begin + chunk_size]
+                        .chunks_exact(2)
+                        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.)
+                        .collect();
+                    return (sample_rate.unwrap(), samples);
+                }
+                _ => (),
+            }
+
+            // Chunks are padded to an even size
+            offset = chunk_begin + chunk_size + chunk_size % 2;
+        }
+
+        panic!("{}: missing data chunk", path.display());
+    }
+
+    #[test]
+    fn echo_cancellation() {
+        let far_voice = synthetic_voice(99);
+        let echo = synthetic_echo(&far_voice);
+
+        // Measured in the second half, when the filter has converged
+        let output = process(
+            &processing_desc(true, false, false),
+            SAMPLE_RATE,
+            &echo,
+            &far_voice,
+        );
+        let range = far_voice.len() / 2..output.len();
+        let erle_db = level_db(&echo[range.clone()]) - level_db(&output[range]);
+        assert!(erle_db > MIN_ECHO_RETURN_LOSS_ENHANCEMENT_DB, "{}", erle_db);
+    }
+
+    #[test]
+    fn echo_cancellation_during_double_talk() {
+        let far_voice = synthetic_voice(99);
+        let near_voice = synthetic_voice(5);
+        let echo = synthetic_echo(&far_voice);
+        let frame_count = far_voice.len();
+
+        // The near voice starts in the second half. The canceller must not diverge.
+        let microphone: Vec<f32> = (0..frame_count)
+            .map(|frame| {
+                echo[frame]
+                    + if frame > frame_count / 2 {
+                        near_voice[frame]
+                    } else {
+                        0.
+                    }
+            })
+            .collect();
+        let output = process(
+            &processing_desc(true, false, false),
+            SAMPLE_RATE,
+            &microphone,
+            &far_voice,
+        );
+        let range = frame_count * 3 / 4..output.len();
+        let residual: Vec<f32> = range
+            .clone()
+            .map(|frame| output[frame] - near_voice[frame])
+            .collect();
+        let reduction_db = level_db(&echo[range]) - level_db(&residual);
+        assert!(
+            reduction_db > MIN_DOUBLE_TALK_RESIDUAL_ECHO_REDUCTION_DB,
+            "{}",
+            reduction_db
+        );
+    }
+
+    #[test]
+    fn noise_suppression() {
+        let near_voice = synthetic_voice(5);
+        let frame_count = near_voice.len();
+
+        // Stationary noise, with the near voice in the second half
+        let mut random_state = 0x8765_4321;
+        let microphone: Vec<f32> = (0..frame_count)
+            .map(|frame| {
+                0.01 * next_random(&mut random_state)
+                    + if frame > frame_count / 2 {
+                        near_voice[frame]
+                    } else {
+                        0.
+                    }
+            })
+            .collect();
+        let output = process(
+            &processing_desc(false, true, false),
+            SAMPLE_RATE,
+            &microphone,
+            &[],
+        );
+
+        let range = frame_count / 4..frame_count / 2 - PROCESSING_DELAY_FRAMES;
+        let shifted_range =
+            range.start + PROCESSING_DELAY_FRAMES..range.end + PROCESSING_DELAY_FRAMES;
+        let noise_reduction_db = level_db(&microphone[range]) - level_db(&output[shifted_range]);
+        assert!(
+            noise_reduction_db > MIN_NOISE_REDUCTION_DB,
+            "{}",
+            noise_reduction_db
+        );
+
+        let range = frame_count * 3 / 4..output.len() - PROCESSING_DELAY_FRAMES;
+        let distortion: Vec<f32> = range
+            .clone()
+            .map(|frame| output[frame + PROCESSING_DELAY_FRAMES] - near_voice[frame])
+            .collect();
+        let distortion_db = level_db(&distortion) - level_db(&near_voice[range]);
+        assert!(
+            distortion_db < MAX_SPEECH_DISTORTION_DB,
+            "{}",
+            distortion_db
+        );
+    }
+
+    #[test]
+    fn gain_control() {
+        let near_voice = synthetic_voice(5);
+
+        // Quiet and loud voices are brought to the target level
+        for &gain in &[0.1, 8.] {
+            let microphone: Vec<f32> = near_voice.iter().map(|sample| sample * gain).collect();
+            let desc = processing_desc(false, false, true);
+            let output = process(&desc, SAMPLE_RATE, &microphone, &[]);
+            let target_level_dbfs = match desc.gain_control {
+                Switch::Enabled(desc) => desc.target_level_dbfs,
+                Switch::Disabled => unreachable!(),
+            };
+            let level_error_db = level_db(&output[near_voice.len() / 2..]) - target_level_dbfs;
+            assert!(
+                level_error_db.abs() < MAX_LEVEL_ERROR_DB,
+                "{}: {}",
+                gain,
+                level_error_db
+            );
+        }
+    }
+
+    // `reference.wav` is the audio played by the speakers and `microphone.wav` the synthetic
+    // capture of its echo, background noise and a near voice. See
+    // fixtures/microphone_processing/README.md.
+    #[test]
+    fn synthetic_fixture_echo() {
+        let fixtures_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/microphone_processing");
+        let (sample_rate, microphone) = read_wav(&fixtures_dir.join("microphone.wav"));
+        let (reference_sample_rate, reference) = read_wav(&fixtures_dir.join("reference.wav"));
+        assert_eq!(sample_rate, reference_sample_rate);
+
+        let output = process(
+            &processing_desc(true, false, false),
+            sample_rate,
+            &microphone,
+            &reference,
+        );
+        let frame_count = usize::min(microphone.len(), output.len());
+
+        // Only the echo in the first half, measured at its end when the filter has converged
+        let range = frame_count * 3 / 8..frame_count / 2;
+        let erle_db = level_db(&microphone[range.clone()]) - level_db(&output[range]);
+        assert!(
+            erle_db > MIN_FIXTURE_ECHO_RETURN_LOSS_ENHANCEMENT_DB,
+            "{}",
+            erle_db
+        );
+
+        // The near voice in the second half is louder than the echo, so it makes most of the level
+        let range = frame_count * 3 / 4..frame_count;
+        let attenuation_db = level_db(&microphone[range.clone()]) - level_db(&output[range]);
+        assert!(
+            attenuation_db < MAX_NEAR_VOICE_ATTENUATION_DB,
+            "{}",
+            attenuation_db
+        );
+    }
+
+    #[test]
+    fn echo_reference_alignment() {
+        let echo_reference = EchoReference::new(SAMPLE_RATE);
+        echo_reference.push(&[1., 2., 3.]);
+
+        let mut output = vec![];
+        echo_reference.pop(2, &mut output);
+        assert_eq!(output, vec![1., 2.]);
+
+        // Missing samples are silent
+        output.clear();
+        echo_reference.pop(3, &mut output);
+        assert_eq!(output, vec![3., 0., 0.]);
+
+        // Samples that were not popped in time are discarded
+        let max_len = (SAMPLE_RATE as f32 * MAX_ECHO_REFERENCE_DURATION_S) as usize;
+        echo_reference.push(&vec![1.; max_len]);
+        echo_reference.push(&[2.]);
+        output.clear();
+        echo_reference.pop(max_len, &mut output);
+        assert_eq!(output[max_len - 1], 2.);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::data::*;
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Weight of each new sample in the averages of the stream latencies. Frames and packets arrive
+// about a hundred times per second, so the averages settle in a few tenths of a second.
+const LATENCY_SMOOTHING: f64 = 0.02;
+
+// After a change of latency the averages move gradually. The delay is updated only once the
+// offset has been out of tolerance for this long, otherwise it would be set to an intermediate
+// value and the rest of the change would be left uncorrected.
+const CORRECTION_SETTLING_TIME: Duration = Duration::from_secs(2);
+
+// Clock used by the server to timestamp the captured audio and video. Only the differences between
+// timestamps are meaningful, so the epoch is arbitrary.
+#[derive(Clone, Copy)]
+pub struct Timebase {
+    epoch: Instant,
+}
+
+impl Timebase {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn timestamp_ns(&self, time: Instant) -> u64 {
+        time.saturating_duration_since(self.epoch).as_nanos() as _
+    }
+
+    pub fn now_ns(&self) -> u64 {
+        self.timestamp_ns(Instant::now())
+    }
+}
+
+impl Default for Timebase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct AvSyncState {
+    tolerance: Duration,
+    max_audio_delay: Duration,
+
+    // Local epoch of the client, arbitrary like the one of the server timebase
+    epoch: Instant,
+
+    // Averages of local time minus capture timestamp, in seconds. They include the unknown offset
+    // between the server and client clocks, which cancels out in their difference.
+    video_offset_s: Option<f64>,
+    audio_offset_s: Option<f64>,
+
+    audio_delay: Duration,
+
+    // Latest time reported by the notifications. Used as clock so the policy does not depend on
+    // when `audio_delay()` is called.
+    last_notification_time: Option<Instant>,
+
+    // Set while the offset is out of tolerance
+    correction_begin_time: Option<Instant>,
+}
+
+impl AvSyncState {
+    fn offset_s(&mut self, capture_timestamp_ns: u64, local_time: Instant) -> f64 {
+        self.last_notification_time = Some(match self.last_notification_time {
+            Some(time) if time > local_time => time,
+            _ => local_time,
+        });
+
+        local_time
+            .saturating_duration_since(self.epoch)
+            .as_secs_f64()
+            - capture_timestamp_ns as f64 / 1e9
+    }
+}
+
+fn update_average(average: &mut Option<f64>, value: f64) {
+    *average = Some(match *average {
+        Some(average) => average + (value - average) * LATENCY_SMOOTHING,
+        None => value,
+    });
+}
+
+// Client side audio/video synchronization policy. The compositor reports when each frame is
+// displayed and the audio player reports when each packet would be played without any delay. The
+// audio is then delayed by the difference, updated only when it drifts by more than the tolerance.
+// Shared between the compositor and the audio player.
+#[derive(Clone)]
+pub struct AvSync(Arc<Mutex<AvSyncState>>);
+
+impl AvSync {
+    pub fn new(desc: &VideoSyncDesc) -> Self {
+        Self(Arc::new(Mutex::new(AvSyncState {
+            tolerance: Duration::from_millis(desc.tolerance_ms as _),
+            max_audio_delay: Duration::from_millis(desc.max_delay_ms as _),
+            epoch: Instant::now(),
+            video_offset_s: None,
+            audio_offset_s: None,
+            audio_delay: Duration::from_secs(0),
+            last_notification_time: None,
+            correction_begin_time: None,
+        })))
+    }
+
+    pub fn notify_frame_displayed(&self, capture_timestamp_ns: u64, display_time: Instant) {
+        let state = &mut *self.0.lock();
+        let offset_s = state.offset_s(capture_timestamp_ns, display_time);
+        update_average(&mut state.video_offset_s, offset_s);
+    }
+
+    // `natural_playout_time` must not include the delay returned by `audio_delay()`, otherwise
+    // the delay would feed back into itself.
+    pub fn notify_audio_packet(&self, capture_timestamp_ns: u64, natural_playout_time: Instant) {
+        let state = &mut *self.0.lock();
+        let offset_s = state.offset_s(capture_timestamp_ns, natural_playout_time);
+        update_average(&mut state.audio_offset_s, offset_s);
+    }
+
+    // Delay to add to the audio playout. Zero until both streams have been reported for
+    // `CORRECTION_SETTLING_TIME`.
+    pub fn audio_delay(&self) -> Duration {
+        let state = &mut *self.0.lock();
+        if let (Some(video_offset_s), Some(audio_offset_s), Some(now)) = (
+            state.video_offset_s,
+            state.audio_offset_s,
+            state.last_notification_time,
+        ) {
+            let target_delay = Duration::from_secs_f64(
+                (video_offset_s - audio_offset_s)
+                    .max(0.)
+                    .min(state.max_audio_delay.as_secs_f64()),
+            );
+
+            if target_delay.abs_diff(state.audio_delay) > state.tolerance {
+                let correction_begin_time = *state.correction_begin_time.get_or_insert(now);
+                if now - correction_begin_time >= CORRECTION_SETTLING_TIME {
+                    state.audio_delay = target_delay;
+                    state.correction_begin_time = None;
+                }
+            } else {
+                state.correction_begin_time = None;
+            }
+        }
+
+        state.audio_delay
+    }
+
+    // Offset of the audio relative to the displayed video, after the delay is applied. Positive
+    // values mean the audio is late.
+    pub fn audio_video_offset_ms(&self) -> Option<f32> {
+        let state = self.0.lock();
+        if let (Some(video_offset_s), Some(audio_offset_s)) =
+            (state.video_offset_s, state.audio_offset_s)
+        {
+            Some((audio_offset_s + state.audio_delay.as_secs_f64() - video_offset_s) as f32 * 1000.)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_INTERVAL_S: f64 = 1. / 90.;
+    const PACKET_INTERVAL_S: f64 = 0.01;
+
+    // Offset of the server clock from the client clock. Unknown to the client.
+    const CLOCK_OFFSET_S: f64 = 1000.;
+
+    // Latencies are uniformly distributed in [latency, latency + jitter)
+    const JITTER_S: f64 = 0.005;
+
+    const AUDIO_LATENCY_S: f64 = 0.03;
+
+    const TOLERANCE_MS: u32 = 20;
+    const MAX_DELAY_MS: u32 = 200;
+
+    // xorshift32, uniform in [0, 1)
+    fn next_random(state: &mut u32) -> f64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state as f64 / (u32::MAX as f64 + 1.)
+    }
+
+    // Feeds the policy with jittery frame and packet timings
+    struct Simulation {
+        av_sync: AvSync,
+        epoch: Instant,
+        time_s: f64,
+        next_frame_s: f64,
+        next_packet_s: f64,
+        random_state: u32,
+        delay_change_count: usize,
+        last_delay: Duration,
+    }
+
+    impl Simulation {
+        fn new() -> Self {
+            Self {
+                av_sync: AvSync::new(&VideoSyncDesc {
+                    tolerance_ms: TOLERANCE_MS,
+                    max_delay_ms: MAX_DELAY_MS,
+                }),
+                epoch: Instant::now(),
+                time_s: 0.,
+                next_frame_s: 0.,
+                next_packet_s: 0.,
+                random_state: 0x1234_5678,
+                delay_change_count: 0,
+                last_delay: Duration::from_secs(0),
+            }
+        }
+
+        // Runs for `duration_s` with the given video latency, from capture to display. Returns
+        // the audio delay at the end.
+        fn run(&mut self, video_latency_s: f64, duration_s: f64) -> Duration {
+            let end_s = self.time_s + duration_s;
+            while self.time_s < end_s {
+                self.time_s = self.next_frame_s.min(self.next_packet_s);
+
+                // Capture timestamps use the server clock
+                let capture_timestamp_ns = ((self.time_s + CLOCK_OFFSET_S) * 1e9) as u64;
+
+                if self.next_frame_s <= self.next_packet_s {
+                    let latency_s =
+                        video_latency_s + next_random(&mut self.random_state) * JITTER_S;
+                    self.av_sync.notify_frame_displayed(
+                        capture_timestamp_ns,
+                        self.epoch + Duration::from_secs_f64(self.time_s + latency_s),
+                    );
+                    self.next_frame_s += FRAME_INTERVAL_S;
+                } else {
+                    let latency_s =
+                        AUDIO_LATENCY_S + next_random(&mut self.random_state) * JITTER_S;
+                    self.av_sync.notify_audio_packet(
+                        capture_timestamp_ns,
+                        self.epoch + Duration::from_secs_f64(self.time_s + latency_s),
+                    );
+                    self.next_packet_s += PACKET_INTERVAL_S;
+
+                    // The player queries the delay once per device callback, about as often as
+                    // packets are received
+                    let delay = self.av_sync.audio_delay();
+                    if delay != self.last_delay {
+                        self.delay_change_count += 1;
+                        self.last_delay = delay;
+                    }
+                }
+            }
+
+            self.last_delay
+        }
+    }
+
+    fn assert_delay(delay: Duration, expected_delay_s: f64) {
+        let error_ms = (delay.as_secs_f64() - expected_delay_s).abs() * 1000.;
+        assert!(
+            error_ms <= TOLERANCE_MS as f64,
+            "delay {:?}, expected {}s",
+            delay,
+            expected_delay_s
+        );
+    }
+
+    #[test]
+    fn no_delay_without_both_streams() {
+        let simulation = Simulation::new();
+        assert_eq!(simulation.av_sync.audio_delay(), Duration::from_secs(0));
+        assert!(simulation.av_sync.audio_video_offset_ms().is_none());
+
+        // Only audio, as when the client does not report the displayed frames
+        let epoch = Instant::now();
+        for idx in 0..1000 {
+            let time = epoch + Duration::from_millis(idx * 10);
+            simulation
+                .av_sync
+                .notify_audio_packet(idx * 10_000_000, time);
+        }
+        assert_eq!(simulation.av_sync.audio_delay(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn delay_settles_before_changing() {
+        let mut simulation = Simulation::new();
+
+        // The offset is out of tolerance from the start, but the delay waits for the averages
+        let delay = simulation.run(0.08, CORRECTION_SETTLING_TIME.as_secs_f64() / 2.);
+        assert_eq!(delay, Duration::from_secs(0));
+
+        let delay = simulation.run(0.08, 10.);
+        assert_delay(delay, 0.05);
+
+        // The jitter alone does not change the delay
+        assert_eq!(simulation.delay_change_count, 1);
+    }
+
+    #[test]
+    fn delay_follows_video_latency() {
+        let mut simulation = Simulation::new();
+        simulation.run(0.08, 10.);
+
+        let delay = simulation.run(0.12, 10.);
+        assert_delay(delay, 0.09);
+
+        let delay = simulation.run(0.02, 10.);
+        assert_delay(delay, 0.);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut simulation = Simulation::new();
+        simulation.run(0.12, 10.);
+        let delay_change_count = simulation.delay_change_count;
+
+        // Changes within the tolerance are ignored
+        let delay = simulation.run(0.11, 10.);
+        assert!(delay.as_secs_f64() >= 0.085, "{:?}", delay);
+        assert_eq!(simulation.delay_change_count, delay_change_count);
+    }
+
+    #[test]
+    fn delay_is_clamped() {
+        let mut simulation = Simulation::new();
+
+        let delay = simulation.run(0.5, 10.);
+        assert_eq!(delay, Duration::from_millis(MAX_DELAY_MS as _));
+
+        // The audio is never played ahead of time
+        let delay = simulation.run(0.01, 10.);
+        assert_eq!(delay, Duration::from_secs(0));
+
+        let offset_ms = simulation.av_sync.audio_video_offset_ms().unwrap();
+        assert!(offset_ms > 0., "{}", offset_ms);
+    }
+}
@@ -2,16 +2,18 @@
 
 mod constants;
 mod settings;
+mod validation;
 
 use crate::*;
 use bitflags::bitflags;
 use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
-use std::{fs, hash::*, path::*};
+use std::{fs, hash::*, net::IpAddr, path::*};
 
 pub use constants::*;
 pub use settings::*;
+pub use validation::*;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MotionSample3DofDesc {
@@ -48,12 +50,32 @@ pub struct ClientHandshakePacket {
     pub preferred_audio_player_sample_rates: u32,
     pub available_microphone_sample_rates: Vec<u32>,
     pub preferred_microphone_sample_rates: Vec<u32>,
+    pub available_audio_player_channel_counts: Vec<u16>,
+    pub available_microphone_channel_counts: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum AudioCodec {
+    Pcm(AudioFormat),
+    Opus(OpusDesc),
+}
+
+// Format of the samples of an audio stream, negotiated during the handshake
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub channel_count: u16,
+    pub codec: AudioCodec,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
     pub version: String,
     pub target_eye_resolution: (u32, u32),
+
+    // None if the stream is disabled
+    pub game_audio_config: Option<AudioConfig>,
+    pub microphone_config: Option<AudioConfig>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -68,16 +90,54 @@ pub struct VideoPacket<'a> {
     pub sub_nal_index: u8,
     pub sub_nal_count: u8,
     pub hmd_pose: Pose,
+
+    // Time at which the frame was captured on the server, in nanoseconds since the server timebase
+    // epoch. Shared with the audio packets so the client can align the two streams
+    pub capture_timestamp_ns: u64,
+
     pub sub_nal: &'a [u8],
 }
 
-// Since BridgeVR does not attempt at any clock synchronization, sending a timestamp is useless
 #[derive(Serialize, Deserialize)]
 pub struct AudioPacket<'a> {
+    // Incremented by one for each packet. Used to tell lost packets from late ones.
+    pub sequence: u64,
+
+    // Samples per channel, used to size the concealment of the packets lost before this one
+    pub sample_count: u32,
+
+    // Capture time of the first sample, in the timebase of `VideoPacket::capture_timestamp_ns`.
+    // Peers have no synchronized clocks, so only differences between the two streams are meaningful
+    pub capture_timestamp_ns: u64,
+
+    // PCM samples or a compressed frame, depending on the negotiated codec.
     // unfortunately serde does not support slice formats other than u8
     pub samples: &'a [u8],
 }
 
+// Exchanged by both peers during the link quality test
+#[derive(Serialize, Deserialize)]
+pub enum ProbePacket<'a> {
+    // Sent until a packet from the peer arrives, to start the test at the same time
+    Ready,
+    Ping {
+        id: u32,
+    },
+    Pong {
+        id: u32,
+    },
+    Data {
+        sequence: u32,
+        padding: &'a [u8],
+    },
+    Report {
+        received_count: u32,
+        expected_count: u32,
+        received_bytes: u64,
+        receive_duration_us: u64,
+    },
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct HapticSample {
     pub duration_seconds: f32,
@@ -186,8 +246,126 @@ pub enum InputDeviceData {
     OculusHands([Vec<MotionSampleDesc>; 2]),
 }
 
-#[derive(Serialize, Deserialize, Default)]
-pub struct ClientStatistics {}
+// Distribution of the latencies measured by `EventTiming`, weighted like its history
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct LatencyStatistics {
+    pub average_ms: f32,
+    pub target_ms: f32,
+
+    // (percentile, latency)
+    pub percentiles_ms: Vec<(f32, f32)>,
+
+    // Fraction of the samples in each bin, starting from zero. Trailing empty bins are omitted.
+    pub histogram_bin_ms: f32,
+    pub histogram: Vec<f32>,
+}
+
+// Counters since the start of the stream
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct AudioPlayerStatistics {
+    pub received_packet_count: u64,
+    pub lost_packet_count: u64,
+
+    // Lost packets reconstructed from the FEC data of the next packet
+    pub recovered_packet_count: u64,
+
+    // Lost packets replaced by extrapolated audio
+    pub concealed_packet_count: u64,
+
+    // Packets received after the following ones were already played. They are discarded.
+    pub late_packet_count: u64,
+
+    pub underrun_count: u64,
+
+    // Latency of the packets in the playback queue. Not a counter, updated periodically.
+    pub latency: LatencyStatistics,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ClientStatistics {
+    pub game_audio: Option<AudioPlayerStatistics>,
+}
+
+// Socket parameters in use, either derived from the path MTU or overridden by the settings
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SocketParams {
+    // UDP payload size. None if path MTU discovery is disabled or failed
+    pub path_max_payload_size: Option<u16>,
+    pub max_packet_size: u64,
+    pub max_fragments: u8,
+    pub fragment_size: u16,
+    pub receive_buffer_max_size: u64,
+}
+
+// Counters of the packets that went through the transport since the connection was established.
+// Sizes include the stream header.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ConnectionStatistics {
+    pub sent_packet_count: u64,
+    pub sent_bytes: u64,
+    pub received_packet_count: u64,
+    pub received_bytes: u64,
+
+    // Chosen at the connection, not a counter
+    pub socket_params: SocketParams,
+}
+
+// Snapshot of the statistics of the server, refreshed periodically. Rates and latencies are
+// measured over the last interval, counters are since the start of the stream.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ServerStatistics {
+    pub interval_s: f32,
+    pub presented_fps: f32,
+    pub encoded_fps: f32,
+    pub video_bitrate_mbps: f32,
+    pub sent_bitrate_mbps: f32,
+    pub received_bitrate_mbps: f32,
+
+    // (percentile, latency) from the present of the frame to the end of the encoding of each slice.
+    // Empty if no frame was encoded in the interval.
+    pub frame_latency_percentiles_ms: Vec<(f32, f32)>,
+
+    pub motion_rates_hz: Vec<(TrackedDeviceType, f32)>,
+
+    pub presented_frame_count: u64,
+    pub encoded_frame_count: u64,
+    pub connection: ConnectionStatistics,
+    pub microphone: Option<AudioPlayerStatistics>,
+
+    // Last report of the client
+    pub client: ClientStatistics,
+}
+
+// Stages of the video pipeline, in order. The client stages and the encode and send stages are
+// recorded once per slice.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum FrameStage {
+    // SteamVR presented the frame to the driver
+    Present,
+    Composition,
+    Encode,
+
+    // From the first to the last packet of the slice handed to the send scheduler
+    Send,
+
+    // From the first to the last packet of the slice received
+    Receive,
+    Decode,
+    Display,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FrameTraceSpan {
+    pub stage: FrameStage,
+
+    // None for the stages that concern the whole frame
+    pub slice_idx: Option<u8>,
+
+    // In nanoseconds since the timebase epoch of the peer that recorded the span. Instant stages
+    // have the same start and end.
+    pub start_ns: u64,
+    pub end_ns: u64,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum OtherClientPacket {
@@ -200,13 +378,99 @@ pub enum OtherClientPacket {
         timestamp_ns: u64,
     },
     Statistics(ClientStatistics),
+
+    // Sent after the frame is displayed, only if frame tracing is enabled
+    FrameTrace {
+        nal_index: u64,
+        spans: Vec<FrameTraceSpan>,
+    },
     Disconnected,
 }
 
+// Result of the link quality test. "send" and "receive" are relative to the peer that ran the
+// test. The send values are measured by the other peer and are None if its report was lost.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LinkQualityDesc {
+    pub rtt_ms: f32,
+    pub rtt_jitter_ms: f32,
+    pub ping_loss: f32,
+    pub send_throughput_mbps: Option<f32>,
+    pub send_packet_loss: Option<f32>,
+    pub receive_throughput_mbps: f32,
+    pub receive_packet_loss: f32,
+}
+
+impl LinkQualityDesc {
+    // Bitrate that uses only a fraction of the measured throughput, to leave room for the other
+    // streams and for the link fluctuations.
+    pub fn suggested_send_bitrate(&self, throughput_fraction: f32) -> Option<u32> {
+        self.send_throughput_mbps
+            .map(|throughput| (throughput * throughput_fraction * 1_000_000_f32) as _)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectedClientDesc {
+    pub address: IpAddr,
+    pub handshake_packet: ClientHandshakePacket,
+    pub target_eye_resolution: (u32, u32),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ConnectionState {
+    Searching,
+    Connected(Box<ConnectedClientDesc>),
+}
+
+// Ordered from the most to the least severe
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Debug)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warning,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+// Sent by the driver to the GUI
+#[derive(Serialize, Deserialize, Clone)]
+pub enum DriverEvent {
+    ConnectionState(ConnectionState),
+    Statistics(Box<ServerStatistics>),
+    Log { level: LogLevel, message: String },
+}
+
+// Sent by the GUI to the driver
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum DriverCommand {
+    DisconnectClient,
+    ForceIdr,
+
+    // The settings are applied at the next connection. The stream is restarted if a client is
+    // connected.
+    ReloadSettings,
+
+    // Sets the current position and heading of the HMD as the origin
+    Recenter,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct SessionDesc {
     pub bitrate: Option<u32>,
     pub last_client_handshake_packet: Option<ClientHandshakePacket>,
+    pub last_link_quality: Option<LinkQualityDesc>,
 
     // managed by GUI
     pub settings_cache: serde_json::Value,
@@ -35,8 +35,40 @@ pub struct SocketConfig {
     pub max_packets_in_flight: Option<u16>,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub enum TetheredTransport {
+    Udp,
+    TcpTunnel,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub enum ConnectionMode {
+    Wireless,
+    Tethered { transport: TetheredTransport },
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct VideoPacingDesc {
+    #[schema(min = 0.1, max = 1., step = 0.05)]
+    pub frame_interval_fraction: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct LinkProbeDesc {
+    #[schema(min = 100, max = 5000, step = 100)]
+    pub bandwidth_test_duration_ms: u32,
+
+    #[schema(min = 1, max = 1000)]
+    pub bandwidth_test_bitrate_mbps: u32,
+
+    #[schema(min = 0.1, max = 1., step = 0.05)]
+    pub starting_bitrate_fraction: f32,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct ConnectionDesc {
+    pub mode: ConnectionMode,
+
     pub client_ip: Option<String>,
 
     #[schema(min = 1024)]
@@ -46,6 +78,12 @@ pub struct ConnectionDesc {
     pub client_port: u16,
 
     pub config: SocketConfig,
+
+    pub path_mtu_discovery: bool,
+
+    pub link_probe: Switch<LinkProbeDesc>,
+
+    pub video_pacing: Switch<VideoPacingDesc>,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
@@ -117,11 +155,23 @@ pub struct VideoDecoderDesc {
     pub windows: VideoCodecDesc,
 }
 
+// Gaussian assumes normally distributed latency. Quantile uses the measured distribution and
+// extrapolates it with an exponential tail when the miss probability is too low to be measured. It
+// is more accurate with the heavy tailed latency of Wi-Fi.
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum LatencyEstimator {
+    Gaussian,
+    Quantile,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub enum LatencyMode {
     Automatic {
         #[schema(min = 1, gui = "UpDown")]
         expected_misses_per_hour: u32,
+
+        #[schema(advanced)]
+        estimator: LatencyEstimator,
     },
     Manual,
 }
@@ -139,6 +189,17 @@ pub struct LatencyDesc {
     pub mode: LatencyMode,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct FrameTraceDesc {
+    // Chrome trace JSON file, written by the server. Relative paths start from the installation
+    // directory.
+    pub output_file: String,
+
+    // Tracing stops after this many frames, to keep the file small enough to be opened
+    #[schema(min = 100, max = 1000000, step = 100)]
+    pub max_frame_count: u32,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct VideoDesc {
     pub frame_size: FrameSize,
@@ -172,37 +233,121 @@ pub struct VideoDesc {
 
     #[schema(advanced)]
     pub reliable: bool,
+
+    #[schema(advanced)]
+    pub frame_trace: Switch<FrameTraceDesc>,
 }
 
-#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum AudioFormat {
     Bit8,
     Bit16,
     Bit24,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum OpusFrameDuration {
+    Ms5,
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct OpusDesc {
+    #[schema(min = 6, max = 510)]
+    pub bitrate_kbps: u32,
+
+    pub frame_duration: OpusFrameDuration,
+
+    pub in_band_fec: bool,
+
+    #[schema(advanced, min = 0, max = 100)]
+    pub expected_packet_loss_percent: u8,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct EchoCancellationDesc {
+    // Must cover the latency of the playback and recording devices and the reverberation of the
+    // room
+    #[schema(advanced, min = 20, max = 500, step = 10)]
+    pub tail_length_ms: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct NoiseSuppressionDesc {
+    #[schema(min = 0., max = 40., step = 1.)]
+    pub max_attenuation_db: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct GainControlDesc {
+    #[schema(min = -40., max = 0., step = 1.)]
+    pub target_level_dbfs: f32,
+
+    #[schema(advanced, min = 0., max = 40., step = 1.)]
+    pub max_gain_db: f32,
+}
+
+// Applied to the recorded samples, in this order. The echo cancellation uses the game audio
+// played on the same device as reference.
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AudioProcessingDesc {
+    pub echo_cancellation: Switch<EchoCancellationDesc>,
+
+    pub noise_suppression: Switch<NoiseSuppressionDesc>,
+
+    pub gain_control: Switch<GainControlDesc>,
+}
+
+// The playout of the audio is delayed to match the latency of the video frames displayed at the
+// same time. Audio can only be delayed: if it is already later than the video, nothing is done.
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct VideoSyncDesc {
+    // Audio/video offset tolerated before the delay is changed. Each change is audible as a short
+    // gap or skip, so this should not be too low.
+    #[schema(min = 5, max = 100, step = 5)]
+    pub tolerance_ms: u32,
+
+    #[schema(advanced, min = 0, max = 500, step = 10)]
+    pub max_delay_ms: u32,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct AudioDesc {
+    // Devices are identified by name. If not set, the default device of the system is used.
     #[schema(advanced)]
-    pub input_device_index: Option<u64>,
+    pub input_device: Option<String>,
 
     #[schema(advanced)]
-    pub output_device_index: Option<u64>,
+    pub output_device: Option<String>,
 
+    // If set, the received audio is written to this file or named pipe instead of being played on
+    // `output_device`, as raw interleaved 32 bit float samples
     #[schema(advanced)]
-    pub preferred_sample_rate: u16,
+    pub output_file: Option<String>,
+
+    #[schema(advanced)]
+    pub preferred_sample_rate: u32,
 
     #[schema(advanced)]
     pub preferred_format: AudioFormat,
 
+    pub opus: Switch<OpusDesc>,
+
+    pub processing: AudioProcessingDesc,
+
     pub buffering_latency: LatencyDesc,
 
+    pub video_sync: Switch<VideoSyncDesc>,
+
     #[schema(advanced)]
     pub reliable: bool,
 }
 
 #[repr(i32)]
-#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum TrackedDeviceType {
     HMD = 0, // HMD = 0 is enforced by OpenVR
     LeftController,
@@ -277,9 +422,19 @@ pub struct OpenvrDesc {
     pub compositor_type: CompositorType,
 }
 
+// HTTP endpoint serving the statistics of the stream in the OpenMetrics text format, bound to
+// localhost
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct MetricsEndpointDesc {
+    #[schema(min = 1024)]
+    pub port: u16,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct VrServerDesc {
     pub openvr: OpenvrDesc,
+
+    pub metrics_endpoint: Switch<MetricsEndpointDesc>,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -368,6 +523,14 @@ pub fn settings_default() -> SettingsDefault {
 
     SettingsDefault {
         connection: ConnectionDescDefault {
+            mode: ConnectionModeDefault {
+                variant: ConnectionModeDefaultVariant::Wireless,
+                Tethered: ConnectionModeTetheredDefault {
+                    transport: TetheredTransportDefault {
+                        variant: TetheredTransportDefaultVariant::TcpTunnel,
+                    },
+                },
+            },
             client_ip: OptionalDefault {
                 set: false,
                 content: "192.168.X.X".into(),
@@ -416,6 +579,21 @@ pub fn settings_default() -> SettingsDefault {
                     content: 512,
                 },
             },
+            path_mtu_discovery: true,
+            link_probe: SwitchDefault {
+                enabled: true,
+                content: LinkProbeDescDefault {
+                    bandwidth_test_duration_ms: 1000,
+                    bandwidth_test_bitrate_mbps: 100,
+                    starting_bitrate_fraction: 0.7,
+                },
+            },
+            video_pacing: SwitchDefault {
+                enabled: true,
+                content: VideoPacingDescDefault {
+                    frame_interval_fraction: 0.8,
+                },
+            },
         },
         video: VideoDescDefault {
             frame_size: FrameSizeDefault {
@@ -608,28 +786,74 @@ pub fn settings_default() -> SettingsDefault {
                     variant: LatencyModeDefaultVariant::Automatic,
                     Automatic: LatencyModeAutomaticDefault {
                         expected_misses_per_hour: 60,
+                        estimator: LatencyEstimatorDefault {
+                            variant: LatencyEstimatorDefaultVariant::Gaussian,
+                        },
                     },
                 },
             },
             pose_prediction_update_history_mean_lifetime_s: 60,
             non_hmd_devices_pose_prediction_multiplier: 1.,
             reliable: false,
+            frame_trace: SwitchDefault {
+                enabled: false,
+                content: FrameTraceDescDefault {
+                    output_file: "frame_trace.json".into(),
+                    max_frame_count: 10000,
+                },
+            },
         },
         game_audio: SwitchDefault {
             enabled: true,
             content: AudioDescDefault {
-                input_device_index: OptionalDefault {
+                input_device: OptionalDefault {
+                    set: false,
+                    content: "".into(),
+                },
+                output_device: OptionalDefault {
                     set: false,
-                    content: 0,
+                    content: "".into(),
                 },
-                output_device_index: OptionalDefault {
+                output_file: OptionalDefault {
                     set: false,
-                    content: 0,
+                    content: "".into(),
                 },
-                preferred_sample_rate: 44100,
+                preferred_sample_rate: 48000,
                 preferred_format: AudioFormatDefault {
                     variant: AudioFormatDefaultVariant::Bit16,
                 },
+                opus: SwitchDefault {
+                    enabled: true,
+                    content: OpusDescDefault {
+                        bitrate_kbps: 128,
+                        frame_duration: OpusFrameDurationDefault {
+                            variant: OpusFrameDurationDefaultVariant::Ms10,
+                        },
+                        in_band_fec: false,
+                        expected_packet_loss_percent: 5,
+                    },
+                },
+                processing: AudioProcessingDescDefault {
+                    echo_cancellation: SwitchDefault {
+                        enabled: false,
+                        content: EchoCancellationDescDefault {
+                            tail_length_ms: 200,
+                        },
+                    },
+                    noise_suppression: SwitchDefault {
+                        enabled: false,
+                        content: NoiseSuppressionDescDefault {
+                            max_attenuation_db: 20.,
+                        },
+                    },
+                    gain_control: SwitchDefault {
+                        enabled: false,
+                        content: GainControlDescDefault {
+                            target_level_dbfs: -20.,
+                            max_gain_db: 30.,
+                        },
+                    },
+                },
                 buffering_latency: LatencyDescDefault {
                     default_ms: 30,
                     history_mean_lifetime_s: 120,
@@ -637,27 +861,73 @@ pub fn settings_default() -> SettingsDefault {
                         variant: LatencyModeDefaultVariant::Automatic,
                         Automatic: LatencyModeAutomaticDefault {
                             expected_misses_per_hour: 30,
+                            estimator: LatencyEstimatorDefault {
+                                variant: LatencyEstimatorDefaultVariant::Gaussian,
+                            },
                         },
                     },
                 },
+                video_sync: SwitchDefault {
+                    enabled: true,
+                    content: VideoSyncDescDefault {
+                        tolerance_ms: 20,
+                        max_delay_ms: 200,
+                    },
+                },
                 reliable: false,
             },
         },
         microphone: SwitchDefault {
             enabled: false,
             content: AudioDescDefault {
-                input_device_index: OptionalDefault {
+                input_device: OptionalDefault {
+                    set: false,
+                    content: "".into(),
+                },
+                output_device: OptionalDefault {
                     set: false,
-                    content: 0,
+                    content: "".into(),
                 },
-                output_device_index: OptionalDefault {
+                output_file: OptionalDefault {
                     set: false,
-                    content: 0,
+                    content: "".into(),
                 },
-                preferred_sample_rate: 44100,
+                preferred_sample_rate: 48000,
                 preferred_format: AudioFormatDefault {
                     variant: AudioFormatDefaultVariant::Bit8,
                 },
+                opus: SwitchDefault {
+                    enabled: true,
+                    content: OpusDescDefault {
+                        bitrate_kbps: 32,
+                        frame_duration: OpusFrameDurationDefault {
+                            variant: OpusFrameDurationDefaultVariant::Ms20,
+                        },
+                        in_band_fec: true,
+                        expected_packet_loss_percent: 10,
+                    },
+                },
+                processing: AudioProcessingDescDefault {
+                    echo_cancellation: SwitchDefault {
+                        enabled: true,
+                        content: EchoCancellationDescDefault {
+                            tail_length_ms: 200,
+                        },
+                    },
+                    noise_suppression: SwitchDefault {
+                        enabled: true,
+                        content: NoiseSuppressionDescDefault {
+                            max_attenuation_db: 20.,
+                        },
+                    },
+                    gain_control: SwitchDefault {
+                        enabled: true,
+                        content: GainControlDescDefault {
+                            target_level_dbfs: -20.,
+                            max_gain_db: 30.,
+                        },
+                    },
+                },
                 buffering_latency: LatencyDescDefault {
                     default_ms: 40,
                     history_mean_lifetime_s: 120,
@@ -665,9 +935,19 @@ pub fn settings_default() -> SettingsDefault {
                         variant: LatencyModeDefaultVariant::Automatic,
                         Automatic: LatencyModeAutomaticDefault {
                             expected_misses_per_hour: 120,
+                            estimator: LatencyEstimatorDefault {
+                                variant: LatencyEstimatorDefaultVariant::Gaussian,
+                            },
                         },
                     },
                 },
+                video_sync: SwitchDefault {
+                    enabled: false,
+                    content: VideoSyncDescDefault {
+                        tolerance_ms: 20,
+                        max_delay_ms: 200,
+                    },
+                },
                 reliable: false,
             },
         },
@@ -800,6 +1080,10 @@ pub fn settings_default() -> SettingsDefault {
                     variant: CompositorTypeDefaultVariant::Custom,
                 },
             },
+            metrics_endpoint: SwitchDefault {
+                enabled: false,
+                content: MetricsEndpointDescDefault { port: 9945 },
+            },
         },
         vr_client: VrClientDescDefault {
             openxr: OpenxrDescDefault {
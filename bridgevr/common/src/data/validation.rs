@@ -0,0 +1,236 @@
+use super::*;
+use serde::de::DeserializeOwned;
+use std::io::Read;
+
+const TRACE_CONTEXT: &str = "Packet validation";
+
+pub const MAX_CLIENT_HANDSHAKE_PACKET_SIZE: u64 = 4_000;
+pub const MAX_SERVER_HANDSHAKE_PACKET_SIZE: u64 = 1_000_000;
+
+const MAX_NAME_LENGTH: usize = 256;
+const MAX_SAMPLE_RATE_COUNT: usize = 64;
+const MAX_AUDIO_CHANNEL_COUNT: u16 = 32;
+// One second at the highest standard sample rate
+const MAX_AUDIO_PACKET_SAMPLE_COUNT: u32 = 192_000;
+const MAX_EYE_RESOLUTION: u32 = 16_384;
+const MAX_FRAME_SLICE_COUNT: u8 = 8;
+const MAX_TRACKED_DEVICE_COUNT: usize = 16;
+const MAX_HAND_SKELETON_JOINT_COUNT: usize = 32;
+// Receive and decode spans for each slice plus the display span, with margin
+const MAX_FRAME_TRACE_SPAN_COUNT: usize = 64;
+
+// Semantic checks done after deserialization, on top of the size limit. A packet that passes the
+// checks can be processed without risk of panics or unbounded allocations.
+pub trait Validate {
+    fn validate(&self) -> StrResult;
+}
+
+// Deserialize a packet coming from the network. `max_size` limits the bytes read and so the size
+// of the allocations made by bincode.
+pub fn deserialize_bounded<'a, T: Deserialize<'a> + Validate>(
+    buffer: &'a [u8],
+    max_size: u64,
+) -> StrResult<T> {
+    let packet: T = trace_err!(bincode::config().limit(max_size).deserialize(buffer))?;
+    packet.validate()?;
+    Ok(packet)
+}
+
+pub fn deserialize_bounded_from<T: DeserializeOwned + Validate>(
+    reader: impl Read,
+    max_size: u64,
+) -> StrResult<T> {
+    let packet: T = trace_err!(bincode::config().limit(max_size).deserialize_from(reader))?;
+    packet.validate()?;
+    Ok(packet)
+}
+
+fn check(condition: bool, message: &str) -> StrResult {
+    if condition {
+        Ok(())
+    } else {
+        trace_str!("Invalid packet: {}", message)
+    }
+}
+
+fn check_finite(values: &[f32], message: &str) -> StrResult {
+    check(values.iter().all(|v| v.is_finite()), message)
+}
+
+impl Validate for MotionSampleDesc {
+    fn validate(&self) -> StrResult {
+        match self {
+            MotionSampleDesc::Dof3(sample) => {
+                check_finite(&sample.default_position, "motion position")?;
+                check_finite(&sample.orientation, "motion orientation")
+            }
+            MotionSampleDesc::Dof6(sample) => {
+                check_finite(&sample.pose.position, "motion position")?;
+                check_finite(&sample.pose.orientation, "motion orientation")
+            }
+        }
+    }
+}
+
+impl Validate for ClientHandshakePacket {
+    fn validate(&self) -> StrResult {
+        check(
+            self.bridgevr_name.len() <= MAX_NAME_LENGTH && self.version.len() <= MAX_NAME_LENGTH,
+            "name too long",
+        )?;
+        let (width, height) = self.native_eye_resolution;
+        check(
+            width > 0 && width <= MAX_EYE_RESOLUTION && height > 0 && height <= MAX_EYE_RESOLUTION,
+            "eye resolution",
+        )?;
+        check(self.fps > 0, "fps")?;
+        check(
+            self.available_audio_player_sample_rates.len() <= MAX_SAMPLE_RATE_COUNT
+                && self.available_microphone_sample_rates.len() <= MAX_SAMPLE_RATE_COUNT
+                && self.preferred_microphone_sample_rates.len() <= MAX_SAMPLE_RATE_COUNT,
+            "too many sample rates",
+        )?;
+        check(
+            self.available_audio_player_channel_counts.len() <= MAX_AUDIO_CHANNEL_COUNT as usize
+                && self.available_microphone_channel_counts.len()
+                    <= MAX_AUDIO_CHANNEL_COUNT as usize,
+            "too many channel counts",
+        )
+    }
+}
+
+impl Validate for AudioConfig {
+    fn validate(&self) -> StrResult {
+        check(
+            self.sample_rate > 0
+                && self.channel_count > 0
+                && self.channel_count <= MAX_AUDIO_CHANNEL_COUNT,
+            "audio config",
+        )?;
+        if let AudioCodec::Opus(desc) = &self.codec {
+            check(
+                crate::audio_codec::supports_opus(self.sample_rate, self.channel_count)
+                    && desc.bitrate_kbps >= 6
+                    && desc.bitrate_kbps <= 510
+                    && desc.expected_packet_loss_percent <= 100,
+                "Opus config",
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Validate for ServerHandshakePacket {
+    fn validate(&self) -> StrResult {
+        check(
+            self.config.version.len() <= MAX_NAME_LENGTH,
+            "name too long",
+        )?;
+        for config in self
+            .config
+            .game_audio_config
+            .iter()
+            .chain(&self.config.microphone_config)
+        {
+            config.validate()?;
+        }
+        let (width, height) = self.config.target_eye_resolution;
+        check(
+            width > 0 && width <= MAX_EYE_RESOLUTION && height > 0 && height <= MAX_EYE_RESOLUTION,
+            "eye resolution",
+        )?;
+        // The slice index is encoded in the stream id
+        check(
+            self.settings.video.frame_slice_count > 0
+                && self.settings.video.frame_slice_count <= MAX_FRAME_SLICE_COUNT,
+            "frame slice count",
+        )?;
+        check(
+            self.settings.tracked_devices.len() <= MAX_TRACKED_DEVICE_COUNT,
+            "too many tracked devices",
+        )
+    }
+}
+
+impl<'a> Validate for VideoPacket<'a> {
+    fn validate(&self) -> StrResult {
+        check(self.sub_nal_index < self.sub_nal_count, "sub NAL index")?;
+        check_finite(&self.hmd_pose.position, "HMD position")?;
+        check_finite(&self.hmd_pose.orientation, "HMD orientation")
+    }
+}
+
+impl<'a> Validate for AudioPacket<'a> {
+    fn validate(&self) -> StrResult {
+        check(
+            self.sample_count > 0 && self.sample_count <= MAX_AUDIO_PACKET_SAMPLE_COUNT,
+            "audio packet sample count",
+        )
+    }
+}
+
+impl<'a> Validate for ProbePacket<'a> {
+    fn validate(&self) -> StrResult {
+        Ok(())
+    }
+}
+
+impl Validate for OtherServerPacket {
+    fn validate(&self) -> StrResult {
+        match self {
+            OtherServerPacket::Haptic { sample, .. } => {
+                check_finite(
+                    &[sample.duration_seconds, sample.frequency, sample.amplitude],
+                    "haptic sample",
+                )?;
+                check(sample.duration_seconds >= 0_f32, "haptic duration")
+            }
+            OtherServerPacket::Shutdown => Ok(()),
+        }
+    }
+}
+
+impl Validate for OtherClientPacket {
+    fn validate(&self) -> StrResult {
+        match self {
+            OtherClientPacket::MotionAndTiming { device_motions, .. } => {
+                check(
+                    device_motions.len() <= MAX_TRACKED_DEVICE_COUNT,
+                    "too many device motions",
+                )?;
+                for motion in device_motions {
+                    motion.sample.validate()?;
+                }
+                Ok(())
+            }
+            OtherClientPacket::InputDeviceData {
+                data: InputDeviceData::OculusHands(hands),
+                ..
+            } => {
+                for hand in hands {
+                    check(
+                        hand.len() <= MAX_HAND_SKELETON_JOINT_COUNT,
+                        "too many hand joints",
+                    )?;
+                    for joint in hand {
+                        joint.validate()?;
+                    }
+                }
+                Ok(())
+            }
+            OtherClientPacket::FrameTrace { spans, .. } => {
+                check(
+                    spans.len() <= MAX_FRAME_TRACE_SPAN_COUNT,
+                    "too many frame trace spans",
+                )?;
+                check(
+                    spans.iter().all(|span| span.start_ns <= span.end_ns),
+                    "frame trace span bounds",
+                )
+            }
+            OtherClientPacket::InputDeviceData { .. }
+            | OtherClientPacket::Statistics(_)
+            | OtherClientPacket::Disconnected => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+// The latency of each packet oscillates because packets and device callbacks have different
+// periods. The average used by `EventTiming` is too slow to react, so a shorter one is used.
+const LATENCY_SMOOTHING_TIME_S: f64 = 2.;
+
+// Gains of the PI controller. The error is the deviation of the queue latency from the target, in
+// seconds. The loop is critically damped with a time constant of 20s: a constant drift is fully
+// compensated in about a minute, while the latency jitter does not modulate the pitch.
+const PROPORTIONAL_GAIN: f64 = 0.1;
+const INTEGRAL_GAIN: f64 = 0.0025;
+
+// A 0.5% speed change shifts the pitch by less than 9 cents, below the audible threshold.
+// Real clocks differ by much less than this, usually less than 300ppm.
+const MAX_SPEED_CORRECTION: f64 = 0.005;
+
+// Compensates the drift between the clock of the sender and the clock of the playback device, that
+// would otherwise make the packet queue slowly grow or shrink. The returned playback speed is
+// applied to the resampling ratio, so the queue is drained at the rate it is filled.
+pub struct DriftController {
+    latency_average_s: Option<f64>,
+    integral: f64,
+}
+
+impl DriftController {
+    pub fn new() -> Self {
+        Self {
+            latency_average_s: None,
+            integral: 0.,
+        }
+    }
+
+    // `latency` is the queue latency of the last packet, `interval` is the time since the last
+    // update. A speed greater than 1 means the input must be consumed faster.
+    pub fn update(
+        &mut self,
+        latency: Duration,
+        target_latency: Duration,
+        interval: Duration,
+    ) -> f64 {
+        let interval_s = interval.as_secs_f64();
+
+        let latency_s = latency.as_secs_f64();
+        let latency_average_s = match self.latency_average_s {
+            Some(average_s) => {
+                let weight = interval_s / (LATENCY_SMOOTHING_TIME_S + interval_s);
+                average_s + (latency_s - average_s) * weight
+            }
+            None => latency_s,
+        };
+        self.latency_average_s = Some(latency_average_s);
+
+        let error_s = latency_average_s - target_latency.as_secs_f64();
+
+        // The integral is clamped to avoid windup when the error cannot be corrected quickly, for
+        // example right after the stream starts
+        let max_integral = MAX_SPEED_CORRECTION / INTEGRAL_GAIN;
+        self.integral = (self.integral + error_s * interval_s).clamp(-max_integral, max_integral);
+
+        let correction = PROPORTIONAL_GAIN * error_s + INTEGRAL_GAIN * self.integral;
+        1. + correction.clamp(-MAX_SPEED_CORRECTION, MAX_SPEED_CORRECTION)
+    }
+}
+
+impl Default for DriftController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET_LATENCY: Duration = Duration::from_millis(30);
+
+    // 1024 frames at 48KHz
+    const CALLBACK_INTERVAL: Duration = Duration::from_micros(21333);
+
+    const SIMULATION_DURATION_S: f64 = 180.;
+
+    // The latency is checked only after the controller had time to converge
+    const SETTLING_TIME_S: f64 = 90.;
+
+    fn ms(value: f64) -> Duration {
+        Duration::from_secs_f64(value / 1000.)
+    }
+
+    // The sender clock is faster than the device clock by `clock_offset_ppm`, so the queue grows
+    // unless the input is consumed faster. The measured latency oscillates by `oscillation_ms`
+    // because packets and callbacks have different periods. Returns the maximum latency error and
+    // the maximum speed error after settling.
+    fn simulate(clock_offset_ppm: f64, oscillation_ms: f64) -> (f64, f64) {
+        let mut controller = DriftController::new();
+        let interval_s = CALLBACK_INTERVAL.as_secs_f64();
+        let ideal_speed = 1. + clock_offset_ppm / 1e6;
+
+        let mut latency_s = TARGET_LATENCY.as_secs_f64();
+        let mut speed = 1.;
+        let mut max_latency_error_s = 0_f64;
+        let mut max_speed_error = 0_f64;
+        let step_count = (SIMULATION_DURATION_S / interval_s) as usize;
+        for step in 0..step_count {
+            latency_s += (ideal_speed - speed) * interval_s;
+
+            let measured_latency_s = latency_s + oscillation_ms / 1000. * (step as f64 * 0.7).sin();
+            speed = controller.update(
+                Duration::from_secs_f64(measured_latency_s.max(0.)),
+                TARGET_LATENCY,
+                CALLBACK_INTERVAL,
+            );
+
+            if step as f64 * interval_s > SETTLING_TIME_S {
+                max_latency_error_s =
+                    max_latency_error_s.max((latency_s - TARGET_LATENCY.as_secs_f64()).abs());
+                max_speed_error = max_speed_error.max((speed - ideal_speed).abs());
+            }
+        }
+
+        (max_latency_error_s, max_speed_error)
+    }
+
+    #[test]
+    fn steady_at_target() {
+        let mut controller = DriftController::new();
+        for _ in 0..100 {
+            let speed = controller.update(TARGET_LATENCY, TARGET_LATENCY, CALLBACK_INTERVAL);
+            assert!((speed - 1.).abs() < f64::EPSILON);
+        }
+    }
+
+    #[test]
+    fn direction_of_correction() {
+        let mut controller = DriftController::new();
+        assert!(controller.update(ms(40.), TARGET_LATENCY, CALLBACK_INTERVAL) > 1.);
+
+        let mut controller = DriftController::new();
+        assert!(controller.update(ms(20.), TARGET_LATENCY, CALLBACK_INTERVAL) < 1.);
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut controller = DriftController::new();
+        for _ in 0..1000 {
+            let speed = controller.update(ms(500.), TARGET_LATENCY, CALLBACK_INTERVAL);
+            assert!(speed <= 1. + MAX_SPEED_CORRECTION);
+        }
+
+        // The integral did not wind up while saturated, so the correction reverses in less than a
+        // minute once the queue is empty
+        let mut controller = DriftController::new();
+        for _ in 0..10000 {
+            controller.update(ms(500.), TARGET_LATENCY, CALLBACK_INTERVAL);
+        }
+        let mut elapsed_s = 0.;
+        while controller.update(ms(0.), TARGET_LATENCY, CALLBACK_INTERVAL) >= 1. {
+            elapsed_s += CALLBACK_INTERVAL.as_secs_f64();
+            assert!(elapsed_s < 60.);
+        }
+    }
+
+    #[test]
+    fn compensates_clock_drift() {
+        for &clock_offset_ppm in &[-300., -20., 20., 300.] {
+            let (latency_error_s, speed_error) = simulate(clock_offset_ppm, 0.);
+            assert!(
+                latency_error_s < 0.001,
+                "{}: {}",
+                clock_offset_ppm,
+                latency_error_s
+            );
+            assert!(speed_error < 10e-6, "{}: {}", clock_offset_ppm, speed_error);
+        }
+    }
+
+    #[test]
+    fn ignores_latency_oscillation() {
+        let (latency_error_s, speed_error) = simulate(100., 5.);
+        assert!(latency_error_s < 0.002, "{}", latency_error_s);
+
+        // 50 ppm is far below the audible pitch change
+        assert!(speed_error < 50e-6, "{}", speed_error);
+    }
+}
@@ -4,6 +4,21 @@ use std::{f32::consts::*, time::*};
 
 const MAX_LATENCY: Duration = Duration::from_millis(500);
 
+// Latencies above MAX_LATENCY are counted in the last bin
+const HISTOGRAM_BIN_WIDTH_S: f64 = 0.0005;
+const HISTOGRAM_BIN_COUNT: usize = 1000;
+
+// Tail probabilities that would be estimated from fewer samples than this are extrapolated instead
+const MIN_TAIL_SAMPLE_COUNT: f64 = 10.;
+
+// Bins are merged when exported, to keep the statistics small
+const EXPORTED_BIN_MERGE_COUNT: usize = 10;
+const EXPORTED_PERCENTILES: [f32; 6] = [0.1, 1., 5., 50., 95., 99.];
+
+// The weight of the samples grows instead of decaying the old ones. Before it overflows, all the
+// weights are scaled down.
+const MAX_SAMPLE_WEIGHT: f64 = 1e100;
+
 fn inverse_q_of_probability(misses_per_sec: f32, notifs_per_sec: f32) -> f32 {
     let miss_probability = misses_per_sec / notifs_per_sec;
     // Q function: https://en.wikipedia.org/wiki/Q-function
@@ -30,29 +45,134 @@ fn update_latency_variance(
     (old_latency_variance_s * history_count + deviation * deviation) / (history_count + 1_f32)
 }
 
+// Latency distribution with the same exponential forgetting of the running average
+struct LatencyHistogram {
+    bins: Vec<f64>,
+    total_weight: f64,
+    sample_weight: f64,
+    history_count: f64,
+    sample_count: u64,
+}
+
+impl LatencyHistogram {
+    fn new(history_count: f32) -> Self {
+        Self {
+            bins: vec![0.; HISTOGRAM_BIN_COUNT],
+            total_weight: 0.,
+            sample_weight: 1.,
+            history_count: history_count as _,
+            sample_count: 0,
+        }
+    }
+
+    fn add(&mut self, latency_s: f32) {
+        let bin_idx =
+            ((latency_s as f64 / HISTOGRAM_BIN_WIDTH_S) as usize).min(HISTOGRAM_BIN_COUNT - 1);
+        self.bins[bin_idx] += self.sample_weight;
+        self.total_weight += self.sample_weight;
+        self.sample_count += 1;
+
+        // Equivalent to multiplying the old weights by history_count / (history_count + 1)
+        self.sample_weight *= (self.history_count + 1.) / self.history_count;
+        if self.sample_weight > MAX_SAMPLE_WEIGHT {
+            for bin in &mut self.bins {
+                *bin /= self.sample_weight;
+            }
+            self.total_weight /= self.sample_weight;
+            self.sample_weight = 1.;
+        }
+    }
+
+    // Number of samples that have a significant weight
+    fn effective_sample_count(&self) -> f64 {
+        (self.sample_count as f64).min(self.history_count)
+    }
+
+    // Returns the latency below which falls the fraction `probability` of the samples, and the
+    // average distance from it of those samples
+    fn lower_tail_s(&self, probability: f64) -> (f64, f64) {
+        let tail_weight = probability * self.total_weight;
+
+        let mut cumulative_weight = 0.;
+        let mut weighted_latency_sum = 0.;
+        for (idx, &weight) in self.bins.iter().enumerate() {
+            if cumulative_weight + weight >= tail_weight {
+                let bin_fraction = if weight > 0. {
+                    (tail_weight - cumulative_weight) / weight
+                } else {
+                    0.
+                };
+                let quantile_s = (idx as f64 + bin_fraction) * HISTOGRAM_BIN_WIDTH_S;
+
+                // The samples of the partial bin are assumed to be at the middle of their part
+                let partial_weight = tail_weight - cumulative_weight;
+                let partial_latency_s = (idx as f64 + bin_fraction / 2.) * HISTOGRAM_BIN_WIDTH_S;
+                let mean_excess_s = if tail_weight > 0. {
+                    quantile_s
+                        - (weighted_latency_sum + partial_latency_s * partial_weight) / tail_weight
+                } else {
+                    0.
+                };
+
+                return (quantile_s, mean_excess_s);
+            }
+
+            cumulative_weight += weight;
+            weighted_latency_sum += (idx as f64 + 0.5) * HISTOGRAM_BIN_WIDTH_S * weight;
+        }
+
+        (HISTOGRAM_BIN_COUNT as f64 * HISTOGRAM_BIN_WIDTH_S, 0.)
+    }
+
+    fn exported_bins(&self) -> Vec<f32> {
+        let mut histogram: Vec<_> = self
+            .bins
+            .chunks(EXPORTED_BIN_MERGE_COUNT)
+            .map(|bins| (bins.iter().sum::<f64>() / self.total_weight) as f32)
+            .collect();
+        while histogram.last() == Some(&0.) {
+            histogram.pop();
+        }
+
+        histogram
+    }
+}
+
 pub struct EventTiming {
     latency_desc: LatencyDesc,
     unmatched_push_times: TimeoutMap<u64, Instant>,
     unmatched_pop_times: TimeoutMap<u64, Instant>,
     inverse_q_of_prob: f32,
+    miss_probability: f64,
     history_count: f32,
     latency_average_s: f32,
     latency_variance_s: f32,
+
+    // Always updated, so the distribution can be exported with any estimator
+    histogram: LatencyHistogram,
+
+    // Updated at each sample when using the quantile estimator
+    quantile_target_latency_s: f32,
 }
 
 impl EventTiming {
     pub fn new(latency_desc: LatencyDesc, notifs_per_sec: f32) -> Self {
         let history_mean_lifetime_s_f32 = latency_desc.history_mean_lifetime_s as f32;
         let latency_average_s = latency_desc.default_ms as f32 / 1000_f32;
+        let history_count = history_mean_lifetime_s_f32 * notifs_per_sec;
 
-        let inverse_q_of_prob = match latency_desc.mode {
+        let (inverse_q_of_prob, miss_probability) = match latency_desc.mode {
             LatencyMode::Automatic {
                 expected_misses_per_hour,
+                ..
             } => {
-                let accepted_misses_per_sec = (expected_misses_per_hour * 60 * 60) as _;
-                inverse_q_of_probability(accepted_misses_per_sec, notifs_per_sec)
+                let accepted_misses_per_sec = expected_misses_per_hour as f32 / (60 * 60) as f32;
+                (
+                    inverse_q_of_probability(accepted_misses_per_sec, notifs_per_sec),
+                    (accepted_misses_per_sec / notifs_per_sec) as f64,
+                )
             }
-            LatencyMode::Manual => 1_f32,
+            LatencyMode::Manual => (1_f32, 0.),
         };
 
         Self {
@@ -60,9 +180,13 @@ impl EventTiming {
             unmatched_push_times: TimeoutMap::new(MAX_LATENCY),
             unmatched_pop_times: TimeoutMap::new(MAX_LATENCY),
             inverse_q_of_prob,
-            history_count: history_mean_lifetime_s_f32 * notifs_per_sec,
+            miss_probability,
+            history_count,
             latency_average_s,
-            latency_variance_s: latency_average_s / inverse_q_of_prob,
+            // Start with a target latency equal to the default latency
+            latency_variance_s: (latency_average_s / inverse_q_of_prob).powi(2),
+            histogram: LatencyHistogram::new(history_count),
+            quantile_target_latency_s: latency_average_s,
         }
     }
 
@@ -72,11 +196,63 @@ impl EventTiming {
             ..
         } = self.latency_desc.mode
         {
-            let accepted_misses_per_sec = expected_misses_per_hour * 60 * 60;
+            let accepted_misses_per_sec = expected_misses_per_hour as f32 / (60 * 60) as f32;
             self.inverse_q_of_prob =
-                inverse_q_of_probability(accepted_misses_per_sec as _, notifs_per_sec);
+                inverse_q_of_probability(accepted_misses_per_sec, notifs_per_sec);
+            self.miss_probability = (accepted_misses_per_sec / notifs_per_sec) as f64;
         }
         self.history_count = self.latency_desc.history_mean_lifetime_s as f32 * notifs_per_sec;
+        self.histogram.history_count = self.history_count as _;
+    }
+
+    fn uses_quantile_estimator(&self) -> bool {
+        matches!(
+            self.latency_desc.mode,
+            LatencyMode::Automatic {
+                estimator: LatencyEstimator::Quantile,
+                ..
+            }
+        )
+    }
+
+    // The target is the distance between the average and the latency of the late samples, the
+    // ones that would be missed if the average was equal to the target. Probabilities too low to
+    // be measured with the available history are reached by fitting an exponential tail to the
+    // latest measurable quantile.
+    fn update_quantile_target_latency(&mut self) {
+        let effective_sample_count = self.histogram.effective_sample_count();
+        let measurable_probability = MIN_TAIL_SAMPLE_COUNT / effective_sample_count;
+
+        // Too few samples to say anything about the tail
+        if measurable_probability > 0.5 {
+            return;
+        }
+
+        let tail_probability = self.miss_probability.max(measurable_probability);
+        let (quantile_s, mean_excess_s) = self.histogram.lower_tail_s(tail_probability);
+        let extrapolation_s = mean_excess_s * (tail_probability / self.miss_probability).ln();
+
+        self.quantile_target_latency_s =
+            (self.latency_average_s as f64 - quantile_s + extrapolation_s).max(0.) as f32;
+    }
+
+    fn notify_latency_sample(&mut self, latency_sample_s: f32) {
+        self.latency_average_s =
+            update_latency_average(self.latency_average_s, self.history_count, latency_sample_s);
+
+        if matches!(self.latency_desc.mode, LatencyMode::Automatic { .. }) {
+            self.latency_variance_s = update_latency_variance(
+                self.latency_variance_s,
+                self.latency_average_s,
+                self.history_count,
+                latency_sample_s,
+            );
+        }
+
+        self.histogram.add(latency_sample_s);
+        if self.uses_quantile_estimator() {
+            self.update_quantile_target_latency();
+        }
     }
 
     // This method call can be skipped for some id or can be in any order.
@@ -92,27 +268,18 @@ impl EventTiming {
         self.unmatched_pop_times.insert(id, now);
 
         let mut pop_ids_to_be_removed = vec![];
+        let mut latency_samples_s = vec![];
         for &id in self.unmatched_pop_times.keys() {
             let maybe_time = self.unmatched_push_times.remove(&id).map(|(_, t)| t);
 
             if let Some(time) = maybe_time {
-                let latency_sample_s = (now - time).as_secs_f32();
-                self.latency_average_s = update_latency_average(
-                    self.latency_average_s,
-                    self.history_count,
-                    latency_sample_s,
-                );
-                if matches!(self.latency_desc.mode, LatencyMode::Automatic { .. }) {
-                    self.latency_variance_s = update_latency_variance(
-                        self.latency_variance_s,
-                        self.latency_average_s,
-                        self.history_count,
-                        latency_sample_s,
-                    );
-                }
+                latency_samples_s.push((now - time).as_secs_f32());
                 pop_ids_to_be_removed.push(id);
             }
         }
+        for latency_sample_s in latency_samples_s {
+            self.notify_latency_sample(latency_sample_s);
+        }
 
         self.unmatched_push_times.remove_expired();
         self.unmatched_pop_times.remove_expired();
@@ -123,22 +290,14 @@ impl EventTiming {
 
     // equivalent to notify_push() + notify_pop() calls with `latency` interval between them.
     pub fn notify_latency(&mut self, latency: Duration) {
-        let latency_sample_s = latency.as_secs_f32();
-
-        self.latency_average_s =
-            update_latency_average(self.latency_average_s, self.history_count, latency_sample_s);
-
-        if matches!(self.latency_desc.mode, LatencyMode::Automatic { .. }) {
-            self.latency_variance_s = update_latency_variance(
-                self.latency_variance_s,
-                self.latency_average_s,
-                self.history_count,
-                latency_sample_s,
-            );
-        }
+        self.notify_latency_sample(latency.as_secs_f32());
     }
 
     pub fn target_latency(&self) -> Duration {
+        if self.uses_quantile_estimator() {
+            return Duration::from_secs_f32(self.quantile_target_latency_s);
+        }
+
         // miss prob = Q(target latency / stddev)
         // target latency = sqrt(latency variance) * Q^-1(miss prob)
         Duration::from_secs_f32(self.latency_variance_s.sqrt() * self.inverse_q_of_prob)
@@ -149,4 +308,142 @@ impl EventTiming {
     pub fn average_latency(&self) -> Duration {
         Duration::from_secs_f32(self.latency_average_s)
     }
+
+    pub fn latency_statistics(&self) -> LatencyStatistics {
+        let percentiles_ms = if self.histogram.total_weight > 0. {
+            EXPORTED_PERCENTILES
+                .iter()
+                .map(|&percentile| {
+                    let (quantile_s, _) = self.histogram.lower_tail_s(percentile as f64 / 100.);
+                    (percentile, quantile_s as f32 * 1000.)
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let histogram = if self.histogram.total_weight > 0. {
+            self.histogram.exported_bins()
+        } else {
+            vec![]
+        };
+
+        LatencyStatistics {
+            average_ms: self.latency_average_s * 1000.,
+            target_ms: self.target_latency().as_secs_f32() * 1000.,
+            percentiles_ms,
+            histogram_bin_ms: (HISTOGRAM_BIN_WIDTH_S * EXPORTED_BIN_MERGE_COUNT as f64 * 1000.)
+                as _,
+            histogram,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFS_PER_SEC: f64 = 100.;
+    const HISTORY_MEAN_LIFETIME_S: u32 = 60;
+    const SETTLING_TIME_S: f64 = 2. * 60.;
+
+    // The queue latency without jitter. The measured latency is this minus the network delay,
+    // exponentially distributed with this mean.
+    const QUEUE_LATENCY_S: f64 = 0.2;
+    const MEAN_NETWORK_DELAY_S: f64 = 0.002;
+
+    // xorshift32, uniform in (0, 1)
+    fn next_random(state: &mut u32) -> f64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state as f64 + 0.5) / (u32::MAX as f64 + 1.)
+    }
+
+    fn quantile_event_timing(expected_misses_per_hour: u32) -> EventTiming {
+        EventTiming::new(
+            LatencyDesc {
+                default_ms: 30,
+                history_mean_lifetime_s: HISTORY_MEAN_LIFETIME_S,
+                mode: LatencyMode::Automatic {
+                    expected_misses_per_hour,
+                    estimator: LatencyEstimator::Quantile,
+                },
+            },
+            NOTIFS_PER_SEC as f32,
+        )
+    }
+
+    // Feeds the latency samples for `duration_s` and returns how many of them were missed after
+    // the settling time. A sample is missed if its deviation below the average is larger than the
+    // target latency, which is what happens when the queue is kept at the target latency.
+    fn simulate(event_timing: &mut EventTiming, duration_s: f64) -> u64 {
+        let mut random_state = 0x1234_5678;
+        let mut miss_count = 0;
+        let settling_sample_count = (SETTLING_TIME_S * NOTIFS_PER_SEC) as u64;
+        for idx in 0..(duration_s * NOTIFS_PER_SEC) as u64 {
+            let network_delay_s = -MEAN_NETWORK_DELAY_S * next_random(&mut random_state).ln();
+            let latency_s = QUEUE_LATENCY_S - network_delay_s.min(QUEUE_LATENCY_S);
+
+            let deviation_s = event_timing.average_latency().as_secs_f64() - latency_s;
+            if idx >= settling_sample_count
+                && deviation_s > event_timing.target_latency().as_secs_f64()
+            {
+                miss_count += 1;
+            }
+
+            event_timing.notify_latency(Duration::from_secs_f64(latency_s));
+        }
+
+        miss_count
+    }
+
+    // The miss probability is high enough to be measured from the history
+    #[test]
+    fn measured_miss_rate() {
+        const EXPECTED_MISSES_PER_HOUR: u32 = 3600;
+        const DURATION_S: f64 = SETTLING_TIME_S + 20. * 60.;
+
+        let mut event_timing = quantile_event_timing(EXPECTED_MISSES_PER_HOUR);
+        let miss_count = simulate(&mut event_timing, DURATION_S);
+
+        let misses_per_hour = miss_count as f64 / (DURATION_S - SETTLING_TIME_S) * 3600.;
+        let ratio = misses_per_hour / EXPECTED_MISSES_PER_HOUR as f64;
+        assert!(
+            (0.8..1.25).contains(&ratio),
+            "{} misses per hour",
+            misses_per_hour
+        );
+    }
+
+    // The miss probability is too low to be measured from the history and the tail is
+    // extrapolated. With an exponential delay of mean m, a sample is missed with probability
+    // exp(-(target + m) / m), so the exact target is m * (ln(1 / miss probability) - 1).
+    #[test]
+    fn extrapolated_target_latency() {
+        const EXPECTED_MISSES_PER_HOUR: u32 = 60;
+
+        let mut event_timing = quantile_event_timing(EXPECTED_MISSES_PER_HOUR);
+        assert!(
+            event_timing.miss_probability
+                < MIN_TAIL_SAMPLE_COUNT / (HISTORY_MEAN_LIFETIME_S as f64 * NOTIFS_PER_SEC)
+        );
+
+        simulate(&mut event_timing, SETTLING_TIME_S + 5. * 60.);
+
+        let target_latency_s = event_timing.target_latency().as_secs_f64();
+        let expected_target_latency_s =
+            MEAN_NETWORK_DELAY_S * ((1. / event_timing.miss_probability).ln() - 1.);
+        let misses_per_hour = (-(target_latency_s + MEAN_NETWORK_DELAY_S) / MEAN_NETWORK_DELAY_S)
+            .exp()
+            * NOTIFS_PER_SEC
+            * 3600.;
+        let ratio = misses_per_hour / EXPECTED_MISSES_PER_HOUR as f64;
+        assert!(
+            (0.5..2.).contains(&ratio),
+            "target {}s, expected {}s",
+            target_latency_s,
+            expected_target_latency_s
+        );
+    }
 }
@@ -0,0 +1,546 @@
+use crate::{av_sync::Timebase, data::*, timeout_map::TimeoutMap, *};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::*,
+};
+
+const TRACE_CONTEXT: &str = "Frame trace";
+
+// Frames still incomplete after this time are returned by `take_expired()`. On the server this
+// happens when the report of the client is lost, on the client when the frame is not displayed.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bound of the frames in flight, in case `take_expired()` is not called
+const MAX_PENDING_FRAME_COUNT: usize = 128;
+
+// The clock offset is the minimum over a window of frames, so it can follow the drift of the
+// clocks. The minimum of the previous window is used too, so the estimate never restarts from a
+// single frame.
+const CLOCK_OFFSET_WINDOW_FRAME_COUNT: u32 = 1000;
+
+const SERVER_PID: u32 = 1;
+const CLIENT_PID: u32 = 2;
+
+// Collects the spans of the pipeline stages of each frame, keyed by NAL index. Cloned by all the
+// threads that record a stage.
+#[derive(Clone)]
+pub struct FrameTraceRecorder {
+    timebase: Timebase,
+    frames: Arc<Mutex<TimeoutMap<u64, Vec<FrameTraceSpan>>>>,
+}
+
+impl FrameTraceRecorder {
+    pub fn new(timebase: Timebase) -> Self {
+        Self {
+            timebase,
+            frames: Arc::new(Mutex::new(TimeoutMap::with_max_len(
+                FRAME_TIMEOUT,
+                MAX_PENDING_FRAME_COUNT,
+            ))),
+        }
+    }
+
+    pub fn record(
+        &self,
+        nal_index: u64,
+        stage: FrameStage,
+        slice_idx: Option<u8>,
+        start: Instant,
+        end: Instant,
+    ) {
+        let span = FrameTraceSpan {
+            stage,
+            slice_idx,
+            start_ns: self.timebase.timestamp_ns(start),
+            end_ns: self.timebase.timestamp_ns(end),
+        };
+
+        let mut frames = self.frames.lock();
+        if let Some(spans) = frames.get_mut(&nal_index) {
+            spans.push(span);
+        } else {
+            frames.insert(nal_index, vec![span]);
+        }
+    }
+
+    pub fn record_instant(
+        &self,
+        nal_index: u64,
+        stage: FrameStage,
+        slice_idx: Option<u8>,
+        time: Instant,
+    ) {
+        self.record(nal_index, stage, slice_idx, time, time)
+    }
+
+    // Removes the spans of a completed frame
+    pub fn take(&self, nal_index: u64) -> Option<Vec<FrameTraceSpan>> {
+        self.frames
+            .lock()
+            .remove(&nal_index)
+            .map(|(_, spans)| spans)
+    }
+
+    pub fn take_expired(&self) -> Vec<(u64, Vec<FrameTraceSpan>)> {
+        self.frames.lock().remove_expired()
+    }
+}
+
+fn stage_name(stage: FrameStage) -> &'static str {
+    match stage {
+        FrameStage::Present => "Present",
+        FrameStage::Composition => "Composition",
+        FrameStage::Encode => "Encode",
+        FrameStage::Send => "Send",
+        FrameStage::Receive => "Receive",
+        FrameStage::Decode => "Decode",
+        FrameStage::Display => "Display",
+    }
+}
+
+// Each stage of each slice has its own track, so that consecutive frames never overlap on the same
+// track, which the viewers cannot draw. Tracks are sorted in pipeline order.
+fn track_id(span: &FrameTraceSpan) -> u32 {
+    (span.stage as u32) * 0x100 + span.slice_idx.unwrap_or(0) as u32
+}
+
+fn track_name(span: &FrameTraceSpan) -> String {
+    match span.slice_idx {
+        Some(idx) => format!("{} slice {}", stage_name(span.stage), idx),
+        None => stage_name(span.stage).into(),
+    }
+}
+
+fn first_start_ns(spans: &[FrameTraceSpan], stage: FrameStage) -> Option<u64> {
+    spans
+        .iter()
+        .filter(|span| span.stage == stage)
+        .map(|span| span.start_ns)
+        .min()
+}
+
+// Writes the traced frames as Chrome trace events, in the JSON array format. The file can be opened
+// in chrome://tracing or in the Perfetto UI (https://ui.perfetto.dev). The closing bracket is
+// optional in this format, so the file is readable even if the server did not shut down cleanly.
+// The client spans are moved to the server timebase. The peers have no synchronized clocks, so the
+// offset is estimated from the frame that went from send to receive in the shortest time, assumed
+// to take half the round trip time.
+pub struct FrameTraceWriter<W: Write> {
+    writer: W,
+    one_way_latency_ns: i64,
+
+    // Minimums of the client receive time minus the server send time, in the previous and in the
+    // current window
+    apparent_offset_windows_ns: [Option<i64>; 2],
+    window_frame_count: u32,
+
+    named_tracks: HashSet<(u32, u32)>,
+    remaining_frame_count: u32,
+    event_count: u64,
+}
+
+impl FrameTraceWriter<BufWriter<File>> {
+    pub fn create(path: &Path, max_frame_count: u32, rtt: Option<Duration>) -> StrResult<Self> {
+        let file = trace_err!(File::create(path))?;
+        Self::new(BufWriter::new(file), max_frame_count, rtt)
+    }
+}
+
+impl<W: Write> FrameTraceWriter<W> {
+    pub fn new(mut writer: W, max_frame_count: u32, rtt: Option<Duration>) -> StrResult<Self> {
+        trace_err!(writer.write_all(b"["))?;
+
+        let mut trace_writer = Self {
+            writer,
+            one_way_latency_ns: rtt.map(|rtt| rtt.as_nanos() as i64 / 2).unwrap_or(0),
+            apparent_offset_windows_ns: [None, None],
+            window_frame_count: 0,
+            named_tracks: HashSet::new(),
+            remaining_frame_count: max_frame_count,
+            event_count: 0,
+        };
+
+        for (pid, name) in &[
+            (SERVER_PID, "BridgeVR server"),
+            (CLIENT_PID, "BridgeVR client"),
+        ] {
+            trace_writer.write_event(json!({
+                "name": "process_name",
+                "ph": "M",
+                "pid": pid,
+                "args": { "name": name },
+            }))?;
+            trace_writer.write_event(json!({
+                "name": "process_sort_index",
+                "ph": "M",
+                "pid": pid,
+                "args": { "sort_index": pid },
+            }))?;
+        }
+
+        Ok(trace_writer)
+    }
+
+    fn write_event(&mut self, event: Value) -> StrResult {
+        let separator: &[u8] = if self.event_count == 0 { b"\n" } else { b",\n" };
+        trace_err!(self.writer.write_all(separator))?;
+        trace_err!(serde_json::to_writer(&mut self.writer, &event))?;
+        self.event_count += 1;
+
+        Ok(())
+    }
+
+    fn write_span(
+        &mut self,
+        pid: u32,
+        nal_index: u64,
+        span: &FrameTraceSpan,
+        offset_ns: i64,
+    ) -> StrResult {
+        let tid = track_id(span);
+        if self.named_tracks.insert((pid, tid)) {
+            self.write_event(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": pid,
+                "tid": tid,
+                "args": { "name": track_name(span) },
+            }))?;
+            self.write_event(json!({
+                "name": "thread_sort_index",
+                "ph": "M",
+                "pid": pid,
+                "tid": tid,
+                "args": { "sort_index": tid },
+            }))?;
+        }
+
+        // Timestamps are in microseconds
+        let start_us = (span.start_ns as i64 - offset_ns) as f64 / 1000.;
+        let mut event = json!({
+            "name": stage_name(span.stage),
+            "cat": "frame",
+            "ts": start_us,
+            "pid": pid,
+            "tid": tid,
+            "args": { "nal_index": nal_index },
+        });
+        if span.start_ns == span.end_ns {
+            event["ph"] = "i".into();
+            event["s"] = "t".into();
+        } else {
+            event["ph"] = "X".into();
+            event["dur"] = ((span.end_ns - span.start_ns) as f64 / 1000.).into();
+        }
+
+        self.write_event(event)
+    }
+
+    fn update_clock_offset(
+        &mut self,
+        server_spans: &[FrameTraceSpan],
+        client_spans: &[FrameTraceSpan],
+    ) {
+        if let (Some(send_ns), Some(receive_ns)) = (
+            first_start_ns(server_spans, FrameStage::Send),
+            first_start_ns(client_spans, FrameStage::Receive),
+        ) {
+            let apparent_offset_ns = receive_ns as i64 - send_ns as i64;
+            let current_window = &mut self.apparent_offset_windows_ns[1];
+            *current_window = Some(current_window.map_or(apparent_offset_ns, |offset_ns| {
+                offset_ns.min(apparent_offset_ns)
+            }));
+
+            self.window_frame_count += 1;
+            if self.window_frame_count >= CLOCK_OFFSET_WINDOW_FRAME_COUNT {
+                self.apparent_offset_windows_ns = [self.apparent_offset_windows_ns[1], None];
+                self.window_frame_count = 0;
+            }
+        }
+    }
+
+    // Client time minus server time
+    pub fn clock_offset_ns(&self) -> Option<i64> {
+        self.apparent_offset_windows_ns
+            .iter()
+            .flatten()
+            .min()
+            .map(|offset_ns| offset_ns - self.one_way_latency_ns)
+    }
+
+    // Client spans are dropped if the clock offset is still unknown
+    pub fn write_frame(
+        &mut self,
+        nal_index: u64,
+        server_spans: &[FrameTraceSpan],
+        client_spans: &[FrameTraceSpan],
+    ) -> StrResult {
+        if self.remaining_frame_count == 0 {
+            return Ok(());
+        }
+        self.remaining_frame_count -= 1;
+
+        self.update_clock_offset(server_spans, client_spans);
+
+        for span in server_spans {
+            self.write_span(SERVER_PID, nal_index, span, 0)?;
+        }
+        if let Some(offset_ns) = self.clock_offset_ns() {
+            for span in client_spans {
+                self.write_span(CLIENT_PID, nal_index, span, offset_ns)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining_frame_count == 0
+    }
+}
+
+impl<W: Write> Drop for FrameTraceWriter<W> {
+    fn drop(&mut self) {
+        self.writer.write_all(b"\n]\n").ok();
+        self.writer.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const FRAME_COUNT: u64 = 600;
+    const MAX_TRACED_FRAME_COUNT: u32 = 500;
+    const FRAME_INTERVAL: Duration = Duration::from_micros(11_111);
+    const SLICE_COUNT: u8 = 2;
+
+    // Offset of the client clock from the server clock. Unknown to the writer.
+    const CLIENT_CLOCK_OFFSET: Duration = Duration::from_secs(1000);
+
+    // The network delay is uniform in [latency, latency + jitter). The round trip is symmetric.
+    const NETWORK_LATENCY: Duration = Duration::from_millis(2);
+    const NETWORK_JITTER_US: u32 = 3000;
+
+    // Frames before this one are placed with a clock offset estimated from few samples
+    const SETTLING_FRAME_COUNT: u64 = 100;
+    const MAX_ALIGNMENT_ERROR_US: f64 = 500.;
+
+    const SERVER_SPAN_COUNT: usize = 2 + 2 * SLICE_COUNT as usize;
+    const CLIENT_SPAN_COUNT: usize = 1 + 2 * SLICE_COUNT as usize;
+
+    // xorshift32
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    fn ms(value: f32) -> Duration {
+        Duration::from_secs_f32(value / 1000.)
+    }
+
+    // Records a simulated video pipeline on a server and on a client with a clock ahead of the
+    // server one. Returns the trace and the receive start of each slice in the server timebase, in
+    // microseconds.
+    fn simulate_trace() -> (Vec<Value>, HashMap<(u64, u8), f64>) {
+        let server_timebase = Timebase::new();
+        let server_recorder = FrameTraceRecorder::new(server_timebase);
+        let client_recorder = FrameTraceRecorder::new(Timebase::new());
+
+        let epoch = Instant::now();
+        let mut random_state = 0x1234_5678;
+        let mut true_receive_us = HashMap::new();
+
+        let mut trace = vec![];
+        {
+            let mut writer = FrameTraceWriter::new(
+                &mut trace,
+                MAX_TRACED_FRAME_COUNT,
+                Some(NETWORK_LATENCY * 2),
+            )
+            .unwrap();
+
+            for nal_index in 0..FRAME_COUNT {
+                let present = epoch + FRAME_INTERVAL * nal_index as u32;
+                server_recorder.record_instant(nal_index, FrameStage::Present, None, present);
+                server_recorder.record(
+                    nal_index,
+                    FrameStage::Composition,
+                    None,
+                    present + ms(0.5),
+                    present + ms(2.),
+                );
+
+                let mut decode_end = present;
+                for slice_idx in 0..SLICE_COUNT {
+                    let encode_start = present + ms(2. + slice_idx as f32 * 0.5);
+                    let encode_end = encode_start + ms(3.);
+                    let send_end = encode_end + ms(1.);
+                    server_recorder.record(
+                        nal_index,
+                        FrameStage::Encode,
+                        Some(slice_idx),
+                        encode_start,
+                        encode_end,
+                    );
+                    server_recorder.record(
+                        nal_index,
+                        FrameStage::Send,
+                        Some(slice_idx),
+                        encode_end,
+                        send_end,
+                    );
+
+                    let delay = NETWORK_LATENCY
+                        + Duration::from_micros(
+                            (next_random(&mut random_state) % NETWORK_JITTER_US) as u64,
+                        );
+                    let receive_start = encode_end + delay;
+                    let receive_end = send_end + delay;
+                    true_receive_us.insert(
+                        (nal_index, slice_idx),
+                        server_timebase.timestamp_ns(receive_start) as f64 / 1000.,
+                    );
+
+                    let slice_decode_end = receive_end + ms(2.);
+                    client_recorder.record(
+                        nal_index,
+                        FrameStage::Receive,
+                        Some(slice_idx),
+                        receive_start + CLIENT_CLOCK_OFFSET,
+                        receive_end + CLIENT_CLOCK_OFFSET,
+                    );
+                    client_recorder.record(
+                        nal_index,
+                        FrameStage::Decode,
+                        Some(slice_idx),
+                        receive_end + CLIENT_CLOCK_OFFSET,
+                        slice_decode_end + CLIENT_CLOCK_OFFSET,
+                    );
+                    decode_end = decode_end.max(slice_decode_end);
+                }
+                client_recorder.record_instant(
+                    nal_index,
+                    FrameStage::Display,
+                    None,
+                    decode_end + ms(5.) + CLIENT_CLOCK_OFFSET,
+                );
+
+                let client_spans = client_recorder.take(nal_index).unwrap();
+                let server_spans = server_recorder.take(nal_index).unwrap();
+                writer
+                    .write_frame(nal_index, &server_spans, &client_spans)
+                    .unwrap();
+            }
+        }
+
+        (serde_json::from_slice(&trace).unwrap(), true_receive_us)
+    }
+
+    #[test]
+    fn trace_shape() {
+        let (events, _) = simulate_trace();
+
+        let span_events: Vec<_> = events.iter().filter(|event| event["ph"] != "M").collect();
+        assert_eq!(
+            span_events.len(),
+            MAX_TRACED_FRAME_COUNT as usize * (SERVER_SPAN_COUNT + CLIENT_SPAN_COUNT)
+        );
+
+        for event in &span_events {
+            assert_eq!(event["cat"], "frame");
+            assert!(event["ts"].is_f64());
+            assert!(event["args"]["nal_index"].is_u64());
+            match event["ph"].as_str().unwrap() {
+                "X" => assert!(event["dur"].as_f64().unwrap() > 0.),
+                "i" => assert_eq!(event["s"], "t"),
+                phase => panic!("Unexpected phase {}", phase),
+            }
+        }
+
+        // Every track is named once
+        let mut span_tracks: Vec<_> = span_events
+            .iter()
+            .map(|event| {
+                (
+                    event["pid"].as_u64().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        span_tracks.sort_unstable();
+        span_tracks.dedup();
+        let mut named_tracks: Vec<_> = events
+            .iter()
+            .filter(|event| event["name"] == "thread_name")
+            .map(|event| {
+                (
+                    event["pid"].as_u64().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        named_tracks.sort_unstable();
+        assert_eq!(named_tracks, span_tracks);
+
+        let process_names: Vec<_> = events
+            .iter()
+            .filter(|event| event["name"] == "process_name")
+            .map(|event| event["pid"].as_u64().unwrap())
+            .collect();
+        assert_eq!(process_names, vec![SERVER_PID as u64, CLIENT_PID as u64]);
+    }
+
+    #[test]
+    fn client_clock_alignment() {
+        let (events, true_receive_us) = simulate_trace();
+
+        let mut receive_event_count = 0;
+        for event in &events {
+            if event["pid"] == CLIENT_PID && event["name"] == "Receive" {
+                let nal_index = event["args"]["nal_index"].as_u64().unwrap();
+                let slice_idx = (event["tid"].as_u64().unwrap() & 0xff) as u8;
+                let error_us = (event["ts"].as_f64().unwrap()
+                    - true_receive_us[&(nal_index, slice_idx)])
+                    .abs();
+                if nal_index >= SETTLING_FRAME_COUNT {
+                    assert!(error_us < MAX_ALIGNMENT_ERROR_US, "{}", error_us);
+                }
+                receive_event_count += 1;
+            }
+        }
+        assert_eq!(
+            receive_event_count,
+            MAX_TRACED_FRAME_COUNT as usize * SLICE_COUNT as usize
+        );
+    }
+
+    #[test]
+    fn client_spans_need_clock_offset() {
+        let recorder = FrameTraceRecorder::new(Timebase::new());
+        let now = Instant::now();
+        recorder.record(0, FrameStage::Decode, Some(0), now, now + ms(1.));
+
+        // No send and receive pair, so the offset is unknown
+        let mut trace = vec![];
+        {
+            let mut writer = FrameTraceWriter::new(&mut trace, 10, None).unwrap();
+            writer
+                .write_frame(0, &[], &recorder.take(0).unwrap())
+                .unwrap();
+            assert!(writer.clock_offset_ns().is_none());
+        }
+
+        let events: Vec<Value> = serde_json::from_slice(&trace).unwrap();
+        assert!(events.iter().all(|event| event["ph"] == "M"));
+    }
+}
@@ -0,0 +1,665 @@
+// Local channel between the driver, that runs inside the SteamVR process, and the GUI. It uses a
+// Unix domain socket on Linux and a named pipe on Windows. The driver publishes DriverEvents to all
+// the connected GUIs and receives DriverCommands from them.
+
+use crate::{
+    data::*,
+    thread_loop::{self, ThreadLoop},
+    *,
+};
+use log::*;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    io::{self, Read, Write},
+    sync::{mpsc::*, Arc},
+    thread,
+    time::Duration,
+};
+
+const TRACE_CONTEXT: &str = "IPC";
+
+pub const DRIVER_IPC_NAME: &str = "bridgevr_driver";
+
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// A peer that does not read its messages is disconnected instead of buffering without bound
+const MAX_QUEUED_MESSAGE_COUNT: usize = 1024;
+
+// Latency of the received messages. Also the interval used to poll for new GUI connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+type IpcStream = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+type IpcStream = std::fs::File;
+
+// The socket is created in the runtime directory of the user, that only the user can access. If it
+// is not set, a private directory is created in the temporary directory, that is shared by all the
+// users.
+#[cfg(unix)]
+fn socket_path(name: &str) -> io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let maybe_runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty());
+    let dir = if let Some(dir) = maybe_runtime_dir {
+        dir.into()
+    } else {
+        let uid = unsafe { libc::getuid() };
+        let dir = std::env::temp_dir().join(format!("bridgevr-{}", uid));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+
+        // The directory could have been created by another user
+        let metadata = std::fs::symlink_metadata(&dir)?;
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not private", dir.display()),
+            ));
+        }
+
+        dir
+    };
+
+    Ok(dir.join(format!("{}.sock", name)))
+}
+
+#[cfg(windows)]
+fn pipe_name(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+#[cfg(unix)]
+fn connect(name: &str) -> io::Result<IpcStream> {
+    IpcStream::connect(socket_path(name)?)
+}
+
+#[cfg(windows)]
+fn connect(name: &str) -> io::Result<IpcStream> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pipe_name(name))
+}
+
+#[cfg(unix)]
+fn prepare_stream(stream: &IpcStream) -> io::Result<()> {
+    // The minimum timeout, so a read returns right away if there is no data
+    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))
+}
+
+// Writes on pipes cannot time out, the size of the queue is the only bound
+#[cfg(windows)]
+fn prepare_stream(_: &IpcStream) -> io::Result<()> {
+    Ok(())
+}
+
+// Appends the bytes that can be read without blocking. Returns false if the peer disconnected.
+#[cfg(unix)]
+fn read_available(stream: &mut IpcStream, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(false),
+            Ok(size) => buffer.extend_from_slice(&chunk[..size]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(true),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Synchronous pipes serialize the reads and the writes on the same handle, so a pending read would
+// block the writes. The pipe is peeked instead.
+#[cfg(windows)]
+fn read_available(stream: &mut IpcStream, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    use std::{os::windows::io::AsRawHandle, ptr::null_mut};
+    use winapi::{shared::winerror::ERROR_BROKEN_PIPE, um::namedpipeapi::PeekNamedPipe};
+
+    let mut available_size = 0;
+    let res = unsafe {
+        PeekNamedPipe(
+            stream.as_raw_handle() as _,
+            null_mut(),
+            0,
+            null_mut(),
+            &mut available_size,
+            null_mut(),
+        )
+    };
+    if res == 0 {
+        let error = io::Error::last_os_error();
+        return if error.raw_os_error() == Some(ERROR_BROKEN_PIPE as _) {
+            Ok(false)
+        } else {
+            Err(error)
+        };
+    }
+
+    let start = buffer.len();
+    buffer.resize(start + available_size as usize, 0);
+    stream.read_exact(&mut buffer[start..])?;
+
+    Ok(true)
+}
+
+#[cfg(unix)]
+struct IpcListener(std::os::unix::net::UnixListener);
+
+#[cfg(unix)]
+impl IpcListener {
+    fn bind(name: &str) -> io::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The socket file of a driver that crashed is left behind and would make bind() fail
+        let path = socket_path(name)?;
+        if IpcStream::connect(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Another driver is running",
+            ));
+        }
+        std::fs::remove_file(&path).ok();
+
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self(listener))
+    }
+
+    // None if no GUI is waiting
+    fn accept(&mut self) -> io::Result<Option<IpcStream>> {
+        match self.0.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some(stream))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IpcListener {
+    fn drop(&mut self) {
+        if let Ok(address) = self.0.local_addr() {
+            if let Some(path) = address.as_pathname() {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+}
+
+// Security descriptor that allows only the current user to open the pipe. With the default one,
+// every user can read from it.
+#[cfg(windows)]
+struct PipeSecurity(winapi::um::winnt::PSECURITY_DESCRIPTOR);
+
+// The descriptor is never modified after creation
+#[cfg(windows)]
+unsafe impl Send for PipeSecurity {}
+
+#[cfg(windows)]
+impl PipeSecurity {
+    fn current_user() -> io::Result<Self> {
+        use std::{iter::once, ptr::null_mut, slice};
+        use winapi::{
+            shared::sddl::*,
+            um::{
+                handleapi::CloseHandle, processthreadsapi::*, securitybaseapi::GetTokenInformation,
+                winbase::LocalFree, winnt::*,
+            },
+        };
+
+        let mut token = null_mut();
+        if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The first call returns the size of the TOKEN_USER, followed by the SID it points to. The
+        // buffer is aligned as the pointers.
+        let mut size = 0;
+        unsafe { GetTokenInformation(token, TokenUser, null_mut(), 0, &mut size) };
+        let mut buffer = vec![0_u64; size as usize / 8 + 1];
+        let res = unsafe {
+            GetTokenInformation(token, TokenUser, buffer.as_mut_ptr() as _, size, &mut size)
+        };
+        let error = io::Error::last_os_error();
+        unsafe { CloseHandle(token) };
+        if res == 0 {
+            return Err(error);
+        }
+        let sid = unsafe { (*(buffer.as_ptr() as *const TOKEN_USER)).User.Sid };
+
+        let mut sid_string = null_mut();
+        if unsafe { ConvertSidToStringSidW(sid, &mut sid_string) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Protected DACL with a single entry: full access for the user
+        let sddl: Vec<u16> = unsafe {
+            let sid_string_len = (0..).take_while(|&i| *sid_string.add(i) != 0).count();
+            let sddl = "D:P(A;;GA;;;"
+                .encode_utf16()
+                .chain(
+                    slice::from_raw_parts(sid_string, sid_string_len)
+                        .iter()
+                        .cloned(),
+                )
+                .chain(")".encode_utf16())
+                .chain(once(0))
+                .collect();
+            LocalFree(sid_string as _);
+            sddl
+        };
+
+        let mut descriptor = null_mut();
+        if unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1 as _,
+                &mut descriptor,
+                null_mut(),
+            )
+        } == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(descriptor))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winbase::LocalFree(self.0) };
+    }
+}
+
+// Each GUI is connected to a different instance of the pipe. There is always one instance waiting
+// for the next GUI.
+#[cfg(windows)]
+struct IpcListener {
+    name: String,
+    security: PipeSecurity,
+    pending_instance: IpcStream,
+}
+
+#[cfg(windows)]
+impl IpcListener {
+    fn create_instance(name: &str, security: &PipeSecurity, first: bool) -> io::Result<IpcStream> {
+        use std::{
+            ffi::OsStr,
+            iter::once,
+            mem::size_of,
+            os::windows::{ffi::OsStrExt, io::FromRawHandle},
+        };
+        use winapi::um::{
+            handleapi::INVALID_HANDLE_VALUE, minwinbase::SECURITY_ATTRIBUTES, winbase::*,
+        };
+
+        const BUFFER_SIZE: u32 = 64 * 1024;
+
+        let wide_name: Vec<u16> = OsStr::new(&pipe_name(name))
+            .encode_wide()
+            .chain(once(0))
+            .collect();
+        let first_instance_flag = if first {
+            FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            0
+        };
+        let mut security_attributes = SECURITY_ATTRIBUTES {
+            nLength: size_of::<SECURITY_ATTRIBUTES>() as _,
+            lpSecurityDescriptor: security.0,
+            bInheritHandle: 0,
+        };
+        // Non blocking mode makes ConnectNamedPipe() return immediately
+        let handle = unsafe {
+            CreateNamedPipeW(
+                wide_name.as_ptr(),
+                PIPE_ACCESS_DUPLEX | first_instance_flag,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_NOWAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                &mut security_attributes,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(unsafe { IpcStream::from_raw_handle(handle as _) })
+        }
+    }
+
+    fn bind(name: &str) -> io::Result<Self> {
+        let security = PipeSecurity::current_user()?;
+        let pending_instance = Self::create_instance(name, &security, true)?;
+
+        Ok(Self {
+            name: name.into(),
+            security,
+            pending_instance,
+        })
+    }
+
+    fn accept(&mut self) -> io::Result<Option<IpcStream>> {
+        use std::{os::windows::io::AsRawHandle, ptr::null_mut};
+        use winapi::{
+            shared::winerror::*,
+            um::{namedpipeapi::*, winbase::*},
+        };
+
+        let handle = self.pending_instance.as_raw_handle() as _;
+
+        // In non blocking mode the result is in the last error. Success means only that the
+        // instance can be connected again.
+        if unsafe { ConnectNamedPipe(handle, null_mut()) } != 0 {
+            return Ok(None);
+        }
+        match io::Error::last_os_error()
+            .raw_os_error()
+            .map(|code| code as u32)
+        {
+            Some(ERROR_PIPE_CONNECTED) => {
+                let mut mode = PIPE_READMODE_BYTE | PIPE_WAIT;
+                if unsafe { SetNamedPipeHandleState(handle, &mut mode, null_mut(), null_mut()) }
+                    == 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let new_instance = Self::create_instance(&self.name, &self.security, false)?;
+                Ok(Some(std::mem::replace(
+                    &mut self.pending_instance,
+                    new_instance,
+                )))
+            }
+            // The GUI closed the pipe before it was accepted
+            Some(ERROR_NO_DATA) => {
+                unsafe { DisconnectNamedPipe(handle) };
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// Messages are serialized with bincode and prefixed by their size, as a little endian u32
+fn encode_message(message: &impl Serialize) -> StrResult<Arc<Vec<u8>>> {
+    let size = trace_err!(bincode::serialized_size(message))? as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return trace_str!("Message too big: {} bytes", size);
+    }
+
+    let mut buffer = Vec::with_capacity(4 + size);
+    buffer.extend_from_slice(&(size as u32).to_le_bytes());
+    trace_err!(bincode::serialize_into(&mut buffer, message))?;
+
+    Ok(Arc::new(buffer))
+}
+
+// Removes the complete messages from the start of the buffer
+fn decode_messages<T: DeserializeOwned>(buffer: &mut Vec<u8>) -> StrResult<Vec<T>> {
+    let mut messages = vec![];
+    let mut offset = 0;
+    while buffer.len() - offset >= 4 {
+        let mut size_bytes = [0; 4];
+        size_bytes.copy_from_slice(&buffer[offset..offset + 4]);
+        let size = u32::from_le_bytes(size_bytes) as usize;
+        if size > MAX_MESSAGE_SIZE {
+            return trace_str!("Message too big: {} bytes", size);
+        }
+
+        if buffer.len() - offset - 4 < size {
+            break;
+        }
+        let message_bytes = &buffer[offset + 4..offset + 4 + size];
+        messages.push(trace_err!(bincode::deserialize(message_bytes))?);
+        offset += 4 + size;
+    }
+    buffer.drain(..offset);
+
+    Ok(messages)
+}
+
+// Sends the queued messages to the peer and forwards the received ones, alternating on the same
+// thread. It ends when the peer disconnects or when the queue sender is dropped.
+fn spawn_connection_thread<T: DeserializeOwned + Send + 'static>(
+    mut stream: IpcStream,
+    outgoing_receiver: Receiver<Arc<Vec<u8>>>,
+    incoming_sender: Sender<T>,
+) -> StrResult {
+    trace_err!(prepare_stream(&stream))?;
+
+    let mut receive_buffer = vec![];
+    let mut run = move || -> StrResult {
+        loop {
+            match outgoing_receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(message) => trace_err!(stream.write_all(&message))?,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            if !trace_err!(read_available(&mut stream, &mut receive_buffer))? {
+                return Ok(());
+            }
+            for message in decode_messages(&mut receive_buffer)? {
+                if incoming_sender.send(message).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    trace_err!(thread::Builder::new()
+        .name("IPC connection".into())
+        .spawn(move || {
+            run()
+                .map_err(|e| debug!("IPC connection closed: {}", e))
+                .ok();
+        }))?;
+
+    Ok(())
+}
+
+struct PublisherState {
+    gui_senders: Vec<SyncSender<Arc<Vec<u8>>>>,
+
+    // Sent to the GUIs as soon as they connect
+    connection_state_message: Option<Arc<Vec<u8>>>,
+    statistics_message: Option<Arc<Vec<u8>>>,
+}
+
+// Sends events to all the connected GUIs. It never blocks and never logs, so it can be used by the
+// logger itself.
+#[derive(Clone)]
+pub struct IpcPublisher {
+    state: Arc<Mutex<PublisherState>>,
+}
+
+impl IpcPublisher {
+    pub fn publish(&self, event: &DriverEvent) {
+        let message = if let Ok(message) = encode_message(event) {
+            message
+        } else {
+            return;
+        };
+
+        let mut state = self.state.lock();
+        match event {
+            DriverEvent::ConnectionState(_) => {
+                state.connection_state_message = Some(message.clone())
+            }
+            DriverEvent::Statistics(_) => state.statistics_message = Some(message.clone()),
+            DriverEvent::Log { .. } => (),
+        }
+
+        // GUIs that disconnected or that fell behind are dropped
+        state
+            .gui_senders
+            .retain(|sender| sender.try_send(message.clone()).is_ok());
+    }
+}
+
+pub struct IpcServer {
+    publisher: IpcPublisher,
+    command_receiver: Receiver<DriverCommand>,
+    accept_loop: ThreadLoop,
+}
+
+impl IpcServer {
+    pub fn start(name: &str) -> StrResult<Self> {
+        let mut listener = trace_err!(IpcListener::bind(name))?;
+
+        let publisher = IpcPublisher {
+            state: Arc::new(Mutex::new(PublisherState {
+                gui_senders: vec![],
+                connection_state_message: None,
+                statistics_message: None,
+            })),
+        };
+        let (command_sender, command_receiver) = channel();
+
+        let accept_loop = thread_loop::spawn("IPC accept loop", {
+            let publisher = publisher.clone();
+            move || match listener.accept() {
+                Ok(Some(stream)) => {
+                    let (gui_sender, gui_receiver) = sync_channel(MAX_QUEUED_MESSAGE_COUNT);
+                    let res = spawn_connection_thread(stream, gui_receiver, command_sender.clone());
+                    if let Err(e) = res {
+                        warn!("{}", e);
+                        return;
+                    }
+
+                    let mut state = publisher.state.lock();
+                    let initial_messages = state
+                        .connection_state_message
+                        .iter()
+                        .chain(&state.statistics_message);
+                    for message in initial_messages {
+                        gui_sender.try_send(message.clone()).ok();
+                    }
+                    state.gui_senders.push(gui_sender);
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    warn!("IPC listener: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        })?;
+
+        Ok(Self {
+            publisher,
+            command_receiver,
+            accept_loop,
+        })
+    }
+
+    pub fn publisher(&self) -> IpcPublisher {
+        self.publisher.clone()
+    }
+
+    pub fn try_recv_command(&self) -> Option<DriverCommand> {
+        self.command_receiver.try_recv().ok()
+    }
+
+    // Closes the listener. The GUIs are disconnected when the last publisher is dropped.
+    pub fn request_stop(&mut self) {
+        self.accept_loop.request_stop()
+    }
+}
+
+// Used by the GUI
+pub struct IpcClient {
+    command_sender: SyncSender<Arc<Vec<u8>>>,
+    event_receiver: Receiver<DriverEvent>,
+}
+
+impl IpcClient {
+    // Fails if the driver is not running
+    pub fn connect(name: &str) -> StrResult<Self> {
+        let stream = trace_err!(connect(name))?;
+
+        let (command_sender, command_receiver) = sync_channel(MAX_QUEUED_MESSAGE_COUNT);
+        let (event_sender, event_receiver) = channel();
+        spawn_connection_thread(stream, command_receiver, event_sender)?;
+
+        Ok(Self {
+            command_sender,
+            event_receiver,
+        })
+    }
+
+    pub fn send_command(&self, command: DriverCommand) -> StrResult {
+        trace_err!(self.command_sender.try_send(encode_message(&command)?))
+    }
+
+    // Events received since the last call. Fails once the driver disconnected and all its events
+    // have been returned.
+    pub fn try_recv_events(&self) -> StrResult<Vec<DriverEvent>> {
+        let mut events = vec![];
+        loop {
+            match self.event_receiver.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Empty) => return Ok(events),
+                Err(TryRecvError::Disconnected) if events.is_empty() => {
+                    return trace_str!("Driver disconnected")
+                }
+                Err(TryRecvError::Disconnected) => return Ok(events),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let name = "bridgevr_ipc_private_test";
+        let _server = IpcServer::start(name).unwrap();
+
+        let path = socket_path(name).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let name = "bridgevr_ipc_round_trip_test";
+        let server = IpcServer::start(name).unwrap();
+        let client = IpcClient::connect(name).unwrap();
+
+        // The connection is accepted asynchronously
+        let deadline = Instant::now() + TIMEOUT;
+        let mut maybe_command = None;
+        while maybe_command.is_none() && Instant::now() < deadline {
+            client.send_command(DriverCommand::ForceIdr).unwrap();
+            thread::sleep(POLL_INTERVAL);
+            maybe_command = server.try_recv_command();
+        }
+        assert!(matches!(maybe_command, Some(DriverCommand::ForceIdr)));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{data::*, event_timing::*};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+// Deviation of the average latency from the target, in pop periods, above which items are dropped
+// instead of waiting for the producer to slow down
+const DROP_LATENCY_DEVIATION_POPS: u32 = 4;
+
+// Items that waited longer than this are discarded, in case the consumer stopped popping
+const MAX_ITEM_WAIT: Duration = Duration::from_secs(1);
+
+// Counters since the creation of the buffer
+#[derive(Default, Clone, Debug)]
+pub struct JitterBufferStatistics {
+    pub pushed_count: u64,
+    pub popped_count: u64,
+
+    // Items pushed after their id was already popped or skipped. They are discarded.
+    pub late_count: u64,
+
+    pub duplicate_count: u64,
+
+    // Ids skipped because their item did not arrive in time
+    pub lost_count: u64,
+
+    // Items discarded to bring the latency back to the target or because they waited too long
+    pub dropped_count: u64,
+
+    pub underrun_count: u64,
+}
+
+struct BufferedItem<T> {
+    arrival_time: Instant,
+    item: T,
+}
+
+// Reorders items by id and releases them to a consumer that pops at a regular rate, like a device
+// callback or the display vsync. The depth of the buffer is decided by `EventTiming`: every
+// underrun makes it grow by one item, and when the average latency is too far above the target
+// items are dropped. Ids must be consecutive, gaps are treated as lost items.
+// All methods take the current time as argument, so the buffer can be driven by a simulated clock.
+pub struct JitterBuffer<T> {
+    event_timing: EventTiming,
+    pop_interval: Duration,
+    items: BTreeMap<u64, BufferedItem<T>>,
+
+    // Not set until the first item is popped
+    next_id: Option<u64>,
+
+    statistics: JitterBufferStatistics,
+}
+
+impl<T> JitterBuffer<T> {
+    pub fn new(latency_desc: LatencyDesc, pops_per_sec: f32) -> Self {
+        Self {
+            event_timing: EventTiming::new(latency_desc, pops_per_sec),
+            pop_interval: Duration::from_secs_f32(1. / pops_per_sec),
+            items: BTreeMap::new(),
+            next_id: None,
+            statistics: JitterBufferStatistics::default(),
+        }
+    }
+
+    pub fn reset_pops_per_sec(&mut self, pops_per_sec: f32) {
+        self.event_timing.reset_notifs_per_sec(pops_per_sec);
+        self.pop_interval = Duration::from_secs_f32(1. / pops_per_sec);
+    }
+
+    // Discards the buffered items and restarts from the next pushed id. The latency statistics
+    // are kept.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.next_id = None;
+    }
+
+    // Returns false if the item is discarded because it is late or a duplicate.
+    pub fn push(&mut self, id: u64, item: T, arrival_time: Instant) -> bool {
+        self.statistics.pushed_count += 1;
+
+        if matches!(self.next_id, Some(next_id) if id < next_id) {
+            self.statistics.late_count += 1;
+            return false;
+        }
+        if self.items.contains_key(&id) {
+            self.statistics.duplicate_count += 1;
+            return false;
+        }
+
+        let item_count = self.items.len();
+        self.items.retain(|_, buffered_item| {
+            arrival_time.saturating_duration_since(buffered_item.arrival_time) <= MAX_ITEM_WAIT
+        });
+        self.statistics.dropped_count += (item_count - self.items.len()) as u64;
+
+        self.items.insert(id, BufferedItem { arrival_time, item });
+
+        true
+    }
+
+    // Returns the item with the next id, or None if it underruns. If the next item is missing but
+    // the following ones are available, it is waited for up to the target latency, since it could
+    // have been reordered.
+    pub fn pop(&mut self, now: Instant) -> Option<(u64, T)> {
+        loop {
+            let (id, arrival_time) = match self.items.iter().next() {
+                Some((&id, buffered_item)) => (id, buffered_item.arrival_time),
+                None => {
+                    // Before the first item there is no stream to underrun
+                    if self.next_id.is_some() {
+                        self.notify_underrun();
+                    }
+                    return None;
+                }
+            };
+
+            if let Some(next_id) = self.next_id {
+                if id > next_id {
+                    if now.saturating_duration_since(arrival_time)
+                        < self.event_timing.target_latency()
+                    {
+                        self.notify_underrun();
+                        return None;
+                    }
+                    self.statistics.lost_count += id - next_id;
+                }
+            }
+
+            // unwrap never fails because the id has just been found
+            let BufferedItem { arrival_time, item } = self.items.remove(&id).unwrap();
+            self.next_id = Some(id.wrapping_add(1));
+
+            self.event_timing
+                .notify_latency(now.saturating_duration_since(arrival_time));
+
+            if let Some(target_latency_deviation) = self
+                .event_timing
+                .average_latency()
+                .checked_sub(self.event_timing.target_latency())
+            {
+                // Dropping is pointless if it would cause an underrun
+                if target_latency_deviation > self.pop_interval * DROP_LATENCY_DEVIATION_POPS
+                    && self.items.contains_key(&id.wrapping_add(1))
+                {
+                    self.statistics.dropped_count += 1;
+
+                    // since EventTiming has "momentum", add a fake notify to normalize its state
+                    // and to avoid dropping items until underrun.
+                    self.event_timing
+                        .notify_latency(self.event_timing.target_latency());
+
+                    continue;
+                }
+            }
+
+            self.statistics.popped_count += 1;
+            return Some((id, item));
+        }
+    }
+
+    fn notify_underrun(&mut self) {
+        self.statistics.underrun_count += 1;
+
+        // fake notify to account for no items found
+        self.event_timing.notify_latency(Duration::from_secs(0));
+    }
+
+    pub fn target_latency(&self) -> Duration {
+        self.event_timing.target_latency()
+    }
+
+    pub fn average_latency(&self) -> Duration {
+        self.event_timing.average_latency()
+    }
+
+    pub fn latency_statistics(&self) -> LatencyStatistics {
+        self.event_timing.latency_statistics()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn statistics(&self) -> JitterBufferStatistics {
+        self.statistics.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same rate for the producer and the consumer, like video frames and the display vsync
+    const RATE: f64 = 100.;
+
+    // Network jitter is uniformly distributed between zero and this value
+    const MAX_JITTER_S: f64 = 0.008;
+
+    const SIMULATION_DURATION_S: f64 = 60.;
+    const ITEM_COUNT: u64 = (SIMULATION_DURATION_S * RATE) as u64;
+
+    // The underruns are counted only after the buffer had time to grow to the target depth
+    const SETTLING_TIME_S: f64 = 20.;
+    const MAX_UNDERRUNS_AFTER_SETTLING: u64 = 10;
+
+    // xorshift32, uniform in [0, 1)
+    fn next_random(state: &mut u32) -> f64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state as f64 / (u32::MAX as f64 + 1.)
+    }
+
+    fn new_buffer() -> JitterBuffer<u64> {
+        JitterBuffer::new(
+            LatencyDesc {
+                default_ms: 10,
+                history_mean_lifetime_s: 5,
+                mode: LatencyMode::Automatic {
+                    expected_misses_per_hour: 60,
+                    estimator: LatencyEstimator::Gaussian,
+                },
+            },
+            RATE as f32,
+        )
+    }
+
+    // Describes how the item with a given index reaches the buffer
+    #[derive(Clone, Copy)]
+    enum Transmission {
+        // Arrives after the given delay from its send time
+        Delayed(f64),
+        Lost,
+        Duplicated(f64),
+    }
+
+    struct SimulationResult {
+        popped_ids: Vec<u64>,
+        underruns_after_settling: u64,
+        final_latency_s: f64,
+        final_item_count: usize,
+        statistics: JitterBufferStatistics,
+    }
+
+    impl SimulationResult {
+        fn assert_in_order(&self) {
+            assert!(self.popped_ids.windows(2).all(|ids| ids[0] < ids[1]));
+        }
+    }
+
+    // Items are sent at `RATE` with increasing ids, the consumer pops with the same rate and a
+    // fixed phase offset. The clock is simulated, so the results are deterministic.
+    fn simulate(mut transmission: impl FnMut(u64) -> Transmission) -> SimulationResult {
+        let epoch = Instant::now();
+        let time = |time_s: f64| epoch + Duration::from_secs_f64(time_s);
+
+        let mut buffer = new_buffer();
+
+        let mut arrivals = vec![];
+        for id in 0..ITEM_COUNT {
+            let send_time_s = id as f64 / RATE;
+            match transmission(id) {
+                Transmission::Delayed(delay_s) => arrivals.push((send_time_s + delay_s, id)),
+                Transmission::Lost => (),
+                Transmission::Duplicated(delay_s) => {
+                    arrivals.push((send_time_s + delay_s, id));
+                    arrivals.push((send_time_s + delay_s + 0.001, id));
+                }
+            }
+        }
+        arrivals.sort_by(|(time1, _), (time2, _)| time1.partial_cmp(time2).unwrap());
+
+        let mut popped_ids = vec![];
+        let mut underruns_before_settling = 0;
+        let mut arrivals_iter = arrivals.into_iter().peekable();
+        for pop_idx in 0..ITEM_COUNT {
+            let pop_time_s = (pop_idx as f64 + 0.5) / RATE;
+            while let Some(&(arrival_time_s, id)) = arrivals_iter.peek() {
+                if arrival_time_s > pop_time_s {
+                    break;
+                }
+                buffer.push(id, id, time(arrival_time_s));
+                arrivals_iter.next();
+            }
+
+            if let Some((id, item)) = buffer.pop(time(pop_time_s)) {
+                assert_eq!(id, item);
+                popped_ids.push(id);
+            }
+
+            if pop_time_s < SETTLING_TIME_S {
+                underruns_before_settling = buffer.statistics().underrun_count;
+            }
+        }
+
+        let statistics = buffer.statistics();
+        SimulationResult {
+            popped_ids,
+            underruns_after_settling: statistics.underrun_count - underruns_before_settling,
+            final_latency_s: buffer.average_latency().as_secs_f64(),
+            final_item_count: buffer.len(),
+            statistics,
+        }
+    }
+
+    #[test]
+    fn jitter() {
+        let mut random_state = 0x1234_5678;
+        let result =
+            simulate(|_| Transmission::Delayed(next_random(&mut random_state) * MAX_JITTER_S));
+
+        result.assert_in_order();
+        assert!(result.underruns_after_settling <= MAX_UNDERRUNS_AFTER_SETTLING);
+        assert!(result.final_latency_s < MAX_JITTER_S + 2. / RATE);
+        assert_eq!(result.statistics.lost_count, 0);
+        assert_eq!(result.statistics.late_count, 0);
+    }
+
+    #[test]
+    fn reordering() {
+        // Every 10th item arrives after the next one
+        let result = simulate(|id| {
+            if id % 10 == 5 {
+                Transmission::Delayed(0.015)
+            } else {
+                Transmission::Delayed(0.002)
+            }
+        });
+
+        result.assert_in_order();
+
+        // No item is skipped after settling
+        assert!(result.popped_ids[(SETTLING_TIME_S * RATE) as usize..]
+            .windows(2)
+            .all(|ids| ids[1] == ids[0] + 1));
+    }
+
+    #[test]
+    fn loss() {
+        let result = simulate(|id| {
+            if id % 50 == 0 && id > 0 {
+                Transmission::Lost
+            } else {
+                Transmission::Delayed(0.002)
+            }
+        });
+
+        result.assert_in_order();
+        assert_eq!(result.statistics.lost_count, ITEM_COUNT / 50 - 1);
+        assert!(result.popped_ids.iter().all(|id| id % 50 != 0 || *id == 0));
+    }
+
+    #[test]
+    fn late_items() {
+        // Arrives long after the following items, when its id has already been skipped
+        let result = simulate(|id| {
+            if id % 100 == 50 {
+                Transmission::Delayed(0.2)
+            } else {
+                Transmission::Delayed(0.002)
+            }
+        });
+
+        result.assert_in_order();
+        assert_eq!(result.statistics.late_count, ITEM_COUNT / 100);
+    }
+
+    #[test]
+    fn duplicates() {
+        let result = simulate(|id| {
+            if id % 10 == 0 {
+                Transmission::Duplicated(0.002)
+            } else {
+                Transmission::Delayed(0.002)
+            }
+        });
+
+        result.assert_in_order();
+        assert_eq!(result.statistics.duplicate_count, ITEM_COUNT / 10);
+    }
+
+    #[test]
+    fn stall() {
+        // The network stalls for 300ms, then all the delayed items arrive together. The buffer
+        // must drop the excess instead of keeping the added latency. The stall inflates the
+        // latency variance, so the excess is dropped gradually.
+        let stall_begin_s = 20.;
+        let stall_end_s = 20.3;
+        let stall_item_count = ((stall_end_s - stall_begin_s) * RATE) as usize;
+        let result = simulate(|id| {
+            let send_time_s = id as f64 / RATE;
+            if send_time_s >= stall_begin_s && send_time_s < stall_end_s {
+                Transmission::Delayed(stall_end_s - send_time_s)
+            } else {
+                Transmission::Delayed(0.002)
+            }
+        });
+
+        result.assert_in_order();
+        assert!(result.statistics.dropped_count > 0);
+        assert!(result.final_item_count < stall_item_count / 2);
+    }
+
+    #[test]
+    fn ids_wrap_around() {
+        let now = Instant::now();
+        let mut buffer = new_buffer();
+
+        buffer.push(u64::MAX, 1, now);
+        assert_eq!(buffer.pop(now), Some((u64::MAX, 1)));
+
+        buffer.push(0, 2, now);
+        assert_eq!(buffer.pop(now), Some((0, 2)));
+        assert_eq!(buffer.statistics().lost_count, 0);
+    }
+}
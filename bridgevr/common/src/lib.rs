@@ -3,12 +3,25 @@ pub mod logging;
 pub use logging::StrResult;
 
 pub mod audio;
+pub mod audio_codec;
+pub mod audio_processing;
+pub mod av_sync;
 pub mod data;
+pub mod drift_controller;
 pub mod event_timing;
 pub mod ffr;
 pub mod frame_slices;
+pub mod frame_trace;
 pub mod graphics;
 pub mod input_paths;
+pub mod ipc;
+pub mod jitter_buffer;
+pub mod link_probe;
+pub mod metrics;
+pub mod path_mtu;
+pub mod resampler;
+pub mod send_scheduler;
 pub mod sockets;
+pub mod tcp_tunnel;
 pub mod thread_loop;
 pub mod timeout_map;
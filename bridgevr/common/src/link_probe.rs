@@ -0,0 +1,213 @@
+use crate::{data::*, sockets::*, *};
+use log::*;
+use std::time::*;
+
+const TRACE_CONTEXT: &str = "Link probe";
+
+const START_TIMEOUT: Duration = Duration::from_secs(3);
+const READY_INTERVAL: Duration = Duration::from_millis(20);
+const PING_PHASE_DURATION: Duration = Duration::from_millis(500);
+const PING_INTERVAL: Duration = Duration::from_millis(20);
+const REPORT_PHASE_DURATION: Duration = Duration::from_millis(500);
+const REPORT_INTERVAL: Duration = Duration::from_millis(50);
+const POLL_TIMEOUT: Duration = Duration::from_micros(100);
+
+// Space reserved for the bincode encoding of `ProbePacket::Data` except the padding
+const DATA_PACKET_OVERHEAD: usize = 32;
+
+// Used if the fragment size set by the user is too small
+const MIN_PADDING_SIZE: usize = 32;
+
+#[derive(Default)]
+struct ReceiveStats {
+    received_count: u32,
+    max_sequence: Option<u32>,
+    received_bytes: u64,
+    first_arrival: Option<Instant>,
+    last_arrival: Option<Instant>,
+}
+
+impl ReceiveStats {
+    fn expected_count(&self) -> u32 {
+        self.max_sequence.map(|seq| seq + 1).unwrap_or(0)
+    }
+
+    fn duration(&self) -> Duration {
+        match (self.first_arrival, self.last_arrival) {
+            (Some(first), Some(last)) => last - first,
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+fn throughput_mbps(bytes: u64, duration: Duration) -> f32 {
+    if duration.as_secs_f32() > 0_f32 {
+        bytes as f32 * 8_f32 / duration.as_secs_f32() / 1_000_000_f32
+    } else {
+        0_f32
+    }
+}
+
+fn loss(received_count: u32, expected_count: u32) -> f32 {
+    if expected_count > 0 {
+        1_f32 - received_count as f32 / expected_count as f32
+    } else {
+        1_f32
+    }
+}
+
+impl ConnectionManager {
+    // Run a short RTT, throughput and loss test over the stream sockets. Both peers must call this
+    // right after the handshake; the test is symmetric and lasts about
+    // `desc.bandwidth_test_duration_ms` + 1 second, after both peers are ready.
+    pub fn probe_link(&mut self, desc: &LinkProbeDesc) -> StrResult<LinkQualityDesc> {
+        let mut enqueuer = self.register_enqueuer(&StreamDesc::probe());
+        let mut dequeuer = self.register_dequeuer(&StreamDesc::probe());
+
+        // Packets sent before the peer registers its dequeuer are dropped and would be counted as
+        // lost. Any packet from the peer means that it is ready: it stops sending `Ready` once it
+        // starts, but then it sends pings.
+        let start_deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            if Instant::now() >= start_deadline {
+                return trace_str!("Peer not ready");
+            }
+
+            enqueuer.enqueue(&ProbePacket::Ready)?;
+
+            if let Ok(packet) = dequeuer.dequeue(READY_INTERVAL) {
+                match packet.get() {
+                    Ok(ProbePacket::Ping { id }) => {
+                        enqueuer.enqueue(&ProbePacket::Pong { id })?;
+                        break;
+                    }
+                    Ok(_) => break,
+                    Err(e) => debug!("{}", e),
+                }
+            }
+        }
+
+        let padding_size = (self.socket_params().fragment_size as usize)
+            .saturating_sub(DATA_PACKET_OVERHEAD)
+            .max(MIN_PADDING_SIZE);
+        let padding = vec![0; padding_size];
+        let bandwidth_test_duration = Duration::from_millis(desc.bandwidth_test_duration_ms as _);
+        let bits_per_sec = desc.bandwidth_test_bitrate_mbps as f32 * 1_000_000_f32;
+
+        let begin_time = Instant::now();
+        let bandwidth_test_begin_time = begin_time + PING_PHASE_DURATION;
+        let report_begin_time = bandwidth_test_begin_time + bandwidth_test_duration;
+        let end_time = report_begin_time + REPORT_PHASE_DURATION;
+
+        let mut ping_send_times = vec![];
+        let mut rtts_s = vec![];
+        let mut next_ping_time = begin_time;
+        let mut sent_data_count = 0;
+        let mut next_report_time = report_begin_time;
+        let mut receive_stats = ReceiveStats::default();
+        let mut maybe_peer_report = None;
+
+        while Instant::now() < end_time {
+            let now = Instant::now();
+
+            if now < bandwidth_test_begin_time {
+                if now >= next_ping_time {
+                    enqueuer.enqueue(&ProbePacket::Ping {
+                        id: ping_send_times.len() as _,
+                    })?;
+                    ping_send_times.push(now);
+                    next_ping_time += PING_INTERVAL;
+                }
+            } else if now < report_begin_time {
+                // Send as many packets as needed to catch up with the target bitrate
+                let elapsed_s = (now - bandwidth_test_begin_time).as_secs_f32();
+                let packet_bits = (padding.len() * 8) as f32;
+                while (sent_data_count as f32 * packet_bits) < elapsed_s * bits_per_sec {
+                    enqueuer.enqueue(&ProbePacket::Data {
+                        sequence: sent_data_count,
+                        padding: &padding,
+                    })?;
+                    sent_data_count += 1;
+                }
+            } else if now >= next_report_time {
+                // Reports are sent multiple times in case some are lost
+                enqueuer.enqueue(&ProbePacket::Report {
+                    received_count: receive_stats.received_count,
+                    expected_count: receive_stats.expected_count(),
+                    received_bytes: receive_stats.received_bytes,
+                    receive_duration_us: receive_stats.duration().as_micros() as _,
+                })?;
+                next_report_time += REPORT_INTERVAL;
+            }
+
+            while let Ok(packet) = dequeuer.dequeue(POLL_TIMEOUT) {
+                let arrival_time = Instant::now();
+                match packet.get() {
+                    // Sent by the peer before it received the first packet of this side
+                    Ok(ProbePacket::Ready) => (),
+                    Ok(ProbePacket::Ping { id }) => {
+                        enqueuer.enqueue(&ProbePacket::Pong { id })?;
+                    }
+                    Ok(ProbePacket::Pong { id }) => {
+                        if let Some(send_time) = ping_send_times.get(id as usize) {
+                            rtts_s.push((arrival_time - *send_time).as_secs_f32());
+                        }
+                    }
+                    Ok(ProbePacket::Data { sequence, padding }) => {
+                        receive_stats.received_count += 1;
+                        receive_stats.max_sequence =
+                            Some(receive_stats.max_sequence.unwrap_or(0).max(sequence));
+                        receive_stats.received_bytes += padding.len() as u64;
+                        if receive_stats.first_arrival.is_none() {
+                            receive_stats.first_arrival = Some(arrival_time);
+                        }
+                        receive_stats.last_arrival = Some(arrival_time);
+                    }
+                    Ok(ProbePacket::Report {
+                        received_count,
+                        expected_count,
+                        received_bytes,
+                        receive_duration_us,
+                    }) => {
+                        maybe_peer_report = Some((
+                            throughput_mbps(
+                                received_bytes,
+                                Duration::from_micros(receive_duration_us),
+                            ),
+                            loss(received_count, expected_count),
+                        ))
+                    }
+                    Err(e) => debug!("{}", e),
+                }
+            }
+        }
+
+        if rtts_s.is_empty() {
+            return trace_str!("No response from peer");
+        }
+
+        let rtt_average_s = rtts_s.iter().sum::<f32>() / rtts_s.len() as f32;
+        let rtt_variance_s = rtts_s
+            .iter()
+            .map(|rtt| (rtt - rtt_average_s) * (rtt - rtt_average_s))
+            .sum::<f32>()
+            / rtts_s.len() as f32;
+
+        let link_quality = LinkQualityDesc {
+            rtt_ms: rtt_average_s * 1000_f32,
+            rtt_jitter_ms: rtt_variance_s.sqrt() * 1000_f32,
+            ping_loss: loss(rtts_s.len() as _, ping_send_times.len() as _),
+            send_throughput_mbps: maybe_peer_report.map(|(throughput, _)| throughput),
+            send_packet_loss: maybe_peer_report.map(|(_, loss)| loss),
+            receive_throughput_mbps: throughput_mbps(
+                receive_stats.received_bytes,
+                receive_stats.duration(),
+            ),
+            receive_packet_loss: loss(receive_stats.received_count, receive_stats.expected_count()),
+        };
+
+        info!("Link quality: {:?}", link_quality);
+
+        Ok(link_quality)
+    }
+}
@@ -0,0 +1,584 @@
+use crate::{
+    data::*,
+    thread_loop::{self, ThreadLoop},
+    *,
+};
+use log::*;
+use std::{
+    fmt::{Display, Write as _},
+    io::{self, Read, Write},
+    net::*,
+    thread,
+    time::Duration,
+};
+
+const TRACE_CONTEXT: &str = "Metrics";
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// The listener is non blocking, so the loop can be stopped
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    // A unit, if any, must also be the suffix of the name
+    fn family(&mut self, name: &str, metric_type: &str, unit: Option<&str>, help: &str) {
+        writeln!(self.text, "# TYPE {} {}", name, metric_type).ok();
+        if let Some(unit) = unit {
+            writeln!(self.text, "# UNIT {} {}", name, unit).ok();
+        }
+        writeln!(self.text, "# HELP {} {}", name, help).ok();
+    }
+
+    // Label values are identifiers and need no escaping. Rust formats non finite floats as "inf",
+    // "-inf" and "NaN", while OpenMetrics expects "+Inf", "-Inf" and "NaN".
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, value))
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(self.text, "{{{}}}", labels).ok();
+        }
+        let value = value.to_string();
+        let value = match value.as_str() {
+            "inf" => "+Inf",
+            "-inf" => "-Inf",
+            "NaN" => "NaN",
+            value => value,
+        };
+        writeln!(self.text, " {}", value).ok();
+    }
+
+    fn gauge(&mut self, name: &str, unit: Option<&str>, help: &str, value: impl Display) {
+        self.family(name, "gauge", unit, help);
+        self.sample(name, &[], value);
+    }
+
+    // The sample name gets the "_total" suffix
+    fn counter(&mut self, name: &str, unit: Option<&str>, help: &str, value: u64) {
+        self.family(name, "counter", unit, help);
+        self.sample(&format!("{}_total", name), &[], value);
+    }
+
+    // `percentiles_ms` contains (percentile, latency) pairs
+    fn latency_quantiles(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        percentiles_ms: &[(f32, f32)],
+    ) {
+        for (percentile, latency_ms) in percentiles_ms {
+            let quantile = (percentile / 100.).to_string();
+            let mut labels = labels.to_vec();
+            labels.push(("quantile", &quantile));
+            self.sample(name, &labels, latency_ms / 1000.);
+        }
+    }
+}
+
+// Encodes the statistics in the OpenMetrics text format (https://openmetrics.io). Rates and
+// latencies refer to the last snapshot interval, counters to the whole stream.
+pub fn encode_openmetrics(statistics: &ServerStatistics) -> String {
+    let mut writer = MetricsWriter {
+        text: String::new(),
+    };
+
+    writer.gauge(
+        "bridgevr_presented_frames_per_second",
+        None,
+        "Frames presented by SteamVR.",
+        statistics.presented_fps,
+    );
+    writer.gauge(
+        "bridgevr_encoded_frames_per_second",
+        None,
+        "Frames encoded and sent to the client.",
+        statistics.encoded_fps,
+    );
+    writer.gauge(
+        "bridgevr_video_bits_per_second",
+        None,
+        "Bitrate of the encoded video.",
+        statistics.video_bitrate_mbps * 1e6,
+    );
+    writer.gauge(
+        "bridgevr_sent_bits_per_second",
+        None,
+        "Bitrate of all the packets sent to the client.",
+        statistics.sent_bitrate_mbps * 1e6,
+    );
+    writer.gauge(
+        "bridgevr_received_bits_per_second",
+        None,
+        "Bitrate of all the packets received from the client.",
+        statistics.received_bitrate_mbps * 1e6,
+    );
+
+    writer.family(
+        "bridgevr_frame_latency_seconds",
+        "summary",
+        Some("seconds"),
+        "Latency from the present of a frame to the end of the encoding of each slice.",
+    );
+    writer.latency_quantiles(
+        "bridgevr_frame_latency_seconds",
+        &[],
+        &statistics.frame_latency_percentiles_ms,
+    );
+
+    writer.family(
+        "bridgevr_motion_samples_per_second",
+        "gauge",
+        None,
+        "Tracking samples received from the client, per device.",
+    );
+    for (device_type, rate) in &statistics.motion_rates_hz {
+        writer.sample(
+            "bridgevr_motion_samples_per_second",
+            &[("device", &format!("{:?}", device_type))],
+            rate,
+        );
+    }
+
+    writer.counter(
+        "bridgevr_presented_frames",
+        None,
+        "Frames presented by SteamVR.",
+        statistics.presented_frame_count,
+    );
+    writer.counter(
+        "bridgevr_encoded_frames",
+        None,
+        "Frames encoded and sent to the client.",
+        statistics.encoded_frame_count,
+    );
+    writer.counter(
+        "bridgevr_sent_packets",
+        None,
+        "Packets sent to the client.",
+        statistics.connection.sent_packet_count,
+    );
+    writer.counter(
+        "bridgevr_sent_bytes",
+        Some("bytes"),
+        "Size of the packets sent to the client.",
+        statistics.connection.sent_bytes,
+    );
+    writer.counter(
+        "bridgevr_received_packets",
+        None,
+        "Packets received from the client.",
+        statistics.connection.received_packet_count,
+    );
+    writer.counter(
+        "bridgevr_received_bytes",
+        Some("bytes"),
+        "Size of the packets received from the client.",
+        statistics.connection.received_bytes,
+    );
+
+    let socket_params = &statistics.connection.socket_params;
+    writer.gauge(
+        "bridgevr_socket_fragment_size_bytes",
+        Some("bytes"),
+        "Maximum size of a fragment of a packet sent through the socket.",
+        socket_params.fragment_size,
+    );
+    writer.gauge(
+        "bridgevr_socket_max_packet_size_bytes",
+        Some("bytes"),
+        "Maximum size of a packet before fragmentation.",
+        socket_params.max_packet_size,
+    );
+    if let Some(size) = socket_params.path_max_payload_size {
+        writer.gauge(
+            "bridgevr_path_max_payload_size_bytes",
+            Some("bytes"),
+            "Maximum UDP payload size that reaches the client without IP fragmentation.",
+            size,
+        );
+    }
+
+    // The game audio is played by the client, the microphone by the server
+    let audio_statistics: Vec<_> = [
+        ("game_audio", &statistics.client.game_audio),
+        ("microphone", &statistics.microphone),
+    ]
+    .iter()
+    .filter_map(|(stream, maybe_statistics)| {
+        maybe_statistics
+            .as_ref()
+            .map(|statistics| (*stream, statistics))
+    })
+    .collect();
+
+    let mut audio_counter =
+        |name: &str, help: &str, get_count: fn(&AudioPlayerStatistics) -> u64| {
+            writer.family(name, "counter", None, help);
+            for (stream, statistics) in &audio_statistics {
+                writer.sample(
+                    &format!("{}_total", name),
+                    &[("stream", stream)],
+                    get_count(statistics),
+                );
+            }
+        };
+    audio_counter(
+        "bridgevr_audio_received_packets",
+        "Audio packets received.",
+        |s| s.received_packet_count,
+    );
+    audio_counter("bridgevr_audio_lost_packets", "Audio packets lost.", |s| {
+        s.lost_packet_count
+    });
+    audio_counter(
+        "bridgevr_audio_recovered_packets",
+        "Lost audio packets reconstructed from the FEC data.",
+        |s| s.recovered_packet_count,
+    );
+    audio_counter(
+        "bridgevr_audio_concealed_packets",
+        "Lost audio packets replaced by extrapolated audio.",
+        |s| s.concealed_packet_count,
+    );
+    audio_counter(
+        "bridgevr_audio_late_packets",
+        "Audio packets discarded because they arrived too late.",
+        |s| s.late_packet_count,
+    );
+    audio_counter(
+        "bridgevr_audio_underruns",
+        "Times the audio buffer ran out of samples.",
+        |s| s.underrun_count,
+    );
+
+    writer.family(
+        "bridgevr_audio_latency_seconds",
+        "summary",
+        Some("seconds"),
+        "Buffering latency of the audio player.",
+    );
+    for (stream, statistics) in &audio_statistics {
+        writer.latency_quantiles(
+            "bridgevr_audio_latency_seconds",
+            &[("stream", stream)],
+            &statistics.latency.percentiles_ms,
+        );
+    }
+
+    writer.text.push_str("# EOF\n");
+
+    writer.text
+}
+
+// Reads the request line. The rest of the request is ignored.
+fn read_request_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(2).any(|bytes| bytes == b"\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..size]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    Ok(request.lines().next().unwrap_or("").into())
+}
+
+fn serve_request(
+    mut stream: TcpStream,
+    get_statistics: &impl Fn() -> Option<ServerStatistics>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let request_line = read_request_line(&mut stream)?;
+    let mut tokens = request_line.split_whitespace();
+    let (status, content_type, body) = match (tokens.next(), tokens.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = match get_statistics() {
+                Some(statistics) => encode_openmetrics(&statistics),
+                None => "# EOF\n".into(),
+            };
+            ("200 OK", CONTENT_TYPE, body)
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".into()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".into(),
+        ),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// Minimal HTTP server for scrapers like Prometheus. The statistics are requested on each scrape,
+// None if no client is connected, in which case no metric is served. Requests are served one at a
+// time and only on the loopback interface.
+pub struct MetricsServer {
+    thread_loop: ThreadLoop,
+}
+
+impl MetricsServer {
+    pub fn start(
+        port: u16,
+        get_statistics: impl Fn() -> Option<ServerStatistics> + Send + 'static,
+    ) -> StrResult<Self> {
+        let listener = trace_err!(TcpListener::bind((Ipv4Addr::LOCALHOST, port)))?;
+        trace_err!(listener.set_nonblocking(true))?;
+        info!("Metrics served at http://localhost:{}/metrics", port);
+
+        let thread_loop =
+            thread_loop::spawn("Metrics server loop", move || match listener.accept() {
+                Ok((stream, _)) => {
+                    serve_request(stream, &get_statistics)
+                        .map_err(|e| debug!("Metrics request failed: {}", e))
+                        .ok();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => {
+                    warn!("Metrics server: {}", e);
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+            })?;
+
+        Ok(Self { thread_loop })
+    }
+
+    pub fn request_stop(&mut self) {
+        self.thread_loop.request_stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    fn statistics() -> ServerStatistics {
+        ServerStatistics {
+            interval_s: 1.,
+            presented_fps: 90.,
+            encoded_fps: 89.5,
+            video_bitrate_mbps: 30.,
+            sent_bitrate_mbps: 31.25,
+            received_bitrate_mbps: 0.5,
+            frame_latency_percentiles_ms: vec![(50., 6.), (90., 8.), (99., 10.), (100., 12.)],
+            motion_rates_hz: vec![
+                (TrackedDeviceType::HMD, 72.),
+                (TrackedDeviceType::LeftController, 70.),
+            ],
+            presented_frame_count: 9000,
+            encoded_frame_count: 8950,
+            connection: ConnectionStatistics {
+                sent_packet_count: 300_000,
+                sent_bytes: 375_000_000,
+                received_packet_count: 20_000,
+                received_bytes: 6_250_000,
+                socket_params: SocketParams {
+                    path_max_payload_size: Some(1472),
+                    max_packet_size: 16 * 1440,
+                    max_fragments: 16,
+                    fragment_size: 1440,
+                    receive_buffer_max_size: 1472,
+                },
+            },
+            microphone: None,
+            client: ClientStatistics {
+                game_audio: Some(AudioPlayerStatistics {
+                    received_packet_count: 10_000,
+                    lost_packet_count: 12,
+                    recovered_packet_count: 10,
+                    concealed_packet_count: 2,
+                    late_packet_count: 1,
+                    underrun_count: 3,
+                    latency: LatencyStatistics {
+                        percentiles_ms: vec![(50., 20.), (99., 35.)],
+                        ..<_>::default()
+                    },
+                }),
+            },
+        }
+    }
+
+    fn get(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    // Let the OS pick a free port
+    fn free_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // Checks the structure of the exposition and returns the samples by name and labels
+    fn parse_openmetrics(text: &str) -> HashMap<String, f64> {
+        assert!(text.ends_with("# EOF\n"));
+
+        let mut families = HashMap::new();
+        let mut finished_families = HashSet::new();
+        let mut current_family: Option<String> = None;
+        let mut samples = HashMap::new();
+        for line in text.lines().filter(|line| *line != "# EOF") {
+            let tokens: Vec<_> = line.splitn(4, ' ').collect();
+            if tokens[0] == "#" {
+                match tokens[1] {
+                    "TYPE" => {
+                        if let Some(family) = current_family.take() {
+                            finished_families.insert(family);
+                        }
+                        assert!(
+                            !families.contains_key(tokens[2]),
+                            "duplicate family {}",
+                            tokens[2]
+                        );
+                        families.insert(tokens[2].to_owned(), tokens[3].to_owned());
+                        current_family = Some(tokens[2].into());
+                    }
+                    "UNIT" => assert!(
+                        tokens[2].ends_with(&format!("_{}", tokens[3])),
+                        "{} has no unit suffix",
+                        tokens[2]
+                    ),
+                    "HELP" => (),
+                    _ => panic!("unknown metadata: {}", line),
+                }
+                continue;
+            }
+
+            let (series, value) = line.split_at(line.rfind(' ').unwrap());
+            let name = series.split('{').next().unwrap();
+            let family = current_family.as_deref().unwrap_or("");
+
+            // Counter samples get the "_total" suffix
+            let expected_name = match families.get(family).map(|t| t.as_str()) {
+                Some("counter") => format!("{}_total", family),
+                _ => family.to_owned(),
+            };
+            assert!(
+                name == expected_name && !finished_families.contains(family),
+                "sample outside of its family: {}",
+                line
+            );
+            if families[family] == "summary" {
+                assert!(series.contains("quantile=\""), "no quantile: {}", line);
+            }
+
+            samples.insert(series.to_owned(), value.trim().parse::<f64>().unwrap());
+        }
+
+        samples
+    }
+
+    #[test]
+    fn openmetrics_format() {
+        let samples = parse_openmetrics(&encode_openmetrics(&statistics()));
+
+        let expected_samples = [
+            ("bridgevr_encoded_frames_per_second", 89.5),
+            ("bridgevr_sent_bits_per_second", 31.25e6),
+            ("bridgevr_frame_latency_seconds{quantile=\"0.99\"}", 0.01),
+            ("bridgevr_frame_latency_seconds{quantile=\"1\"}", 0.012),
+            (
+                "bridgevr_motion_samples_per_second{device=\"LeftController\"}",
+                70.,
+            ),
+            ("bridgevr_sent_bytes_total", 375e6),
+            ("bridgevr_path_max_payload_size_bytes", 1472.),
+            (
+                "bridgevr_audio_lost_packets_total{stream=\"game_audio\"}",
+                12.,
+            ),
+            ("bridgevr_audio_underruns_total{stream=\"game_audio\"}", 3.),
+            (
+                "bridgevr_audio_latency_seconds{stream=\"game_audio\",quantile=\"0.5\"}",
+                0.02,
+            ),
+        ];
+        for (series, expected_value) in &expected_samples {
+            let value = samples[*series];
+            assert!(
+                (value - expected_value).abs() <= expected_value * 1e-6,
+                "{}: {}, expected {}",
+                series,
+                value,
+                expected_value
+            );
+        }
+
+        // The microphone is disabled
+        assert!(!samples.keys().any(|series| series.contains("microphone")));
+    }
+
+    #[test]
+    fn non_finite_values() {
+        let mut statistics = statistics();
+        statistics.presented_fps = f32::INFINITY;
+        statistics.encoded_fps = f32::NAN;
+        statistics.motion_rates_hz = vec![(TrackedDeviceType::HMD, f32::NEG_INFINITY)];
+
+        let text = encode_openmetrics(&statistics);
+        assert!(text.contains("\nbridgevr_presented_frames_per_second +Inf\n"));
+        assert!(text.contains("\nbridgevr_encoded_frames_per_second NaN\n"));
+        assert!(text.contains("\nbridgevr_motion_samples_per_second{device=\"HMD\"} -Inf\n"));
+
+        let samples = parse_openmetrics(&text);
+        assert_eq!(
+            samples["bridgevr_presented_frames_per_second"],
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn http_endpoint() {
+        let port = free_port();
+        let _server = MetricsServer::start(port, || Some(statistics())).unwrap();
+
+        let response = get(port, "/metrics");
+        let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains("Content-Type: application/openmetrics-text; version=1.0.0"));
+        assert_eq!(body, encode_openmetrics(&statistics()));
+
+        assert!(get(port, "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn empty_when_disconnected() {
+        let port = free_port();
+        let _server = MetricsServer::start(port, || None).unwrap();
+
+        let response = get(port, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("\r\n\r\n# EOF\n"));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::*;
+use log::*;
+use std::{
+    net::*,
+    time::{Duration, Instant},
+};
+
+const TRACE_CONTEXT: &str = "Path MTU";
+
+// IPv4 header + UDP header
+const IP_UDP_HEADER_SIZE: usize = 28;
+
+// Minimum MTU that every IPv4 host must accept
+const MIN_PAYLOAD_SIZE: usize = 576 - IP_UDP_HEADER_SIZE;
+
+// Jumbo frames
+const MAX_PAYLOAD_SIZE: usize = 9000 - IP_UDP_HEADER_SIZE;
+
+// Most common Ethernet MTU. It is tested first to speed up the search.
+const ETHERNET_PAYLOAD_SIZE: usize = 1500 - IP_UDP_HEADER_SIZE;
+
+const PROBE_TIMEOUT: Duration = Duration::from_millis(50);
+const PROBE_RETRIES: usize = 3;
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Longer than the connectivity check of the prober, which lasts up to
+// PROBE_RETRIES * CONNECTIVITY_TIMEOUT
+const RESPONDER_FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(4);
+
+// After the first message, the prober sends one at least every PROBE_TIMEOUT until the result is
+// acknowledged
+const RESPONDER_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The responder keeps acknowledging the result until no retransmission arrives for this long. Longer
+// than all the retries of the prober, PROBE_RETRIES * PROBE_TIMEOUT.
+const RESULT_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+const PROBE_TAG: u8 = 0;
+const RESULT_TAG: u8 = 1;
+// An ack echoes the tag of the acknowledged message with this flag set
+const ACK_FLAG: u8 = 0x80;
+
+fn encode_message(buffer: &mut [u8], tag: u8, size: usize) {
+    buffer[0] = tag;
+    buffer[1..3].copy_from_slice(&(size as u16).to_le_bytes());
+}
+
+fn decode_message(buffer: &[u8]) -> Option<(u8, usize)> {
+    if buffer.len() >= 3 {
+        Some((buffer[0], u16::from_le_bytes([buffer[1], buffer[2]]) as _))
+    } else {
+        None
+    }
+}
+
+// Datagrams bigger than the path MTU must be dropped instead of being fragmented at the IP level,
+// otherwise the probe would succeed for any size.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dont_fragment(socket: &UdpSocket) -> StrResult {
+    use std::os::unix::io::AsRawFd;
+
+    let value = libc::IP_PMTUDISC_DO;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const _ as _,
+            std::mem::size_of_val(&value) as _,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        trace_str!("setsockopt: {}", std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn set_dont_fragment(socket: &UdpSocket) -> StrResult {
+    use std::os::windows::io::AsRawSocket;
+    use winapi::{shared::ws2def::IPPROTO_IP, shared::ws2ipdef::IP_DONTFRAGMENT, um::winsock2};
+
+    let value: u32 = 1;
+    let res = unsafe {
+        winsock2::setsockopt(
+            socket.as_raw_socket() as _,
+            IPPROTO_IP as _,
+            IP_DONTFRAGMENT,
+            &value as *const _ as _,
+            std::mem::size_of_val(&value) as _,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        trace_str!("setsockopt: {}", std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn set_dont_fragment(_: &UdpSocket) -> StrResult {
+    trace_str!("Unsupported platform")
+}
+
+// The whole buffer is sent as a single datagram
+fn send_and_wait_ack(
+    socket: &UdpSocket,
+    peer_address: SocketAddr,
+    buffer: &mut [u8],
+    tag: u8,
+    size: usize,
+    timeout: Duration,
+) -> bool {
+    encode_message(buffer, tag, size);
+
+    for _ in 0..PROBE_RETRIES {
+        // Sending fails immediately if size is bigger than the MTU of the local interface
+        if socket.send_to(buffer, peer_address).is_err() {
+            return false;
+        }
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Ok((received_size, address)) = socket.recv_from(buffer) {
+                if address == peer_address
+                    && decode_message(&buffer[..received_size]) == Some((tag | ACK_FLAG, size))
+                {
+                    return true;
+                }
+            }
+        }
+
+        // the buffer could have been overwritten
+        encode_message(buffer, tag, size);
+    }
+
+    false
+}
+
+fn bind_probe_socket(local_address: SocketAddr) -> StrResult<UdpSocket> {
+    let socket = trace_err!(UdpSocket::bind(local_address))?;
+    trace_err!(socket.set_read_timeout(Some(PROBE_TIMEOUT)))?;
+    set_dont_fragment(&socket)?;
+
+    Ok(socket)
+}
+
+// Run by the server. Returns the maximum UDP payload size that reaches the client without IP
+// fragmentation, or None if the probe could not start or the client does not respond. In these
+// cases the client does not get a result either and both peers use the default sizes. The client
+// must run `respond_to_path_mtu_probe()` at the same time.
+// Returns an error if the client did not acknowledge the result: it could be using the probed
+// sizes or the default ones, so the connection must be aborted.
+pub fn probe_path_mtu(
+    local_address: SocketAddr,
+    peer_address: SocketAddr,
+) -> StrResult<Option<usize>> {
+    let socket = match bind_probe_socket(local_address) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Path MTU discovery failed: {}", e);
+            return Ok(None);
+        }
+    };
+
+    let mut buffer = vec![0; MAX_PAYLOAD_SIZE];
+
+    // The client could be still binding its socket
+    if !send_and_wait_ack(
+        &socket,
+        peer_address,
+        &mut buffer[..MIN_PAYLOAD_SIZE],
+        PROBE_TAG,
+        MIN_PAYLOAD_SIZE,
+        CONNECTIVITY_TIMEOUT,
+    ) {
+        warn!("Path MTU discovery failed: client not responding");
+        return Ok(None);
+    }
+
+    let mut probe = |size| {
+        send_and_wait_ack(
+            &socket,
+            peer_address,
+            &mut buffer[..size],
+            PROBE_TAG,
+            size,
+            PROBE_TIMEOUT,
+        )
+    };
+
+    // Binary search. `min_size` is always valid, `max_size` + 1 is always invalid
+    let (mut min_size, mut max_size) = if probe(ETHERNET_PAYLOAD_SIZE) {
+        (ETHERNET_PAYLOAD_SIZE, MAX_PAYLOAD_SIZE)
+    } else {
+        (MIN_PAYLOAD_SIZE, ETHERNET_PAYLOAD_SIZE - 1)
+    };
+    while min_size < max_size {
+        let size = (min_size + max_size) / 2 + 1;
+        if probe(size) {
+            min_size = size;
+        } else {
+            max_size = size - 1;
+        }
+    }
+
+    let mut result_buffer = [0; 3];
+    if !send_and_wait_ack(
+        &socket,
+        peer_address,
+        &mut result_buffer,
+        RESULT_TAG,
+        min_size,
+        PROBE_TIMEOUT,
+    ) {
+        return trace_str!("Client did not receive the path MTU");
+    }
+
+    info!(
+        "Path MTU: {} (UDP payload: {})",
+        min_size + IP_UDP_HEADER_SIZE,
+        min_size
+    );
+
+    Ok(Some(min_size))
+}
+
+// Run by the client. Returns the maximum UDP payload size found by the server. The result is
+// acknowledged until the server stops retransmitting it, so that both peers agree on the sizes.
+pub fn respond_to_path_mtu_probe(local_address: SocketAddr) -> StrResult<usize> {
+    let socket = trace_err!(UdpSocket::bind(local_address))?;
+    trace_err!(socket.set_read_timeout(Some(PROBE_TIMEOUT)))?;
+
+    let mut buffer = vec![0; MAX_PAYLOAD_SIZE];
+    let mut maybe_result = None;
+    let mut deadline = Instant::now() + RESPONDER_FIRST_MESSAGE_TIMEOUT;
+    while Instant::now() < deadline {
+        let (received_size, address) = if let Ok(pair) = socket.recv_from(&mut buffer) {
+            pair
+        } else {
+            continue;
+        };
+
+        match decode_message(&buffer[..received_size]) {
+            Some((PROBE_TAG, size)) if size == received_size && maybe_result.is_none() => {
+                encode_message(&mut buffer, PROBE_TAG | ACK_FLAG, size);
+                socket.send_to(&buffer[..3], address).ok();
+
+                deadline = Instant::now() + RESPONDER_IDLE_TIMEOUT;
+            }
+            Some((RESULT_TAG, size)) if maybe_result.unwrap_or(size) == size => {
+                encode_message(&mut buffer, RESULT_TAG | ACK_FLAG, size);
+                socket.send_to(&buffer[..3], address).ok();
+
+                maybe_result = Some(size);
+                deadline = Instant::now() + RESULT_QUIET_PERIOD;
+            }
+            _ => debug!("Invalid path MTU probe packet"),
+        }
+    }
+
+    if let Some(size) = maybe_result {
+        info!(
+            "Path MTU: {} (UDP payload: {})",
+            size + IP_UDP_HEADER_SIZE,
+            size
+        );
+
+        Ok(size)
+    } else {
+        trace_str!("Timeout")
+    }
+}
@@ -0,0 +1,288 @@
+use std::f64::consts::PI;
+
+// Number of input frames on each side of the output frame used for interpolation. Higher values
+// give a sharper low pass filter at the cost of latency and CPU time.
+const KERNEL_HALF_LENGTH: usize = 16;
+
+// Kernel samples per input frame. The kernel is linearly interpolated between the samples.
+const KERNEL_TABLE_RESOLUTION: usize = 256;
+
+// Keep the cutoff slightly below the Nyquist frequency to leave room for the transition band
+const CUTOFF_FACTOR: f64 = 0.95;
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Blackman-Harris window, `x` in [-1, 1]
+fn window(x: f64) -> f64 {
+    let phase = PI * (x + 1.);
+    0.35875 - 0.48829 * phase.cos() + 0.14128 * (2. * phase).cos() - 0.01168 * (3. * phase).cos()
+}
+
+// Windowed sinc resampler for interleaved samples. It works on a continuous stream: input can be
+// pushed in buffers of any size and the output is produced as soon as enough input is available.
+pub struct Resampler {
+    channel_count: usize,
+
+    // Input frames consumed per output frame, at normal speed
+    nominal_step: f64,
+
+    // Input frames consumed per output frame
+    step: f64,
+
+    // Kernel evaluated for distances in [0, KERNEL_HALF_LENGTH]
+    kernel_table: Vec<f32>,
+
+    // Input frames not yet fully consumed, interleaved
+    history: Vec<f32>,
+
+    // Position of the next output frame, in input frames relative to the start of `history`
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(input_sample_rate: u32, output_sample_rate: u32, channel_count: u16) -> Self {
+        let step = input_sample_rate as f64 / output_sample_rate as f64;
+
+        // When downsampling, the cutoff must follow the output Nyquist frequency to avoid aliasing
+        let cutoff = f64::min(1., 1. / step) * CUTOFF_FACTOR;
+
+        let table_size = KERNEL_HALF_LENGTH * KERNEL_TABLE_RESOLUTION + 2;
+        let kernel_table = (0..table_size)
+            .map(|idx| {
+                let distance = idx as f64 / KERNEL_TABLE_RESOLUTION as f64;
+                if distance < KERNEL_HALF_LENGTH as f64 {
+                    let value = cutoff
+                        * sinc(cutoff * distance)
+                        * window(distance / KERNEL_HALF_LENGTH as f64);
+                    value as f32
+                } else {
+                    0.
+                }
+            })
+            .collect();
+
+        let channel_count = channel_count as usize;
+
+        Self {
+            channel_count,
+            nominal_step: step,
+            step,
+            kernel_table,
+            // Prime with silence so the first output frames are aligned with the first input frame
+            history: vec![0.; KERNEL_HALF_LENGTH * channel_count],
+            position: KERNEL_HALF_LENGTH as f64,
+        }
+    }
+
+    // Consume the input faster (`speed` > 1) or slower than the nominal rate, to follow a clock that
+    // drifts. The change is applied smoothly, from the next output frame.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.step = self.nominal_step * speed;
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        (self.step - 1.).abs() < f64::EPSILON
+    }
+
+    fn kernel(&self, distance: f64) -> f32 {
+        let table_position = distance.abs() * KERNEL_TABLE_RESOLUTION as f64;
+        let idx = table_position as usize;
+        let fraction = (table_position - idx as f64) as f32;
+        self.kernel_table[idx] * (1. - fraction) + self.kernel_table[idx + 1] * fraction
+    }
+
+    // Resample `input` and append the result to `output`. Both are interleaved.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+
+            // Keep the last frames and point to the next input frame, so the output stays
+            // continuous if the speed is changed
+            self.history.extend_from_slice(input);
+            let max_len = 2 * KERNEL_HALF_LENGTH * self.channel_count;
+            if self.history.len() > max_len {
+                self.history.drain(0..self.history.len() - max_len);
+            }
+            self.position = (self.history.len() / self.channel_count) as f64;
+
+            return;
+        }
+
+        self.history.extend_from_slice(input);
+        let frame_count = self.history.len() / self.channel_count;
+
+        loop {
+            let center_frame = self.position as usize;
+            if center_frame + KERNEL_HALF_LENGTH >= frame_count {
+                break;
+            }
+
+            let first_frame = center_frame + 1 - KERNEL_HALF_LENGTH;
+            for channel in 0..self.channel_count {
+                let mut sample = 0.;
+                for frame in first_frame..=center_frame + KERNEL_HALF_LENGTH {
+                    sample += self.history[frame * self.channel_count + channel]
+                        * self.kernel(self.position - frame as f64);
+                }
+                output.push(sample);
+            }
+
+            self.position += self.step;
+        }
+
+        // Discard the frames that will not be used anymore
+        let consumed_frames = (self.position as usize + 1).saturating_sub(KERNEL_HALF_LENGTH);
+        self.history.drain(0..consumed_frames * self.channel_count);
+        self.position -= consumed_frames as f64;
+    }
+}
+
+// Convert interleaved samples to a different channel count. Downmixing to mono averages all
+// channels, upmixing from mono copies the single channel. For other layouts the common channels are
+// kept and the missing ones are left silent.
+pub fn remap_channels(
+    input: &[f32],
+    input_channel_count: u16,
+    output_channel_count: u16,
+    output: &mut Vec<f32>,
+) {
+    let input_channel_count = input_channel_count as usize;
+    let output_channel_count = output_channel_count as usize;
+
+    if input_channel_count == output_channel_count {
+        output.extend_from_slice(input);
+        return;
+    }
+
+    for frame in input.chunks_exact(input_channel_count) {
+        if output_channel_count == 1 {
+            output.push(frame.iter().sum::<f32>() / input_channel_count as f32);
+        } else if input_channel_count == 1 {
+            output.extend((0..output_channel_count).map(|_| frame[0]));
+        } else {
+            output.extend(
+                (0..output_channel_count).map(|channel| frame.get(channel).cloned().unwrap_or(0.)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH_FRAME_COUNT: usize = 441;
+
+    fn sine(frequency: f64, sample_rate: u32, frame_count: usize) -> Vec<f32> {
+        (0..frame_count)
+            .map(|frame| (2. * PI * frequency * frame as f64 / sample_rate as f64).sin() as f32)
+            .collect()
+    }
+
+    // Input is pushed in buffers not aligned to the output frames, like the player does
+    fn resample(resampler: &mut Resampler, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![];
+        for buffer in input.chunks(PUSH_FRAME_COUNT) {
+            resampler.process(buffer, &mut output);
+        }
+
+        output
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn output_length_follows_rate() {
+        for &(input_rate, output_rate) in &[(48000, 44100), (44100, 48000), (16000, 48000)] {
+            let mut resampler = Resampler::new(input_rate, output_rate, 2);
+            let input = vec![0.; input_rate as usize * 2];
+            let output = resample(&mut resampler, &input);
+
+            // The last KERNEL_HALF_LENGTH input frames are retained until more input arrives
+            let expected_frame_count = output_rate as f64
+                * (input_rate as usize - KERNEL_HALF_LENGTH) as f64
+                / input_rate as f64;
+            assert_eq!(output.len() % 2, 0);
+            assert!(((output.len() / 2) as f64 - expected_frame_count).abs() <= 1.);
+        }
+    }
+
+    #[test]
+    fn tone_is_preserved() {
+        let frequency = 1000.;
+        let mut resampler = Resampler::new(44100, 48000, 1);
+        let output = resample(&mut resampler, &sine(frequency, 44100, 44100));
+
+        // Output frames are aligned with the input frames at the same time. The first frames are
+        // filtered together with the silence that primes the resampler.
+        let expected = sine(frequency, 48000, output.len());
+        let skipped_count = 2 * KERNEL_HALF_LENGTH;
+        for (actual, expected) in output.iter().zip(&expected).skip(skipped_count) {
+            assert!((actual - expected).abs() < 0.01, "{} {}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn downsampling_removes_aliases() {
+        // Above the Nyquist frequency of the output
+        let mut resampler = Resampler::new(48000, 16000, 1);
+        let output = resample(&mut resampler, &sine(12000., 48000, 48000));
+
+        assert!(rms(&output) < 0.01);
+    }
+
+    #[test]
+    fn passthrough_and_speed() {
+        let input = sine(1000., 48000, 48000);
+
+        let mut resampler = Resampler::new(48000, 48000, 1);
+        assert!(resampler.is_passthrough());
+        assert_eq!(resample(&mut resampler, &input), input);
+
+        // Consuming the input faster produces fewer output frames
+        resampler.set_speed(1.01);
+        assert!(!resampler.is_passthrough());
+        let output = resample(&mut resampler, &input);
+        assert!(
+            (output.len() as f64 - input.len() as f64 / 1.01).abs()
+                < 2. * KERNEL_HALF_LENGTH as f64
+        );
+    }
+
+    #[test]
+    fn remap_downmix_to_mono() {
+        let mut output = vec![];
+        remap_channels(&[1., 0., 0.5, 0.5], 2, 1, &mut output);
+        assert_eq!(output, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn remap_upmix_from_mono() {
+        let mut output = vec![];
+        remap_channels(&[0.25, -0.5], 1, 2, &mut output);
+        assert_eq!(output, vec![0.25, 0.25, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn remap_other_layouts() {
+        let mut output = vec![];
+        remap_channels(&[1., 2., 3., 4.], 2, 4, &mut output);
+        assert_eq!(output, vec![1., 2., 0., 0., 3., 4., 0., 0.]);
+
+        output.clear();
+        remap_channels(&[1., 2., 3., 4.], 4, 2, &mut output);
+        assert_eq!(output, vec![1., 2.]);
+
+        output.clear();
+        remap_channels(&[1., 2.], 2, 2, &mut output);
+        assert_eq!(output, vec![1., 2.]);
+    }
+}
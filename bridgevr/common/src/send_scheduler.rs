@@ -0,0 +1,154 @@
+use crate::{sockets::StreamType, thread_loop::*, *};
+use crossbeam_channel::{select, Receiver, Sender};
+use laminar::Packet;
+use log::*;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::*};
+
+const TRACE_CONTEXT: &str = "Send scheduler";
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SendPriority {
+    // Motion, input, haptics and control packets are small and the most latency sensitive
+    High,
+    Medium,
+    Low,
+}
+
+impl From<StreamType> for SendPriority {
+    fn from(stream_type: StreamType) -> Self {
+        match stream_type {
+            StreamType::Other => Self::High,
+            StreamType::GameAudio | StreamType::Microphone => Self::Medium,
+            StreamType::VideoSlice(_) | StreamType::Probe => Self::Low,
+        }
+    }
+}
+
+// Reorders the outgoing packets by priority before they are handed to Laminar. Higher priority
+// packets are always sent first. Low priority (video) packets can be paced so that a burst (for
+// example an IDR frame) is spread across a time window instead of filling the Wi-Fi buffers.
+// DSCP/TOS marks are out of scope: Laminar binds and owns the UDP socket and sends all the streams
+// through it, so marks could neither be set nor differ per stream.
+pub struct SendScheduler {
+    packet_senders: [Sender<Packet>; 3],
+    pacing_window: Arc<Mutex<Option<Duration>>>,
+    thread: ThreadLoop,
+}
+
+impl SendScheduler {
+    pub fn new(laminar_packet_sender: Sender<Packet>) -> StrResult<Self> {
+        let (high_sender, high_receiver) = crossbeam_channel::unbounded();
+        let (medium_sender, medium_receiver) = crossbeam_channel::unbounded();
+        let (low_sender, low_receiver) = crossbeam_channel::unbounded();
+
+        let pacing_window = Arc::new(Mutex::new(None));
+
+        let mut pending_low_packet = None;
+        let mut burst_in_progress = false;
+        let mut pacing_window_end = Instant::now();
+        let mut next_low_send_time = Instant::now();
+
+        let thread = thread_loop::spawn("Send scheduler loop", {
+            let pacing_window = pacing_window.clone();
+            move || {
+                let forward = |packet| {
+                    laminar_packet_sender
+                        .send(packet)
+                        .map_err(|e| debug!("{}", e))
+                        .ok();
+                };
+
+                while let Ok(packet) = high_receiver
+                    .try_recv()
+                    .or_else(|_| medium_receiver.try_recv())
+                {
+                    forward(packet);
+                }
+
+                if pending_low_packet.is_none() {
+                    pending_low_packet = low_receiver.try_recv().ok();
+                }
+
+                if let Some(packet) = pending_low_packet.take() {
+                    let now = Instant::now();
+                    if now >= next_low_send_time {
+                        forward(packet);
+
+                        next_low_send_time = match *pacing_window.lock() {
+                            Some(window) if !low_receiver.is_empty() => {
+                                if !burst_in_progress {
+                                    pacing_window_end = now + window;
+                                    burst_in_progress = true;
+                                }
+
+                                // Spread the queued packets evenly in the remaining window
+                                let queued_count = low_receiver.len() as u32;
+                                now + pacing_window_end.saturating_duration_since(now)
+                                    / (queued_count + 1)
+                            }
+                            _ => {
+                                burst_in_progress = false;
+                                now
+                            }
+                        };
+                    } else {
+                        pending_low_packet = Some(packet);
+
+                        // Wait for higher priority packets until it's time to send the next low
+                        // priority packet
+                        select! {
+                            recv(high_receiver) -> packet => {
+                                if let Ok(packet) = packet {
+                                    forward(packet)
+                                }
+                            },
+                            recv(medium_receiver) -> packet => {
+                                if let Ok(packet) = packet {
+                                    forward(packet)
+                                }
+                            },
+                            default(next_low_send_time - now) => (),
+                        }
+                    }
+                } else {
+                    select! {
+                        recv(high_receiver) -> packet => {
+                            if let Ok(packet) = packet {
+                                forward(packet)
+                            }
+                        },
+                        recv(medium_receiver) -> packet => {
+                            if let Ok(packet) = packet {
+                                forward(packet)
+                            }
+                        },
+                        recv(low_receiver) -> packet => pending_low_packet = packet.ok(),
+                        default(TIMEOUT) => (),
+                    }
+                }
+            }
+        })?;
+
+        Ok(Self {
+            packet_senders: [high_sender, medium_sender, low_sender],
+            pacing_window,
+            thread,
+        })
+    }
+
+    pub fn packet_sender(&self, priority: SendPriority) -> Sender<Packet> {
+        self.packet_senders[priority as usize].clone()
+    }
+
+    // When the window is `None`, low priority packets are sent as soon as possible.
+    pub fn set_pacing_window(&self, window: Option<Duration>) {
+        *self.pacing_window.lock() = window;
+    }
+
+    pub fn request_stop(&mut self) {
+        self.thread.request_stop();
+    }
+}
@@ -1,33 +1,50 @@
-use crate::{data::*, thread_loop::ThreadLoop, *};
+use crate::{data::*, path_mtu::*, send_scheduler::*, tcp_tunnel::*, thread_loop::ThreadLoop, *};
 use laminar::{Config, LinkConditioner, Packet, Socket, SocketEvent};
 use log::*;
 use parking_lot::Mutex;
 use serde::{de::*, *};
+use settings_schema::Switch;
 use std::{
     cmp::*,
     collections::*,
+    marker::PhantomData,
     net::*,
     sync::{mpsc::*, Arc},
+    thread,
     time::*,
 };
 
 const TRACE_CONTEXT: &str = "Sockets";
 
-const MAX_HANDSHAKE_PACKET_SIZE_BYTES: usize = 4_000;
-
 const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const LOCALHOST_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
 
 const HANDSHAKE_PORT: u16 = 9943;
 
+// Port to be forwarded when tethered, for example with `adb reverse tcp:9942 tcp:9942`
+const TETHERED_HANDSHAKE_PORT: u16 = 9942;
+
 const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+const TETHERED_ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Upper bound of the size of the headers added by Laminar to each fragment
+const LAMINAR_MAX_HEADER_SIZE: usize = 32;
+
+// Enough buffers to hold a few frames worth of packets in flight between the receive thread and
+// the consumers
+const RECEIVE_BUFFER_POOL_SIZE: usize = 512;
+const RECEIVE_BUFFER_CAPACITY: usize = 2048;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum StreamType {
     VideoSlice(u8),
     GameAudio,
     Microphone,
 
+    // Link quality test run right after the handshake
+    Probe,
+
     // Other types of streams don't have an ordering requirement and are collected by a single
     // receiver. This is done to reduce the number of parallel threads needed.
     // Haptic and shutdown for server; motion, input, statistics and disconnected for client
@@ -40,7 +57,20 @@ impl Into<u8> for StreamType {
             Self::Other => 0,
             Self::GameAudio => 1,
             Self::Microphone => 2,
-            Self::VideoSlice(idx) => 3 + idx,
+            Self::Probe => 3,
+            Self::VideoSlice(idx) => 4 + idx,
+        }
+    }
+}
+
+impl From<u8> for StreamType {
+    fn from(stream_id: u8) -> Self {
+        match stream_id {
+            0 => Self::Other,
+            1 => Self::GameAudio,
+            2 => Self::Microphone,
+            3 => Self::Probe,
+            idx => Self::VideoSlice(idx - 4),
         }
     }
 }
@@ -60,7 +90,7 @@ pub fn search_client(
         None => None,
     };
 
-    let mut packet_buffer = [0u8; MAX_HANDSHAKE_PACKET_SIZE_BYTES];
+    let mut packet_buffer = [0u8; MAX_CLIENT_HANDSHAKE_PACKET_SIZE as usize];
     let mut try_find_client = || -> Result<(IpAddr, ClientHandshakePacket), ()> {
         let (hanshake_packet_size, address) = listener
             .recv_from(&mut packet_buffer)
@@ -73,8 +103,11 @@ pub fn search_client(
             }
         }
 
-        let client_handshake_packet = bincode::deserialize(&packet_buffer[..hanshake_packet_size])
-            .map_err(|e| warn!("Received handshake packet: {}", e))?;
+        let client_handshake_packet = deserialize_bounded(
+            &packet_buffer[..hanshake_packet_size],
+            MAX_CLIENT_HANDSHAKE_PACKET_SIZE,
+        )
+        .map_err(|e| warn!("Received handshake packet: {}", e))?;
 
         Ok((address.ip(), client_handshake_packet))
     };
@@ -88,6 +121,54 @@ pub fn search_client(
     }
 }
 
+// Used instead of `search_client()` when tethered. The TCP connection is kept open and must be
+// passed to `ConnectionManager::connect_to_tethered_client()`.
+pub fn search_tethered_client(timeout: Duration) -> StrResult<(TcpStream, ClientHandshakePacket)> {
+    let deadline = Instant::now() + timeout;
+
+    let listener = trace_err!(TcpListener::bind(SocketAddr::new(
+        LOCALHOST_IP,
+        TETHERED_HANDSHAKE_PORT
+    )))?;
+    trace_err!(listener.set_nonblocking(true))?;
+
+    let mut buffer = vec![];
+    let mut try_find_client = || -> Result<(TcpStream, ClientHandshakePacket), ()> {
+        let (mut handshake_stream, _) = listener
+            .accept()
+            .map_err(|e| debug!("No tethered client connected: {}", e))?;
+
+        handshake_stream
+            .set_nonblocking(false)
+            .map_err(|e| warn!("Control socket: {}", e))?;
+        handshake_stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .map_err(|e| warn!("Control socket: {}", e))?;
+
+        read_frame(
+            &mut handshake_stream,
+            &mut buffer,
+            MAX_CLIENT_HANDSHAKE_PACKET_SIZE as _,
+        )
+        .map_err(|e| warn!("Received handshake packet: {}", e))?;
+        let client_handshake_packet =
+            deserialize_bounded(&buffer, MAX_CLIENT_HANDSHAKE_PACKET_SIZE)
+                .map_err(|e| warn!("Received handshake packet: {}", e))?;
+
+        Ok((handshake_stream, client_handshake_packet))
+    };
+
+    loop {
+        if let Ok(pair) = try_find_client() {
+            break Ok(pair);
+        } else if Instant::now() > deadline {
+            break Err("No valid client found".into());
+        }
+        thread::sleep(TETHERED_ACCEPT_INTERVAL);
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum SendMode {
     UnreliableUnordered,
     UnreliableSequential,
@@ -95,21 +176,182 @@ pub enum SendMode {
     ReliableOrdered,
 }
 
-pub struct PacketEnqueuer {
+// Associates a stream to the type of its packets. Packets can borrow from the receive buffer, so
+// the packet type is parametrized by the buffer lifetime.
+pub trait StreamPacket<'a> {
+    type Packet: Serialize + Deserialize<'a> + Validate;
+}
+
+pub struct VideoSliceStream;
+
+impl<'a> StreamPacket<'a> for VideoSliceStream {
+    type Packet = VideoPacket<'a>;
+}
+
+pub struct AudioStream;
+
+impl<'a> StreamPacket<'a> for AudioStream {
+    type Packet = AudioPacket<'a>;
+}
+
+pub struct ProbeStream;
+
+impl<'a> StreamPacket<'a> for ProbeStream {
+    type Packet = ProbePacket<'a>;
+}
+
+pub struct OtherServerStream;
+
+impl<'a> StreamPacket<'a> for OtherServerStream {
+    type Packet = OtherServerPacket;
+}
+
+pub struct OtherClientStream;
+
+impl<'a> StreamPacket<'a> for OtherClientStream {
+    type Packet = OtherClientPacket;
+}
+
+// Stream declaration used by both peers to create the two ends of a stream. A packet of the wrong
+// type cannot be sent or received on a stream.
+pub struct StreamDesc<S> {
+    stream_type: StreamType,
+    send_mode: SendMode,
+    _phantom: PhantomData<S>,
+}
+
+impl<S> StreamDesc<S> {
+    fn new(stream_type: StreamType, send_mode: SendMode) -> Self {
+        Self {
+            stream_type,
+            send_mode,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn stream_type(&self) -> StreamType {
+        self.stream_type
+    }
+
+    pub fn send_mode(&self) -> SendMode {
+        self.send_mode
+    }
+}
+
+impl StreamDesc<VideoSliceStream> {
+    pub fn video_slice(slice_idx: u8, send_mode: SendMode) -> Self {
+        Self::new(StreamType::VideoSlice(slice_idx), send_mode)
+    }
+}
+
+impl StreamDesc<AudioStream> {
+    pub fn game_audio(send_mode: SendMode) -> Self {
+        Self::new(StreamType::GameAudio, send_mode)
+    }
+
+    pub fn microphone(send_mode: SendMode) -> Self {
+        Self::new(StreamType::Microphone, send_mode)
+    }
+}
+
+impl StreamDesc<ProbeStream> {
+    pub fn probe() -> Self {
+        Self::new(StreamType::Probe, SendMode::UnreliableUnordered)
+    }
+}
+
+// Server to client
+impl StreamDesc<OtherServerStream> {
+    pub fn other_server(send_mode: SendMode) -> Self {
+        Self::new(StreamType::Other, send_mode)
+    }
+}
+
+// Client to server
+impl StreamDesc<OtherClientStream> {
+    pub fn other_client(send_mode: SendMode) -> Self {
+        Self::new(StreamType::Other, send_mode)
+    }
+}
+
+// Pool of reusable receive buffers. Buffers are recycled when they are dropped by the consumer, so
+// in steady state the receive path performs no allocation.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffer_enqueuer: crossbeam_channel::Sender<Vec<u8>>,
+    buffer_dequeuer: crossbeam_channel::Receiver<Vec<u8>>,
+    default_capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffer_count: usize, default_capacity: usize) -> Self {
+        let (buffer_enqueuer, buffer_dequeuer) = crossbeam_channel::bounded(max_buffer_count);
+
+        // Preallocate the buffers so that the first packets do not cause allocation spikes
+        for _ in 0..max_buffer_count {
+            buffer_enqueuer
+                .try_send(Vec::with_capacity(default_capacity))
+                .ok();
+        }
+
+        Self {
+            buffer_enqueuer,
+            buffer_dequeuer,
+            default_capacity,
+        }
+    }
+
+    // The returned buffer is empty but it can have any capacity.
+    pub fn get(&self) -> Vec<u8> {
+        if let Ok(mut buffer) = self.buffer_dequeuer.try_recv() {
+            buffer.clear();
+            buffer
+        } else {
+            Vec::with_capacity(self.default_capacity)
+        }
+    }
+
+    // If the pool is full, the buffer is deallocated.
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        self.buffer_enqueuer.try_send(buffer).ok();
+    }
+}
+
+// Serialize a packet prefixed by its stream id. The buffer is allocated once with the exact size,
+// so it never needs to grow during serialization and Laminar will not need to shrink it when
+// converting it to a boxed slice.
+pub fn serialize_packet<T: Serialize>(stream_id: u8, packet: &T) -> StrResult<Vec<u8>> {
+    let packet_size = trace_err!(bincode::serialized_size(packet))? as usize;
+
+    let mut buffer = Vec::with_capacity(packet_size + 1);
+    buffer.push(stream_id);
+    // <&mut Vec>::write() appends the writtend data
+    trace_err!(bincode::serialize_into(&mut buffer, packet))?;
+
+    Ok(buffer)
+}
+
+pub struct PacketEnqueuer<S> {
     peer_address: SocketAddr,
     stream_id: u8,
     send_mode: SendMode,
     packet_sender: crossbeam_channel::Sender<Packet>,
+    statistics: Arc<Mutex<ConnectionStatistics>>,
+    _phantom: PhantomData<S>,
 }
 
-impl PacketEnqueuer {
-    // todo: find a way to move the type parameter at struct level (issue with lifetimes)
-    pub fn enqueue<T: Serialize>(&mut self, packet: &T) -> StrResult {
-        // Laminar API takes ownership of the packet payloads so we need to reallocate new buffers
-        // for every send
-        let mut buffer = vec![self.stream_id];
-        // <&mut Vec>::write() appends the writtend data
-        trace_err!(bincode::serialize_into(&mut buffer, packet))?;
+impl<S: for<'a> StreamPacket<'a>> PacketEnqueuer<S> {
+    pub fn enqueue<'a>(&mut self, packet: &<S as StreamPacket<'a>>::Packet) -> StrResult {
+        // Send buffers cannot be pooled: Laminar takes the payload by value, converts it to a boxed
+        // slice and drops it once it is copied into the outgoing datagrams, without handing it
+        // back. Instead make sure that there is only one allocation per packet.
+        let buffer = serialize_packet(self.stream_id, packet)?;
+
+        {
+            let mut statistics = self.statistics.lock();
+            statistics.sent_packet_count += 1;
+            statistics.sent_bytes += buffer.len() as u64;
+        }
 
         // todo: use const generics when stabilized
         let packet = match self.send_mode {
@@ -126,73 +368,132 @@ impl PacketEnqueuer {
     }
 }
 
-pub struct ReceivedPacket {
+// Owns the receive buffer. The packet is deserialized borrowing from it.
+pub struct ReceivedPacket<S> {
     buffer: Option<Vec<u8>>,
-    return_buffer_enqueuer: Sender<Vec<u8>>,
+    buffer_pool: BufferPool,
+    _phantom: PhantomData<S>,
 }
 
-impl ReceivedPacket {
-    pub fn get<'a, T: Deserialize<'a>>(&'a self) -> StrResult<T> {
-        trace_err!(bincode::deserialize(&self.buffer.as_ref().unwrap()))
+impl<S: for<'a> StreamPacket<'a>> ReceivedPacket<S> {
+    pub fn get<'a>(&'a self) -> StrResult<<S as StreamPacket<'a>>::Packet> {
+        let buffer = self.buffer.as_ref().unwrap();
+        deserialize_bounded(buffer, buffer.len() as _)
     }
 }
 
-impl Drop for ReceivedPacket {
+impl<S> Drop for ReceivedPacket<S> {
     fn drop(&mut self) {
-        self.return_buffer_enqueuer
-            .send(self.buffer.take().unwrap())
-            .ok();
+        self.buffer_pool.recycle(self.buffer.take().unwrap());
     }
 }
 
-pub struct PacketDequeuer {
+pub struct PacketDequeuer<S> {
     receive_buffer_dequeuer: Receiver<Vec<u8>>,
-    return_buffer_enqueuer: Sender<Vec<u8>>,
+    buffer_pool: BufferPool,
+    _phantom: PhantomData<S>,
 }
 
-impl PacketDequeuer {
-    // todo: find a way to deserialize inside this function (issue with lifetimes)
-    pub fn dequeue(&mut self, timeout: Duration) -> StrResult<ReceivedPacket> {
+impl<S> PacketDequeuer<S> {
+    pub fn dequeue(&mut self, timeout: Duration) -> StrResult<ReceivedPacket<S>> {
         let buffer = trace_err!(self.receive_buffer_dequeuer.recv_timeout(timeout))?;
         Ok(ReceivedPacket {
             buffer: Some(buffer),
-            return_buffer_enqueuer: self.return_buffer_enqueuer.clone(),
+            buffer_pool: self.buffer_pool.clone(),
+            _phantom: PhantomData,
         })
     }
 }
 
+enum Transport {
+    Laminar {
+        socket: Socket,
+        receive_thread: ThreadLoop,
+    },
+    TcpTunnel(TcpTunnel),
+}
+
 pub struct ConnectionManager {
     peer_address: SocketAddr,
-    socket: Socket,
-    receive_thread: ThreadLoop,
+    socket_params: SocketParams,
+    link_quality: Option<LinkQualityDesc>,
+    transport: Transport,
+    send_scheduler: SendScheduler,
     receive_buffer_enqueuers: Arc<Mutex<HashMap<u8, Sender<Vec<u8>>>>>,
-    return_buffer_enqueuer: Sender<Vec<u8>>,
+    buffer_pool: BufferPool,
+    statistics: Arc<Mutex<ConnectionStatistics>>,
+}
+
+fn dispatch_payload(
+    payload: &[u8],
+    receive_buffer_enqueuers: &Mutex<HashMap<u8, Sender<Vec<u8>>>>,
+    buffer_pool: &BufferPool,
+    statistics: &Mutex<ConnectionStatistics>,
+) {
+    {
+        let mut statistics = statistics.lock();
+        statistics.received_packet_count += 1;
+        statistics.received_bytes += payload.len() as u64;
+    }
+
+    if let Some((&stream_id, data)) = payload.split_first() {
+        if let Some(enqueuer) = receive_buffer_enqueuers.lock().get(&stream_id) {
+            // The transport retains ownership of the payload, a copy is needed. The buffer is
+            // taken from the pool to avoid allocations.
+            let mut buffer = buffer_pool.get();
+            buffer.extend_from_slice(data);
+            enqueuer.send(buffer).ok();
+        }
+    }
 }
 
 impl ConnectionManager {
-    fn create_config(socket_config: SocketConfig) -> Config {
+    fn create_config(
+        socket_config: SocketConfig,
+        path_max_payload_size: Option<usize>,
+    ) -> (Config, SocketParams) {
         let mut config = Config::default();
         config.blocking_mode = false;
         config.heartbeat_interval = None;
 
-        if let Some(value) = socket_config.idle_connection_timeout_ms {
-            config.idle_connection_timeout = Duration::from_millis(value);
-        }
-        if let Some(value) = socket_config.max_packet_size {
-            config.max_packet_size = value as _;
-        }
         if let Some(value) = socket_config.max_fragments {
             config.max_fragments = value;
         }
+
+        // Each fragment must fit in a single datagram. The settings take precedence.
         if let Some(value) = socket_config.fragment_size {
             config.fragment_size = value;
+        } else if let Some(payload_size) = path_max_payload_size {
+            config.fragment_size = (payload_size - LAMINAR_MAX_HEADER_SIZE) as _;
         }
-        if let Some(value) = socket_config.fragment_reassembly_buffer_size {
-            config.fragment_reassembly_buffer_size = value;
+        if let Some(payload_size) = path_max_payload_size {
+            if config.fragment_size as usize + LAMINAR_MAX_HEADER_SIZE > payload_size {
+                warn!(
+                    "Fragment size {} exceeds the path MTU, packets will be fragmented by IP",
+                    config.fragment_size
+                );
+            }
+        }
+
+        // Follow the fragment size, unless overridden too
+        if socket_config.fragment_size.is_some() || path_max_payload_size.is_some() {
+            config.max_packet_size = config.fragment_size as usize * config.max_fragments as usize;
+            config.receive_buffer_max_size =
+                config.fragment_size as usize + LAMINAR_MAX_HEADER_SIZE;
+        }
+        if let Some(value) = socket_config.max_packet_size {
+            config.max_packet_size = value as _;
         }
         if let Some(value) = socket_config.receive_buffer_max_size {
             config.receive_buffer_max_size = value as _;
         }
+
+        if let Some(value) = socket_config.idle_connection_timeout_ms {
+            config.idle_connection_timeout = Duration::from_millis(value);
+        }
+        if let Some(value) = socket_config.fragment_reassembly_buffer_size {
+            config.fragment_reassembly_buffer_size = value;
+        }
         if let Some(value) = socket_config.rtt_smoothing_factor {
             config.rtt_smoothing_factor = value;
         }
@@ -206,87 +507,186 @@ impl ConnectionManager {
             config.max_packets_in_flight = value;
         }
 
-        config
+        let socket_params = SocketParams {
+            path_max_payload_size: path_max_payload_size.map(|size| size as _),
+            max_packet_size: config.max_packet_size as _,
+            max_fragments: config.max_fragments,
+            fragment_size: config.fragment_size,
+            receive_buffer_max_size: config.receive_buffer_max_size as _,
+        };
+
+        (config, socket_params)
     }
 
     fn create_connection_manager(
         local_address: SocketAddr,
         peer_address: SocketAddr,
         socket_config: SocketConfig,
+        path_max_payload_size: Option<usize>,
         mut timeout_callback: impl FnMut() + Send + 'static,
     ) -> StrResult<Self> {
-        let config = Self::create_config(socket_config);
+        let (config, socket_params) = Self::create_config(socket_config, path_max_payload_size);
+        info!("Socket parameters: {:?}", socket_params);
+
         let mut socket = trace_err!(
             Socket::bind_with_config(local_address, config),
             "Handshake failed"
         )?;
 
-        let (return_buffer_enqueuer, return_buffer_dequeuer) = channel::<Vec<_>>();
+        let send_scheduler = SendScheduler::new(socket.get_packet_sender())?;
+
+        let buffer_pool = BufferPool::new(RECEIVE_BUFFER_POOL_SIZE, RECEIVE_BUFFER_CAPACITY);
         let event_receiver = socket.get_event_receiver();
         let receive_buffer_enqueuers = Arc::new(Mutex::new(HashMap::<_, Sender<_>>::new()));
+        let statistics = Arc::new(Mutex::new(ConnectionStatistics {
+            socket_params: socket_params.clone(),
+            ..<_>::default()
+        }));
         let receive_thread = thread_loop::spawn("Socket receiver loop", {
             let receive_buffer_enqueuers = receive_buffer_enqueuers.clone();
-            move || {
-                let mut buffer = if let Ok(mut buffer) = return_buffer_dequeuer.try_recv() {
-                    buffer.clear();
-                    buffer
-                } else {
-                    vec![]
-                };
-
-                match event_receiver.recv() {
-                    Ok(SocketEvent::Packet(packet)) => {
-                        let payload = packet.payload();
-                        let stream_id = payload[0];
-                        buffer.extend(&payload[0..]);
-                        if let Some(enqueuer) = receive_buffer_enqueuers.lock().get(&stream_id) {
-                            enqueuer.send(buffer).ok();
-                        }
-                    }
-                    Ok(SocketEvent::Timeout(_)) => {
-                        timeout_callback();
-                    }
-                    _ => warn!("Unknown socket error"),
+            let buffer_pool = buffer_pool.clone();
+            let statistics = statistics.clone();
+            move || match event_receiver.recv() {
+                Ok(SocketEvent::Packet(packet)) => dispatch_payload(
+                    packet.payload(),
+                    &receive_buffer_enqueuers,
+                    &buffer_pool,
+                    &statistics,
+                ),
+                Ok(SocketEvent::Timeout(_)) => {
+                    timeout_callback();
                 }
+                _ => warn!("Unknown socket error"),
             }
         })?;
 
         Ok(ConnectionManager {
             peer_address,
-            socket,
-            receive_thread,
+            socket_params,
+            link_quality: None,
+            transport: Transport::Laminar {
+                socket,
+                receive_thread,
+            },
+            send_scheduler,
             receive_buffer_enqueuers,
-            return_buffer_enqueuer,
+            buffer_pool,
+            statistics,
         })
     }
 
-    pub fn register_enqueuer(
-        &mut self,
-        stream_type: StreamType,
-        send_mode: SendMode,
-    ) -> PacketEnqueuer {
-        let packet_sender = self.socket.get_packet_sender();
+    fn create_tunneled_connection_manager(
+        stream: TcpStream,
+        socket_config: SocketConfig,
+        timeout_callback: impl FnMut() + Send + 'static,
+    ) -> StrResult<Self> {
+        // Only the fragment size is relevant, used as reference for the packet size
+        let (_, socket_params) = Self::create_config(socket_config, None);
+        info!("Streams tunneled over TCP");
+
+        let peer_address = trace_err!(stream.peer_addr())?;
+
+        let (packet_sender, packet_receiver) = crossbeam_channel::unbounded();
+        let send_scheduler = SendScheduler::new(packet_sender)?;
+
+        let buffer_pool = BufferPool::new(RECEIVE_BUFFER_POOL_SIZE, RECEIVE_BUFFER_CAPACITY);
+        let receive_buffer_enqueuers = Arc::new(Mutex::new(HashMap::<_, Sender<_>>::new()));
+        let statistics = Arc::new(Mutex::new(ConnectionStatistics {
+            socket_params: socket_params.clone(),
+            ..<_>::default()
+        }));
+        let tunnel = TcpTunnel::new(
+            stream,
+            packet_receiver,
+            {
+                let receive_buffer_enqueuers = receive_buffer_enqueuers.clone();
+                let buffer_pool = buffer_pool.clone();
+                let statistics = statistics.clone();
+                move |payload| {
+                    dispatch_payload(
+                        payload,
+                        &receive_buffer_enqueuers,
+                        &buffer_pool,
+                        &statistics,
+                    )
+                }
+            },
+            timeout_callback,
+        )?;
+
+        Ok(ConnectionManager {
+            peer_address,
+            socket_params,
+            link_quality: None,
+            transport: Transport::TcpTunnel(tunnel),
+            send_scheduler,
+            receive_buffer_enqueuers,
+            buffer_pool,
+            statistics,
+        })
+    }
+
+    pub fn register_enqueuer<S>(&mut self, stream_desc: &StreamDesc<S>) -> PacketEnqueuer<S> {
+        let packet_sender = self
+            .send_scheduler
+            .packet_sender(stream_desc.stream_type.into());
         PacketEnqueuer {
             peer_address: self.peer_address,
-            stream_id: stream_type.into(),
-            send_mode,
+            stream_id: stream_desc.stream_type.into(),
+            send_mode: stream_desc.send_mode,
             packet_sender,
+            statistics: self.statistics.clone(),
+            _phantom: PhantomData,
         }
     }
 
-    pub fn register_dequeuer(&mut self, stream_type: StreamType) -> PacketDequeuer {
+    pub fn register_dequeuer<S>(&mut self, stream_desc: &StreamDesc<S>) -> PacketDequeuer<S> {
         let (receive_buffer_enqueuer, receive_buffer_dequeuer) = channel();
 
         self.receive_buffer_enqueuers
             .lock()
-            .insert(stream_type.into(), receive_buffer_enqueuer);
+            .insert(stream_desc.stream_type.into(), receive_buffer_enqueuer);
 
         PacketDequeuer {
             receive_buffer_dequeuer,
-            return_buffer_enqueuer: self.return_buffer_enqueuer.clone(),
+            buffer_pool: self.buffer_pool.clone(),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn socket_params(&self) -> &SocketParams {
+        &self.socket_params
+    }
+
+    pub fn statistics(&self) -> ConnectionStatistics {
+        self.statistics.lock().clone()
+    }
+
+    // None if the link probe is disabled or failed
+    pub fn link_quality(&self) -> Option<&LinkQualityDesc> {
+        self.link_quality.as_ref()
+    }
+
+    fn run_link_probe_if_enabled(&mut self, settings: &Settings) {
+        if let Switch::Enabled(desc) = &settings.connection.link_probe {
+            self.link_quality = self
+                .probe_link(desc)
+                .map_err(|e| warn!("Link probe failed: {}", e))
+                .ok();
+
+            // The probe dequeuer has been dropped. Without its entry, late probe packets from the
+            // peer are discarded before being copied into a receive buffer.
+            let stream_id: u8 = StreamType::Probe.into();
+            self.receive_buffer_enqueuers.lock().remove(&stream_id);
         }
     }
 
+    // Spread bursts of video packets across `window`. This should be a fraction of the frame
+    // interval.
+    pub fn set_video_pacing_window(&mut self, window: Option<Duration>) {
+        self.send_scheduler.set_pacing_window(window);
+    }
+
     pub fn enable_debug(&mut self, packet_loss_rate: Option<f64>, latency: Option<Duration>) {
         let mut conditioner = LinkConditioner::new();
 
@@ -297,7 +697,11 @@ impl ConnectionManager {
             conditioner.set_latency(latency);
         }
 
-        self.socket.set_link_conditioner(Some(conditioner));
+        if let Transport::Laminar { socket, .. } = &mut self.transport {
+            socket.set_link_conditioner(Some(conditioner));
+        } else {
+            warn!("Link conditioner not supported with TCP tunnel");
+        }
     }
 
     pub fn connect_to_client(
@@ -322,14 +726,68 @@ impl ConnectionManager {
 
         let server_address =
             SocketAddr::new(LOCAL_IP, handshake_packet.settings.connection.server_port);
-        Self::create_connection_manager(
+
+        let path_max_payload_size = if handshake_packet.settings.connection.path_mtu_discovery {
+            // The client could be using the probed sizes or the default ones, the connection cannot
+            // continue
+            trace_err!(probe_path_mtu(server_address, client_address))?
+        } else {
+            None
+        };
+
+        let mut connection_manager = Self::create_connection_manager(
             server_address,
             client_address,
             socket_config,
+            path_max_payload_size,
             timeout_callback,
-        )
+        )?;
+
+        connection_manager.run_link_probe_if_enabled(&handshake_packet.settings);
+
+        Ok(connection_manager)
+    }
+
+    // The tethered client is found with `search_tethered_client()`
+    pub fn connect_to_tethered_client(
+        mut handshake_stream: TcpStream,
+        socket_config: SocketConfig,
+        handshake_packet: ServerHandshakePacket,
+        timeout_callback: impl FnMut() + Send + 'static,
+    ) -> StrResult<Self> {
+        write_frame(
+            &mut handshake_stream,
+            &mut vec![],
+            &trace_err!(bincode::serialize(&handshake_packet))?,
+        )?;
+
+        let connection = &handshake_packet.settings.connection;
+        let mut connection_manager = if let ConnectionMode::Tethered {
+            transport: TetheredTransport::TcpTunnel,
+        } = connection.mode
+        {
+            Self::create_tunneled_connection_manager(
+                handshake_stream,
+                socket_config,
+                timeout_callback,
+            )?
+        } else {
+            // handshake_stream dropped here
+            Self::create_connection_manager(
+                SocketAddr::new(LOCAL_IP, connection.server_port),
+                SocketAddr::new(LOCALHOST_IP, connection.client_port),
+                socket_config,
+                None,
+                timeout_callback,
+            )?
+        };
+
+        connection_manager.run_link_probe_if_enabled(&handshake_packet.settings);
+
+        Ok(connection_manager)
     }
 
+    // The server can be either found with multicast or, if tethered, on localhost
     pub fn connect_to_server(
         handshake_packet: ClientHandshakePacket,
         timeout_callback: impl FnMut() + Send + 'static,
@@ -363,18 +821,56 @@ impl ConnectionManager {
                 .set_nonblocking(false)
                 .map_err(|err| warn!("Control socket: {}", err))?;
 
-            let server_handshake_packet = bincode::deserialize_from(handshake_receiver)
-                .map_err(|err| warn!("Handshake packet receive: {}", err))?;
+            let server_handshake_packet =
+                deserialize_bounded_from(handshake_receiver, MAX_SERVER_HANDSHAKE_PACKET_SIZE)
+                    .map_err(|err| warn!("Handshake packet receive: {}", err))?;
             // handshake_receiver dropped here. Close TCP connection because it can interfere with
             // Laminar
 
             Ok((address.ip(), server_handshake_packet))
         };
 
+        let try_tethered_handshake = || -> Result<(TcpStream, ServerHandshakePacket), ()> {
+            let mut handshake_stream = TcpStream::connect_timeout(
+                &SocketAddr::new(LOCALHOST_IP, TETHERED_HANDSHAKE_PORT),
+                HANDSHAKE_TIMEOUT,
+            )
+            .map_err(|err| debug!("Tethered handshake: {}", err))?;
+            handshake_stream
+                .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+                .map_err(|err| warn!("Control socket: {}", err))?;
+
+            let mut buffer = vec![];
+            write_frame(&mut handshake_stream, &mut buffer, &client_hanshake_packet)
+                .map_err(|err| warn!("Tethered handshake packet send: {}", err))?;
+            read_frame(
+                &mut handshake_stream,
+                &mut buffer,
+                MAX_SERVER_HANDSHAKE_PACKET_SIZE as _,
+            )
+            .map_err(|err| warn!("Tethered handshake packet receive: {}", err))?;
+            let server_handshake_packet =
+                deserialize_bounded(&buffer, MAX_SERVER_HANDSHAKE_PACKET_SIZE)
+                    .map_err(|err| warn!("Tethered handshake packet receive: {}", err))?;
+
+            Ok((handshake_stream, server_handshake_packet))
+        };
+
         let (server_ip, server_handshake_packet) = loop {
             if let Ok(server_candidate) = try_handshake() {
                 break server_candidate;
             }
+
+            if let Ok((handshake_stream, server_handshake_packet)) = try_tethered_handshake() {
+                let mut connection_manager = Self::connect_tethered_server(
+                    handshake_stream,
+                    &server_handshake_packet,
+                    timeout_callback,
+                )?;
+                connection_manager.run_link_probe_if_enabled(&server_handshake_packet.settings);
+
+                return Ok((connection_manager, server_handshake_packet));
+            }
         };
 
         let client_address = SocketAddr::new(
@@ -386,17 +882,126 @@ impl ConnectionManager {
             server_handshake_packet.settings.connection.server_port,
         );
 
-        let connection_manager = Self::create_connection_manager(
+        let path_max_payload_size = if server_handshake_packet
+            .settings
+            .connection
+            .path_mtu_discovery
+        {
+            respond_to_path_mtu_probe(client_address)
+                .map_err(|e| warn!("Path MTU discovery failed: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        let mut connection_manager = Self::create_connection_manager(
             client_address,
             server_address,
             server_handshake_packet.settings.connection.config.clone(),
+            path_max_payload_size,
             timeout_callback,
         )?;
 
+        connection_manager.run_link_probe_if_enabled(&server_handshake_packet.settings);
+
         Ok((connection_manager, server_handshake_packet))
     }
 
+    fn connect_tethered_server(
+        handshake_stream: TcpStream,
+        server_handshake_packet: &ServerHandshakePacket,
+        timeout_callback: impl FnMut() + Send + 'static,
+    ) -> StrResult<Self> {
+        let connection = &server_handshake_packet.settings.connection;
+        if let ConnectionMode::Tethered {
+            transport: TetheredTransport::TcpTunnel,
+        } = connection.mode
+        {
+            Self::create_tunneled_connection_manager(
+                handshake_stream,
+                connection.config.clone(),
+                timeout_callback,
+            )
+        } else {
+            // handshake_stream dropped here
+            Self::create_connection_manager(
+                SocketAddr::new(LOCAL_IP, connection.client_port),
+                SocketAddr::new(LOCALHOST_IP, connection.server_port),
+                connection.config.clone(),
+                None,
+                timeout_callback,
+            )
+        }
+    }
+
     pub fn request_stop(&mut self) {
-        self.receive_thread.request_stop();
+        self.send_scheduler.request_stop();
+        match &mut self.transport {
+            Transport::Laminar { receive_thread, .. } => receive_thread.request_stop(),
+            Transport::TcpTunnel(tunnel) => tunnel.request_stop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_config() -> SocketConfig {
+        SocketConfig {
+            idle_connection_timeout_ms: None,
+            max_packet_size: None,
+            max_fragments: None,
+            fragment_size: None,
+            fragment_reassembly_buffer_size: None,
+            receive_buffer_max_size: None,
+            rtt_smoothing_factor: None,
+            rtt_max_value: None,
+            socket_event_buffer_size: None,
+            max_packets_in_flight: None,
+        }
+    }
+
+    #[test]
+    fn sizes_follow_path_mtu() {
+        let (config, _) = ConnectionManager::create_config(socket_config(), Some(1400));
+        assert_eq!(
+            config.fragment_size as usize,
+            1400 - LAMINAR_MAX_HEADER_SIZE
+        );
+        assert_eq!(
+            config.max_packet_size,
+            config.fragment_size as usize * config.max_fragments as usize
+        );
+        assert_eq!(config.receive_buffer_max_size, 1400);
+    }
+
+    #[test]
+    fn sizes_follow_overridden_fragment_size() {
+        let socket_config = SocketConfig {
+            fragment_size: Some(1000),
+            ..socket_config()
+        };
+        let (config, _) = ConnectionManager::create_config(socket_config, Some(1400));
+        assert_eq!(config.fragment_size, 1000);
+        assert_eq!(config.max_packet_size, 1000 * config.max_fragments as usize);
+        assert_eq!(
+            config.receive_buffer_max_size,
+            1000 + LAMINAR_MAX_HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn explicit_sizes_take_precedence() {
+        let socket_config = SocketConfig {
+            fragment_size: Some(1000),
+            max_packet_size: Some(20_000),
+            receive_buffer_max_size: Some(4000),
+            ..socket_config()
+        };
+        let (config, socket_params) = ConnectionManager::create_config(socket_config, Some(1400));
+        assert_eq!(config.max_packet_size, 20_000);
+        assert_eq!(config.receive_buffer_max_size, 4000);
+        assert_eq!(socket_params.path_max_payload_size, Some(1400));
     }
 }
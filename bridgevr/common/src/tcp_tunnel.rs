@@ -0,0 +1,113 @@
+use crate::{thread_loop::*, *};
+use laminar::Packet;
+use log::*;
+use std::{
+    io::{Read, Write},
+    net::*,
+    thread,
+    time::Duration,
+};
+
+const TRACE_CONTEXT: &str = "TCP tunnel";
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Frames are prefixed by their size as little endian u32.
+pub fn write_frame(stream: &mut TcpStream, buffer: &mut Vec<u8>, payload: &[u8]) -> StrResult {
+    // Header and payload are copied in a single buffer to send them with one syscall
+    buffer.clear();
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(payload);
+    trace_err!(stream.write_all(buffer))
+}
+
+// The buffer is resized to the size of the frame. Bigger frames are considered corrupted.
+pub fn read_frame(stream: &mut TcpStream, buffer: &mut Vec<u8>, max_size: usize) -> StrResult {
+    let mut header = [0; 4];
+    trace_err!(stream.read_exact(&mut header))?;
+
+    let size = u32::from_le_bytes(header) as usize;
+    if size > max_size {
+        return trace_str!("Frame too big: {} bytes", size);
+    }
+
+    buffer.resize(size, 0);
+    trace_err!(stream.read_exact(buffer))
+}
+
+// Carries the stream packets over a single TCP connection, for when UDP cannot be forwarded. The
+// packets are produced in the same format used by Laminar so the rest of the pipeline is unaware
+// of the transport. Delivery guarantees are ignored since TCP is always reliable and ordered.
+pub struct TcpTunnel {
+    stream: TcpStream,
+    send_thread: ThreadLoop,
+    receive_thread: ThreadLoop,
+}
+
+impl TcpTunnel {
+    pub fn new(
+        stream: TcpStream,
+        packet_receiver: crossbeam_channel::Receiver<Packet>,
+        mut payload_callback: impl FnMut(&[u8]) + Send + 'static,
+        mut disconnected_callback: impl FnMut() + Send + 'static,
+    ) -> StrResult<Self> {
+        trace_err!(stream.set_nodelay(true))?;
+        trace_err!(stream.set_read_timeout(None))?;
+
+        let send_thread = thread_loop::spawn("TCP tunnel send loop", {
+            let mut stream = trace_err!(stream.try_clone())?;
+            let mut buffer = vec![];
+            move || {
+                if let Ok(packet) = packet_receiver.recv_timeout(TIMEOUT) {
+                    write_frame(&mut stream, &mut buffer, packet.payload())
+                        .map_err(|e| debug!("{}", e))
+                        .ok();
+                }
+            }
+        })?;
+
+        let receive_thread = thread_loop::spawn("TCP tunnel receive loop", {
+            let mut stream = trace_err!(stream.try_clone())?;
+            let mut buffer = vec![];
+            let mut connected = true;
+            move || {
+                if connected {
+                    match read_frame(&mut stream, &mut buffer, MAX_FRAME_SIZE) {
+                        Ok(()) => payload_callback(&buffer),
+                        Err(e) => {
+                            debug!("{}", e);
+
+                            // The framing cannot be recovered after an error
+                            connected = false;
+                            disconnected_callback();
+                        }
+                    }
+                } else {
+                    thread::sleep(TIMEOUT);
+                }
+            }
+        })?;
+
+        Ok(Self {
+            stream,
+            send_thread,
+            receive_thread,
+        })
+    }
+
+    pub fn request_stop(&mut self) {
+        self.send_thread.request_stop();
+        self.receive_thread.request_stop();
+
+        // unblock the receive thread
+        self.stream.shutdown(Shutdown::Both).ok();
+    }
+}
+
+impl Drop for TcpTunnel {
+    fn drop(&mut self) {
+        self.request_stop();
+    }
+}
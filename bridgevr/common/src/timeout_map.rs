@@ -1,5 +1,25 @@
-use std::collections::VecDeque;
-use std::time::*;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::*,
+};
+
+// Holes left by removed entries are compacted when they are more than the entries plus this
+const MIN_COMPACTION_HOLE_COUNT: usize = 16;
+
+// Source of the timestamps of the entries. It must be monotonic.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
 
 struct TimedEntry<K, V> {
     key: K,
@@ -7,88 +27,432 @@ struct TimedEntry<K, V> {
     timestamp: Instant,
 }
 
-pub struct TimeoutMap<K, V> {
-    // By not using an HashMap I avoid deriving Eq, Hash, Copy and Clone for K
-    // A VecDeque is used because elements are inserted an removed almost always as FIFO.
-    buffer: VecDeque<TimedEntry<K, V>>,
+// Map whose entries expire after a timeout. Entries are kept in insertion order, which is also the
+// order of their timestamps, so expired entries are always at the front.
+// All operations are O(1) amortized.
+pub struct TimeoutMap<K, V, C = SystemClock> {
+    // Removed entries leave a hole, so the position of the other entries does not change. Holes are
+    // discarded when they reach the front or when there are too many of them.
+    buffer: VecDeque<Option<TimedEntry<K, V>>>,
+
+    // Sequence number of the front of `buffer`. Each entry is at its sequence number minus this.
+    front_sequence: u64,
+
+    // Key -> sequence number
+    index: HashMap<K, u64>,
+
     timeout: Duration,
+
+    // When reached, inserting a new key removes the oldest entry
+    max_len: Option<usize>,
+
+    clock: C,
 }
 
-impl<K, V> TimeoutMap<K, V> {
+impl<K: Hash + Eq + Clone, V> TimeoutMap<K, V> {
     pub fn new(timeout: Duration) -> Self {
+        Self::with_clock(timeout, None, SystemClock)
+    }
+
+    pub fn with_max_len(timeout: Duration, max_len: usize) -> Self {
+        Self::with_clock(timeout, Some(max_len), SystemClock)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> TimeoutMap<K, V, C> {
+    pub fn with_clock(timeout: Duration, max_len: Option<usize>, clock: C) -> Self {
         Self {
             buffer: VecDeque::new(),
+            front_sequence: 0,
+            index: HashMap::new(),
             timeout,
+            max_len,
+            clock,
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        self.buffer.push_back(TimedEntry {
+    fn entry(&self, sequence: u64) -> Option<&TimedEntry<K, V>> {
+        self.buffer
+            .get((sequence - self.front_sequence) as usize)
+            .and_then(Option::as_ref)
+    }
+
+    fn take_entry(&mut self, sequence: u64) -> Option<TimedEntry<K, V>> {
+        let entry = self
+            .buffer
+            .get_mut((sequence - self.front_sequence) as usize)
+            .and_then(Option::take);
+
+        while let Some(None) = self.buffer.front() {
+            self.buffer.pop_front();
+            self.front_sequence += 1;
+        }
+
+        if self.buffer.len() > self.index.len() * 2 + MIN_COMPACTION_HOLE_COUNT {
+            self.compact();
+        }
+
+        entry
+    }
+
+    fn compact(&mut self) {
+        self.buffer.retain(Option::is_some);
+        for (idx, entry) in self.buffer.iter().flatten().enumerate() {
+            self.index
+                .insert(entry.key.clone(), self.front_sequence + idx as u64);
+        }
+    }
+
+    // If the key is already present, its entry is replaced and moved to the back with a new
+    // timestamp. Returns the replaced entry, or the oldest entry if it was removed to respect the
+    // maximum length.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let removed_entry = if let Some(&sequence) = self.index.get(&key) {
+            self.remove_sequence(sequence)
+        } else if matches!(self.max_len, Some(max_len) if self.len() >= max_len) {
+            self.remove_any()
+        } else {
+            None
+        };
+
+        self.index
+            .insert(key.clone(), self.front_sequence + self.buffer.len() as u64);
+        self.buffer.push_back(Some(TimedEntry {
             key,
             value,
-            timestamp: Instant::now(),
-        });
+            timestamp: self.clock.now(),
+        }));
+
+        removed_entry
     }
 
-    pub fn remove_any(&mut self) -> Option<(K, V)> {
+    fn remove_sequence(&mut self, sequence: u64) -> Option<(K, V)> {
+        self.take_entry(sequence).map(|entry| {
+            self.index.remove(&entry.key);
+            (entry.key, entry.value)
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.index
+            .get(key)
+            .and_then(|&sequence| self.entry(sequence))
+            .map(|entry| &entry.value)
+    }
+
+    // Does not refresh the timestamp of the entry
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let sequence = *self.index.get(key)?;
         self.buffer
-            .pop_front()
-            .map(|TimedEntry { key, value, .. }| (key, value))
+            .get_mut((sequence - self.front_sequence) as usize)
+            .and_then(Option::as_mut)
+            .map(|entry| &mut entry.value)
     }
 
-    pub fn remove_expired(&mut self) -> Vec<V> {
-        let max_time = Instant::now() - self.timeout;
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
 
-        let idx_to_be_removed: Vec<_> = self
-            .buffer
-            .iter()
-            .enumerate()
-            .filter(|(_, entry)| entry.timestamp > max_time)
-            .map(|(i, _)| i)
-            .collect();
+    pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
+        let sequence = *self.index.get(key)?;
+        self.remove_sequence(sequence)
+    }
+
+    // Removes the oldest entry
+    pub fn remove_any(&mut self) -> Option<(K, V)> {
+        let sequence = self.front_sequence;
+        self.remove_sequence(sequence)
+    }
+
+    // Removes the entries inserted more than `timeout` ago, oldest first
+    pub fn remove_expired(&mut self) -> Vec<(K, V)> {
+        let now = self.clock.now();
 
         let mut expired = vec![];
-        for idx in idx_to_be_removed {
-            if let Some(entry) = self.buffer.remove(idx) {
-                expired.push(entry.value)
+        while let Some(Some(entry)) = self.buffer.front() {
+            if now.saturating_duration_since(entry.timestamp) <= self.timeout {
+                break;
             }
+            expired.extend(self.remove_any());
         }
 
         expired
     }
 
+    pub fn clear(&mut self) {
+        self.front_sequence += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.index.clear();
+    }
+
+    // Oldest first
     pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
-        self.buffer.iter().map(|entry| (&entry.key, &entry.value))
+        self.buffer
+            .iter()
+            .flatten()
+            .map(|entry| (&entry.key, &entry.value))
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &K> {
-        self.buffer.iter().map(|entry| &entry.key)
+        self.iter().map(|(key, _)| key)
     }
 
     pub fn len(&self) -> usize {
-        self.buffer.len()
+        self.index.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.buffer.is_empty()
+        self.index.is_empty()
     }
 }
 
-impl<K: PartialEq, V> TimeoutMap<K, V> {
-    pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
-        // front to back iterator
-        let entry = self
-            .buffer
-            .iter()
-            .enumerate()
-            .find(|(_, entry)| entry.key == *key);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, fmt::Debug, rc::Rc};
 
-        if let Some((idx, _)) = entry {
-            self.buffer
-                .remove(idx)
-                .map(|entry| (entry.key, entry.value))
+    const SEED_COUNT: u32 = 500;
+    const OPERATION_COUNT: usize = 2000;
+
+    // A small key space makes replacements and removals of present keys frequent
+    const KEY_COUNT: u64 = 32;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[derive(Clone)]
+    struct SimulatedClock(Rc<Cell<Instant>>);
+
+    impl Clock for SimulatedClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    // Same semantics as `TimeoutMap`, with linear scans
+    struct Model {
+        entries: Vec<(u64, u32, Instant)>,
+        max_len: Option<usize>,
+    }
+
+    impl Model {
+        fn insert(&mut self, key: u64, value: u32, now: Instant) -> Option<(u64, u32)> {
+            let removed = if let Some(idx) = self.entries.iter().position(|(k, ..)| *k == key) {
+                Some(self.entries.remove(idx))
+            } else if matches!(self.max_len, Some(max_len) if self.entries.len() >= max_len) {
+                Some(self.entries.remove(0))
+            } else {
+                None
+            };
+            self.entries.push((key, value, now));
+
+            removed.map(|(k, v, _)| (k, v))
+        }
+
+        fn remove(&mut self, key: u64) -> Option<(u64, u32)> {
+            let idx = self.entries.iter().position(|(k, ..)| *k == key)?;
+            let (k, v, _) = self.entries.remove(idx);
+            Some((k, v))
+        }
+
+        fn remove_any(&mut self) -> Option<(u64, u32)> {
+            if self.entries.is_empty() {
+                None
+            } else {
+                let (k, v, _) = self.entries.remove(0);
+                Some((k, v))
+            }
+        }
+
+        // Does not rely on the entries being sorted by timestamp
+        fn remove_expired(&mut self, now: Instant) -> Vec<(u64, u32)> {
+            let (expired, alive) = self
+                .entries
+                .drain(..)
+                .partition(|(_, _, timestamp)| now - *timestamp > TIMEOUT);
+            self.entries = alive;
+
+            expired.into_iter().map(|(k, v, _)| (k, v)).collect()
+        }
+
+        fn get(&self, key: u64) -> Option<u32> {
+            self.entries
+                .iter()
+                .find(|(k, ..)| *k == key)
+                .map(|(_, v, _)| *v)
+        }
+    }
+
+    // xorshift32
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    fn compare<T: PartialEq + Debug>(expected: T, actual: T) -> Result<(), String> {
+        if expected == actual {
+            Ok(())
         } else {
-            None
+            Err(format!("expected {:?}, got {:?}", expected, actual))
+        }
+    }
+
+    // Runs a random sequence of operations on `TimeoutMap` and on the model, with a simulated
+    // clock, and compares the results after each operation
+    fn run_model(seed: u32) -> Result<(), String> {
+        let mut random_state = seed;
+        let clock = SimulatedClock(Rc::new(Cell::new(Instant::now())));
+        let max_len = match next_random(&mut random_state) % 3 {
+            0 => None,
+            _ => Some(1 + next_random(&mut random_state) as usize % KEY_COUNT as usize),
+        };
+
+        let mut map = TimeoutMap::with_clock(TIMEOUT, max_len, clock.clone());
+        let mut model = Model {
+            entries: vec![],
+            max_len,
+        };
+
+        for operation_idx in 0..OPERATION_COUNT {
+            let key = next_random(&mut random_state) as u64 % KEY_COUNT;
+            let operation = next_random(&mut random_state) % 100;
+            let now = clock.now();
+
+            let (name, result) = match operation {
+                0..=39 => {
+                    let value = next_random(&mut random_state);
+                    (
+                        format!("insert({}, {})", key, value),
+                        compare(model.insert(key, value, now), map.insert(key, value)),
+                    )
+                }
+                40..=59 => (
+                    format!("remove({})", key),
+                    compare(model.remove(key), map.remove(&key)),
+                ),
+                60..=64 => (
+                    "remove_any()".into(),
+                    compare(model.remove_any(), map.remove_any()),
+                ),
+                65..=74 => (
+                    "remove_expired()".into(),
+                    compare(model.remove_expired(now), map.remove_expired()),
+                ),
+                75..=84 => (
+                    format!("get({})", key),
+                    compare(model.get(key), map.get(&key).copied())
+                        .and(compare(
+                            model.get(key),
+                            map.get_mut(&key).map(|value| *value),
+                        ))
+                        .and(compare(model.get(key).is_some(), map.contains_key(&key))),
+                ),
+                85..=85 => {
+                    model.entries.clear();
+                    map.clear();
+                    ("clear()".into(), Ok(()))
+                }
+                _ => {
+                    let step = Duration::from_millis(next_random(&mut random_state) as u64 % 30);
+                    clock.0.set(now + step);
+                    (format!("advance({:?})", step), Ok(()))
+                }
+            };
+
+            let result = result
+                .and(compare(model.entries.len(), map.len()))
+                .and(compare(model.entries.is_empty(), map.is_empty()))
+                .and(compare(
+                    model
+                        .entries
+                        .iter()
+                        .map(|(k, v, _)| (*k, *v))
+                        .collect::<Vec<_>>(),
+                    map.iter().map(|(k, v)| (*k, *v)).collect(),
+                ))
+                .and(compare(
+                    model.entries.iter().map(|(k, ..)| *k).collect::<Vec<_>>(),
+                    map.keys().copied().collect(),
+                ));
+
+            if let Err(e) = result {
+                return Err(format!(
+                    "seed {}, operation {} {}: {}",
+                    seed, operation_idx, name, e
+                ));
+            }
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_model() {
+        let failures: Vec<_> = (1..=SEED_COUNT)
+            .filter_map(|seed| run_model(seed).err())
+            .collect();
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn expires_after_timeout() {
+        let clock = SimulatedClock(Rc::new(Cell::new(Instant::now())));
+        let mut map = TimeoutMap::with_clock(TIMEOUT, None, clock.clone());
+
+        map.insert(1, "a");
+        clock.0.set(clock.now() + TIMEOUT / 2);
+        map.insert(2, "b");
+
+        clock.0.set(clock.now() + TIMEOUT / 2);
+        assert!(map.remove_expired().is_empty());
+
+        clock.0.set(clock.now() + Duration::from_millis(1));
+        assert_eq!(map.remove_expired(), vec![(1, "a")]);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    #[test]
+    fn reinsertion_refreshes_timestamp() {
+        let clock = SimulatedClock(Rc::new(Cell::new(Instant::now())));
+        let mut map = TimeoutMap::with_clock(TIMEOUT, None, clock.clone());
+
+        map.insert(1, "a");
+        map.insert(2, "b");
+        clock.0.set(clock.now() + TIMEOUT);
+        assert_eq!(map.insert(1, "c"), Some((1, "a")));
+
+        clock.0.set(clock.now() + Duration::from_millis(1));
+        assert_eq!(map.remove_expired(), vec![(2, "b")]);
+        assert_eq!(map.get(&1), Some(&"c"));
+    }
+
+    #[test]
+    fn max_len_removes_oldest() {
+        let mut map = TimeoutMap::with_max_len(TIMEOUT, 2);
+
+        assert_eq!(map.insert(1, "a"), None);
+        assert_eq!(map.insert(2, "b"), None);
+        assert_eq!(map.insert(3, "c"), Some((1, "a")));
+        assert_eq!(map.len(), 2);
+        assert!(!map.contains_key(&1));
+    }
+
+    #[test]
+    fn compaction_keeps_positions() {
+        let mut map = TimeoutMap::new(TIMEOUT);
+        for key in 0..100 {
+            map.insert(key, key * 10);
+        }
+
+        // Removing from the middle leaves holes until they are compacted
+        for key in 1..99 {
+            assert_eq!(map.remove(&key), Some((key, key * 10)));
+        }
+
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&99), Some(&990));
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(&0, &0), (&99, &990)]);
     }
 }
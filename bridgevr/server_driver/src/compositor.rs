@@ -1,10 +1,12 @@
 // #![allow(clippy::type_complexity)]
 
-// use crate::video_encoder::aligned_resolution;
+// use crate::{statistics::StatisticsAggregator, video_encoder::aligned_resolution};
 // use bridgevr_common::{
+//     av_sync::Timebase,
 //     data::*,
 //     ffr::*,
 //     frame_slices::*,
+//     frame_trace::FrameTraceRecorder,
 //     graphics::*,
 //     thread_loop::{self, ThreadLoop},
 //     *,
@@ -14,8 +16,12 @@
 // use std::{
 //     collections::{hash_map::*, VecDeque},
 //     ops::RangeFrom,
-//     sync::{mpsc::*, Arc},
-//     time::Duration,
+//     sync::{
+//         atomic::{AtomicBool, Ordering},
+//         mpsc::*,
+//         Arc,
+//     },
+//     time::{Duration, Instant},
 // };
 
 // const TRACE_CONTEXT: &str = "Compositor";
@@ -52,10 +58,14 @@
 //     pub texture: Arc<Texture>,
 //     pub pose: Pose,
 //     pub force_idr: bool,
+//     pub present_time: Instant,
+//     // Present time in the timebase shared with the game audio recorder
+//     pub capture_timestamp_ns: u64,
 // }
 
 // pub struct PresentData {
 //     pub frame_index: u64,
+//     pub present_time: Instant,
 //     pub layers: Vec<([(Arc<Texture>, TextureBounds); 2], Pose)>,
 //     pub sync_texture: Arc<Texture>,
 //     pub force_idr_slice_idxs: Vec<usize>,
@@ -179,6 +189,11 @@
 //         present_done_notif_sender: Sender<()>,
 //         slice_senders: Vec<Sender<FrameSlice>>,
 //         slice_encoded_notif_receivers: Vec<Receiver<()>>,
+//         timebase: Timebase,
+//         frame_trace_recorder: Option<FrameTraceRecorder>,
+//         statistics: StatisticsAggregator,
+//         // Set to force an IDR frame on all the slices of the next frame
+//         idr_request: Arc<AtomicBool>,
 //     ) -> StrResult<Self> {
 //         let CompositorDesc {
 //             target_eye_resolution,
@@ -259,6 +274,8 @@
 
 //         let render = move |layers_buffers_history: &mut Vec<_>| -> StrResult {
 //             let present_data = trace_err!(present_receiver.recv_timeout(TIMEOUT))?;
+//             let composition_start = Instant::now();
+//             statistics.report_present();
 
 //             let graphics = present_data.sync_texture.graphics();
 
@@ -321,15 +338,36 @@
 
 //             rendering_operation_buffer.execute();
 
+//             if let Some(recorder) = &frame_trace_recorder {
+//                 let frame_index = present_data.frame_index;
+//                 recorder.record_instant(
+//                     frame_index,
+//                     FrameStage::Present,
+//                     None,
+//                     present_data.present_time,
+//                 );
+//                 recorder.record(
+//                     frame_index,
+//                     FrameStage::Composition,
+//                     None,
+//                     composition_start,
+//                     Instant::now(),
+//                 );
+//             }
+
 //             // Improvement: use pose to do reprojection
 //             let pose = present_data.layers[0].1;
 
+//             let force_idr_all = idr_request.swap(false, Ordering::Relaxed);
 //             for (idx, sender) in slice_senders.iter().enumerate() {
 //                 trace_err!(sender.send(FrameSlice {
 //                     frame_index: present_data.frame_index,
 //                     texture: slice_textures[idx].clone(),
 //                     pose,
-//                     force_idr: present_data.force_idr_slice_idxs.contains(&idx),
+//                     force_idr: force_idr_all
+//                         || present_data.force_idr_slice_idxs.contains(&idx),
+//                     present_time: present_data.present_time,
+//                     capture_timestamp_ns: timebase.timestamp_ns(present_data.present_time),
 //                 }))?
 //             }
 
@@ -64,9 +64,34 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //         .unwrap_or(TIMEOUT);
     //     let mut deadline = Instant::now() + timeout;
 
+    //     let ipc_server = ipc::IpcServer::start(ipc::DRIVER_IPC_NAME)?;
+    //     let ipc_publisher = ipc_server.publisher();
+    //     logging_backend::set_log_publisher(ipc_publisher.clone());
+
+    //     // Latest statistics snapshot of the connected client, None while disconnected
+    //     let latest_statistics = Arc::new(Mutex::new(None));
+
+    //     // Started once, like the IPC server, so scrapers do not see the endpoint disappear between
+    //     // connections. Not fatal, the port could be taken by another application.
+    //     let mut maybe_metrics_server = match maybe_settings
+    //         .as_ref()
+    //         .map(|settings| &settings.vr_server.metrics_endpoint)
+    //     {
+    //         Some(Switch::Enabled(desc)) => MetricsServer::start(desc.port, {
+    //             let latest_statistics = latest_statistics.clone();
+    //             move || latest_statistics.lock().clone()
+    //         })
+    //         .map_err(|e| warn!("{}", e))
+    //         .ok(),
+    //         _ => None,
+    //     };
+
     //     let try_connect = {
     //         let vr_server = vr_server.clone();
     //         move |shutdown_signal_receiver: &Receiver<ShutdownSignal>| -> StrResult<ShutdownSignal> {
+    //             ipc_publisher.publish(&DriverEvent::ConnectionState(ConnectionState::Searching));
+    //             *latest_statistics.lock() = None;
+
     //             let settings = if let Ok(settings) = get_settings() {
     //                 settings
     //             } else {
@@ -106,10 +131,49 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 FrameSize::Absolute(width, height) => (*width, *height),
     //             };
 
+    //             let game_audio_config = match &settings.game_audio {
+    //                 Switch::Enabled(desc) => {
+    //                     let capabilities = query_audio_device(
+    //                         desc.input_device.as_deref(),
+    //                         AudioMode::Loopback,
+    //                     )?;
+    //                     Some(negotiate_audio_config(
+    //                         desc,
+    //                         &[client_handshake_packet.preferred_audio_player_sample_rates],
+    //                         &client_handshake_packet.available_audio_player_sample_rates,
+    //                         capabilities.default_channel_count,
+    //                         &client_handshake_packet.available_audio_player_channel_counts,
+    //                     )?)
+    //                 }
+    //                 Switch::Disabled => None,
+    //             };
+
+    //             let microphone_config = match &settings.microphone {
+    //                 Switch::Enabled(desc) => {
+    //                     // Voice is mono, there is no device to match when writing to a file
+    //                     let channel_count = if desc.output_file.is_some() {
+    //                         1
+    //                     } else {
+    //                         query_audio_device(desc.output_device.as_deref(), AudioMode::Output)?
+    //                             .default_channel_count
+    //                     };
+    //                     Some(negotiate_audio_config(
+    //                         desc,
+    //                         &client_handshake_packet.preferred_microphone_sample_rates,
+    //                         &client_handshake_packet.available_microphone_sample_rates,
+    //                         channel_count,
+    //                         &client_handshake_packet.available_microphone_channel_counts,
+    //                     )?)
+    //                 }
+    //                 Switch::Disabled => None,
+    //             };
+
     //             let server_handshake_packet = ServerHandshakePacket {
     //                 config: ServerConfig {
     //                     version: BVR_VERSION_SERVER,
     //                     target_eye_resolution,
+    //                     game_audio_config,
+    //                     microphone_config,
     //                 },
     //                 settings: settings.clone(),
     //             };
@@ -130,6 +194,46 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 },
     //             )?;
 
+    //             if let Some(link_quality) = connection_manager.link_quality() {
+    //                 if let Switch::Enabled(desc) = &settings.connection.link_probe {
+    //                     let mut session_desc_loader = session_desc_loader.lock();
+    //                     let session_desc = session_desc_loader.get_mut();
+    //                     session_desc.bitrate =
+    //                         link_quality.suggested_send_bitrate(desc.starting_bitrate_fraction);
+    //                     session_desc.last_link_quality = Some(link_quality.clone());
+    //                     session_desc_loader.save().map_err(|e| warn!("{}", e)).ok();
+    //                 }
+    //             }
+
+    //             if let Switch::Enabled(desc) = &settings.connection.video_pacing {
+    //                 connection_manager.set_video_pacing_window(Some(Duration::from_secs_f32(
+    //                     desc.frame_interval_fraction / client_handshake_packet.fps as f32,
+    //                 )));
+    //             }
+
+    //             // Shared by the captured streams, so the client can align the audio with the video
+    //             let timebase = Timebase::new();
+
+    //             let (maybe_frame_trace_recorder, mut maybe_frame_trace_writer) =
+    //                 match &settings.video.frame_trace {
+    //                     Switch::Enabled(desc) => {
+    //                         let rtt = connection_manager
+    //                             .link_quality()
+    //                             .map(|quality| Duration::from_secs_f32(quality.rtt_ms / 1000.));
+    //                         (
+    //                             Some(FrameTraceRecorder::new(timebase)),
+    //                             Some(FrameTraceWriter::create(
+    //                                 &Path::new(env!("INSTALL_ROOT")).join(&desc.output_file),
+    //                                 desc.max_frame_count,
+    //                                 rtt,
+    //                             )?),
+    //                         )
+    //                     }
+    //                     Switch::Disabled => (None, None),
+    //                 };
+
+    //             let statistics = StatisticsAggregator::new(STATISTICS_MAX_INTERVAL);
+
     //             let (present_sender, present_receiver) = channel();
     //             let (present_done_notif_sender, present_done_notif_receiver) = channel();
 
@@ -144,6 +248,8 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 slice_interop_encoders.push((slice_receiver, slice_encoded_notif_sender));
     //             }
 
+    //             let idr_request = Arc::new(atomic::AtomicBool::new(false));
+
     //             let mut compositor = Compositor::new(
     //                 graphics.clone(),
     //                 CompositorDesc {
@@ -155,6 +261,10 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 present_done_notif_sender,
     //                 slice_senders,
     //                 slice_encoded_notif_receivers,
+    //                 timebase,
+    //                 maybe_frame_trace_recorder.clone(),
+    //                 statistics.clone(),
+    //                 idr_request.clone(),
     //             )?;
 
     //             let video_encoder_resolution = compositor.encoder_resolution();
@@ -169,54 +279,66 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                     SendMode::UnreliableSequential
     //                 };
     //                 let packet_enqueuer = connection_manager
-    //                     .register_enqueuer(StreamType::VideoSlice(idx as _), send_mode);
+    //                     .register_enqueuer(&StreamDesc::video_slice(idx as _, send_mode));
 
     //                 video_encoders.push(VideoEncoder::new(
     //                     &format!("Video encoder loop {}", idx),
+    //                     idx as _,
     //                     settings.video.encoder.clone(),
     //                     video_encoder_resolution,
     //                     client_handshake_packet.fps,
     //                     slice_receiver,
     //                     slice_encoded_notif_sender,
     //                     packet_enqueuer,
+    //                     maybe_frame_trace_recorder.clone(),
+    //                     statistics.clone(),
     //                 )?);
     //             }
 
-    //             let mut maybe_game_audio_recorder = match &settings.game_audio {
-    //                 Switch::Enabled(desc) => {
+    //             let mut maybe_game_audio_recorder = match (&settings.game_audio, game_audio_config) {
+    //                 (Switch::Enabled(desc), Some(config)) => {
     //                     let send_mode = if desc.reliable {
     //                         SendMode::ReliableOrdered
     //                     } else {
     //                         SendMode::UnreliableSequential
     //                     };
     //                     let packet_enqueuer =
-    //                         connection_manager.register_enqueuer(StreamType::GameAudio, send_mode);
+    //                         connection_manager.register_enqueuer(&StreamDesc::game_audio(send_mode));
 
     //                     Some(AudioRecorder::start_recording(
-    //                         desc.input_device_index,
+    //                         desc.input_device.clone(),
     //                         true,
+    //                         config,
+    //                         &desc.processing,
+    //                         // Loopback recording does not pick up any echo. Echo cancellation
+    //                         // runs on the client, where the microphone is.
+    //                         None,
+    //                         timebase,
     //                         packet_enqueuer,
     //                     )?)
     //                 }
-    //                 Switch::Disabled => None,
+    //                 _ => None,
     //             };
 
-    //             let mut maybe_microphone_player = match &settings.microphone {
-    //                 Switch::Enabled(desc) => {
-    //                     let packet_dequeuer =
-    //                         connection_manager.register_dequeuer(StreamType::Microphone);
+    //             let mut maybe_microphone_player = match (&settings.microphone, microphone_config) {
+    //                 (Switch::Enabled(desc), Some(config)) => {
+    //                     let packet_dequeuer = connection_manager
+    //                         .register_dequeuer(&StreamDesc::microphone(SendMode::UnreliableSequential));
 
     //                     Some(AudioPlayer::start_playback(
-    //                         desc.output_device_index,
+    //                         AudioSink::from_desc(desc),
+    //                         config,
     //                         desc.buffering_latency.clone(),
+    //                         None,
+    //                         None,
     //                         packet_dequeuer,
     //                     )?)
     //                 }
-    //                 Switch::Disabled => None,
+    //                 _ => None,
     //             };
 
     //             let haptic_enqueuer = connection_manager
-    //                 .register_enqueuer(StreamType::Other, SendMode::UnreliableUnordered);
+    //                 .register_enqueuer(&StreamDesc::other_server(SendMode::UnreliableUnordered));
 
     //             vr_server.lock().initialize_for_client_or_request_restart(
     //                 &settings,
@@ -226,10 +348,23 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 haptic_enqueuer,
     //             )?;
 
-    //             let mut other_packet_dequeuer = connection_manager.register_dequeuer(StreamType::Other);
+    //             let mut other_packet_dequeuer = connection_manager
+    //                 .register_dequeuer(&StreamDesc::other_client(SendMode::UnreliableUnordered));
+
+    //             ipc_publisher.publish(&DriverEvent::ConnectionState(ConnectionState::Connected(
+    //                 Box::new(ConnectedClientDesc {
+    //                     address: found_client_ip,
+    //                     handshake_packet: client_handshake_packet.clone(),
+    //                     target_eye_resolution,
+    //                 }),
+    //             )));
+
+    //             // Commands sent while no client was connected are stale
+    //             while ipc_server.try_recv_command().is_some() {}
+
     //             let shutdown_signal = loop {
     //                 if let Ok(packet) = other_packet_dequeuer.dequeue(STATISTICS_MAX_INTERVAL) {
-    //                     match packet.get::<OtherClientPacket>() {
+    //                     match packet.get() {
     //                         Ok(OtherClientPacket::MotionAndTiming {
     //                             device_motions,
     //                             virtual_vsync_offset_ns,
@@ -249,14 +384,26 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                                     sample_6dof,
     //                                     device_motion.timestamp_ns,
     //                                 );
+    //                                 statistics.report_motion(device_motion.device_type);
     //                             }
     //                             vr_server.update_virtual_vsync(virtual_vsync_offset_ns);
     //                         }
     //                         Ok(OtherClientPacket::InputDeviceData { data, timestamp_ns }) => {
     //                             vr_server.lock().process_input(data, timestamp_ns)
     //                         }
-    //                         Ok(OtherClientPacket::Statistics(_)) => {
-    //                             log_statistics(); // todo
+    //                         Ok(OtherClientPacket::Statistics(client_statistics)) => {
+    //                             statistics.update_client(client_statistics)
+    //                         }
+    //                         Ok(OtherClientPacket::FrameTrace { nal_index, spans }) => {
+    //                             if let (Some(recorder), Some(writer)) =
+    //                                 (&maybe_frame_trace_recorder, &mut maybe_frame_trace_writer)
+    //                             {
+    //                                 let server_spans = recorder.take(nal_index).unwrap_or_default();
+    //                                 writer
+    //                                     .write_frame(nal_index, &server_spans, &spans)
+    //                                     .map_err(|e| warn!("{}", e))
+    //                                     .ok();
+    //                             }
     //                         }
     //                         Ok(OtherClientPacket::Disconnected) => {
     //                             break ShutdownSignal::ClientDisconnected
@@ -265,6 +412,40 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                     }
     //                 }
 
+    //                 statistics.update_connection(connection_manager.statistics());
+    //                 if let Some(player) = &maybe_microphone_player {
+    //                     statistics.update_microphone(player.statistics());
+    //                 }
+    //                 if let Some(snapshot) = statistics.update() {
+    //                     *latest_statistics.lock() = Some(snapshot.clone());
+    //                     ipc_publisher.publish(&DriverEvent::Statistics(Box::new(snapshot)));
+    //                 }
+
+    //                 match ipc_server.try_recv_command() {
+    //                     // Settings are read again on the next connection
+    //                     Some(DriverCommand::DisconnectClient)
+    //                     | Some(DriverCommand::ReloadSettings) => {
+    //                         break ShutdownSignal::ClientDisconnected
+    //                     }
+    //                     Some(DriverCommand::ForceIdr) => {
+    //                         idr_request.store(true, atomic::Ordering::Relaxed)
+    //                     }
+    //                     Some(DriverCommand::Recenter) => vr_server.lock().recenter(),
+    //                     None => (),
+    //                 }
+
+    //                 // Frames whose client report was lost
+    //                 if let (Some(recorder), Some(writer)) =
+    //                     (&maybe_frame_trace_recorder, &mut maybe_frame_trace_writer)
+    //                 {
+    //                     for (nal_index, server_spans) in recorder.take_expired() {
+    //                         writer
+    //                             .write_frame(nal_index, &server_spans, &[])
+    //                             .map_err(|e| warn!("{}", e))
+    //                             .ok();
+    //                     }
+    //                 }
+
     //                 match shutdown_signal_receiver.try_recv() {
     //                     Ok(signal) => break signal,
     //                     Err(TryRecvError::Disconnected) => break ShutdownSignal::BackendShutdown,
@@ -273,8 +454,8 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //             };
 
     //             connection_manager
-    //                 .register_enqueuer(StreamType::Other, SendMode::ReliableUnordered)
-    //                 .enqueue(&())
+    //                 .register_enqueuer(&StreamDesc::other_server(SendMode::ReliableUnordered))
+    //                 .enqueue(&OtherServerPacket::Shutdown)
     //                 .ok();
 
     //             connection_manager.request_stop();
@@ -292,25 +473,35 @@ fn begin_server_loop(// graphics: Arc<GraphicsContext>,
     //                 player.request_stop();
     //             }
 
+    //             *latest_statistics.lock() = None;
+
     //             Ok(shutdown_signal)
     //         }
     //     };
 
     //     trace_err!(thread::Builder::new()
     //         .name("Connection/statistics loop".into())
-    //         .spawn(move || while Instant::now() < deadline {
-    //             match show_err!(try_connect(&shutdown_signal_receiver)) {
-    //                 Ok(ShutdownSignal::ClientDisconnected) => deadline = Instant::now() + timeout,
-    //                 Ok(ShutdownSignal::BackendShutdown) => break,
-    //                 Err(()) => {
-    //                     if let Ok(ShutdownSignal::BackendShutdown) | Err(TryRecvError::Disconnected) =
-    //                         shutdown_signal_receiver.try_recv()
-    //                     {
-    //                         break;
+    //         .spawn(move || {
+    //             while Instant::now() < deadline {
+    //                 match show_err!(try_connect(&shutdown_signal_receiver)) {
+    //                     Ok(ShutdownSignal::ClientDisconnected) => {
+    //                         deadline = Instant::now() + timeout
+    //                     }
+    //                     Ok(ShutdownSignal::BackendShutdown) => break,
+    //                     Err(()) => {
+    //                         if let Ok(ShutdownSignal::BackendShutdown)
+    //                         | Err(TryRecvError::Disconnected) = shutdown_signal_receiver.try_recv()
+    //                         {
+    //                             break;
+    //                         }
     //                     }
     //                 }
+    //                 vr_server.lock().deinitialize_for_client();
+    //             }
+
+    //             if let Some(server) = &mut maybe_metrics_server {
+    //                 server.request_stop();
     //             }
-    //             vr_server.lock().deinitialize_for_client();
     //         })
     //         .map(|_| ()))
 
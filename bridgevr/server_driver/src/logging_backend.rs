@@ -1,8 +1,29 @@
+use bridgevr_common::{data::*, ipc::IpcPublisher};
+use lazy_static::lazy_static;
 use log::*;
+use parking_lot::Mutex;
 use std::{path::Path, sync::Once};
 
 static INIT_LOGGING_ENTRY_POINT: Once = Once::new();
 
+lazy_static! {
+    static ref LOG_PUBLISHER: Mutex<Option<IpcPublisher>> = Mutex::new(None);
+}
+
+// Log records are also sent to the connected GUIs, from this point on
+pub fn set_log_publisher(publisher: IpcPublisher) {
+    *LOG_PUBLISHER.lock() = Some(publisher);
+}
+
+fn publish_log(record: &Record) {
+    if let Some(publisher) = &*LOG_PUBLISHER.lock() {
+        publisher.publish(&DriverEvent::Log {
+            level: record.level().into(),
+            message: record.args().to_string(),
+        });
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn show_error_message_box(_: &str, message_with_intro: &str) {
     use gtk::*;
@@ -32,7 +53,7 @@ pub fn init_logging() {
     // startup, init_logging will be called a second time on the same process. To ensure that
     // logging initialization happens only once, use an Once object.
     INIT_LOGGING_ENTRY_POINT.call_once(|| {
-        if cfg!(debug_assertions) {
+        let file_dispatch = if cfg!(debug_assertions) {
             fern::Dispatch::new()
                 .format(|out, message, record| {
                     out.finish(format_args!(
@@ -58,9 +79,18 @@ pub fn init_logging() {
                 })
                 .level(LevelFilter::Info)
         }
-        .chain(fern::log_file(Path::new(env!("INSTALL_ROOT")).join("log.txt")).unwrap())
-        .apply()
-        .unwrap();
+        .chain(fern::log_file(Path::new(env!("INSTALL_ROOT")).join("log.txt")).unwrap());
+
+        // The GUI gets the unformatted messages
+        let gui_dispatch = fern::Dispatch::new()
+            .level(LevelFilter::Info)
+            .chain(fern::Output::call(publish_log));
+
+        fern::Dispatch::new()
+            .chain(file_dispatch)
+            .chain(gui_dispatch)
+            .apply()
+            .unwrap();
 
         fn log_error_fn(message: &str) {
             show_error_message_box("BridgeVR crashed", &message);
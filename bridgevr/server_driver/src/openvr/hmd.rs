@@ -295,6 +295,7 @@ unsafe impl Sync for HmdContext {}
 //                 .present_sender
 //                 .send(PresentData {
 //                     frame_index,
+//                     present_time: Instant::now(),
 //                     layers,
 //                     sync_texture,
 //                     force_idr_slice_idxs: vec![], // todo
@@ -496,6 +497,7 @@ unsafe impl Sync for HmdContext {}
 //                 .present_sender
 //                 .send(PresentData {
 //                     frame_index,
+//                     present_time: Instant::now(),
 //                     layers,
 //                     sync_texture,
 //                     force_idr_slice_idxs: vec![], // todo
@@ -61,7 +61,7 @@ struct ServerContext {
     // settings: Arc<Mutex<OpenvrSettings>>,
     tracked_devices_ptrs: Vec<(TrackedDeviceType, *mut vr::TrackedDeviceServerDriver)>,
     // tracked_devices_contexts: Vec<(TrackedDeviceType, Arc<TrackedDeviceContext>)>,
-    // haptic_enqueuer: Mutex<Option<PacketEnqueuer>>,
+    // haptic_enqueuer: Mutex<Option<PacketEnqueuer<OtherServerStream>>>,
     // shutdown_signal_sender: Arc<Mutex<Sender<ShutdownSignal>>>,
 }
 
@@ -195,6 +195,9 @@ pub struct VrServer {
     // // input_thread: Option<ThreadLoop>,
     // input_timer: Instant,
     // controllers_contexts: Vec<Arc<TrackedDeviceContext>>,
+    // latest_hmd_pose: Option<Pose>,
+    // // Applied to the motion of all the tracked devices. Set by `recenter()`.
+    // recenter_transform: nalgebra::Isometry3<f32>,
 }
 
 unsafe impl Send for VrServer {}
@@ -316,6 +319,8 @@ impl VrServer {
             // tracked_devices_contexts: tracked_devices_contexts.into_iter().collect(),
             // input_timer: Instant::now(),
             // controllers_contexts,
+            // latest_hmd_pose: None,
+            // recenter_transform: nalgebra::Isometry3::identity(),
         }
     }
 
@@ -325,6 +330,11 @@ impl VrServer {
     //     sample: MotionSample6DofDesc,
     //     timestamp_ns: u64,
     // ) {
+    //     if device_type == TrackedDeviceType::HMD {
+    //         self.latest_hmd_pose = Some(sample.pose);
+    //     }
+    //     let sample = self.recenter_motion(sample);
+
     //     let pose_timestamp_ns = timestamp_ns as i64;
 
     //     let server_elapsed_ns = self.input_timer.elapsed().as_nanos() as i64;
@@ -370,6 +380,47 @@ impl VrServer {
     //     }
     // }
 
+    // fn recenter_motion(&self, sample: MotionSample6DofDesc) -> MotionSample6DofDesc {
+    //     use nalgebra::{Point3, Quaternion, UnitQuaternion, Vector3};
+
+    //     let rotation = self.recenter_transform.rotation;
+    //     let [w, x, y, z] = sample.pose.orientation;
+    //     let orientation = rotation * UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+    //     let position = self.recenter_transform * Point3::from(Vector3::from(sample.pose.position));
+
+    //     MotionSample6DofDesc {
+    //         pose: Pose {
+    //             position: position.coords.into(),
+    //             orientation: [orientation.w, orientation.i, orientation.j, orientation.k],
+    //         },
+    //         linear_velocity: (rotation * Vector3::from(sample.linear_velocity)).into(),
+    //         angular_velocity: (rotation * Vector3::from(sample.angular_velocity)).into(),
+    //     }
+    // }
+
+    // // The heading of the HMD becomes the forward direction and its position projected on the
+    // // floor the origin. Pitch and roll are not changed, so the floor stays level.
+    // pub fn recenter(&mut self) {
+    //     use nalgebra::{Isometry3, Quaternion, UnitQuaternion, Vector3};
+
+    //     if let Some(pose) = &self.latest_hmd_pose {
+    //         let [w, x, y, z] = pose.orientation;
+    //         let orientation = UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+
+    //         // OpenVR forward direction is -Z
+    //         let forward = orientation * -Vector3::z();
+    //         let yaw = (-forward.x).atan2(-forward.z);
+    //         let rotation = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -yaw);
+
+    //         let [x, _, z] = pose.position;
+    //         let translation = rotation * -Vector3::new(x, 0., z);
+
+    //         self.recenter_transform = Isometry3::from_parts(translation.into(), rotation);
+    //     } else {
+    //         warn!("Cannot recenter before the HMD is tracked");
+    //     }
+    // }
+
     // pub fn update_virtual_vsync(&mut self, virtual_vsync_offset_ns: i32) {
     //     if let Some(hmd_context) = &self.hmd_context {
     //         let (vsync, _) = &mut *hmd_context.latest_vsync.lock();
@@ -428,7 +479,7 @@ impl VrServer {
     //     session_desc: &SessionDesc,
     //     present_sender: Sender<PresentData>,
     //     present_done_notif_receiver: Receiver<()>,
-    //     haptic_enqueuer: PacketEnqueuer,
+    //     haptic_enqueuer: PacketEnqueuer<OtherServerStream>,
     // ) -> StrResult {
     //     // the same openvr settings instance is shared between hmd, controllers and server.
     //     let new_settings = create_openvr_settings(Some(settings), session_desc);
@@ -1,4 +1,296 @@
+use bridgevr_common::data::*;
+use log::*;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc, time::*};
 
-pub fn log_statistics() {
+const EXPORTED_PERCENTILES: [f32; 4] = [50., 90., 99., 100.];
 
-}
\ No newline at end of file
+// Summaries are written to the log at this interval, with rates averaged over the whole interval
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default, Clone)]
+struct Counters {
+    presented_frame_count: u64,
+    encoded_frame_count: u64,
+    encoded_bytes: u64,
+    motion_sample_counts: HashMap<TrackedDeviceType, u64>,
+    connection: ConnectionStatistics,
+}
+
+// Counters at the start of an interval and latencies sampled since then
+struct Interval {
+    start: Instant,
+    start_counters: Counters,
+    frame_latencies: Vec<Duration>,
+}
+
+impl Interval {
+    fn new(counters: &Counters) -> Self {
+        Self {
+            start: Instant::now(),
+            start_counters: counters.clone(),
+            frame_latencies: vec![],
+        }
+    }
+}
+
+struct StatisticsState {
+    counters: Counters,
+    microphone: Option<AudioPlayerStatistics>,
+    client: ClientStatistics,
+
+    snapshot_interval: Interval,
+    log_interval: Interval,
+    snapshot: ServerStatistics,
+
+    // Audio statistics at the last log summary, to log only new problems
+    logged_microphone: Option<AudioPlayerStatistics>,
+    logged_game_audio: Option<AudioPlayerStatistics>,
+}
+
+fn rate(count: u64, start_count: u64, interval_s: f32) -> f32 {
+    (count - start_count) as f32 / interval_s
+}
+
+fn bitrate_mbps(bytes: u64, start_bytes: u64, interval_s: f32) -> f32 {
+    rate(bytes, start_bytes, interval_s) * 8. / 1e6
+}
+
+fn latency_percentiles_ms(latencies: &mut [Duration]) -> Vec<(f32, f32)> {
+    if latencies.is_empty() {
+        return vec![];
+    }
+
+    latencies.sort();
+    EXPORTED_PERCENTILES
+        .iter()
+        .map(|&percentile| {
+            let idx = ((latencies.len() - 1) as f32 * percentile / 100.).round() as usize;
+            (percentile, latencies[idx].as_secs_f32() * 1000.)
+        })
+        .collect()
+}
+
+// Ends the interval and starts a new one. The audio and client statistics are left empty.
+fn summarize(counters: &Counters, interval: &mut Interval) -> ServerStatistics {
+    let interval_s = interval.start.elapsed().as_secs_f32();
+    let start_counters = &interval.start_counters;
+
+    let mut motion_rates_hz: Vec<_> = counters
+        .motion_sample_counts
+        .iter()
+        .map(|(device_type, count)| {
+            let start_count = start_counters
+                .motion_sample_counts
+                .get(device_type)
+                .cloned()
+                .unwrap_or(0);
+            (*device_type, rate(*count, start_count, interval_s))
+        })
+        .collect();
+    motion_rates_hz.sort_by_key(|(device_type, _)| *device_type as i32);
+
+    let statistics = ServerStatistics {
+        interval_s,
+        presented_fps: rate(
+            counters.presented_frame_count,
+            start_counters.presented_frame_count,
+            interval_s,
+        ),
+        encoded_fps: rate(
+            counters.encoded_frame_count,
+            start_counters.encoded_frame_count,
+            interval_s,
+        ),
+        video_bitrate_mbps: bitrate_mbps(
+            counters.encoded_bytes,
+            start_counters.encoded_bytes,
+            interval_s,
+        ),
+        sent_bitrate_mbps: bitrate_mbps(
+            counters.connection.sent_bytes,
+            start_counters.connection.sent_bytes,
+            interval_s,
+        ),
+        received_bitrate_mbps: bitrate_mbps(
+            counters.connection.received_bytes,
+            start_counters.connection.received_bytes,
+            interval_s,
+        ),
+        frame_latency_percentiles_ms: latency_percentiles_ms(&mut interval.frame_latencies),
+        motion_rates_hz,
+        presented_frame_count: counters.presented_frame_count,
+        encoded_frame_count: counters.encoded_frame_count,
+        connection: counters.connection.clone(),
+        ..<_>::default()
+    };
+
+    *interval = Interval::new(counters);
+
+    statistics
+}
+
+// Logs only if there were problems since the previous summary
+fn log_audio_statistics(
+    name: &str,
+    statistics: &AudioPlayerStatistics,
+    previous: Option<&AudioPlayerStatistics>,
+) {
+    let (lost_packet_count, late_packet_count, underrun_count) = previous
+        .map(|previous| {
+            (
+                previous.lost_packet_count,
+                previous.late_packet_count,
+                previous.underrun_count,
+            )
+        })
+        .unwrap_or((0, 0, 0));
+
+    if statistics.lost_packet_count > lost_packet_count
+        || statistics.late_packet_count > late_packet_count
+        || statistics.underrun_count > underrun_count
+    {
+        info!(
+            "{}: {} packets, {} lost ({} recovered, {} concealed), {} late, {} underruns",
+            name,
+            statistics.received_packet_count,
+            statistics.lost_packet_count,
+            statistics.recovered_packet_count,
+            statistics.concealed_packet_count,
+            statistics.late_packet_count,
+            statistics.underrun_count
+        );
+    }
+}
+
+fn log_summary(statistics: &ServerStatistics) {
+    let frame_latency = statistics
+        .frame_latency_percentiles_ms
+        .iter()
+        .map(|(percentile, latency)| format!("p{} {:.1}ms", percentile, latency))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let motion_rates = statistics
+        .motion_rates_hz
+        .iter()
+        .map(|(device_type, rate)| format!("{:?} {:.0}Hz", device_type, rate))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    info!(
+        "Last {:.0}s: presented {:.1} fps, encoded {:.1} fps, video {:.1} Mbps, \
+         sent {:.1} Mbps, received {:.2} Mbps, frame latency [{}], motion [{}]",
+        statistics.interval_s,
+        statistics.presented_fps,
+        statistics.encoded_fps,
+        statistics.video_bitrate_mbps,
+        statistics.sent_bitrate_mbps,
+        statistics.received_bitrate_mbps,
+        frame_latency,
+        motion_rates
+    );
+}
+
+// Collects the statistics of a stream. Cloned by the threads that report them: the compositor,
+// which receives the frames presented by the OpenVR frame loop, the video encoders and the
+// connection loop, which also refreshes the snapshot and writes the log summaries.
+#[derive(Clone)]
+pub struct StatisticsAggregator {
+    snapshot_interval: Duration,
+    state: Arc<Mutex<StatisticsState>>,
+}
+
+impl StatisticsAggregator {
+    pub fn new(snapshot_interval: Duration) -> Self {
+        let counters = Counters::default();
+        Self {
+            snapshot_interval,
+            state: Arc::new(Mutex::new(StatisticsState {
+                snapshot_interval: Interval::new(&counters),
+                log_interval: Interval::new(&counters),
+                counters,
+                microphone: None,
+                client: ClientStatistics::default(),
+                snapshot: ServerStatistics::default(),
+                logged_microphone: None,
+                logged_game_audio: None,
+            })),
+        }
+    }
+
+    pub fn report_present(&self) {
+        self.state.lock().counters.presented_frame_count += 1;
+    }
+
+    // To be called for each slice. The frame is counted on the first slice.
+    pub fn report_encoded_slice(&self, slice_idx: u8, size: usize, present_time: Instant) {
+        let latency = present_time.elapsed();
+
+        let mut state = self.state.lock();
+        if slice_idx == 0 {
+            state.counters.encoded_frame_count += 1;
+        }
+        state.counters.encoded_bytes += size as u64;
+        state.snapshot_interval.frame_latencies.push(latency);
+        state.log_interval.frame_latencies.push(latency);
+    }
+
+    pub fn report_motion(&self, device_type: TrackedDeviceType) {
+        *self
+            .state
+            .lock()
+            .counters
+            .motion_sample_counts
+            .entry(device_type)
+            .or_insert(0) += 1;
+    }
+
+    pub fn update_connection(&self, statistics: ConnectionStatistics) {
+        self.state.lock().counters.connection = statistics;
+    }
+
+    pub fn update_microphone(&self, statistics: AudioPlayerStatistics) {
+        self.state.lock().microphone = Some(statistics);
+    }
+
+    pub fn update_client(&self, statistics: ClientStatistics) {
+        self.state.lock().client = statistics;
+    }
+
+    // Refreshes the snapshot and writes the log summary when their intervals elapsed. To be called
+    // at least once per snapshot interval. Returns the new snapshot, if refreshed.
+    pub fn update(&self) -> Option<ServerStatistics> {
+        let state = &mut *self.state.lock();
+
+        let maybe_snapshot = if state.snapshot_interval.start.elapsed() >= self.snapshot_interval {
+            state.snapshot = ServerStatistics {
+                microphone: state.microphone.clone(),
+                client: state.client.clone(),
+                ..summarize(&state.counters, &mut state.snapshot_interval)
+            };
+            Some(state.snapshot.clone())
+        } else {
+            None
+        };
+
+        if state.log_interval.start.elapsed() >= LOG_INTERVAL {
+            log_summary(&summarize(&state.counters, &mut state.log_interval));
+
+            if let Some(statistics) = &state.client.game_audio {
+                log_audio_statistics("Game audio", statistics, state.logged_game_audio.as_ref());
+            }
+            if let Some(statistics) = &state.microphone {
+                log_audio_statistics("Microphone", statistics, state.logged_microphone.as_ref());
+            }
+            state.logged_game_audio = state.client.game_audio.clone();
+            state.logged_microphone = state.microphone.clone();
+        }
+
+        maybe_snapshot
+    }
+
+    // Latest snapshot, refreshed by `update()`
+    pub fn snapshot(&self) -> ServerStatistics {
+        self.state.lock().snapshot.clone()
+    }
+}
@@ -1,12 +1,13 @@
-// use crate::compositor::*;
+// use crate::{compositor::*, statistics::StatisticsAggregator};
 // use bridgevr_common::{
-//     data::VideoEncoderDesc,
+//     data::{FrameStage, VideoEncoderDesc, VideoPacket},
+//     frame_trace::FrameTraceRecorder,
 //     sockets::*,
 //     thread_loop::{self, *},
 //     *,
 // };
 // use log::debug;
-// use std::{sync::mpsc::*, time::Duration};
+// use std::{sync::mpsc::*, time::*};
 
 // const TRACE_CONTEXT: &str = "Video encoder";
 
@@ -26,12 +27,15 @@
 // impl VideoEncoder {
 //     pub fn new(
 //         thread_name: &str,
+//         slice_idx: u8,
 //         settings: VideoEncoderDesc,
 //         resolution: (u32, u32),
 //         frame_rate: u32,
 //         slice_receiver: Receiver<FrameSlice>,
 //         slice_encoded_notif_sender: Sender<()>,
-//         packet_enqueuer: PacketEnqueuer,
+//         mut packet_enqueuer: PacketEnqueuer<VideoSliceStream>,
+//         frame_trace_recorder: Option<FrameTraceRecorder>,
+//         statistics: StatisticsAggregator,
 //     ) -> StrResult<Self> {
 //         // let encode_callback = match settings {
 //         //     VideoEncoderDesc::Nvidia(nv_codec) => {
@@ -44,27 +48,52 @@
 //         // };
 
 //         // let thread_loop = thread_loop::spawn(thread_name, move || {
-//         //     let mut maybe_video_packet = None;
-//         //     frame_consumer
-//         //         .consume(TIMEOUT, |frame_slice| {
-//         //             maybe_video_packet =
-//         //                 encode_callback(frame_slice.texture.clone(), frame_slice.force_idr)
-//         //                     .map_err(|e| debug!("{}", e))
-//         //                     .ok();
-//         //             Ok(())
-//         //         })
-//         //         .map_err(|e| debug!("{:?}", e))
+//         //     let frame_slice = match slice_receiver.recv_timeout(TIMEOUT) {
+//         //         Ok(frame_slice) => frame_slice,
+//         //         Err(_) => return,
+//         //     };
+
+//         //     let encode_start = Instant::now();
+//         //     let maybe_nal = encode_callback(frame_slice.texture.clone(), frame_slice.force_idr)
+//         //         .map_err(|e| debug!("{}", e))
 //         //         .ok();
+//         //     let encode_end = Instant::now();
+
+//         //     // The compositor can reuse the texture
+//         //     slice_encoded_notif_sender.send(()).ok();
 
-//         //     if let Some(video_packet) = maybe_video_packet {
-//         //         packet_producer
-//         //             .fill(TIMEOUT, |sender_data| {
-//         //                 sender_data.packet = video_packet;
-//         //                 // todo fill other fields
-//         //                 Ok(())
+//         //     if let Some(nal) = maybe_nal {
+//         //         // Laminar splits the NAL in fragments
+//         //         packet_enqueuer
+//         //             .enqueue(&VideoPacket {
+//         //                 nal_index: frame_slice.frame_index,
+//         //                 sub_nal_index: 0,
+//         //                 sub_nal_count: 1,
+//         //                 hmd_pose: frame_slice.pose,
+//         //                 capture_timestamp_ns: frame_slice.capture_timestamp_ns,
+//         //                 sub_nal: &nal,
 //         //             })
-//         //             .map_err(|e| debug!("{:?}", e))
+//         //             .map_err(|e| debug!("{}", e))
 //         //             .ok();
+
+//         //         statistics.report_encoded_slice(slice_idx, nal.len(), frame_slice.present_time);
+
+//         //         if let Some(recorder) = &frame_trace_recorder {
+//         //             recorder.record(
+//         //                 frame_slice.frame_index,
+//         //                 FrameStage::Encode,
+//         //                 Some(slice_idx),
+//         //                 encode_start,
+//         //                 encode_end,
+//         //             );
+//         //             recorder.record(
+//         //                 frame_slice.frame_index,
+//         //                 FrameStage::Send,
+//         //                 Some(slice_idx),
+//         //                 encode_end,
+//         //                 Instant::now(),
+//         //             );
+//         //         }
 //         //     }
 //         // })?;
 
@@ -0,0 +1,219 @@
+use bridgevr_common::data::LogLevel;
+use iced::{
+    scrollable, text_input, Align, Color, Column, Element, Length, Radio, Row, Scrollable, Text,
+    TextInput,
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+const MAX_ENTRY_COUNT: usize = 1000;
+
+// A long log is read only from this far from its end, enough to fill the entries
+const MAX_INITIAL_READ_SIZE: u64 = 256 * 1024;
+
+const LEVELS: [(LogLevel, &str); 5] = [
+    (LogLevel::Error, "Error"),
+    (LogLevel::Warning, "Warning"),
+    (LogLevel::Info, "Info"),
+    (LogLevel::Debug, "Debug"),
+    (LogLevel::Trace, "Trace"),
+];
+
+// Lines are formatted by the driver as "<time> [<LEVEL>] <message>"
+fn parse_level(line: &str) -> Option<LogLevel> {
+    match line.split(' ').nth(1)? {
+        "[ERROR]" => Some(LogLevel::Error),
+        "[WARN]" => Some(LogLevel::Warning),
+        "[INFO]" => Some(LogLevel::Info),
+        "[DEBUG]" => Some(LogLevel::Debug),
+        "[TRACE]" => Some(LogLevel::Trace),
+        _ => None,
+    }
+}
+
+pub fn level_color(level: LogLevel) -> Color {
+    match level {
+        LogLevel::Error => Color::from_rgb(0.8, 0.1, 0.1),
+        LogLevel::Warning => Color::from_rgb(0.8, 0.5, 0.),
+        LogLevel::Info => Color::BLACK,
+        LogLevel::Debug | LogLevel::Trace => Color::from_rgb(0.5, 0.5, 0.5),
+    }
+}
+
+struct LogEntry {
+    level: LogLevel,
+    text: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum LogViewEvent {
+    MaxLevelSelected(LogLevel),
+    SearchChanged(String),
+}
+
+// Tails the log file written by the driver
+pub struct LogView {
+    file_path: PathBuf,
+    read_offset: u64,
+    incomplete_line: String,
+    // Set when reading starts in the middle of the file, until the end of the first line is read
+    skip_partial_line: bool,
+    entries: VecDeque<LogEntry>,
+    max_level: LogLevel,
+    search_text: String,
+    search_state: text_input::State,
+    scroll_state: scrollable::State,
+}
+
+impl LogView {
+    pub fn new(file_path: &Path) -> Self {
+        Self {
+            file_path: file_path.to_owned(),
+            read_offset: 0,
+            incomplete_line: String::new(),
+            skip_partial_line: false,
+            entries: VecDeque::new(),
+            max_level: LogLevel::Info,
+            search_text: String::new(),
+            search_state: <_>::default(),
+            scroll_state: <_>::default(),
+        }
+    }
+
+    fn push_line(&mut self, line: &str) {
+        match (parse_level(line), self.entries.back_mut()) {
+            (Some(level), _) => {
+                self.entries.push_back(LogEntry {
+                    level,
+                    text: line.to_owned(),
+                });
+                if self.entries.len() > MAX_ENTRY_COUNT {
+                    self.entries.pop_front();
+                }
+            }
+            // Debug builds write the message on the lines after the header
+            (None, Some(entry)) => {
+                entry.text.push('\n');
+                entry.text.push_str(line);
+            }
+            (None, None) => (),
+        }
+    }
+
+    // Reads the lines appended since the last call. The file can be missing if the driver never
+    // ran.
+    pub fn update(&mut self) {
+        let mut file = if let Ok(file) = File::open(&self.file_path) {
+            file
+        } else {
+            return;
+        };
+
+        let file_size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if file_size < self.read_offset {
+            // The file was deleted and created again
+            self.read_offset = 0;
+            self.incomplete_line.clear();
+            self.skip_partial_line = false;
+            self.entries.clear();
+        }
+
+        if self.read_offset == 0 && file_size > MAX_INITIAL_READ_SIZE {
+            // Reading starts one byte early, so a line that begins exactly at the tail is kept
+            self.read_offset = file_size - MAX_INITIAL_READ_SIZE - 1;
+            self.skip_partial_line = true;
+        }
+
+        let mut buffer = vec![];
+        if file.seek(SeekFrom::Start(self.read_offset)).is_err()
+            || file.read_to_end(&mut buffer).is_err()
+        {
+            return;
+        }
+        self.read_offset += buffer.len() as u64;
+
+        let mut buffer = &buffer[..];
+        if self.skip_partial_line {
+            if let Some(idx) = buffer.iter().position(|&byte| byte == b'\n') {
+                buffer = &buffer[idx + 1..];
+                self.skip_partial_line = false;
+            } else {
+                return;
+            }
+        }
+
+        let text = format!(
+            "{}{}",
+            self.incomplete_line,
+            String::from_utf8_lossy(buffer)
+        );
+        let mut lines: Vec<_> = text.split('\n').collect();
+
+        // The last line is complete only if the text ends with a newline
+        self.incomplete_line = lines.pop().unwrap_or("").to_owned();
+        for line in lines {
+            self.push_line(line.trim_end_matches('\r'));
+        }
+    }
+
+    pub fn handle_event(&mut self, event: LogViewEvent) {
+        match event {
+            LogViewEvent::MaxLevelSelected(level) => self.max_level = level,
+            LogViewEvent::SearchChanged(text) => self.search_text = text,
+        }
+    }
+
+    pub fn view(&mut self) -> Element<LogViewEvent> {
+        let mut level_radios = Row::new()
+            .spacing(10)
+            .align_items(Align::Center)
+            .push(Text::new("Level:"));
+        for (level, label) in &LEVELS {
+            level_radios = level_radios.push(Radio::new(
+                *level,
+                *label,
+                Some(self.max_level),
+                LogViewEvent::MaxLevelSelected,
+            ));
+        }
+
+        let search_input = TextInput::new(
+            &mut self.search_state,
+            "Search",
+            &self.search_text,
+            LogViewEvent::SearchChanged,
+        )
+        .padding(5)
+        .width(Length::Units(300));
+
+        let search_text = self.search_text.to_lowercase();
+        let max_level = self.max_level;
+
+        // iced cannot scroll to the bottom programmatically, so the newest entries are on top
+        let mut entries = Scrollable::new(&mut self.scroll_state)
+            .width(Length::Fill)
+            .height(Length::Fill);
+        for entry in self.entries.iter().rev().filter(|entry| {
+            entry.level <= max_level
+                && (search_text.is_empty() || entry.text.to_lowercase().contains(&search_text))
+        }) {
+            entries = entries.push(Text::new(&entry.text).color(level_color(entry.level)));
+        }
+
+        Column::new()
+            .spacing(10)
+            .push(
+                Row::new()
+                    .spacing(20)
+                    .align_items(Align::Center)
+                    .push(level_radios)
+                    .push(search_input),
+            )
+            .push(entries)
+            .into()
+    }
+}
@@ -1,16 +1,21 @@
+mod log_view;
+mod monitor;
 mod settings;
+mod time;
 
 use iced::{
-    button, checkbox, scrollable, Align, Button, Column, Container, Element, Length, Row, Sandbox,
-    Scrollable, Settings, Space, Text, TextInput,
+    button, checkbox, executor, scrollable, Align, Application, Button, Column, Command, Container,
+    Element, Length, Row, Scrollable, Settings, Space, Subscription, Text, TextInput,
 };
+use monitor::*;
+use std::{path::Path, time::*};
 
 const BVR_SERVER_VERSION: &str = env!("BVR_SERVER_VERSION");
 
-enum MonitorMode {
-    Events,
-    Log,
-}
+// The driver and the GUI are in the same folder, which is the working directory of the GUI
+const LOG_PATH: &str = "./log.txt";
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
 
 enum SettingsViewMode {
     Basic,
@@ -18,7 +23,7 @@ enum SettingsViewMode {
     Text { save_button_state: button::State },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Tab {
     Monitor,
     Settings,
@@ -48,22 +53,28 @@ enum Event {
     Request(Action),
     MessageBoxOk,
     MessageBoxCancel,
+    Update(Instant),
+    Monitor(MonitorEvent),
 }
 
 struct Gui {
     selected_tab: Tab,
-    monitor_mode: MonitorMode,
+    tab_button_states: [button::State; 3],
+    monitor: Monitor,
     settings_view_mode: SettingsViewMode,
     message_box: Option<MessageBox>,
 }
 
-impl Sandbox for Gui {
+impl Application for Gui {
+    type Executor = executor::Default;
     type Message = Event;
+    type Flags = ();
 
-    fn new() -> Self {
-        Self {
+    fn new(_: ()) -> (Self, Command<Event>) {
+        let gui = Self {
             selected_tab: Tab::Monitor,
-            monitor_mode: MonitorMode::Events,
+            tab_button_states: <_>::default(),
+            monitor: Monitor::new(Path::new(LOG_PATH)),
             settings_view_mode: SettingsViewMode::Basic,
             message_box: Some(MessageBox {
                 icon_type: MessageBoxIconType::Info,
@@ -73,30 +84,44 @@ impl Sandbox for Gui {
                 do_not_show_again_checkbox_checked: None,
                 ok_action: None,
             }),
-        }
+        };
+
+        (gui, Command::none())
     }
 
     fn title(&self) -> String {
         format!("BridgeVR v{}", BVR_SERVER_VERSION)
     }
 
-    fn update(&mut self, event: Event) {
+    fn update(&mut self, event: Event) -> Command<Event> {
         match event {
             Event::MessageBoxOk => {
                 settings::generate_default_settings();
                 std::fs::write("./settings.json", settings::generate_default_settings()).unwrap();
+                self.message_box = None;
             }
-            Event::MessageBoxCancel => (),
+            Event::MessageBoxCancel => self.message_box = None,
+            Event::TabSelected(tab) => self.selected_tab = tab,
+            Event::Update(_) => self.monitor.update(),
+            Event::Monitor(event) => self.monitor.handle_event(event),
             _ => (),
         }
+
+        Command::none()
+    }
+
+    // The monitor is updated also while other tabs are shown, so no event is missed
+    fn subscription(&self) -> Subscription<Event> {
+        time::every(UPDATE_INTERVAL).map(Event::Update)
     }
 
     fn view(&mut self) -> Element<Event> {
         if let Some(message_box) = &mut self.message_box {
             let mut buttons = Row::new().spacing(10);
             if let Some(state) = &mut message_box.cancel_button_state {
-                buttons = buttons
-                    .push(Button::new(state, Text::new("Cancel")).on_press(Event::MessageBoxOk));
+                buttons = buttons.push(
+                    Button::new(state, Text::new("Cancel")).on_press(Event::MessageBoxCancel),
+                );
             }
             buttons = buttons.push(
                 Button::new(&mut message_box.ok_button_state, Text::new("Ok"))
@@ -115,7 +140,38 @@ impl Sandbox for Gui {
             .center_y()
             .into()
         } else {
-            todo!()
+            let mut tab_buttons = Row::new().spacing(10);
+            for ((tab, label), state) in [
+                (Tab::Monitor, "Monitor"),
+                (Tab::Settings, "Settings"),
+                (Tab::About, "About"),
+            ]
+            .iter()
+            .zip(&mut self.tab_button_states)
+            {
+                // The button of the selected tab is disabled
+                let mut button = Button::new(state, Text::new(*label));
+                if *tab != self.selected_tab {
+                    button = button.on_press(Event::TabSelected(*tab));
+                }
+                tab_buttons = tab_buttons.push(button);
+            }
+
+            let content = match self.selected_tab {
+                Tab::Monitor => self.monitor.view().map(Event::Monitor),
+                Tab::Settings => {
+                    Text::new("Edit settings.json, then reload the settings from the monitor tab")
+                        .into()
+                }
+                Tab::About => Text::new(format!("BridgeVR server v{}", BVR_SERVER_VERSION)).into(),
+            };
+
+            Column::new()
+                .padding(20)
+                .spacing(20)
+                .push(tab_buttons)
+                .push(content)
+                .into()
         }
     }
 }
@@ -0,0 +1,378 @@
+use crate::log_view::*;
+use bridgevr_common::{data::*, ipc::*};
+use iced::{
+    button,
+    canvas::{self, layer::Cache, Canvas},
+    Align, Button, Color, Column, Element, Length, Point, Row, Text,
+};
+use std::{
+    collections::VecDeque,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_GRAPH_SAMPLE_COUNT: usize = 120;
+const GRAPH_HEIGHT: u16 = 60;
+const GRAPH_COLOR: Color = Color {
+    r: 0.1,
+    g: 0.4,
+    b: 0.8,
+    a: 1.,
+};
+
+const MAX_NOTIFICATION_COUNT: usize = 10;
+
+const COMMANDS: [(DriverCommand, &str); 4] = [
+    (DriverCommand::DisconnectClient, "Disconnect client"),
+    (DriverCommand::ForceIdr, "Force IDR frame"),
+    (DriverCommand::ReloadSettings, "Reload settings"),
+    (DriverCommand::Recenter, "Recenter"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonitorMode {
+    Events,
+    Log,
+}
+
+#[derive(Debug, Clone)]
+pub enum MonitorEvent {
+    ModeSelected(MonitorMode),
+    Command(DriverCommand),
+    Log(LogViewEvent),
+}
+
+struct Plot {
+    samples: VecDeque<f32>,
+}
+
+impl canvas::Drawable for Plot {
+    fn draw(&self, frame: &mut canvas::Frame) {
+        let width = frame.width();
+        let height = frame.height();
+
+        // Leave some headroom above the highest sample
+        let max_value = self.samples.iter().cloned().fold(0_f32, f32::max) * 1.2;
+        let y_scale = if max_value > 0. {
+            height / max_value
+        } else {
+            0.
+        };
+        let x_step = width / (MAX_GRAPH_SAMPLE_COUNT - 1) as f32;
+
+        let path = canvas::Path::new(|builder| {
+            for (idx, value) in self.samples.iter().enumerate() {
+                let point = Point::new(idx as f32 * x_step, height - value * y_scale);
+                if idx == 0 {
+                    builder.move_to(point);
+                } else {
+                    builder.line_to(point);
+                }
+            }
+        });
+
+        frame.stroke(
+            &path,
+            canvas::Stroke {
+                color: GRAPH_COLOR,
+                width: 2.,
+                ..<_>::default()
+            },
+        );
+    }
+}
+
+// Plots a value of the latest statistics snapshots
+struct StatisticsGraph {
+    label: &'static str,
+    unit: &'static str,
+    get_value: fn(&ServerStatistics) -> f32,
+    plot: Plot,
+    cache: Cache<Plot>,
+}
+
+impl StatisticsGraph {
+    fn new(
+        label: &'static str,
+        unit: &'static str,
+        get_value: fn(&ServerStatistics) -> f32,
+    ) -> Self {
+        Self {
+            label,
+            unit,
+            get_value,
+            plot: Plot {
+                samples: VecDeque::new(),
+            },
+            cache: Cache::new(),
+        }
+    }
+
+    fn push(&mut self, statistics: &ServerStatistics) {
+        self.plot.samples.push_back((self.get_value)(statistics));
+        if self.plot.samples.len() > MAX_GRAPH_SAMPLE_COUNT {
+            self.plot.samples.pop_front();
+        }
+        self.cache.clear();
+    }
+
+    fn clear(&mut self) {
+        self.plot.samples.clear();
+        self.cache.clear();
+    }
+
+    fn view(&self) -> Element<MonitorEvent> {
+        let value = self.plot.samples.back().cloned().unwrap_or(0.);
+        Column::new()
+            .spacing(5)
+            .push(Text::new(format!(
+                "{}: {:.1} {}",
+                self.label, value, self.unit
+            )))
+            .push(
+                Canvas::new()
+                    .width(Length::Fill)
+                    .height(Length::Units(GRAPH_HEIGHT))
+                    .push(self.cache.with(&self.plot)),
+            )
+            .into()
+    }
+}
+
+fn frame_latency_percentile_ms(statistics: &ServerStatistics, percentile: f32) -> f32 {
+    statistics
+        .frame_latency_percentiles_ms
+        .iter()
+        .find(|(p, _)| *p == percentile)
+        .map(|(_, latency_ms)| *latency_ms)
+        .unwrap_or(0.)
+}
+
+fn client_info_view<'a>(client: &ConnectedClientDesc) -> Element<'a, MonitorEvent> {
+    let handshake_packet = &client.handshake_packet;
+    let (native_width, native_height) = handshake_packet.native_eye_resolution;
+    let (target_width, target_height) = client.target_eye_resolution;
+
+    let mut info = Column::new()
+        .spacing(5)
+        .push(Text::new(format!(
+            "Client: {} v{} ({})",
+            handshake_packet.bridgevr_name, handshake_packet.version, client.address
+        )))
+        .push(Text::new(format!(
+            "Eye resolution: {}x{} native, {}x{} streamed",
+            native_width, native_height, target_width, target_height
+        )))
+        .push(Text::new(format!(
+            "Refresh rate: {} Hz",
+            handshake_packet.fps
+        )));
+    for (eye, fov) in ["Left", "Right"].iter().zip(&handshake_packet.fov) {
+        info = info.push(Text::new(format!(
+            "{} eye FOV: left {:.1}°, right {:.1}°, top {:.1}°, bottom {:.1}°",
+            eye, fov.left, fov.right, fov.top, fov.bottom
+        )));
+    }
+
+    info.into()
+}
+
+// Connection state, statistics and notifications received from the driver
+struct EventsView {
+    // None if the driver is not running
+    maybe_connection_state: Option<ConnectionState>,
+
+    graphs: Vec<StatisticsGraph>,
+
+    // Latest warnings and errors of the driver
+    notifications: VecDeque<(LogLevel, String)>,
+
+    command_button_states: [button::State; 4],
+}
+
+impl EventsView {
+    fn new() -> Self {
+        Self {
+            maybe_connection_state: None,
+            graphs: vec![
+                StatisticsGraph::new("Encoded frame rate", "FPS", |s| s.encoded_fps),
+                StatisticsGraph::new("Sent bitrate", "Mbps", |s| s.sent_bitrate_mbps),
+                StatisticsGraph::new("Frame latency (99th percentile)", "ms", |s| {
+                    frame_latency_percentile_ms(s, 99.)
+                }),
+            ],
+            notifications: VecDeque::new(),
+            command_button_states: <_>::default(),
+        }
+    }
+
+    fn handle_driver_event(&mut self, event: DriverEvent) {
+        match event {
+            DriverEvent::ConnectionState(state) => {
+                // The graphs show one stream at a time
+                if let ConnectionState::Connected(_) = &state {
+                    for graph in &mut self.graphs {
+                        graph.clear();
+                    }
+                }
+                self.maybe_connection_state = Some(state);
+            }
+            DriverEvent::Statistics(statistics) => {
+                for graph in &mut self.graphs {
+                    graph.push(&statistics);
+                }
+            }
+            DriverEvent::Log { level, message } => {
+                if level <= LogLevel::Warning {
+                    self.notifications.push_back((level, message));
+                    if self.notifications.len() > MAX_NOTIFICATION_COUNT {
+                        self.notifications.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    fn view(&mut self) -> Element<MonitorEvent> {
+        let (status, maybe_client) = match &self.maybe_connection_state {
+            None => ("BridgeVR driver not running", None),
+            Some(ConnectionState::Searching) => ("Searching for a client", None),
+            Some(ConnectionState::Connected(client)) => ("Client connected", Some(client.as_ref())),
+        };
+
+        let mut content = Column::new().spacing(15).push(Text::new(status).size(24));
+        if let Some(client) = maybe_client {
+            content = content.push(client_info_view(client));
+        }
+
+        let mut command_buttons = Row::new().spacing(10);
+        for ((command, label), state) in COMMANDS.iter().zip(&mut self.command_button_states) {
+            let enabled = match command {
+                DriverCommand::ReloadSettings => self.maybe_connection_state.is_some(),
+                _ => maybe_client.is_some(),
+            };
+            let mut button = Button::new(state, Text::new(*label));
+            if enabled {
+                button = button.on_press(MonitorEvent::Command(*command));
+            }
+            command_buttons = command_buttons.push(button);
+        }
+        content = content.push(command_buttons);
+
+        for graph in &self.graphs {
+            content = content.push(graph.view());
+        }
+
+        if !self.notifications.is_empty() {
+            let mut notifications = Column::new()
+                .spacing(5)
+                .push(Text::new("Latest warnings").size(24));
+            for (level, message) in self.notifications.iter().rev() {
+                notifications = notifications.push(Text::new(message).color(level_color(*level)));
+            }
+            content = content.push(notifications);
+        }
+
+        content.into()
+    }
+}
+
+pub struct Monitor {
+    mode: MonitorMode,
+    events_button_state: button::State,
+    log_button_state: button::State,
+    maybe_ipc_client: Option<IpcClient>,
+    last_connection_attempt: Option<Instant>,
+    events_view: EventsView,
+    log_view: LogView,
+}
+
+impl Monitor {
+    pub fn new(log_path: &Path) -> Self {
+        Self {
+            mode: MonitorMode::Events,
+            events_button_state: <_>::default(),
+            log_button_state: <_>::default(),
+            maybe_ipc_client: None,
+            last_connection_attempt: None,
+            events_view: EventsView::new(),
+            log_view: LogView::new(log_path),
+        }
+    }
+
+    fn disconnect_driver(&mut self) {
+        self.maybe_ipc_client = None;
+        self.events_view.maybe_connection_state = None;
+    }
+
+    // Polls the driver and the log file. The driver can be started and stopped at any time.
+    pub fn update(&mut self) {
+        if self.maybe_ipc_client.is_none()
+            && self
+                .last_connection_attempt
+                .map(|instant| instant.elapsed() >= RECONNECT_INTERVAL)
+                .unwrap_or(true)
+        {
+            self.last_connection_attempt = Some(Instant::now());
+            self.maybe_ipc_client = IpcClient::connect(DRIVER_IPC_NAME).ok();
+        }
+
+        if let Some(client) = &self.maybe_ipc_client {
+            match client.try_recv_events() {
+                Ok(events) => {
+                    for event in events {
+                        self.events_view.handle_driver_event(event);
+                    }
+                }
+                Err(_) => self.disconnect_driver(),
+            }
+        }
+
+        self.log_view.update();
+    }
+
+    pub fn handle_event(&mut self, event: MonitorEvent) {
+        match event {
+            MonitorEvent::ModeSelected(mode) => self.mode = mode,
+            MonitorEvent::Command(command) => {
+                if let Some(client) = &self.maybe_ipc_client {
+                    if client.send_command(command).is_err() {
+                        self.disconnect_driver();
+                    }
+                }
+            }
+            MonitorEvent::Log(event) => self.log_view.handle_event(event),
+        }
+    }
+
+    pub fn view(&mut self) -> Element<MonitorEvent> {
+        // The button of the selected mode is disabled
+        let mut events_button = Button::new(&mut self.events_button_state, Text::new("Events"));
+        let mut log_button = Button::new(&mut self.log_button_state, Text::new("Log"));
+        let content = match self.mode {
+            MonitorMode::Events => {
+                log_button = log_button.on_press(MonitorEvent::ModeSelected(MonitorMode::Log));
+                self.events_view.view()
+            }
+            MonitorMode::Log => {
+                events_button =
+                    events_button.on_press(MonitorEvent::ModeSelected(MonitorMode::Events));
+                self.log_view.view().map(MonitorEvent::Log)
+            }
+        };
+
+        Column::new()
+            .spacing(10)
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .align_items(Align::Center)
+                    .push(events_button)
+                    .push(log_button),
+            )
+            .push(content)
+            .into()
+    }
+}
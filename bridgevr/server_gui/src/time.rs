@@ -0,0 +1,39 @@
+use iced::{
+    futures::{channel::mpsc, stream::BoxStream, StreamExt},
+    Subscription,
+};
+use std::{
+    any::TypeId,
+    hash::{Hash, Hasher},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Emits the current time at a fixed interval
+pub fn every(interval: Duration) -> Subscription<Instant> {
+    Subscription::from_recipe(Every(interval))
+}
+
+struct Every(Duration);
+
+impl<H: Hasher, I> iced_native::subscription::Recipe<H, I> for Every {
+    type Output = Instant;
+
+    fn hash(&self, state: &mut H) {
+        TypeId::of::<Self>().hash(state);
+        self.0.hash(state);
+    }
+
+    // The timer thread exits when the subscription is dropped
+    fn stream(self: Box<Self>, _: BoxStream<'static, I>) -> BoxStream<'static, Instant> {
+        let (sender, receiver) = mpsc::unbounded();
+        let interval = self.0;
+        thread::spawn(move || {
+            while sender.unbounded_send(Instant::now()).is_ok() {
+                thread::sleep(interval);
+            }
+        });
+
+        receiver.boxed()
+    }
+}